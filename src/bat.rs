@@ -1,7 +1,7 @@
 use bitvec::view::BitView;
 use bitvec::{field::BitField, prelude::Lsb0};
 
-use crate::{error::VhdxError, meta_data::SectorSize, DeSerialise};
+use crate::{error::VhdxError, meta_data::SectorSize, vhdx::Vhdx, DeSerialise, Serialise};
 
 #[allow(dead_code)]
 pub struct BatTable {
@@ -9,18 +9,26 @@ pub struct BatTable {
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BatEntry {
     state: BatEntryState,
     file_offset_mb: usize,
 }
 impl BatEntry {
-    fn new(state: BatEntryState, file_offset_mb: usize) -> BatEntry {
+    pub(crate) fn new(state: BatEntryState, file_offset_mb: usize) -> BatEntry {
         Self {
             state,
             file_offset_mb,
         }
     }
+
+    pub fn state(&self) -> BatEntryState {
+        self.state
+    }
+
+    pub fn file_offset(&self) -> u64 {
+        self.file_offset_mb as u64 * Vhdx::MB
+    }
 }
 
 impl<T> DeSerialise<T> for BatEntry {
@@ -42,7 +50,18 @@ impl<T> DeSerialise<T> for BatEntry {
     }
 }
 
-#[derive(Debug)]
+impl<T> Serialise<T> for BatEntry {
+    fn serialize(&self, writer: &mut T) -> Result<(), VhdxError>
+    where
+        T: std::io::Write + std::io::Seek,
+    {
+        let value = self.state.to_bits() as u64 | ((self.file_offset_mb as u64) << 20);
+        writer.write_all(&value.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BatEntryState {
     NotPresent = 0,
     Undefined = 1,
@@ -65,6 +84,40 @@ impl BatEntryState {
             _ => BatEntryState::Unknown,
         }
     }
+
+    fn to_bits(self) -> u8 {
+        match self {
+            BatEntryState::Unknown => BatEntryState::Undefined as u8,
+            state => state as u8,
+        }
+    }
+
+    // Whether a payload block in this state has its data stored in the file.
+    pub(crate) fn is_stored(self) -> bool {
+        matches!(
+            self,
+            BatEntryState::FullyPresent | BatEntryState::PartiallyPresent
+        )
+    }
+}
+
+// Every chunk_ratio payload block entries in the BAT are followed by the sector bitmap block entry
+// covering them, so the index of a payload entry is shifted by the bitmap entries in front of it.
+pub(crate) fn payload_bat_index(block: u64, chunk_ratio: u64) -> usize {
+    (block + block / chunk_ratio) as usize
+}
+
+// The sector bitmap block entry of the chunk the payload block belongs to.
+pub(crate) fn sector_bitmap_bat_index(block: u64, chunk_ratio: u64) -> usize {
+    ((block / chunk_ratio) * (chunk_ratio + 1) + chunk_ratio) as usize
+}
+
+// The payload block a BAT entry belongs to, None for sector bitmap block entries.
+pub(crate) fn payload_block(index: u64, chunk_ratio: u64) -> Option<u64> {
+    if (index + 1).is_multiple_of(chunk_ratio + 1) {
+        return None;
+    }
+    Some(index - index / (chunk_ratio + 1))
 }
 
 pub(crate) fn calc_chunk_ratio(sector_size: SectorSize, block_size: usize) -> u64 {
@@ -86,7 +139,8 @@ pub(crate) fn calc_total_bat_entries_fixed_dynamic(
     payload_blocks_count: u64,
     chunk_ratio: u64,
 ) -> u64 {
-    ((payload_blocks_count - 1) as f64 / chunk_ratio as f64).floor() as u64 + payload_blocks_count
+    (payload_blocks_count.saturating_sub(1) as f64 / chunk_ratio as f64).floor() as u64
+        + payload_blocks_count
 }
 
 pub(crate) fn calc_total_bat_entries_differencing(
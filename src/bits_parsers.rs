@@ -4,32 +4,55 @@ use crate::error::VhdxParseError;
 
 pub type BitInput<'a> = (&'a [u8], usize);
 
-pub fn t_3_flags_u32(
-    input: BitInput,
-) -> IResult<BitInput, (bool, bool, bool), VhdxParseError<(&[u8], usize)>> {
+pub type BitResult<'a, O> = IResult<BitInput<'a>, O, VhdxParseError<BitInput<'a>>>;
+
+pub fn t_3_flags_u32(input: BitInput<'_>) -> BitResult<'_, (bool, bool, bool)> {
     map(
         tuple((take(5usize), t_flag_u8, t_flag_u8, t_flag_u8)),
         |(_, a, b, c): (u8, bool, bool, bool)| (c, b, a),
     )(input)
 }
 
-pub fn t_2_flags_u32(
-    input: BitInput,
-) -> IResult<BitInput, (bool, bool), VhdxParseError<(&[u8], usize)>> {
+// The two lowest bits of the first byte, bit 0 first. Bits are taken from the most significant
+// one, so the six above them are skipped.
+pub fn t_2_flags_u32(input: BitInput<'_>) -> BitResult<'_, (bool, bool)> {
     map(
-        tuple((take(4usize), t_flag_u8, t_flag_u8)),
+        tuple((take(6usize), t_flag_u8, t_flag_u8)),
         |(_, b, a): (u8, bool, bool)| (a, b),
     )(input)
 }
 
-pub fn t_flag_u8(i: BitInput) -> IResult<BitInput, bool, VhdxParseError<(&[u8], usize)>> {
+pub fn t_flag_u8(i: BitInput<'_>) -> BitResult<'_, bool> {
     map(take(1usize), |bits: u8| bits > 0)(i)
 }
 
-pub fn t_reserved(i: BitInput, length: usize) -> IResult<BitInput, usize> {
+pub fn t_reserved(i: BitInput<'_>, length: usize) -> IResult<BitInput<'_>, usize> {
     take(length)(i)
 }
 
-pub fn t_file_offset(i: BitInput) -> IResult<BitInput, usize> {
+pub fn t_file_offset(i: BitInput<'_>) -> IResult<BitInput<'_>, usize> {
     take(44usize)(i)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::bits::bits;
+    use pretty_assertions::assert_eq;
+
+    fn two_flags(bytes: &[u8]) -> (bool, bool) {
+        bits::<_, _, VhdxParseError<BitInput>, VhdxParseError<&[u8]>, _>(t_2_flags_u32)(bytes)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn should_read_the_two_lowest_bits() {
+        assert_eq!((true, false), two_flags(&[0b01, 0, 0, 0]));
+        assert_eq!((false, true), two_flags(&[0b10, 0, 0, 0]));
+        assert_eq!((true, true), two_flags(&[0b11, 0, 0, 0]));
+
+        // Bits 2 and 3, read as the flags by mistake before, are reserved.
+        assert_eq!((false, false), two_flags(&[0b1100, 0, 0, 0]));
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use crate::{
+    error::VhdxError,
+    image_store::{FileSystem, ImageStore},
+    parent_resolver::{ParentResolver, ResolvedBy, ResolvedParent},
+    vhdx::Vhdx,
+};
+
+#[derive(Debug)]
+pub struct ChainReport {
+    // One link per differencing disk, from the leaf down to the base disk or to the first parent
+    // that couldn't be opened.
+    pub links: Vec<LinkReport>,
+}
+
+#[derive(Debug)]
+pub struct LinkReport {
+    pub child: PathBuf,
+
+    // The parent the locator led to, or why there is none.
+    pub parent: Result<ResolvedParent, VhdxError>,
+
+    // How the child and its parent compare, once the parent could be opened.
+    pub checks: Option<LinkChecks>,
+}
+
+// How the child refers to its parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linkage {
+    // The parent_linkage of the child is the DataWriteGuid of the parent.
+    ParentLinkage,
+
+    // Only the parent_linkage2 of the child is.
+    ParentLinkage2,
+
+    // The parent has been written to since the child was created, or isn't its parent at all.
+    Mismatch,
+
+    // The locator has no parent_linkage, nothing tells whether the parent is the right one.
+    Missing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkChecks {
+    pub linkage: Linkage,
+    pub parent_linkage: Option<Uuid>,
+    pub parent_linkage2: Option<Uuid>,
+    pub parent_data_write_guid: Uuid,
+    pub child_virtual_size: u64,
+    pub parent_virtual_size: u64,
+    pub child_logical_sector_size: u32,
+    pub parent_logical_sector_size: u32,
+    pub child_block_size: u64,
+    pub parent_block_size: u64,
+}
+
+impl Vhdx {
+    // Follows the parents of the disk at the path and reports how every link of the chain holds.
+    pub fn verify_chain(leaf: &impl AsRef<Path>) -> Result<ChainReport, VhdxError> {
+        Vhdx::verify_chain_with_resolver(leaf, &ParentResolver::default())
+    }
+
+    pub fn verify_chain_with_resolver(
+        leaf: &impl AsRef<Path>,
+        resolver: &ParentResolver,
+    ) -> Result<ChainReport, VhdxError> {
+        Vhdx::verify_chain_in(&FileSystem, leaf, resolver)
+    }
+
+    // Disks are opened read only and their logs aren't replayed, the report is about what they
+    // hold in the store. Only failing to open the leaf is an error, every other problem is
+    // reported on the link it breaks, which ends the chain.
+    pub fn verify_chain_in<S>(
+        store: &S,
+        leaf: &impl AsRef<Path>,
+        resolver: &ParentResolver,
+    ) -> Result<ChainReport, VhdxError>
+    where
+        S: ImageStore,
+    {
+        let mut path = leaf.as_ref().to_path_buf();
+        let mut child = Vhdx::from_reader(store.open(&path, false)?)?;
+        let mut visited = BTreeSet::from([store.canonicalize(&path)?]);
+        let mut links = Vec::new();
+
+        while let Some(locator) = &child.meta_data.parent_locator {
+            let opened = resolver
+                .resolve_in(store, &path, locator)
+                .and_then(|resolved| {
+                    if !visited.insert(store.canonicalize(&resolved.path)?) {
+                        return Err(VhdxError::ParentCycle(
+                            resolved.path.to_string_lossy().into_owned(),
+                        ));
+                    }
+                    let parent = Vhdx::from_reader(store.open(&resolved.path, false)?)?;
+                    Ok((resolved, parent))
+                });
+            let (resolved, parent) = match opened {
+                Ok(opened) => opened,
+                Err(error) => {
+                    links.push(LinkReport {
+                        child: path,
+                        parent: Err(error),
+                        checks: None,
+                    });
+                    break;
+                }
+            };
+
+            links.push(LinkReport {
+                child: path,
+                parent: Ok(resolved.clone()),
+                checks: Some(LinkChecks::new(&child, &parent)),
+            });
+            path = resolved.path;
+            child = parent;
+        }
+        Ok(ChainReport { links })
+    }
+}
+
+impl ChainReport {
+    // Whether the chain reads the way it was written: every parent found, linked to and of the
+    // same geometry as its child.
+    pub fn is_valid(&self) -> bool {
+        self.links.iter().all(LinkReport::is_valid)
+    }
+
+    pub fn to_json(&self) -> String {
+        let links: Vec<String> = self.links.iter().map(LinkReport::to_json).collect();
+        format!(
+            r#"{{"valid":{},"links":[{}]}}"#,
+            self.is_valid(),
+            links.join(",")
+        )
+    }
+}
+
+impl LinkReport {
+    pub fn is_valid(&self) -> bool {
+        self.checks.as_ref().is_some_and(LinkChecks::is_valid)
+    }
+
+    fn to_json(&self) -> String {
+        let mut json = format!(
+            r#"{{"valid":{},"child":{}"#,
+            self.is_valid(),
+            json_string(&self.child.to_string_lossy())
+        );
+        match &self.parent {
+            Ok(resolved) => {
+                let (resolved_by, directory) = match &resolved.resolved_by {
+                    ResolvedBy::RelativePath => ("relative_path", None),
+                    ResolvedBy::VolumePath => ("volume_path", None),
+                    ResolvedBy::AbsoluteWin32Path => ("absolute_win32_path", None),
+                    ResolvedBy::SearchDirectory(directory) => ("search_directory", Some(directory)),
+                };
+                let _ = write!(
+                    json,
+                    r#","parent":{},"resolved_by":"{}","search_directory":{},"error":null"#,
+                    json_string(&resolved.path.to_string_lossy()),
+                    resolved_by,
+                    directory.map_or("null".to_string(), |directory| json_string(
+                        &directory.to_string_lossy()
+                    ))
+                );
+            }
+            Err(error) => {
+                let _ = write!(
+                    json,
+                    r#","parent":null,"resolved_by":null,"search_directory":null,"error":{}"#,
+                    json_string(&error.to_string())
+                );
+            }
+        }
+        match &self.checks {
+            Some(checks) => {
+                let _ = write!(json, r#","checks":{}}}"#, checks.to_json());
+            }
+            None => json.push_str(r#","checks":null}"#),
+        }
+        json
+    }
+}
+
+impl LinkChecks {
+    pub fn new<C, P>(child: &Vhdx<C>, parent: &Vhdx<P>) -> Self
+    where
+        C: Read + Seek,
+        P: Read + Seek,
+    {
+        let locator = child.meta_data.parent_locator.as_ref();
+        let parent_linkage = locator.and_then(|locator| locator.parent_linkage());
+        let parent_linkage2 = locator.and_then(|locator| locator.parent_linkage2());
+        let parent_data_write_guid = parent.header().data_write_guid();
+        let linkage = if parent_linkage.is_none() {
+            Linkage::Missing
+        } else if parent_linkage == Some(parent_data_write_guid) {
+            Linkage::ParentLinkage
+        } else if parent_linkage2 == Some(parent_data_write_guid) {
+            Linkage::ParentLinkage2
+        } else {
+            Linkage::Mismatch
+        };
+
+        Self {
+            linkage,
+            parent_linkage,
+            parent_linkage2,
+            parent_data_write_guid,
+            child_virtual_size: child.virtual_disk_size(),
+            parent_virtual_size: parent.virtual_disk_size(),
+            child_logical_sector_size: child.meta_data.logical_sector_size as u32,
+            parent_logical_sector_size: parent.meta_data.logical_sector_size as u32,
+            child_block_size: child.block_size(),
+            parent_block_size: parent.block_size(),
+        }
+    }
+
+    pub fn is_linked(&self) -> bool {
+        matches!(
+            self.linkage,
+            Linkage::ParentLinkage | Linkage::ParentLinkage2
+        )
+    }
+
+    // A child smaller than its parent hides some of its sectors. A larger one reads zeros past
+    // the end of the parent, the way a disk grown after its parent was does.
+    pub fn virtual_size_compatible(&self) -> bool {
+        self.child_virtual_size >= self.parent_virtual_size
+    }
+
+    pub fn logical_sector_size_compatible(&self) -> bool {
+        self.child_logical_sector_size == self.parent_logical_sector_size
+    }
+
+    // Not a problem on its own, the chain reads the same, but worth knowing about.
+    pub fn block_sizes_differ(&self) -> bool {
+        self.child_block_size != self.parent_block_size
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.is_linked() && self.virtual_size_compatible() && self.logical_sector_size_compatible()
+    }
+
+    fn to_json(self) -> String {
+        let linkage = match self.linkage {
+            Linkage::ParentLinkage => "parent_linkage",
+            Linkage::ParentLinkage2 => "parent_linkage2",
+            Linkage::Mismatch => "mismatch",
+            Linkage::Missing => "missing",
+        };
+        let guid =
+            |guid: Option<Uuid>| guid.map_or("null".to_string(), |guid| format!(r#""{}""#, guid));
+        format!(
+            concat!(
+                r#"{{"linkage":"{}","parent_linkage":{},"parent_linkage2":{},"#,
+                r#""parent_data_write_guid":"{}","virtual_size_compatible":{},"#,
+                r#""child_virtual_size":{},"parent_virtual_size":{},"#,
+                r#""logical_sector_size_compatible":{},"child_logical_sector_size":{},"#,
+                r#""parent_logical_sector_size":{},"block_sizes_differ":{},"#,
+                r#""child_block_size":{},"parent_block_size":{}}}"#
+            ),
+            linkage,
+            guid(self.parent_linkage),
+            guid(self.parent_linkage2),
+            self.parent_data_write_guid,
+            self.virtual_size_compatible(),
+            self.child_virtual_size,
+            self.parent_virtual_size,
+            self.logical_sector_size_compatible(),
+            self.child_logical_sector_size,
+            self.parent_logical_sector_size,
+            self.block_sizes_differ(),
+            self.child_block_size,
+            self.parent_block_size,
+        )
+    }
+}
+
+// A JSON string literal holding the value. Only what RFC 8259 requires is escaped: the quote and
+// the backslash with a backslash, and the control characters U+0000 to U+001F as \u00XX. Every
+// other character, DEL and non ASCII ones included, is written as it is since the output is
+// UTF-8. Paths that aren't valid Unicode are converted lossily before they get here.
+fn json_string(value: &str) -> String {
+    let mut json = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str(r#"\""#),
+            '\\' => json.push_str(r"\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, r"\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    use super::*;
+    use crate::meta_data::ParentLocator;
+    use crate::test_utils::{
+        differencing_image, dynamic_image, parent_locator, TempDir, BLOCK_SIZE, DATA_WRITE_GUID,
+    };
+    use pretty_assertions::assert_eq;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn should_accept_a_parent_matching_parent_linkage2() {
+        let mut parent = Vhdx::from_reader(dynamic_image(4 * BLOCK_SIZE, &[])).unwrap();
+        let header = parent
+            .header()
+            .successor(Uuid::nil())
+            .with_new_data_write_guid();
+        parent.write_header(header).unwrap();
+        let data_write_guid = parent.header().data_write_guid();
+        let stale = Vhdx::from_reader(differencing_image(
+            4 * BLOCK_SIZE,
+            parent_locator("parent.vhdx"),
+            &[],
+        ))
+        .unwrap();
+        let relinked = Vhdx::from_reader(differencing_image(
+            4 * BLOCK_SIZE,
+            ParentLocator::new(BTreeMap::from([
+                (
+                    ParentLocator::PARENT_LINKAGE.to_string(),
+                    format!("{{{}}}", DATA_WRITE_GUID),
+                ),
+                (
+                    ParentLocator::PARENT_LINKAGE2.to_string(),
+                    format!("{{{}}}", data_write_guid),
+                ),
+            ])),
+            &[],
+        ))
+        .unwrap();
+
+        let stale_checks = LinkChecks::new(&stale, &parent);
+        let relinked_checks = LinkChecks::new(&relinked, &parent);
+
+        assert_eq!(Linkage::Mismatch, stale_checks.linkage);
+        assert!(!stale_checks.is_valid());
+        assert_eq!(Linkage::ParentLinkage2, relinked_checks.linkage);
+        assert!(relinked_checks.is_valid());
+        assert!(relinked.with_parent(parent).is_ok());
+    }
+
+    #[test]
+    fn should_report_a_parent_larger_than_its_child() {
+        let parent = Vhdx::from_reader(dynamic_image(4 * BLOCK_SIZE, &[])).unwrap();
+        let child = |size| {
+            Vhdx::from_reader(differencing_image(size, parent_locator("parent.vhdx"), &[])).unwrap()
+        };
+
+        let smaller = LinkChecks::new(&child(2 * BLOCK_SIZE), &parent);
+        let larger = LinkChecks::new(&child(8 * BLOCK_SIZE), &parent);
+
+        assert_eq!(Linkage::ParentLinkage, smaller.linkage);
+        assert!(!smaller.virtual_size_compatible());
+        assert!(smaller.logical_sector_size_compatible());
+        assert!(!smaller.block_sizes_differ());
+        assert!(!smaller.is_valid());
+        assert!(larger.virtual_size_compatible());
+        assert!(larger.is_valid());
+    }
+
+    #[test]
+    fn should_follow_the_chain_on_disk_up_to_a_missing_parent() {
+        let directory = TempDir::new("chain");
+        let image = |locator| differencing_image(4 * BLOCK_SIZE, locator, &[]).into_inner();
+        fs::write(
+            directory.join("base.vhdx"),
+            dynamic_image(4 * BLOCK_SIZE, &[]).into_inner(),
+        )
+        .unwrap();
+        fs::write(
+            directory.join("child.vhdx"),
+            image(parent_locator("base.vhdx")),
+        )
+        .unwrap();
+        fs::write(
+            directory.join("leaf.vhdx"),
+            image(parent_locator("child.vhdx")),
+        )
+        .unwrap();
+        fs::write(
+            directory.join("orphan.vhdx"),
+            image(parent_locator("gone.vhdx")),
+        )
+        .unwrap();
+
+        let chain = Vhdx::verify_chain(&directory.join("leaf.vhdx")).unwrap();
+        let orphan = Vhdx::verify_chain(&directory.join("orphan.vhdx")).unwrap();
+
+        assert_eq!(2, chain.links.len());
+        assert!(chain.is_valid());
+        assert_eq!(
+            Some(&ResolvedParent {
+                path: directory.join("base.vhdx"),
+                resolved_by: ResolvedBy::RelativePath,
+            }),
+            chain.links[1].parent.as_ref().ok()
+        );
+        let json: Value = serde_json::from_str(&chain.to_json()).unwrap();
+        assert_eq!(Value::Bool(true), json["valid"]);
+        let link = &json["links"][1];
+        assert_eq!(
+            json!(directory.join("child.vhdx").to_string_lossy()),
+            link["child"]
+        );
+        assert_eq!(
+            json!(directory.join("base.vhdx").to_string_lossy()),
+            link["parent"]
+        );
+        assert_eq!(json!("relative_path"), link["resolved_by"]);
+        assert_eq!(Value::Null, link["error"]);
+        assert_eq!(json!("parent_linkage"), link["checks"]["linkage"]);
+        assert_eq!(
+            json!(DATA_WRITE_GUID.to_string()),
+            link["checks"]["parent_data_write_guid"]
+        );
+        assert_eq!(json!(4 * BLOCK_SIZE), link["checks"]["child_virtual_size"]);
+
+        assert_eq!(1, orphan.links.len());
+        assert!(!orphan.is_valid());
+        assert!(matches!(
+            orphan.links[0].parent,
+            Err(VhdxError::ParentNotFound(_))
+        ));
+        let json: Value = serde_json::from_str(&orphan.to_json()).unwrap();
+        assert_eq!(Value::Bool(false), json["valid"]);
+        assert_eq!(Value::Null, json["links"][0]["parent"]);
+        assert_eq!(Value::Null, json["links"][0]["checks"]);
+        assert_eq!(
+            json!(orphan.links[0].parent.as_ref().unwrap_err().to_string()),
+            json["links"][0]["error"]
+        );
+    }
+
+    #[test]
+    fn should_escape_control_characters_in_json() {
+        let path = "/images/\u{0}tab\t\r\u{1f}\u{7f}é.vhdx";
+
+        let escaped = json_string(path);
+
+        assert_eq!(
+            "\"/images/\\u0000tab\\u0009\\u000d\\u001f\u{7f}é.vhdx\"",
+            escaped
+        );
+        assert_eq!(
+            json!(path),
+            serde_json::from_str::<Value>(&escaped).unwrap()
+        );
+    }
+
+    #[test]
+    fn should_escape_paths_and_errors_in_json() {
+        let report = ChainReport {
+            links: vec![LinkReport {
+                child: PathBuf::from("/images/\"quoted\"\\child\n.vhdx"),
+                parent: Err(VhdxError::ParentNotFound(r"..\gone.vhdx".to_string())),
+                checks: None,
+            }],
+        };
+
+        let json: Value = serde_json::from_str(&report.to_json()).unwrap();
+
+        assert_eq!(
+            json!("/images/\"quoted\"\\child\n.vhdx"),
+            json["links"][0]["child"]
+        );
+        assert_eq!(
+            json!(r"Parent of the differencing disk not found: ..\gone.vhdx"),
+            json["links"][0]["error"]
+        );
+    }
+}
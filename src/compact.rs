@@ -0,0 +1,313 @@
+use std::io::{Read, Seek, Write};
+
+use crate::{
+    bat::{payload_bat_index, payload_block, BatEntry, BatEntryState},
+    error::VhdxError,
+    vhdx::Vhdx,
+    vhdx_header::KnowRegion,
+    SetLen, SyncData,
+};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CompactReport {
+    // Blocks that held nothing but zeros and no longer take space in the file.
+    pub zero_blocks: u64,
+
+    // Blocks moved towards the start of the file to fill the space given back.
+    pub relocated_blocks: u64,
+
+    pub original_length: u64,
+    pub compacted_length: u64,
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Write + Seek + SetLen + SyncData,
+{
+    // Gives the space of unused blocks back to the host, the equivalent of Optimize-VHD. Blocks
+    // holding only zeros are released, NotPresent on a disk without a parent and Zero on a
+    // differencing disk so they don't show the parent through. The blocks left are then moved
+    // down into the holes and the file is truncated after the last of them. A disk asking to
+    // leave its blocks allocated, like a fixed one, keeps them all and is only packed.
+    //
+    // Every BAT change goes through the log. A block is only ever copied to space no BAT entry
+    // points to, and its entry is switched to the copy after the copy is flushed, so a crash
+    // leaves the old or the new location in use, never a half copied block.
+    pub fn compact(&mut self) -> Result<CompactReport, VhdxError> {
+        let mut report = CompactReport {
+            original_length: self.file.seek(std::io::SeekFrom::End(0))?,
+            ..Default::default()
+        };
+
+        self.release_zero_blocks(&mut report)?;
+        self.relocate_blocks(&mut report)?;
+
+        report.compacted_length = self
+            .used_intervals()?
+            .iter()
+            .map(|(_, end)| *end)
+            .max()
+            .unwrap_or(0);
+        self.file.set_len(report.compacted_length)?;
+        self.file.sync_data()?;
+        Ok(report)
+    }
+
+    fn release_zero_blocks(&mut self, report: &mut CompactReport) -> Result<(), VhdxError> {
+        if self.meta_data.file_parameters.leave_block_allocated {
+            return Ok(());
+        }
+        let released_state = match self.has_parent() {
+            true => BatEntryState::Zero,
+            false => BatEntryState::NotPresent,
+        };
+        let block_size = self.block_size();
+        let chunk_ratio = self.meta_data.chunk_ratio;
+
+        let mut updates = Vec::new();
+        for block in 0..self.meta_data.payload_blocks_count {
+            let Some(entry) = self.payload_entry(block) else {
+                continue;
+            };
+            if entry.state() != BatEntryState::FullyPresent {
+                continue;
+            }
+            let data = self.read_at(entry.file_offset(), block_size)?;
+            if data.iter().all(|byte| *byte == 0) {
+                updates.push((
+                    payload_bat_index(block, chunk_ratio),
+                    BatEntry::new(released_state, 0),
+                ));
+            }
+        }
+
+        report.zero_blocks = updates.len() as u64;
+        if !updates.is_empty() {
+            self.update_bat(&updates)?;
+        }
+        Ok(())
+    }
+
+    // Moves the payload blocks, lowest first, into the lowest hole they fit in. A move whose
+    // destination overlaps the old location of a move not yet committed waits for that one to
+    // be committed first.
+    fn relocate_blocks(&mut self, report: &mut CompactReport) -> Result<(), VhdxError> {
+        let block_size = self.block_size();
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        let fixed = self.fixed_intervals()?;
+        let mut blocks: Vec<(usize, BatEntry)> = self
+            .bat_table
+            .iter()
+            .enumerate()
+            .filter(|(index, entry)| {
+                payload_block(*index as u64, chunk_ratio).is_some() && entry.state().is_stored()
+            })
+            .map(|(index, entry)| (index, entry.clone()))
+            .collect();
+        blocks.sort_by_key(|(_, entry)| entry.file_offset());
+
+        let mut placed: Vec<(u64, u64)> = Vec::new();
+        let mut pending: Vec<(usize, BatEntry)> = Vec::new();
+        let mut pending_sources: Vec<(u64, u64)> = Vec::new();
+        for (index, entry) in blocks {
+            let source = entry.file_offset();
+            let destination = lowest_hole(&fixed, &placed, block_size, source);
+
+            if destination == source {
+                placed.push((source, source + block_size));
+                continue;
+            }
+            let interval = (destination, destination + block_size);
+            if pending_sources
+                .iter()
+                .any(|other| overlaps(*other, interval))
+            {
+                self.update_bat(&std::mem::take(&mut pending))?;
+                pending_sources.clear();
+            }
+
+            let data = self.read_at(source, block_size)?;
+            self.file.seek(std::io::SeekFrom::Start(destination))?;
+            self.file.write_all(&data)?;
+            self.file.sync_data()?;
+
+            placed.push(interval);
+            pending.push((
+                index,
+                BatEntry::new(entry.state(), (destination / Vhdx::MB) as usize),
+            ));
+            pending_sources.push((source, source + block_size));
+            report.relocated_blocks += 1;
+        }
+        if !pending.is_empty() {
+            self.update_bat(&pending)?;
+        }
+        Ok(())
+    }
+
+    // Ranges of the file that don't move: the headers, the regions and the sector bitmap blocks.
+    fn fixed_intervals(&self) -> Result<Vec<(u64, u64)>, VhdxError> {
+        let header = self.header();
+        let mut intervals = vec![
+            (0, Vhdx::MB),
+            (
+                header.log_offset,
+                header.log_offset + header.log_length as u64,
+            ),
+        ];
+        for region in [KnowRegion::MetaData, KnowRegion::Bat] {
+            let entry = self
+                .region(region)
+                .ok_or(VhdxError::MissingKnownRegion("MetaData or Bat"))?;
+            intervals.push((entry.file_offset, entry.file_offset + entry.length as u64));
+        }
+
+        // Sector bitmap blocks are always 1 MB and present whenever they are stored.
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        intervals.extend(
+            self.bat_table
+                .iter()
+                .enumerate()
+                .filter(|(index, entry)| {
+                    payload_block(*index as u64, chunk_ratio).is_none()
+                        && entry.state() == BatEntryState::FullyPresent
+                })
+                .map(|(_, entry)| (entry.file_offset(), entry.file_offset() + Vhdx::MB)),
+        );
+        Ok(intervals)
+    }
+
+    // Ranges of the file in use, the payload blocks along with everything that doesn't move.
+    fn used_intervals(&self) -> Result<Vec<(u64, u64)>, VhdxError> {
+        let block_size = self.block_size();
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        let mut intervals = self.fixed_intervals()?;
+        intervals.extend(
+            self.bat_table
+                .iter()
+                .enumerate()
+                .filter(|(index, entry)| {
+                    payload_block(*index as u64, chunk_ratio).is_some() && entry.state().is_stored()
+                })
+                .map(|(_, entry)| (entry.file_offset(), entry.file_offset() + block_size)),
+        );
+        Ok(intervals)
+    }
+}
+
+fn overlaps(a: (u64, u64), b: (u64, u64)) -> bool {
+    a.0 < b.1 && b.0 < a.1
+}
+
+// The lowest MB aligned offset a block fits at without overlapping anything in use, nor the
+// block's own current location unless it stays where it is.
+fn lowest_hole(fixed: &[(u64, u64)], placed: &[(u64, u64)], block_size: u64, source: u64) -> u64 {
+    let mut candidate = Vhdx::MB;
+    while candidate < source {
+        let interval = (candidate, candidate + block_size);
+        let blocker = fixed
+            .iter()
+            .chain(placed)
+            .chain(std::iter::once(&(source, source + block_size)))
+            .filter(|other| overlaps(**other, interval))
+            .map(|(_, end)| *end)
+            .max();
+        match blocker {
+            None => return candidate,
+            Some(end) => candidate = end.next_multiple_of(Vhdx::MB),
+        }
+    }
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, SeekFrom};
+
+    use super::*;
+    use crate::test_utils::{dynamic_image, BAT_OFFSET, BLOCK_SIZE, PAYLOAD_OFFSET};
+    use pretty_assertions::assert_eq;
+
+    fn read_disk(vhdx: &mut Vhdx<Cursor<Vec<u8>>>) -> Vec<u8> {
+        let mut data = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn should_release_zero_blocks_and_fill_the_holes() {
+        let image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0), (1, 0x11), (2, 0), (3, 0x33)]);
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        let before = read_disk(&mut vhdx);
+
+        let report = vhdx.compact().unwrap();
+
+        assert_eq!(2, report.zero_blocks);
+        assert_eq!(2, report.relocated_blocks);
+        assert_eq!(
+            PAYLOAD_OFFSET + 4 * BLOCK_SIZE as u64,
+            report.original_length
+        );
+        assert_eq!(
+            PAYLOAD_OFFSET + 2 * BLOCK_SIZE as u64,
+            report.compacted_length
+        );
+        assert_eq!(BatEntryState::NotPresent, vhdx.bat_table[0].state());
+        assert_eq!(PAYLOAD_OFFSET, vhdx.bat_table[1].file_offset());
+        assert_eq!(
+            PAYLOAD_OFFSET + BLOCK_SIZE as u64,
+            vhdx.bat_table[3].file_offset()
+        );
+        assert_eq!(before, read_disk(&mut vhdx));
+
+        // The result is a valid file on its own, with nothing left to replay.
+        let mut reopened = Vhdx::from_reader(vhdx.file).unwrap();
+        assert!(!reopened.replay_log().unwrap());
+        assert_eq!(before, read_disk(&mut reopened));
+    }
+
+    #[test]
+    fn should_keep_zero_blocks_of_a_disk_leaving_blocks_allocated() {
+        let image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0), (1, 0x11), (2, 0), (3, 0x33)]);
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        vhdx.meta_data.file_parameters.leave_block_allocated = true;
+        let before = read_disk(&mut vhdx);
+
+        let report = vhdx.compact().unwrap();
+
+        assert_eq!(0, report.zero_blocks);
+        assert_eq!(0, report.relocated_blocks);
+        assert_eq!(report.original_length, report.compacted_length);
+        assert!((0..4).all(|block| {
+            vhdx.payload_entry(block).unwrap().state() == BatEntryState::FullyPresent
+        }));
+        assert_eq!(before, read_disk(&mut vhdx));
+    }
+
+    #[test]
+    fn should_leave_a_packed_disk_as_it_is() {
+        let image = dynamic_image(2 * BLOCK_SIZE, &[(1, 0x11), (0, 0x22)]);
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+
+        let report = vhdx.compact().unwrap();
+
+        assert_eq!(0, report.zero_blocks);
+        assert_eq!(0, report.relocated_blocks);
+        assert_eq!(report.original_length, report.compacted_length);
+    }
+
+    #[test]
+    fn should_keep_zero_blocks_zero() {
+        let mut image = dynamic_image(2 * BLOCK_SIZE, &[(1, 0x11)]);
+        image.seek(SeekFrom::Start(BAT_OFFSET)).unwrap();
+        image
+            .write_all(&(BatEntryState::Zero as u64).to_le_bytes())
+            .unwrap();
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+
+        vhdx.compact().unwrap();
+
+        assert_eq!(BatEntryState::Zero, vhdx.bat_table[0].state());
+        assert_eq!(BatEntryState::FullyPresent, vhdx.bat_table[1].state());
+    }
+}
@@ -0,0 +1,722 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use uuid::Uuid;
+
+use crate::{
+    bat::{payload_bat_index, BatEntry, BatEntryState},
+    error::VhdxError,
+    image_store::{FileSystem, ImageStore},
+    meta_data::{FileParameters, MetaData, ParentLocator, SectorSize},
+    parent_resolver::ParentResolver,
+    rebase::{is_absolute_win32_path, relative_path},
+    vhdx::Vhdx,
+    vhdx_header::{FileTypeIdentifier, Header, KnowRegion, RTEntry, RegionTable, VhdxHeader},
+    virtual_disk::Allocation,
+    Serialise, Signature, SyncData,
+};
+
+// Options for new dynamic disks.
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    // Size of the payload blocks, a power of two between 1 MB and 256 MB.
+    pub block_size: u32,
+    pub logical_sector_size: SectorSize,
+    pub physical_sector_size: SectorSize,
+
+    // State of the blocks that only hold zeros. NotPresent blocks read as zeros, Zero blocks are
+    // explicitly zeroed and keep doing so even when the disk later becomes a differencing disk.
+    pub zero_block_state: BatEntryState,
+}
+
+impl Default for CreateOptions {
+    // The defaults of Hyper-V.
+    fn default() -> Self {
+        Self {
+            block_size: 32 * Vhdx::MB as u32,
+            logical_sector_size: SectorSize::Sector512,
+            physical_sector_size: SectorSize::Sector4096,
+            zero_block_state: BatEntryState::NotPresent,
+        }
+    }
+}
+
+// Layout of the fixed structures, laid out the way Hyper-V does, each in its own MB: the
+// headers, the log, the metadata and then the BAT.
+const LOG_OFFSET: u64 = Vhdx::MB;
+const LOG_LENGTH: u64 = Vhdx::MB;
+const META_DATA_OFFSET: u64 = 2 * Vhdx::MB;
+const META_DATA_LENGTH: u64 = Vhdx::MB;
+const BAT_OFFSET: u64 = 3 * Vhdx::MB;
+
+fn validate(virtual_disk_size: u64, options: &CreateOptions) -> Result<(), VhdxError> {
+    let block_size = options.block_size as u64;
+    if !block_size.is_power_of_two() || !(Vhdx::MB..=256 * Vhdx::MB).contains(&block_size) {
+        return Err(VhdxError::InvalidBlockSize(block_size));
+    }
+    if !virtual_disk_size.is_multiple_of(options.logical_sector_size as u64) {
+        return Err(VhdxError::InvalidVirtualDiskSize(virtual_disk_size));
+    }
+    if !matches!(
+        options.zero_block_state,
+        BatEntryState::NotPresent | BatEntryState::Zero
+    ) {
+        return Err(VhdxError::InvalidZeroBlockState(options.zero_block_state));
+    }
+    Ok(())
+}
+
+fn new_meta_data(
+    virtual_disk_size: u64,
+    options: &CreateOptions,
+    virtual_disk_id: Uuid,
+    parent_locator: Option<ParentLocator>,
+) -> MetaData {
+    let file_parameters = FileParameters {
+        block_size: options.block_size as usize,
+        leave_block_allocated: false,
+        has_parent: parent_locator.is_some(),
+    };
+    MetaData::create(
+        file_parameters,
+        virtual_disk_size as usize,
+        virtual_disk_id,
+        options.logical_sector_size,
+        options.physical_sector_size,
+        parent_locator,
+    )
+}
+
+// The BAT takes whole MBs.
+fn bat_length(meta_data: &MetaData) -> u64 {
+    (meta_data.total_bat_entries() * 8).div_ceil(Vhdx::MB) * Vhdx::MB
+}
+
+// The BAT holding the entries of the payload blocks, sector bitmap blocks are not present.
+fn bat_bytes(
+    meta_data: &MetaData,
+    payload: &[BatEntry],
+    bat_length: u64,
+) -> Result<Vec<u8>, VhdxError> {
+    let mut bat =
+        vec![BatEntry::new(BatEntryState::NotPresent, 0); meta_data.total_bat_entries() as usize];
+    for (block, entry) in payload.iter().enumerate() {
+        bat[payload_bat_index(block as u64, meta_data.chunk_ratio)] = entry.clone();
+    }
+
+    let mut bytes = Cursor::new(vec![0; bat_length as usize]);
+    for entry in &bat {
+        entry.serialize(&mut bytes)?;
+    }
+    Ok(bytes.into_inner())
+}
+
+// Everything in front of the BAT: the headers and region tables, the zeroed log and the metadata.
+// The log is zeroed so no stale entries from a previous file are ever picked up.
+fn fixed_regions(
+    meta_data: &MetaData,
+    bat_offset: u64,
+    bat_length: u64,
+) -> Result<Vec<u8>, VhdxError> {
+    let mut regions = Cursor::new(vec![0; BAT_OFFSET as usize]);
+
+    regions.seek(SeekFrom::Start(META_DATA_OFFSET))?;
+    meta_data.serialize(&mut regions)?;
+
+    let mut header = Header::new(
+        Signature::Head,
+        0,
+        1,
+        Uuid::new_v4(),
+        Uuid::new_v4(),
+        Uuid::nil(),
+        0,
+        1,
+        LOG_LENGTH as u32,
+        LOG_OFFSET,
+    );
+    header.update_checksum();
+
+    let region_table = RegionTable::with_entries(BTreeMap::from([
+        (
+            KnowRegion::Bat,
+            RTEntry::new(RegionTable::BAT_ENTRY, bat_offset, bat_length as u32, true),
+        ),
+        (
+            KnowRegion::MetaData,
+            RTEntry::new(
+                RegionTable::META_DATA_ENTRY,
+                META_DATA_OFFSET,
+                META_DATA_LENGTH as u32,
+                true,
+            ),
+        ),
+    ]));
+
+    regions.rewind()?;
+    VhdxHeader::new(
+        FileTypeIdentifier::new(Signature::Vhdxfile, "vhdx-rs".to_string()),
+        header.clone(),
+        header,
+        region_table.clone(),
+        region_table,
+    )
+    .serialize(&mut regions)?;
+
+    Ok(regions.into_inner())
+}
+
+// Writes a new dynamic VHDX. Payload blocks are appended in the order they are written, the
+// headers, metadata and BAT are only written by finish. The BAT sits in front of the payload, or
+// after it when the size of the disk is only known at the end.
+pub struct DynamicWriter<W> {
+    writer: W,
+    options: CreateOptions,
+    meta_data: MetaData,
+    // The BAT entry of every payload block.
+    payload: Vec<BatEntry>,
+    // Offset and length of the BAT reserved in front of the payload, None for a provisional size.
+    bat: Option<(u64, u64)>,
+    next_offset: u64,
+}
+
+impl<W> DynamicWriter<W>
+where
+    W: Write + Seek,
+{
+    pub fn new(
+        writer: W,
+        virtual_disk_size: u64,
+        options: CreateOptions,
+    ) -> Result<Self, VhdxError> {
+        DynamicWriter::with_parent(writer, virtual_disk_size, options, None)
+    }
+
+    // A writer of a differencing disk when given the locator of its parent.
+    pub fn with_parent(
+        writer: W,
+        virtual_disk_size: u64,
+        options: CreateOptions,
+        parent_locator: Option<ParentLocator>,
+    ) -> Result<Self, VhdxError> {
+        validate(virtual_disk_size, &options)?;
+        let meta_data = new_meta_data(virtual_disk_size, &options, Uuid::new_v4(), parent_locator);
+        let bat_length = bat_length(&meta_data);
+
+        Ok(Self {
+            writer,
+            payload: vec![
+                BatEntry::new(BatEntryState::NotPresent, 0);
+                meta_data.payload_blocks_count as usize
+            ],
+            bat: Some((BAT_OFFSET, bat_length)),
+            next_offset: BAT_OFFSET + bat_length,
+            options,
+            meta_data,
+        })
+    }
+
+    // A writer of a disk whose size isn't known yet. It starts out empty, set_virtual_disk_size
+    // makes room for the blocks to write and fixes the size up at the end. The BAT is placed after
+    // the payload.
+    pub fn provisional(writer: W, options: CreateOptions) -> Result<Self, VhdxError> {
+        validate(0, &options)?;
+        let meta_data = new_meta_data(0, &options, Uuid::new_v4(), None);
+
+        Ok(Self {
+            writer,
+            payload: Vec::new(),
+            bat: None,
+            next_offset: BAT_OFFSET,
+            options,
+            meta_data,
+        })
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.options.block_size as u64
+    }
+
+    // Gives the disk the identity of another one instead of a new one.
+    pub fn set_virtual_disk_id(&mut self, virtual_disk_id: Uuid) {
+        self.meta_data.virtual_disk_id = virtual_disk_id;
+    }
+
+    pub fn payload_blocks_count(&self) -> u64 {
+        self.meta_data.payload_blocks_count
+    }
+
+    // Changes the size of the disk. Blocks past the new end are dropped. A BAT reserved in front of
+    // the payload can't grow, so only a provisional writer can grow past the BAT it started with.
+    pub fn set_virtual_disk_size(&mut self, virtual_disk_size: u64) -> Result<(), VhdxError> {
+        validate(virtual_disk_size, &self.options)?;
+        let meta_data = new_meta_data(
+            virtual_disk_size,
+            &self.options,
+            self.meta_data.virtual_disk_id,
+            self.meta_data.parent_locator.clone(),
+        );
+        if self
+            .bat
+            .is_some_and(|(_, length)| bat_length(&meta_data) > length)
+        {
+            return Err(VhdxError::InvalidVirtualDiskSize(virtual_disk_size));
+        }
+
+        self.payload.resize(
+            meta_data.payload_blocks_count as usize,
+            BatEntry::new(BatEntryState::NotPresent, 0),
+        );
+        self.meta_data = meta_data;
+        Ok(())
+    }
+
+    // Stores a block of the virtual disk. Blocks only holding zeros are not stored, they get the
+    // zero block state instead. A short last block is padded with zeros.
+    pub fn write_block(&mut self, block: u64, data: &[u8]) -> Result<(), VhdxError> {
+        if data.len() as u64 > self.block_size() {
+            return Err(VhdxError::BlockDataTooLong(data.len() as u64));
+        }
+        if data.iter().all(|b| *b == 0) {
+            return self.set_state(block, self.options.zero_block_state);
+        }
+
+        self.check_block(block)?;
+        self.writer.seek(SeekFrom::Start(self.next_offset))?;
+        self.writer.write_all(data)?;
+        let padding = self.block_size() as usize - data.len();
+        self.writer.write_all(&vec![0; padding])?;
+
+        self.payload[block as usize] = BatEntry::new(
+            BatEntryState::FullyPresent,
+            (self.next_offset / Vhdx::MB) as usize,
+        );
+        self.next_offset += self.block_size();
+        Ok(())
+    }
+
+    // Sets the state of a block without any payload.
+    pub fn set_state(&mut self, block: u64, state: BatEntryState) -> Result<(), VhdxError> {
+        self.check_block(block)?;
+        self.payload[block as usize] = BatEntry::new(state, 0);
+        Ok(())
+    }
+
+    fn check_block(&self, block: u64) -> Result<(), VhdxError> {
+        if block >= self.meta_data.payload_blocks_count {
+            return Err(VhdxError::BlockOutOfRange(block));
+        }
+        Ok(())
+    }
+
+    // Writes the BAT, the metadata and the headers, which turns what has been written into a
+    // valid VHDX, and hands the writer back. The headers come last so the file only becomes a
+    // VHDX once everything else is in place.
+    pub fn finish(mut self) -> Result<W, VhdxError> {
+        let (bat_offset, bat_length) = self
+            .bat
+            .unwrap_or((self.next_offset, bat_length(&self.meta_data)));
+
+        let bat = bat_bytes(&self.meta_data, &self.payload, bat_length)?;
+        self.writer.seek(SeekFrom::Start(bat_offset))?;
+        self.writer.write_all(&bat)?;
+
+        let regions = fixed_regions(&self.meta_data, bat_offset, bat_length)?;
+        self.writer.seek(SeekFrom::Start(LOG_OFFSET))?;
+        self.writer.write_all(&regions[LOG_OFFSET as usize..])?;
+        self.writer.rewind()?;
+        self.writer.write_all(&regions[..LOG_OFFSET as usize])?;
+
+        // Payload is written in whole blocks, but the file still has to cover the BAT even when
+        // no block has been written.
+        let end = self.writer.seek(SeekFrom::End(0))?;
+        if end < self.next_offset {
+            self.writer.seek(SeekFrom::Start(self.next_offset - 1))?;
+            self.writer.write_all(&[0])?;
+        }
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+// Writes a new dynamic VHDX front to back without ever seeking, so it can go into a pipe. The
+// allocation of every block has to be known up front: the headers, metadata and BAT are written
+// first, then the payload of the FullyPresent blocks in increasing block order.
+pub struct StreamWriter<W> {
+    writer: W,
+    block_size: u64,
+    // Blocks still to be written, in the order they are laid out.
+    blocks: Vec<u64>,
+    next: usize,
+}
+
+impl<W> StreamWriter<W>
+where
+    W: Write,
+{
+    // The allocation map holds the state of every payload block, FullyPresent, NotPresent or
+    // Zero.
+    pub fn new(
+        mut writer: W,
+        virtual_disk_size: u64,
+        options: CreateOptions,
+        allocation_map: &[BatEntryState],
+    ) -> Result<Self, VhdxError> {
+        validate(virtual_disk_size, &options)?;
+        let meta_data = new_meta_data(virtual_disk_size, &options, Uuid::new_v4(), None);
+        if allocation_map.len() as u64 != meta_data.payload_blocks_count {
+            return Err(VhdxError::InvalidAllocationMap(
+                "Allocation map doesn't hold every payload block",
+            ));
+        }
+
+        let block_size = options.block_size as u64;
+        let bat_length = bat_length(&meta_data);
+        let mut payload = Vec::with_capacity(allocation_map.len());
+        let mut blocks = Vec::new();
+        let mut offset = BAT_OFFSET + bat_length;
+        for (block, state) in allocation_map.iter().enumerate() {
+            payload.push(match state {
+                BatEntryState::FullyPresent => {
+                    blocks.push(block as u64);
+                    offset += block_size;
+                    BatEntry::new(*state, ((offset - block_size) / Vhdx::MB) as usize)
+                }
+                BatEntryState::NotPresent | BatEntryState::Zero => BatEntry::new(*state, 0),
+                _ => {
+                    return Err(VhdxError::InvalidAllocationMap(
+                        "Only FullyPresent, NotPresent and Zero blocks can be streamed",
+                    ))
+                }
+            });
+        }
+
+        writer.write_all(&fixed_regions(&meta_data, BAT_OFFSET, bat_length)?)?;
+        writer.write_all(&bat_bytes(&meta_data, &payload, bat_length)?)?;
+        Ok(Self {
+            writer,
+            block_size,
+            blocks,
+            next: 0,
+        })
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    // Writes the payload of the next FullyPresent block of the allocation map. A short last block
+    // is padded with zeros.
+    pub fn write_block(&mut self, block: u64, data: &[u8]) -> Result<(), VhdxError> {
+        if self.blocks.get(self.next) != Some(&block) {
+            return Err(VhdxError::UnexpectedBlock(block));
+        }
+        if data.len() as u64 > self.block_size {
+            return Err(VhdxError::BlockDataTooLong(data.len() as u64));
+        }
+
+        self.writer.write_all(data)?;
+        let padding = self.block_size as usize - data.len();
+        self.writer.write_all(&vec![0; padding])?;
+        self.next += 1;
+        Ok(())
+    }
+
+    // Hands the writer back once every FullyPresent block has been written.
+    pub fn finish(mut self) -> Result<W, VhdxError> {
+        if let Some(block) = self.blocks.get(self.next) {
+            return Err(VhdxError::IncompleteStream(*block));
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    // Writes the disk as a new dynamic VHDX into a writer that can't seek. Differencing disks are
+    // flattened, blocks reading as zeros keep their Zero or NotPresent state.
+    pub fn export_vhdx_stream<W>(
+        &mut self,
+        writer: W,
+        options: CreateOptions,
+    ) -> Result<W, VhdxError>
+    where
+        W: Write,
+    {
+        let size = self.virtual_disk_size();
+        let block_size = options.block_size as u64;
+        let source_block_size = self.block_size();
+        let mut disk = self.virtual_disk();
+
+        let mut allocation_map = Vec::new();
+        for start in (0..size).step_by(block_size as usize) {
+            let end = (start + block_size).min(size);
+            let mut state = BatEntryState::NotPresent;
+            let mut position = start;
+            while position < end {
+                let next = ((position / source_block_size + 1) * source_block_size).min(end);
+                if disk.allocation(position, next - position)? != Allocation::Zero {
+                    state = BatEntryState::FullyPresent;
+                    break;
+                }
+                if disk.state(position)? == BatEntryState::Zero {
+                    state = BatEntryState::Zero;
+                }
+                position = next;
+            }
+            allocation_map.push(state);
+        }
+
+        let mut stream = StreamWriter::new(writer, size, options, &allocation_map)?;
+        let mut buffer = vec![0; block_size as usize];
+        for (block, state) in allocation_map.iter().enumerate() {
+            if *state != BatEntryState::FullyPresent {
+                continue;
+            }
+            let start = block as u64 * block_size;
+            let data = &mut buffer[..block_size.min(size - start) as usize];
+            disk.seek(SeekFrom::Start(start))?;
+            disk.read_exact(data)?;
+            stream.write_block(block as u64, data)?;
+        }
+        stream.finish()
+    }
+}
+
+impl Vhdx {
+    // Creates an empty dynamic disk.
+    pub fn create(
+        path: &impl AsRef<Path>,
+        virtual_disk_size: u64,
+        options: CreateOptions,
+    ) -> Result<Self, VhdxError> {
+        let file = File::create_new(path)?;
+        DynamicWriter::new(file, virtual_disk_size, options)?
+            .finish()?
+            .sync_all()?;
+        Vhdx::new(path)
+    }
+
+    // Creates a differencing disk on top of the disk at the parent path.
+    pub fn create_differencing(
+        path: &impl AsRef<Path>,
+        parent_path: &impl AsRef<Path>,
+    ) -> Result<Self, VhdxError> {
+        Vhdx::create_differencing_in(&FileSystem, path, parent_path, &ParentResolver::default())
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Write + Seek + SyncData,
+{
+    // Creates a differencing disk of the store on top of another one and opens it with its chain.
+    // The child gets the size, block size and sector sizes of its parent, and a locator holding
+    // the path of the parent relative to it and its absolute path when that is a Windows one.
+    pub fn create_differencing_in<S>(
+        store: &S,
+        path: &impl AsRef<Path>,
+        parent_path: &impl AsRef<Path>,
+        resolver: &ParentResolver,
+    ) -> Result<Self, VhdxError>
+    where
+        S: ImageStore<Image = T>,
+    {
+        let path = path.as_ref();
+        let parent_path = store.canonicalize(parent_path.as_ref())?;
+        let parent = Vhdx::from_reader(store.open(&parent_path, false)?)?;
+
+        let directory = match path.parent() {
+            Some(directory) if directory != Path::new("") => directory,
+            _ => Path::new("."),
+        };
+        let directory = store.canonicalize(directory)?;
+        let mut locator = ParentLocator::new(BTreeMap::from([
+            (
+                ParentLocator::PARENT_LINKAGE.to_string(),
+                format!("{{{}}}", parent.header().data_write_guid()),
+            ),
+            (
+                ParentLocator::RELATIVE_PATH.to_string(),
+                relative_path(&directory, &parent_path),
+            ),
+        ]));
+        let absolute_path = parent_path.to_string_lossy();
+        if is_absolute_win32_path(&absolute_path) {
+            locator.entries.insert(
+                ParentLocator::ABSOLUTE_WIN32_PATH.to_string(),
+                absolute_path.into_owned(),
+            );
+        }
+        let options = CreateOptions {
+            block_size: parent.block_size() as u32,
+            logical_sector_size: parent.meta_data.logical_sector_size,
+            physical_sector_size: parent.meta_data.physical_sector_size,
+            ..CreateOptions::default()
+        };
+
+        let writer = store.create(path)?;
+        let writer =
+            DynamicWriter::with_parent(writer, parent.virtual_disk_size(), options, Some(locator))?;
+        writer.finish()?.flush()?;
+        Vhdx::open_in(store, &path, resolver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{dynamic_image, BLOCK_SIZE};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_write_readable_dynamic_disk() {
+        let options = CreateOptions {
+            block_size: 2 * Vhdx::MB as u32,
+            ..CreateOptions::default()
+        };
+        let mut writer =
+            DynamicWriter::new(Cursor::new(Vec::new()), 5 * Vhdx::MB, options).unwrap();
+        writer.write_block(0, &[0; 4096]).unwrap();
+        writer.write_block(2, &[0xAB; Vhdx::MB as usize]).unwrap();
+
+        let mut vhdx = Vhdx::from_reader(writer.finish().unwrap()).unwrap();
+
+        assert_eq!(2 * Vhdx::MB, vhdx.block_size());
+        assert_eq!(SectorSize::Sector4096, vhdx.meta_data.physical_sector_size);
+        assert_eq!(
+            BatEntryState::NotPresent,
+            vhdx.payload_entry(0).unwrap().state()
+        );
+        let mut content = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut content).unwrap();
+        assert_eq!(5 * Vhdx::MB as usize, content.len());
+        assert!(content[..4 * Vhdx::MB as usize].iter().all(|b| *b == 0));
+        assert!(content[4 * Vhdx::MB as usize..].iter().all(|b| *b == 0xAB));
+    }
+
+    #[test]
+    fn should_reject_invalid_block_size() {
+        let options = CreateOptions {
+            block_size: 3 * Vhdx::MB as u32,
+            ..CreateOptions::default()
+        };
+
+        let result = DynamicWriter::new(Cursor::new(Vec::new()), Vhdx::MB, options);
+
+        assert!(matches!(result, Err(VhdxError::InvalidBlockSize(_))));
+    }
+
+    #[test]
+    fn should_refuse_data_longer_than_a_block() {
+        let options = CreateOptions {
+            block_size: Vhdx::MB as u32,
+            ..CreateOptions::default()
+        };
+        let mut writer =
+            DynamicWriter::new(Cursor::new(Vec::new()), 2 * Vhdx::MB, options).unwrap();
+
+        let result = writer.write_block(0, &[0xAB; Vhdx::MB as usize + 1]);
+        writer.write_block(1, &[0xCD; Vhdx::MB as usize]).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(VhdxError::BlockDataTooLong(length)) if length == Vhdx::MB + 1
+        ));
+        let mut vhdx = Vhdx::from_reader(writer.finish().unwrap()).unwrap();
+        assert_eq!(
+            BatEntryState::NotPresent,
+            vhdx.payload_entry(0).unwrap().state()
+        );
+        let mut content = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut content).unwrap();
+        assert!(content[Vhdx::MB as usize..].iter().all(|b| *b == 0xCD));
+    }
+
+    #[test]
+    fn should_stream_dynamic_disk_front_to_back() {
+        let options = CreateOptions {
+            block_size: Vhdx::MB as u32,
+            ..CreateOptions::default()
+        };
+        use BatEntryState::*;
+        let allocation_map = [FullyPresent, Zero, NotPresent, FullyPresent];
+        let mut stream =
+            StreamWriter::new(Vec::new(), 4 * Vhdx::MB, options, &allocation_map).unwrap();
+
+        assert!(matches!(
+            stream.write_block(3, &[0x33; 16]),
+            Err(VhdxError::UnexpectedBlock(3))
+        ));
+        assert!(matches!(
+            stream.write_block(0, &[0x11; Vhdx::MB as usize + 1]),
+            Err(VhdxError::BlockDataTooLong(length)) if length == Vhdx::MB + 1
+        ));
+        stream.write_block(0, &[0x11; Vhdx::MB as usize]).unwrap();
+        stream.write_block(3, &[0x33; 16]).unwrap();
+        let image = stream.finish().unwrap();
+
+        // Headers, log, metadata and BAT take 4 MB, followed by the two blocks.
+        assert_eq!(6 * Vhdx::MB as usize, image.len());
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        let states: Vec<BatEntryState> = (0..4)
+            .map(|block| vhdx.payload_entry(block).unwrap().state())
+            .collect();
+        assert_eq!(allocation_map.to_vec(), states);
+        let mut content = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut content).unwrap();
+        assert!(content[..Vhdx::MB as usize].iter().all(|b| *b == 0x11));
+        assert!(content[3 * Vhdx::MB as usize..][..16]
+            .iter()
+            .all(|b| *b == 0x33));
+        assert!(content[3 * Vhdx::MB as usize + 16..]
+            .iter()
+            .all(|b| *b == 0));
+    }
+
+    #[test]
+    fn should_not_finish_incomplete_stream() {
+        let options = CreateOptions {
+            block_size: Vhdx::MB as u32,
+            ..CreateOptions::default()
+        };
+        let allocation_map = [BatEntryState::FullyPresent];
+
+        let stream = StreamWriter::new(Vec::new(), Vhdx::MB, options, &allocation_map).unwrap();
+
+        assert!(matches!(
+            stream.finish(),
+            Err(VhdxError::IncompleteStream(0))
+        ));
+    }
+
+    #[test]
+    fn should_export_vhdx_stream_with_larger_blocks() {
+        let image = dynamic_image(5 * BLOCK_SIZE, &[(3, 0xAB)]);
+        let mut source = Vhdx::from_reader(image).unwrap();
+        let mut expected = Vec::new();
+        source.virtual_disk().read_to_end(&mut expected).unwrap();
+        let options = CreateOptions {
+            block_size: 2 * BLOCK_SIZE as u32,
+            ..CreateOptions::default()
+        };
+
+        let image = source.export_vhdx_stream(Vec::new(), options).unwrap();
+
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        let states: Vec<BatEntryState> = (0..3)
+            .map(|block| vhdx.payload_entry(block).unwrap().state())
+            .collect();
+        use BatEntryState::*;
+        assert_eq!(vec![NotPresent, FullyPresent, NotPresent], states);
+        let mut content = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut content).unwrap();
+        assert!(content == expected);
+    }
+}
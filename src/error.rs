@@ -1,12 +1,14 @@
 use std::io;
+use std::path::PathBuf;
 
 use nom::{
     error::{make_error, FromExternalError, ParseError},
     ErrorConvert,
 };
 use thiserror::Error;
+use uuid::Uuid;
 
-use crate::Signature;
+use crate::{bat::BatEntryState, Signature};
 
 pub type Result<T, E = VhdxParseError<T>> = core::result::Result<T, E>;
 
@@ -33,6 +35,9 @@ pub enum VhdxError {
     #[error("No valid VHDX header found")]
     VhdxHeaderError,
 
+    #[error("No intact region table found")]
+    NoRegionTable,
+
     #[error("VHDX Version error should be 1 got: {0}")]
     VersionError(u16),
 
@@ -45,6 +50,126 @@ pub enum VhdxError {
     #[error("{0} value, is not dividable by 4096 (4KB): {1}")]
     NotDivisbleBy4KB(&'static str, u64),
 
+    #[error("Log entry length doesn't fit its descriptors expected: {0}, got: {1}")]
+    LogEntryLengthError(u64, u64),
+
+    #[error("{0} sequence number doesn't match its log entry expected: {1}, got: {2}")]
+    LogSequenceNumberError(&'static str, u64, u64),
+
+    #[error("Unknown required metadata item: {0}")]
+    UnknownMetaDataItem(Uuid),
+
+    #[error("Missing metadata item: {0}")]
+    MissingMetaDataItem(&'static str),
+
+    #[error("No room left in the metadata region for item: {0}")]
+    MetaDataRegionFull(&'static str),
+
+    #[error("Parent of the differencing disk not found: {0}")]
+    ParentNotFound(String),
+
+    #[error("Parent linkage doesn't match the parent expected: {0}, got: {1}")]
+    ParentLinkageError(Uuid, Uuid),
+
+    #[error("Parent is already part of the chain: {0}")]
+    ParentCycle(String),
+
+    #[error("More than one file matches the parent path regardless of case: {0}")]
+    AmbiguousParent(String),
+
+    #[error("Parent has log entries that were never replayed: {0}")]
+    ParentLogNotReplayed(String),
+
+    #[error("Parent isn't compatible with the differencing disk: {0}")]
+    IncompatibleParent(&'static str),
+
+    #[error("Block size must be a power of two between 1 MB and 256 MB got: {0}")]
+    InvalidBlockSize(u64),
+
+    #[error("Virtual disk size must be a multiple of the logical sector size got: {0}")]
+    InvalidVirtualDiskSize(u64),
+
+    #[error("Blocks of zeros can only be NotPresent or Zero got: {0:?}")]
+    InvalidZeroBlockState(BatEntryState),
+
+    #[error("Payload block {0} is past the end of the virtual disk")]
+    BlockOutOfRange(u64),
+
+    #[error("{0} bytes don't fit in a payload block")]
+    BlockDataTooLong(u64),
+
+    #[error("Invalid allocation map: {0}")]
+    InvalidAllocationMap(&'static str),
+
+    #[error("Payload block {0} isn't the next block of the stream")]
+    UnexpectedBlock(u64),
+
+    #[error("Stream finished before payload block {0} was written")]
+    IncompleteStream(u64),
+
+    #[error("Not a valid VHD: {0}")]
+    InvalidVhd(&'static str),
+
+    #[error("VHD checksum doesn't match expected: {0}, got: {1}")]
+    VhdChecksumError(u32, u32),
+
+    #[error("Unsupported VHD disk type: {0}")]
+    UnsupportedVhdType(u32),
+
+    #[error("Virtual disk is larger than the 2040 GB a VHD supports got: {0}")]
+    VhdTooLarge(u64),
+
+    #[error("Not a valid qcow2 image: {0}")]
+    InvalidQcow2(&'static str),
+
+    #[error("Unsupported qcow2 feature: {0}")]
+    UnsupportedQcow2(&'static str),
+
+    #[error("qcow2 cluster bits must be between 9 and 20 got: {0}")]
+    InvalidClusterSize(u32),
+
+    #[error("Not a valid VMDK: {0}")]
+    InvalidVmdk(&'static str),
+
+    #[error("Not a valid VDI: {0}")]
+    InvalidVdi(&'static str),
+
+    #[error("Unsupported VDI feature: {0}")]
+    UnsupportedVdi(&'static str),
+
+    #[error("Shrinking would discard data of payload block {0}")]
+    ShrinkDiscardsData(u64),
+
+    #[error("Not a valid partition table: {0}")]
+    InvalidPartitionTable(&'static str),
+
+    #[error("Not a valid FAT or exFAT filesystem: {0}")]
+    InvalidFat(&'static str),
+
+    #[error("Not a valid ext2, ext3 or ext4 filesystem: {0}")]
+    InvalidExt4(&'static str),
+
+    #[error("Unsupported ext4 feature: {0}")]
+    UnsupportedExt4(&'static str),
+
+    #[error("Not a symbolic link: {0}")]
+    NotASymlink(String),
+
+    #[error("Too many levels of symbolic links: {0}")]
+    SymlinkLoop(String),
+
+    #[error("No such file or directory: {0}")]
+    FileNotFound(String),
+
+    #[error("Not a directory: {0}")]
+    NotADirectory(String),
+
+    #[error("Is a directory: {0}")]
+    IsADirectory(String),
+
+    #[error("Path is outside of the image store: {0}")]
+    OutsideImageStore(PathBuf),
+
     #[error("{0} number is not allowed to be zero")]
     NotAllowedToBeZero(&'static str),
 }
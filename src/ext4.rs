@@ -0,0 +1,1016 @@
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+};
+
+use crate::{
+    error::VhdxError,
+    partition::{Partition, PartitionReader},
+    vhdx::Vhdx,
+    virtual_disk::VirtualDisk,
+};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_64BIT: u32 = 0x0080;
+
+// Features changing where metadata lives or what names and contents mean, that a reader not
+// knowing them would get wrong.
+const INCOMPAT_UNSUPPORTED: [(u32, &str); 5] = [
+    (0x0001, "Compression"),
+    (0x0008, "External journal device"),
+    (0x0010, "Meta block groups"),
+    (0x1_0000, "Encryption"),
+    (0x2_0000, "Case folding"),
+];
+
+const EXTENTS_FL: u32 = 0x8_0000;
+const INLINE_DATA_FL: u32 = 0x1000_0000;
+const EXTENT_MAGIC: u16 = 0xF30A;
+const MAX_EXTENT_DEPTH: u16 = 5;
+
+// Extents longer than this are preallocated but unwritten, their length is the excess.
+const MAX_INITIALIZED_EXTENT: u16 = 32768;
+
+// The 15 block pointers, or the extent tree root, or the inline data, of an inode.
+const I_BLOCK_LENGTH: usize = 60;
+const DIRECT_BLOCKS: u64 = 12;
+
+// The limit of Linux when resolving a path.
+const MAX_SYMLINKS: u32 = 40;
+
+// Directories larger than this are taken for corrupted rather than read into memory.
+const MAX_DIRECTORY_LENGTH: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ext4FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharacterDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    Unknown,
+}
+
+impl Ext4FileType {
+    fn from_mode(mode: u16) -> Self {
+        match mode >> 12 {
+            0x8 => Ext4FileType::Regular,
+            0x4 => Ext4FileType::Directory,
+            0xA => Ext4FileType::Symlink,
+            0x2 => Ext4FileType::CharacterDevice,
+            0x6 => Ext4FileType::BlockDevice,
+            0x1 => Ext4FileType::Fifo,
+            0xC => Ext4FileType::Socket,
+            _ => Ext4FileType::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ext4Metadata {
+    pub inode: u32,
+    pub file_type: Ext4FileType,
+
+    // The permission bits, with setuid, setgid and sticky.
+    pub permissions: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub links: u16,
+
+    // Seconds since the Unix epoch.
+    pub accessed: i64,
+    pub modified: i64,
+    pub changed: i64,
+
+    flags: u32,
+    block: [u8; I_BLOCK_LENGTH],
+}
+
+impl Ext4Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.file_type == Ext4FileType::Directory
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == Ext4FileType::Symlink
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ext4DirEntry {
+    pub name: String,
+    pub metadata: Ext4Metadata,
+}
+
+// A run of blocks of a file, in blocks of the filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Extent {
+    logical: u64,
+    length: u64,
+    physical: u64,
+
+    // Preallocated blocks that were never written read as zeros.
+    initialized: bool,
+}
+
+// An ext2, ext3 or ext4 filesystem opened for reading, without replaying its journal. Paths are
+// separated by slashes and matched exactly, symbolic links in them are followed with absolute
+// targets taken from the root of this filesystem.
+pub struct Ext4FileSystem<R> {
+    volume: R,
+    block_size: u64,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    descriptor_size: u64,
+
+    // Block holding the first group descriptor, the one following the superblock.
+    descriptor_block: u64,
+    incompat_features: u32,
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    pub fn open_ext4(
+        &mut self,
+        partition: &Partition,
+    ) -> Result<Ext4FileSystem<PartitionReader<VirtualDisk<'_, T>>>, VhdxError> {
+        Ext4FileSystem::new(self.open_partition(partition))
+    }
+}
+
+impl<R> Ext4FileSystem<R>
+where
+    R: Read + Seek,
+{
+    pub fn new(mut volume: R) -> Result<Self, VhdxError> {
+        let mut superblock = vec![0; 1024];
+        volume.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+        volume.read_exact(&mut superblock)?;
+        if le16(&superblock, 56) != MAGIC {
+            return Err(VhdxError::InvalidExt4("Superblock magic not found"));
+        }
+
+        let log_block_size = le32(&superblock, 24);
+        if log_block_size > 6 {
+            return Err(VhdxError::InvalidExt4("Block size larger than 64 KB"));
+        }
+        let block_size = 1024 << log_block_size;
+        let first_data_block = le32(&superblock, 20) as u64;
+        let inodes_count = le32(&superblock, 0);
+        let inodes_per_group = le32(&superblock, 40);
+        if inodes_per_group == 0 {
+            return Err(VhdxError::InvalidExt4("No inodes per group"));
+        }
+
+        // Revision 0 has fixed 128 byte inodes.
+        let inode_size = match le32(&superblock, 76) {
+            0 => 128,
+            _ => le16(&superblock, 88) as u64,
+        };
+        if inode_size < 128 || !inode_size.is_power_of_two() || inode_size > block_size {
+            return Err(VhdxError::InvalidExt4("Invalid inode size"));
+        }
+
+        let incompat_features = le32(&superblock, 96);
+        if let Some((_, feature)) = INCOMPAT_UNSUPPORTED
+            .iter()
+            .find(|(flag, _)| incompat_features & flag != 0)
+        {
+            return Err(VhdxError::UnsupportedExt4(feature));
+        }
+        let descriptor_size = match incompat_features & INCOMPAT_64BIT {
+            0 => 32,
+            _ => le16(&superblock, 254) as u64,
+        };
+        if !(32..=block_size).contains(&descriptor_size) {
+            return Err(VhdxError::InvalidExt4("Invalid group descriptor size"));
+        }
+
+        Ok(Self {
+            volume,
+            block_size,
+            inodes_count,
+            inodes_per_group,
+            inode_size,
+            descriptor_size,
+            descriptor_block: first_data_block + 1,
+            incompat_features,
+        })
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    // Lists a directory, without its "." and ".." entries.
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<Ext4DirEntry>, VhdxError> {
+        let directory = self.metadata(path)?;
+        if !directory.is_dir() {
+            return Err(VhdxError::NotADirectory(path.to_string()));
+        }
+        let mut entries = Vec::new();
+        for (name, inode) in self.entries(&directory)? {
+            if name != "." && name != ".." {
+                let metadata = self.inode(inode)?;
+                entries.push(Ext4DirEntry { name, metadata });
+            }
+        }
+        Ok(entries)
+    }
+
+    // The metadata of a file, following symbolic links.
+    pub fn metadata(&mut self, path: &str) -> Result<Ext4Metadata, VhdxError> {
+        self.lookup(path, true)
+    }
+
+    // The metadata of a file, or of the symbolic link itself.
+    pub fn symlink_metadata(&mut self, path: &str) -> Result<Ext4Metadata, VhdxError> {
+        self.lookup(path, false)
+    }
+
+    pub fn read_link(&mut self, path: &str) -> Result<String, VhdxError> {
+        let link = self.symlink_metadata(path)?;
+        if !link.is_symlink() {
+            return Err(VhdxError::NotASymlink(path.to_string()));
+        }
+        self.link_target(&link)
+    }
+
+    // Opens a file to read its contents, following symbolic links.
+    pub fn open(&mut self, path: &str) -> Result<Ext4File<'_, R>, VhdxError> {
+        let file = self.metadata(path)?;
+        if file.is_dir() {
+            return Err(VhdxError::IsADirectory(path.to_string()));
+        }
+        let content = self.content(&file)?;
+        Ok(Ext4File {
+            filesystem: self,
+            content,
+            size: file.size,
+            position: 0,
+        })
+    }
+
+    fn lookup(&mut self, path: &str, follow_last: bool) -> Result<Ext4Metadata, VhdxError> {
+        let mut remaining = components(path);
+        let mut current = self.inode(ROOT_INODE)?;
+        let mut symlinks = 0;
+        while let Some(component) = remaining.pop_front() {
+            if !current.is_dir() {
+                return Err(VhdxError::NotADirectory(path.to_string()));
+            }
+            let inode = self
+                .entries(&current)?
+                .into_iter()
+                .find(|(name, _)| *name == component)
+                .map(|(_, inode)| inode)
+                .ok_or_else(|| VhdxError::FileNotFound(path.to_string()))?;
+            let entry = self.inode(inode)?;
+
+            if entry.is_symlink() && (follow_last || !remaining.is_empty()) {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    return Err(VhdxError::SymlinkLoop(path.to_string()));
+                }
+                let target = self.link_target(&entry)?;
+                if target.starts_with('/') {
+                    current = self.inode(ROOT_INODE)?;
+                }
+                for component in components(&target).into_iter().rev() {
+                    remaining.push_front(component);
+                }
+                continue;
+            }
+            current = entry;
+        }
+        Ok(current)
+    }
+
+    fn inode(&mut self, inode: u32) -> Result<Ext4Metadata, VhdxError> {
+        if inode == 0 || inode > self.inodes_count {
+            return Err(VhdxError::InvalidExt4("Inode number out of range"));
+        }
+        let group = ((inode - 1) / self.inodes_per_group) as u64;
+        let index = ((inode - 1) % self.inodes_per_group) as u64;
+
+        let descriptor = self.read_at(
+            self.descriptor_block * self.block_size + group * self.descriptor_size,
+            self.descriptor_size,
+        )?;
+        let mut inode_table = le32(&descriptor, 8) as u64;
+        if self.incompat_features & INCOMPAT_64BIT != 0 && self.descriptor_size >= 64 {
+            inode_table |= (le32(&descriptor, 40) as u64) << 32;
+        }
+        let offset = inode_table
+            .checked_mul(self.block_size)
+            .and_then(|table| table.checked_add(index * self.inode_size))
+            .ok_or(VhdxError::InvalidExt4(
+                "Inode table past the end of the volume",
+            ))?;
+        let raw = self.read_at(offset, self.inode_size)?;
+
+        // Timestamps past 2038 carry their upper bits in the extra fields of large inodes.
+        let extra_size = match self.inode_size {
+            128 => 0,
+            _ => le16(&raw, 128) as usize,
+        };
+        let timestamp = |offset: usize, extra: usize| {
+            let seconds = le32(&raw, offset) as i32 as i64;
+            match extra + 4 <= 128 + extra_size && extra + 4 <= raw.len() {
+                true => seconds + ((le32(&raw, extra) as i64 & 0x3) << 32),
+                false => seconds,
+            }
+        };
+
+        let mode = le16(&raw, 0);
+        Ok(Ext4Metadata {
+            inode,
+            file_type: Ext4FileType::from_mode(mode),
+            permissions: mode & 0o7777,
+            uid: le16(&raw, 2) as u32 | (le16(&raw, 120) as u32) << 16,
+            gid: le16(&raw, 24) as u32 | (le16(&raw, 122) as u32) << 16,
+            size: le32(&raw, 4) as u64 | (le32(&raw, 108) as u64) << 32,
+            links: le16(&raw, 26),
+            accessed: timestamp(8, 140),
+            modified: timestamp(16, 136),
+            changed: timestamp(12, 132),
+            flags: le32(&raw, 32),
+            block: raw[40..40 + I_BLOCK_LENGTH].try_into().unwrap(),
+        })
+    }
+
+    // The names and inodes of every entry of a directory, "." and ".." included.
+    fn entries(&mut self, directory: &Ext4Metadata) -> Result<Vec<(String, u32)>, VhdxError> {
+        if directory.size > MAX_DIRECTORY_LENGTH {
+            return Err(VhdxError::InvalidExt4("Directory too large"));
+        }
+        // Inline directories start with the inode of their parent.
+        if directory.flags & INLINE_DATA_FL != 0 {
+            let mut entries = vec![
+                (".".to_string(), directory.inode),
+                ("..".to_string(), le32(&directory.block, 0)),
+            ];
+            entries.extend(self.directory_entries(&directory.block[4..]));
+            return Ok(entries);
+        }
+
+        let content = self.content(directory)?;
+        let mut data = Vec::new();
+        Ext4File {
+            filesystem: self,
+            content,
+            size: directory.size,
+            position: 0,
+        }
+        .read_to_end(&mut data)?;
+
+        let mut entries = Vec::new();
+        for block in data.chunks(self.block_size as usize) {
+            entries.extend(self.directory_entries(block));
+        }
+        Ok(entries)
+    }
+
+    // The entries of a directory block. Entries of deleted files and the checksum tail have no
+    // inode, and hashed directories hide their index in such entries too.
+    fn directory_entries(&self, block: &[u8]) -> Vec<(String, u32)> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= block.len() {
+            let inode = le32(block, offset);
+            let record_length = le16(block, offset + 4) as usize;
+            let name_length = match self.incompat_features & INCOMPAT_FILETYPE {
+                0 => le16(block, offset + 6) as usize,
+                _ => block[offset + 6] as usize,
+            };
+            if record_length < 8 || offset + 8 + name_length > block.len() {
+                break;
+            }
+            if inode != 0 && name_length > 0 {
+                let name = &block[offset + 8..offset + 8 + name_length];
+                entries.push((String::from_utf8_lossy(name).into_owned(), inode));
+            }
+            offset += record_length;
+        }
+        entries
+    }
+
+    fn link_target(&mut self, link: &Ext4Metadata) -> Result<String, VhdxError> {
+        if link.size > self.block_size {
+            return Err(VhdxError::InvalidExt4("Symbolic link target too long"));
+        }
+        let content = self.content(link)?;
+        let mut target = Vec::new();
+        Ext4File {
+            filesystem: self,
+            content,
+            size: link.size,
+            position: 0,
+        }
+        .read_to_end(&mut target)?;
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    // Where the contents of a file are. Symbolic links of less than 60 bytes keep their target
+    // in the inode instead of the block map, like inline data.
+    fn content(&mut self, file: &Ext4Metadata) -> Result<Content, VhdxError> {
+        let inline = file.flags & INLINE_DATA_FL != 0
+            || (file.is_symlink() && file.size < I_BLOCK_LENGTH as u64);
+        if inline {
+            if file.size > I_BLOCK_LENGTH as u64 {
+                return Err(VhdxError::UnsupportedExt4(
+                    "Inline data in extended attributes",
+                ));
+            }
+            return Ok(Content::Inline(file.block[..file.size as usize].to_vec()));
+        }
+
+        // Only the extents of the blocks below the size are kept, and there can't be more of
+        // them than blocks nor more nodes leading to them than one per level for each. This
+        // keeps a tree sharing its nodes between many entries from being walked for ages.
+        let blocks = file.size.div_ceil(self.block_size);
+        let mut extents = Vec::new();
+        if file.flags & EXTENTS_FL != 0 {
+            let mut nodes = 0;
+            self.map_extents(&file.block, None, blocks, &mut nodes, &mut extents)?;
+            extents.sort_by_key(|extent| extent.logical);
+        } else {
+            let pointers_per_block = self.block_size / 4;
+            let mut logical = 0;
+            for (i, pointer) in file.block.chunks_exact(4).enumerate() {
+                let depth = (i as u32).saturating_sub(DIRECT_BLOCKS as u32 - 1);
+                self.map_indirect(le32(pointer, 0), depth, logical, blocks, &mut extents)?;
+                logical += pointers_per_block.pow(depth);
+            }
+        }
+        Ok(Content::Extents(extents))
+    }
+
+    // Collects the extents of a node of an extent tree, the root one being in the inode.
+    fn map_extents(
+        &mut self,
+        node: &[u8],
+        expected_depth: Option<u16>,
+        blocks: u64,
+        nodes: &mut u64,
+        extents: &mut Vec<Extent>,
+    ) -> Result<(), VhdxError> {
+        if le16(node, 0) != EXTENT_MAGIC {
+            return Err(VhdxError::InvalidExt4("Extent header magic not found"));
+        }
+        let entries = le16(node, 2) as usize;
+        let depth = le16(node, 6);
+        if depth > MAX_EXTENT_DEPTH || expected_depth.is_some_and(|expected| expected != depth) {
+            return Err(VhdxError::InvalidExt4("Invalid extent tree depth"));
+        }
+        if 12 + entries * 12 > node.len() {
+            return Err(VhdxError::InvalidExt4("Extent node overflows its block"));
+        }
+
+        for entry in node[12..12 + entries * 12].chunks_exact(12) {
+            // Blocks preallocated past the end of the file are never read.
+            if le32(entry, 0) as u64 >= blocks {
+                continue;
+            }
+            if depth == 0 {
+                if extents.len() as u64 >= blocks {
+                    return Err(VhdxError::InvalidExt4(
+                        "More extents than blocks in the file",
+                    ));
+                }
+                let length = le16(entry, 4);
+                let (length, initialized) = match length > MAX_INITIALIZED_EXTENT {
+                    true => (length - MAX_INITIALIZED_EXTENT, false),
+                    false => (length, true),
+                };
+                extents.push(Extent {
+                    logical: le32(entry, 0) as u64,
+                    length: length as u64,
+                    physical: (le16(entry, 6) as u64) << 32 | le32(entry, 8) as u64,
+                    initialized,
+                });
+            } else {
+                *nodes += 1;
+                if *nodes > blocks.saturating_mul(MAX_EXTENT_DEPTH as u64) {
+                    return Err(VhdxError::InvalidExt4("Extent tree larger than the file"));
+                }
+                let leaf = le32(entry, 4) as u64 | (le16(entry, 8) as u64) << 32;
+                let offset = leaf
+                    .checked_mul(self.block_size)
+                    .ok_or(VhdxError::InvalidExt4(
+                        "Extent node past the end of the volume",
+                    ))?;
+                let child = self.read_at(offset, self.block_size)?;
+                self.map_extents(&child, Some(depth - 1), blocks, nodes, extents)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Collects the blocks under a block pointer of ext2 and ext3 files, a data block at depth 0
+    // or a block of pointers for each level above. Null pointers are holes.
+    fn map_indirect(
+        &mut self,
+        pointer: u32,
+        depth: u32,
+        logical: u64,
+        blocks: u64,
+        extents: &mut Vec<Extent>,
+    ) -> Result<(), VhdxError> {
+        if pointer == 0 || logical >= blocks {
+            return Ok(());
+        }
+        if depth == 0 {
+            let count = extents.len() as u64;
+            match extents.last_mut() {
+                Some(last)
+                    if last.logical + last.length == logical
+                        && last.physical + last.length == pointer as u64 =>
+                {
+                    last.length += 1
+                }
+                _ if count >= blocks => {
+                    return Err(VhdxError::InvalidExt4(
+                        "More extents than blocks in the file",
+                    ))
+                }
+                _ => extents.push(Extent {
+                    logical,
+                    length: 1,
+                    physical: pointer as u64,
+                    initialized: true,
+                }),
+            }
+            return Ok(());
+        }
+
+        let pointers = self.read_at(pointer as u64 * self.block_size, self.block_size)?;
+        let span = (self.block_size / 4).pow(depth - 1);
+        for (i, child) in pointers.chunks_exact(4).enumerate() {
+            let logical = logical + i as u64 * span;
+            self.map_indirect(le32(child, 0), depth - 1, logical, blocks, extents)?;
+        }
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u64, length: u64) -> Result<Vec<u8>, VhdxError> {
+        let mut buffer = vec![0; length as usize];
+        self.volume.seek(SeekFrom::Start(offset))?;
+        self.volume.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+fn components(path: &str) -> VecDeque<String> {
+    path.split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .map(str::to_string)
+        .collect()
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+enum Content {
+    Inline(Vec<u8>),
+
+    // Sorted by logical block, blocks between them are holes.
+    Extents(Vec<Extent>),
+}
+
+// The contents of a file of an ext4 filesystem.
+pub struct Ext4File<'a, R> {
+    filesystem: &'a mut Ext4FileSystem<R>,
+    content: Content,
+    size: u64,
+    position: u64,
+}
+
+impl<R> Ext4File<'_, R> {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<R> Read for Ext4File<'_, R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let remaining = (buf.len() as u64).min(self.size - self.position);
+        let extents = match &self.content {
+            Content::Inline(data) => {
+                let start = self.position as usize;
+                let length = remaining as usize;
+                buf[..length].copy_from_slice(&data[start..start + length]);
+                self.position += remaining;
+                return Ok(length);
+            }
+            Content::Extents(extents) => extents,
+        };
+
+        let block_size = self.filesystem.block_size;
+        let block = self.position / block_size;
+        let index = extents.partition_point(|extent| extent.logical <= block);
+        let extent = index
+            .checked_sub(1)
+            .map(|i| extents[i])
+            .filter(|extent| block < extent.logical + extent.length);
+
+        let length = match extent {
+            Some(extent) => {
+                let end = (extent.logical + extent.length) * block_size;
+                let length = remaining.min(end - self.position);
+                if extent.initialized {
+                    let offset = (extent.physical + block - extent.logical)
+                        .checked_mul(block_size)
+                        .and_then(|offset| offset.checked_add(self.position % block_size))
+                        .ok_or_else(|| {
+                            io::Error::new(
+                                ErrorKind::InvalidData,
+                                "Extent past the end of the volume",
+                            )
+                        })?;
+                    let volume = &mut self.filesystem.volume;
+                    volume.seek(SeekFrom::Start(offset))?;
+                    volume.read_exact(&mut buf[..length as usize])?;
+                } else {
+                    buf[..length as usize].fill(0);
+                }
+                length
+            }
+            None => {
+                let hole_end = extents
+                    .get(index)
+                    .map_or(u64::MAX, |next| next.logical.saturating_mul(block_size));
+                let length = remaining.min(hole_end - self.position);
+                buf[..length as usize].fill(0);
+                length
+            }
+        };
+        self.position += length;
+        Ok(length as usize)
+    }
+}
+
+impl<R> Seek for Ext4File<'_, R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const BLOCK: u64 = 1024;
+    const INODE_TABLE: u64 = 5;
+
+    fn put(volume: &mut [u8], offset: u64, bytes: &[u8]) {
+        volume[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn superblock(volume: &mut [u8], incompat_features: u32) {
+        for (offset, value) in [
+            (0, 32u32),
+            (4, 128),
+            (20, 1),
+            (32, 8192),
+            (40, 32),
+            (76, 1),
+            (96, incompat_features),
+        ] {
+            put(volume, BLOCK + offset, &value.to_le_bytes());
+        }
+        put(volume, BLOCK + 56, &MAGIC.to_le_bytes());
+        put(volume, BLOCK + 88, &256u16.to_le_bytes());
+        put(volume, 2 * BLOCK + 8, &(INODE_TABLE as u32).to_le_bytes());
+    }
+
+    fn inode(volume: &mut [u8], inode: u32, mode: u16, size: u64, flags: u32, block: &[u8]) {
+        let offset = INODE_TABLE * BLOCK + (inode as u64 - 1) * 256;
+        put(volume, offset, &mode.to_le_bytes());
+        put(volume, offset + 2, &1000u16.to_le_bytes());
+        put(volume, offset + 4, &(size as u32).to_le_bytes());
+        put(volume, offset + 16, &1_700_000_000u32.to_le_bytes());
+        put(volume, offset + 26, &1u16.to_le_bytes());
+        put(volume, offset + 32, &flags.to_le_bytes());
+        put(volume, offset + 40, block);
+        put(volume, offset + 128, &32u16.to_le_bytes());
+        // 2446-05-10, past the 32 bits of the base field.
+        put(volume, offset + 136, &1u32.to_le_bytes());
+    }
+
+    fn extent_header(entries: u16, depth: u16) -> Vec<u8> {
+        [EXTENT_MAGIC, entries, 4, depth, 0, 0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    fn extent(logical: u32, length: u16, physical: u32) -> Vec<u8> {
+        let mut extent = logical.to_le_bytes().to_vec();
+        extent.extend(length.to_le_bytes());
+        extent.extend(0u16.to_le_bytes());
+        extent.extend(physical.to_le_bytes());
+        extent
+    }
+
+    fn directory(volume: &mut [u8], block: u64, entries: &[(u32, &str)]) {
+        let mut offset = block * BLOCK;
+        for (i, (inode, name)) in entries.iter().enumerate() {
+            let length = match i == entries.len() - 1 {
+                true => (block + 1) * BLOCK - offset,
+                false => (8 + name.len() as u64).next_multiple_of(4),
+            };
+            put(volume, offset, &inode.to_le_bytes());
+            put(volume, offset + 4, &(length as u16).to_le_bytes());
+            put(volume, offset + 6, &[name.len() as u8, 0]);
+            put(volume, offset + 8, name.as_bytes());
+            offset += length;
+        }
+    }
+
+    // A filesystem of 1 KB blocks with /var/log holding a log mapped by a two level extent tree
+    // with a hole and an unwritten extent, links to it, and an ext3 file of indirect blocks.
+    fn volume() -> Vec<u8> {
+        let mut volume = vec![0; 128 * BLOCK as usize];
+        superblock(&mut volume, INCOMPAT_FILETYPE | 0x0040);
+        let one_block = |block: u32| [extent_header(1, 0), extent(0, 1, block)].concat();
+
+        inode(&mut volume, 2, 0o40755, BLOCK, EXTENTS_FL, &one_block(20));
+        directory(
+            &mut volume,
+            20,
+            &[
+                (2, "."),
+                (2, ".."),
+                (12, "var"),
+                (17, "old.log"),
+                (18, "loop"),
+            ],
+        );
+        inode(&mut volume, 12, 0o40755, BLOCK, EXTENTS_FL, &one_block(21));
+        directory(&mut volume, 21, &[(12, "."), (2, ".."), (13, "log")]);
+        inode(&mut volume, 13, 0o40750, BLOCK, EXTENTS_FL, &one_block(22));
+        directory(
+            &mut volume,
+            22,
+            &[
+                (13, "."),
+                (12, ".."),
+                (0, "deleted"),
+                (14, "syslog"),
+                (15, "messages"),
+                (16, "current"),
+            ],
+        );
+
+        let mut root = extent_header(1, 1);
+        root.extend([0, 0, 0, 0, 25, 0, 0, 0, 0, 0, 0, 0]);
+        inode(&mut volume, 14, 0o100640, 3500, EXTENTS_FL, &root);
+        let leaf = [
+            extent_header(3, 0),
+            extent(0, 1, 30),
+            extent(2, 1, 40),
+            extent(3, MAX_INITIALIZED_EXTENT + 1, 41),
+        ]
+        .concat();
+        put(&mut volume, 25 * BLOCK, &leaf);
+        put(&mut volume, 30 * BLOCK, &[b'a'; 1024]);
+        put(&mut volume, 40 * BLOCK, &[b'c'; 1024]);
+        put(&mut volume, 41 * BLOCK, &[b'x'; 1024]);
+
+        inode(&mut volume, 15, 0o120777, 6, 0, b"syslog");
+        let target = format!("/var/log/{}syslog", "../log/".repeat(8));
+        inode(
+            &mut volume,
+            16,
+            0o120777,
+            target.len() as u64,
+            EXTENTS_FL,
+            &one_block(23),
+        );
+        put(&mut volume, 23 * BLOCK, target.as_bytes());
+
+        // Twelve direct blocks 50 to 61, then blocks 62 and 63 through the indirect block 49.
+        let mut pointers: Vec<u8> = (50u32..62).flat_map(u32::to_le_bytes).collect();
+        pointers.extend(49u32.to_le_bytes());
+        pointers.resize(60, 0);
+        inode(&mut volume, 17, 0o100644, 14 * BLOCK - 10, 0, &pointers);
+        put(&mut volume, 49 * BLOCK, &[62, 0, 0, 0, 63, 0, 0, 0]);
+        for block in 50..64 {
+            put(&mut volume, block * BLOCK, &[block as u8; 1024]);
+        }
+
+        inode(&mut volume, 18, 0o120777, 4, 0, b"loop");
+        volume
+    }
+
+    #[test]
+    fn should_read_files_through_extent_trees_and_block_maps() {
+        let mut filesystem = Ext4FileSystem::new(Cursor::new(volume())).unwrap();
+
+        let mut syslog = Vec::new();
+        filesystem
+            .open("/var/log/syslog")
+            .unwrap()
+            .read_to_end(&mut syslog)
+            .unwrap();
+        let mut old = Vec::new();
+        let mut file = filesystem.open("old.log").unwrap();
+        file.seek(SeekFrom::Start(11 * BLOCK + 1000)).unwrap();
+        file.read_to_end(&mut old).unwrap();
+
+        assert_eq!(3500, syslog.len());
+        assert!(syslog[..1024].iter().all(|b| *b == b'a'));
+        assert!(syslog[1024..2048].iter().all(|b| *b == 0));
+        assert!(syslog[2048..3072].iter().all(|b| *b == b'c'));
+        assert!(syslog[3072..].iter().all(|b| *b == 0));
+        assert_eq!([vec![61; 24], vec![62; 1024], vec![63; 1014]].concat(), old);
+    }
+
+    #[test]
+    fn should_list_directories_and_follow_symbolic_links() {
+        let mut filesystem = Ext4FileSystem::new(Cursor::new(volume())).unwrap();
+
+        let entries: Vec<(String, Ext4FileType)> = filesystem
+            .read_dir("/var/log/")
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.name, entry.metadata.file_type))
+            .collect();
+        let syslog = filesystem.metadata("/var/log/syslog").unwrap();
+        let messages = filesystem.symlink_metadata("/var/log/messages").unwrap();
+
+        assert_eq!(
+            vec![
+                ("syslog".to_string(), Ext4FileType::Regular),
+                ("messages".to_string(), Ext4FileType::Symlink),
+                ("current".to_string(), Ext4FileType::Symlink),
+            ],
+            entries
+        );
+        assert_eq!(
+            (14, 0o640, 1000, 3500, 1_700_000_000 + (1 << 32)),
+            (
+                syslog.inode,
+                syslog.permissions,
+                syslog.uid,
+                syslog.size,
+                syslog.modified
+            )
+        );
+        assert_eq!((15, 6), (messages.inode, messages.size));
+        assert_eq!(syslog, filesystem.metadata("var/log/messages").unwrap());
+        assert_eq!(syslog, filesystem.metadata("/var/log/current").unwrap());
+        assert_eq!(
+            syslog,
+            filesystem.metadata("/var/./log/../log/syslog").unwrap()
+        );
+        assert_eq!("syslog", filesystem.read_link("/var/log/messages").unwrap());
+        assert!(matches!(
+            filesystem.metadata("/loop"),
+            Err(VhdxError::SymlinkLoop(_))
+        ));
+        assert!(matches!(
+            filesystem.read_dir("/var/log/syslog"),
+            Err(VhdxError::NotADirectory(_))
+        ));
+        assert!(matches!(
+            filesystem.metadata("/var/log/deleted"),
+            Err(VhdxError::FileNotFound(_))
+        ));
+        assert!(matches!(
+            filesystem.open("/var"),
+            Err(VhdxError::IsADirectory(_))
+        ));
+    }
+
+    #[test]
+    fn should_refuse_unknown_volumes_and_features() {
+        let mut meta_block_groups = volume();
+        superblock(&mut meta_block_groups, INCOMPAT_FILETYPE | 0x0010);
+
+        assert!(matches!(
+            Ext4FileSystem::new(Cursor::new(vec![0; 4096])),
+            Err(VhdxError::InvalidExt4(_))
+        ));
+        assert!(matches!(
+            Ext4FileSystem::new(Cursor::new(meta_block_groups)),
+            Err(VhdxError::UnsupportedExt4("Meta block groups"))
+        ));
+    }
+
+    #[test]
+    fn should_refuse_corrupt_extent_trees() {
+        const SYSLOG: u64 = INODE_TABLE * BLOCK + 13 * 256 + 40;
+        let open = |patches: &[(u64, Vec<u8>)]| {
+            let mut volume = volume();
+            for (offset, bytes) in patches {
+                put(&mut volume, *offset, bytes);
+            }
+            let mut filesystem = Ext4FileSystem::new(Cursor::new(volume)).unwrap();
+            filesystem.open("/var/log/syslog").map(|_| ()).unwrap_err()
+        };
+        // An index node of 84 entries all pointing to the same empty leaf.
+        let mut fan_out = extent_header(84, 1);
+        for _ in 0..84 {
+            fan_out.extend([0, 0, 0, 0, 26, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        let mut repeated = extent_header(5, 0);
+        for _ in 0..5 {
+            repeated.extend(extent(0, 1, 30));
+        }
+
+        assert!(matches!(
+            open(&[(25 * BLOCK, extent_header(3, 1))]),
+            VhdxError::InvalidExt4("Invalid extent tree depth")
+        ));
+        assert!(matches!(
+            open(&[(SYSLOG, extent_header(5, 1))]),
+            VhdxError::InvalidExt4("Extent node overflows its block")
+        ));
+        assert!(matches!(
+            open(&[(25 * BLOCK, repeated)]),
+            VhdxError::InvalidExt4("More extents than blocks in the file")
+        ));
+        assert!(matches!(
+            open(&[
+                (SYSLOG, extent_header(1, 2)),
+                (25 * BLOCK, fan_out),
+                (26 * BLOCK, extent_header(0, 0)),
+            ]),
+            VhdxError::InvalidExt4("Extent tree larger than the file")
+        ));
+    }
+
+    #[test]
+    fn should_refuse_descriptors_and_inode_tables_out_of_range() {
+        let mut small_descriptors = volume();
+        superblock(
+            &mut small_descriptors,
+            INCOMPAT_FILETYPE | 0x0040 | INCOMPAT_64BIT,
+        );
+        put(&mut small_descriptors, BLOCK + 254, &16u16.to_le_bytes());
+        let mut far_inode_table = small_descriptors.clone();
+        put(&mut far_inode_table, BLOCK + 254, &64u16.to_le_bytes());
+        put(
+            &mut far_inode_table,
+            2 * BLOCK + 40,
+            &u32::MAX.to_le_bytes(),
+        );
+
+        let mut filesystem = Ext4FileSystem::new(Cursor::new(far_inode_table)).unwrap();
+
+        assert!(matches!(
+            Ext4FileSystem::new(Cursor::new(small_descriptors)),
+            Err(VhdxError::InvalidExt4("Invalid group descriptor size"))
+        ));
+        assert!(matches!(
+            filesystem.metadata("/"),
+            Err(VhdxError::InvalidExt4(
+                "Inode table past the end of the volume"
+            ))
+        ));
+    }
+}
@@ -0,0 +1,1035 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+};
+
+use crate::{
+    error::VhdxError,
+    partition::{Partition, PartitionReader},
+    vhdx::Vhdx,
+    virtual_disk::VirtualDisk,
+};
+
+pub const READ_ONLY: u16 = 0x01;
+pub const HIDDEN: u16 = 0x02;
+pub const SYSTEM: u16 = 0x04;
+pub const VOLUME_ID: u16 = 0x08;
+pub const DIRECTORY: u16 = 0x10;
+pub const ARCHIVE: u16 = 0x20;
+const LONG_NAME: u8 = 0x0F;
+
+// Directories are read no further than this, FAT caps them at 65536 entries.
+const MAX_DIRECTORY_LENGTH: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+    ExFat,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FatDateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl FatDateTime {
+    // The date in the high 16 bits and the time, to two seconds, in the low ones.
+    fn from_packed(packed: u32) -> Self {
+        Self {
+            year: 1980 + (packed >> 25) as u16,
+            month: (packed >> 21 & 0x0F) as u8,
+            day: (packed >> 16 & 0x1F) as u8,
+            hour: (packed >> 11 & 0x1F) as u8,
+            minute: (packed >> 5 & 0x3F) as u8,
+            second: (packed & 0x1F) as u8 * 2,
+        }
+    }
+}
+
+// Where the data of a file or directory is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Allocation {
+    // The root directory of FAT12 and FAT16, between the FATs and the data area.
+    Region { offset: u64, length: u64 },
+
+    // A chain of clusters in the FAT, or clusters following each other when exFAT doesn't use
+    // the FAT for them. Empty files have no first cluster.
+    Clusters { first: u32, contiguous: bool },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FatDirEntry {
+    // The long name when there's one, the 8.3 name otherwise.
+    pub name: String,
+
+    // The 8.3 name of FAT entries, exFAT has none.
+    pub short_name: Option<String>,
+    pub attributes: u16,
+    pub size: u64,
+    pub modified: FatDateTime,
+
+    allocation: Allocation,
+
+    // Bytes of exFAT files past this length have never been written and read as zeros.
+    valid_length: u64,
+}
+
+impl FatDirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & DIRECTORY != 0
+    }
+}
+
+// A FAT12, FAT16, FAT32 or exFAT filesystem opened for reading. Paths are separated by slashes
+// or backslashes and matched regardless of case, the way Windows does.
+pub struct FatFileSystem<R> {
+    volume: R,
+    fat_type: FatType,
+    fat_offset: u64,
+
+    // Offset of cluster 2, the first one of the data area.
+    data_offset: u64,
+    cluster_size: u64,
+    cluster_count: u32,
+    root: Allocation,
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    pub fn open_fat(
+        &mut self,
+        partition: &Partition,
+    ) -> Result<FatFileSystem<PartitionReader<VirtualDisk<'_, T>>>, VhdxError> {
+        FatFileSystem::new(self.open_partition(partition))
+    }
+}
+
+impl<R> FatFileSystem<R>
+where
+    R: Read + Seek,
+{
+    pub fn new(mut volume: R) -> Result<Self, VhdxError> {
+        let mut boot = vec![0; 512];
+        volume.rewind()?;
+        volume.read_exact(&mut boot)?;
+        if boot[510..] != [0x55, 0xAA] {
+            return Err(VhdxError::InvalidFat("Boot sector signature not found"));
+        }
+        if &boot[3..11] == b"EXFAT   " {
+            return FatFileSystem::new_exfat(volume, &boot);
+        }
+
+        let bytes_per_sector = le16(&boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = le16(&boot, 14) as u64;
+        let fats = boot[16] as u64;
+        let root_entries = le16(&boot, 17) as u64;
+        if !(512..=4096).contains(&bytes_per_sector)
+            || !bytes_per_sector.is_power_of_two()
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fats == 0
+        {
+            return Err(VhdxError::InvalidFat("Invalid BIOS parameter block"));
+        }
+        let total_sectors = match le16(&boot, 19) as u64 {
+            0 => le32(&boot, 32) as u64,
+            sectors => sectors,
+        };
+        let fat_sectors = match le16(&boot, 22) as u64 {
+            0 => le32(&boot, 36) as u64,
+            sectors => sectors,
+        };
+
+        let root_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+        let root_offset = (reserved_sectors + fats * fat_sectors) * bytes_per_sector;
+        let data_sectors = total_sectors
+            .checked_sub(reserved_sectors + fats * fat_sectors + root_sectors)
+            .ok_or(VhdxError::InvalidFat("Volume smaller than its FATs"))?;
+        let cluster_count = data_sectors / sectors_per_cluster;
+        // The type only depends on the number of clusters.
+        let (fat_type, root) = match cluster_count {
+            0..4085 => (
+                FatType::Fat12,
+                Allocation::Region {
+                    offset: root_offset,
+                    length: root_sectors * bytes_per_sector,
+                },
+            ),
+            4085..65525 => (
+                FatType::Fat16,
+                Allocation::Region {
+                    offset: root_offset,
+                    length: root_sectors * bytes_per_sector,
+                },
+            ),
+            _ => (
+                FatType::Fat32,
+                Allocation::Clusters {
+                    first: le32(&boot, 44),
+                    contiguous: false,
+                },
+            ),
+        };
+
+        Ok(Self {
+            volume,
+            fat_type,
+            fat_offset: reserved_sectors * bytes_per_sector,
+            data_offset: root_offset + root_sectors * bytes_per_sector,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            cluster_count: cluster_count as u32,
+            root,
+        })
+    }
+
+    fn new_exfat(volume: R, boot: &[u8]) -> Result<Self, VhdxError> {
+        let sector_shift = boot[108] as u32;
+        let cluster_shift = sector_shift + boot[109] as u32;
+        if !(9..=12).contains(&sector_shift) || cluster_shift > 25 {
+            return Err(VhdxError::InvalidFat(
+                "Invalid exFAT sector or cluster size",
+            ));
+        }
+        Ok(Self {
+            volume,
+            fat_type: FatType::ExFat,
+            fat_offset: (le32(boot, 80) as u64) << sector_shift,
+            data_offset: (le32(boot, 88) as u64) << sector_shift,
+            cluster_size: 1 << cluster_shift,
+            cluster_count: le32(boot, 92),
+            root: Allocation::Clusters {
+                first: le32(boot, 96),
+                contiguous: false,
+            },
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<FatDirEntry>, VhdxError> {
+        let directory = self.metadata(path)?;
+        if !directory.is_dir() {
+            return Err(VhdxError::NotADirectory(path.to_string()));
+        }
+        self.entries(&directory)
+    }
+
+    pub fn metadata(&mut self, path: &str) -> Result<FatDirEntry, VhdxError> {
+        let mut entry = FatDirEntry {
+            name: String::new(),
+            short_name: None,
+            attributes: DIRECTORY,
+            size: 0,
+            modified: FatDateTime::default(),
+            allocation: self.root,
+            valid_length: 0,
+        };
+        for component in path
+            .split(['/', '\\'])
+            .filter(|c| !c.is_empty() && *c != ".")
+        {
+            if !entry.is_dir() {
+                return Err(VhdxError::NotADirectory(path.to_string()));
+            }
+            let wanted = component.to_lowercase();
+            entry = self
+                .entries(&entry)?
+                .into_iter()
+                .find(|entry| {
+                    entry.name.to_lowercase() == wanted
+                        || entry
+                            .short_name
+                            .as_ref()
+                            .is_some_and(|short_name| short_name.to_lowercase() == wanted)
+                })
+                .ok_or_else(|| VhdxError::FileNotFound(path.to_string()))?;
+        }
+        Ok(entry)
+    }
+
+    // Opens a file to read its contents.
+    pub fn open(&mut self, path: &str) -> Result<FatFile<'_, R>, VhdxError> {
+        let entry = self.metadata(path)?;
+        if entry.is_dir() {
+            return Err(VhdxError::IsADirectory(path.to_string()));
+        }
+        let clusters = self.clusters(entry.allocation, Some(entry.size))?;
+        Ok(FatFile {
+            filesystem: self,
+            clusters,
+            size: entry.size,
+            valid_length: entry.valid_length,
+            position: 0,
+        })
+    }
+
+    fn entries(&mut self, directory: &FatDirEntry) -> Result<Vec<FatDirEntry>, VhdxError> {
+        let data = match directory.allocation {
+            Allocation::Region { offset, length } => self.read_at(offset, length)?,
+            allocation => {
+                // exFAT directories have a size, FAT ones end with their chain.
+                let length = (self.fat_type == FatType::ExFat && directory.size > 0)
+                    .then_some(directory.size);
+                let mut data = Vec::new();
+                for cluster in self.clusters(allocation, length)? {
+                    data.extend(self.read_at(self.cluster_offset(cluster), self.cluster_size)?);
+                }
+                data
+            }
+        };
+        Ok(match self.fat_type {
+            FatType::ExFat => exfat_entries(&data),
+            _ => fat_entries(&data, self.fat_type),
+        })
+    }
+
+    // The clusters holding the given number of bytes, or the whole chain.
+    fn clusters(
+        &mut self,
+        allocation: Allocation,
+        length: Option<u64>,
+    ) -> Result<Vec<u32>, VhdxError> {
+        let Allocation::Clusters { first, contiguous } = allocation else {
+            return Ok(Vec::new());
+        };
+        let needed = length.map(|length| length.div_ceil(self.cluster_size));
+        if needed == Some(0) || (first == 0 && needed.is_none()) {
+            return Ok(Vec::new());
+        }
+        if first == 0 {
+            return Err(VhdxError::InvalidFat("File without clusters"));
+        }
+        // Bounds are compared in u64 since the cluster count read from the boot sector may be
+        // anything up to u32::MAX, past the clusters a u32 can number.
+        let end = (self.cluster_count as u64 + 2).min(1 << 32);
+        if needed.is_some_and(|needed| needed > self.cluster_count as u64) {
+            return Err(VhdxError::InvalidFat("File larger than the volume"));
+        }
+        if contiguous {
+            let needed = needed.unwrap_or(1);
+            if first < 2 || first as u64 + needed > end {
+                return Err(VhdxError::InvalidFat("File past the end of the volume"));
+            }
+            return Ok((first as u64..first as u64 + needed)
+                .map(|cluster| cluster as u32)
+                .collect());
+        }
+
+        let limit = needed
+            .unwrap_or((MAX_DIRECTORY_LENGTH / self.cluster_size).min(self.cluster_count as u64));
+        let mut clusters = Vec::new();
+        let mut visited = BTreeSet::new();
+        let mut cluster = first;
+        loop {
+            if cluster < 2 || cluster as u64 >= end {
+                return Err(VhdxError::InvalidFat(
+                    "Cluster chain leads out of the volume",
+                ));
+            }
+            if !visited.insert(cluster) {
+                return Err(VhdxError::InvalidFat("Cluster chain loops"));
+            }
+            clusters.push(cluster);
+            if clusters.len() as u64 == limit {
+                break;
+            }
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None if needed.is_some() => {
+                    return Err(VhdxError::InvalidFat("Cluster chain shorter than the file"))
+                }
+                None => break,
+            }
+        }
+        Ok(clusters)
+    }
+
+    // The cluster following the given one in its chain, None at the end of the chain.
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, VhdxError> {
+        let (value, end) = match self.fat_type {
+            FatType::Fat12 => {
+                let bytes = self.read_at(self.fat_offset + cluster as u64 * 3 / 2, 2)?;
+                let value = u16::from_le_bytes([bytes[0], bytes[1]]) as u32;
+                let value = match cluster % 2 {
+                    0 => value & 0xFFF,
+                    _ => value >> 4,
+                };
+                (value, 0xFF8)
+            }
+            FatType::Fat16 => {
+                let bytes = self.read_at(self.fat_offset + cluster as u64 * 2, 2)?;
+                (u16::from_le_bytes([bytes[0], bytes[1]]) as u32, 0xFFF8)
+            }
+            FatType::Fat32 => {
+                let bytes = self.read_at(self.fat_offset + cluster as u64 * 4, 4)?;
+                (le32(&bytes, 0) & 0x0FFF_FFFF, 0x0FFF_FFF8)
+            }
+            FatType::ExFat => {
+                let bytes = self.read_at(self.fat_offset + cluster as u64 * 4, 4)?;
+                (le32(&bytes, 0), 0xFFFF_FFF8)
+            }
+        };
+        Ok((value < end).then_some(value))
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    fn read_at(&mut self, offset: u64, length: u64) -> Result<Vec<u8>, VhdxError> {
+        let mut buffer = vec![0; length as usize];
+        self.volume.seek(SeekFrom::Start(offset))?;
+        self.volume.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+// The entries of a FAT directory, their long names put back together from the entries
+// preceding them. A long name whose checksum doesn't match the 8.3 name was left behind by a
+// system that doesn't know about long names, the 8.3 name is used then.
+fn fat_entries(data: &[u8], fat_type: FatType) -> Vec<FatDirEntry> {
+    const END_OF_DIRECTORY: u8 = 0x00;
+    const DELETED: u8 = 0xE5;
+    const LAST_LONG_ENTRY: u8 = 0x40;
+    // Characters of a long name, in UCS-2, held by every long entry.
+    const LONG_NAME_RANGES: [(usize, usize); 3] = [(1, 11), (14, 26), (28, 32)];
+
+    let mut entries = Vec::new();
+    // The parts of the long name being read, from the last one, with their checksum.
+    let mut long_name: Vec<[u16; 13]> = Vec::new();
+    let mut long_checksum = None;
+    for entry in data.chunks_exact(32) {
+        match entry[0] {
+            END_OF_DIRECTORY => break,
+            DELETED => {
+                long_name.clear();
+                continue;
+            }
+            _ => {}
+        }
+        let attributes = entry[11];
+
+        if attributes & 0x3F == LONG_NAME {
+            let order = entry[0];
+            if order & LAST_LONG_ENTRY != 0 {
+                long_name.clear();
+                long_checksum = Some(entry[13]);
+            } else if long_checksum != Some(entry[13]) {
+                long_name.clear();
+                long_checksum = None;
+            }
+            let mut units = [0; 13];
+            let mut i = 0;
+            for (start, end) in LONG_NAME_RANGES {
+                for unit in entry[start..end].chunks_exact(2) {
+                    units[i] = u16::from_le_bytes([unit[0], unit[1]]);
+                    i += 1;
+                }
+            }
+            long_name.push(units);
+            continue;
+        }
+        if attributes as u16 & VOLUME_ID != 0 {
+            long_name.clear();
+            continue;
+        }
+
+        let short_name = short_name(entry);
+        let checksum = entry[..11]
+            .iter()
+            .fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b));
+        let name = (long_checksum == Some(checksum) && !long_name.is_empty()).then(|| {
+            let units: Vec<u16> = long_name
+                .iter()
+                .rev()
+                .flatten()
+                .copied()
+                .take_while(|unit| *unit != 0)
+                .collect();
+            String::from_utf16_lossy(&units)
+        });
+        long_name.clear();
+        long_checksum = None;
+        if short_name == "." || short_name == ".." {
+            continue;
+        }
+
+        let high = match fat_type {
+            FatType::Fat32 => le16(entry, 20) as u32,
+            _ => 0,
+        };
+        let size = le32(entry, 28) as u64;
+        entries.push(FatDirEntry {
+            name: name.unwrap_or_else(|| short_name.clone()),
+            short_name: Some(short_name),
+            attributes: attributes as u16,
+            size,
+            modified: FatDateTime::from_packed(
+                (le16(entry, 24) as u32) << 16 | le16(entry, 22) as u32,
+            ),
+            allocation: Allocation::Clusters {
+                first: high << 16 | le16(entry, 26) as u32,
+                contiguous: false,
+            },
+            valid_length: size,
+        });
+    }
+    entries
+}
+
+// The 8.3 name of an entry, shown in lower case when Windows NT flagged it that way.
+fn short_name(entry: &[u8]) -> String {
+    const LOWER_CASE_BASE: u8 = 0x08;
+    const LOWER_CASE_EXTENSION: u8 = 0x10;
+    // A first byte of 0xE5 is stored as 0x05, 0xE5 marking deleted entries.
+    const KANJI_E5: u8 = 0x05;
+
+    let mut base = entry[..8].to_vec();
+    if base[0] == KANJI_E5 {
+        base[0] = 0xE5;
+    }
+    let name = |bytes: &[u8], lower_case: bool| {
+        let name: String = bytes.iter().map(|b| *b as char).collect();
+        let name = name.trim_end().to_string();
+        match lower_case {
+            true => name.to_lowercase(),
+            false => name,
+        }
+    };
+    let base = name(&base, entry[12] & LOWER_CASE_BASE != 0);
+    let extension = name(&entry[8..11], entry[12] & LOWER_CASE_EXTENSION != 0);
+    match extension.is_empty() {
+        true => base,
+        false => format!("{}.{}", base, extension),
+    }
+}
+
+// The entries of an exFAT directory. Every file is a set of entries: a file entry, a stream
+// extension entry and then the file name entries.
+fn exfat_entries(data: &[u8]) -> Vec<FatDirEntry> {
+    const END_OF_DIRECTORY: u8 = 0x00;
+    const FILE: u8 = 0x85;
+    const STREAM_EXTENSION: u8 = 0xC0;
+    const FILE_NAME: u8 = 0xC1;
+    const NO_FAT_CHAIN: u8 = 0x02;
+
+    let sets: Vec<&[u8]> = data.chunks_exact(32).collect();
+    let mut entries = Vec::new();
+    let mut i = 0;
+    while i < sets.len() {
+        let file = sets[i];
+        match file[0] {
+            END_OF_DIRECTORY => break,
+            FILE => {}
+            _ => {
+                i += 1;
+                continue;
+            }
+        }
+        let secondary_count = file[1] as usize;
+        let set = &sets[i + 1..sets.len().min(i + 1 + secondary_count)];
+        i += 1 + secondary_count;
+        let Some((stream, names)) = set.split_first() else {
+            continue;
+        };
+        if stream[0] != STREAM_EXTENSION {
+            continue;
+        }
+
+        let mut name: Vec<u16> = names
+            .iter()
+            .take_while(|entry| entry[0] == FILE_NAME)
+            .flat_map(|entry| entry[2..].chunks_exact(2))
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+        name.truncate(stream[3] as usize);
+        entries.push(FatDirEntry {
+            name: String::from_utf16_lossy(&name),
+            short_name: None,
+            attributes: le16(file, 4),
+            size: le64(stream, 24),
+            modified: FatDateTime::from_packed(le32(file, 12)),
+            allocation: Allocation::Clusters {
+                first: le32(stream, 20),
+                contiguous: stream[1] & NO_FAT_CHAIN != 0,
+            },
+            valid_length: le64(stream, 8),
+        });
+    }
+    entries
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// The contents of a file of a FAT filesystem.
+pub struct FatFile<'a, R> {
+    filesystem: &'a mut FatFileSystem<R>,
+    clusters: Vec<u32>,
+    size: u64,
+    valid_length: u64,
+    position: u64,
+}
+
+impl<R> FatFile<'_, R> {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<R> Read for FatFile<'_, R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let cluster_size = self.filesystem.cluster_size;
+        let offset_in_cluster = self.position % cluster_size;
+        let mut length = (buf.len() as u64)
+            .min(cluster_size - offset_in_cluster)
+            .min(self.size - self.position);
+        if self.position >= self.valid_length {
+            buf[..length as usize].fill(0);
+        } else {
+            length = length.min(self.valid_length - self.position);
+            let cluster = self.clusters[(self.position / cluster_size) as usize];
+            let offset = self.filesystem.cluster_offset(cluster) + offset_in_cluster;
+            let volume = &mut self.filesystem.volume;
+            volume.seek(SeekFrom::Start(offset))?;
+            volume.read_exact(&mut buf[..length as usize])?;
+        }
+        self.position += length;
+        Ok(length as usize)
+    }
+}
+
+impl<R> Seek for FatFile<'_, R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn put(volume: &mut [u8], offset: u64, bytes: &[u8]) {
+        volume[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn boot_sector(volume: &mut [u8], fields: &[(u64, &[u8])]) {
+        put(volume, 0, &[0xEB, 0x58, 0x90]);
+        put(volume, 510, &[0x55, 0xAA]);
+        for (offset, bytes) in fields {
+            put(volume, *offset, bytes);
+        }
+    }
+
+    fn short_entry(name: &[u8; 11], attributes: u8, case: u8, cluster: u32, size: u32) -> Vec<u8> {
+        let mut entry = name.to_vec();
+        entry.extend_from_slice(&[attributes, case, 0]);
+        entry.extend_from_slice(&[0; 6]);
+        entry.extend_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        // 2024-03-15 10:30:20
+        entry.extend_from_slice(&(10u16 << 11 | 30 << 5 | 10).to_le_bytes());
+        entry.extend_from_slice(&(44u16 << 9 | 3 << 5 | 15).to_le_bytes());
+        entry.extend_from_slice(&(cluster as u16).to_le_bytes());
+        entry.extend_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    // The long name entries preceding the entry of the 8.3 name, the last part first.
+    fn long_entries(name: &str, short_name: &[u8; 11]) -> Vec<u8> {
+        let checksum = short_name
+            .iter()
+            .fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b));
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        if !units.len().is_multiple_of(13) {
+            units.push(0);
+        }
+        units.resize(units.len().div_ceil(13) * 13, 0xFFFF);
+        let parts: Vec<&[u16]> = units.chunks(13).collect();
+
+        let mut entries = Vec::new();
+        for (i, part) in parts.iter().enumerate().rev() {
+            let mut entry = vec![0; 32];
+            entry[0] = (i as u8 + 1) | if i == parts.len() - 1 { 0x40 } else { 0 };
+            entry[11] = LONG_NAME;
+            entry[13] = checksum;
+            let offsets = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain([28, 30]);
+            for (offset, unit) in offsets.zip(part.iter()) {
+                put(&mut entry, offset, &unit.to_le_bytes());
+            }
+            entries.extend(entry);
+        }
+        entries
+    }
+
+    // A FAT32 volume of 512 byte clusters holding /EFI/BOOT with a file with a long name
+    // spread over clusters 5 and 7, and a file with a lower case 8.3 name.
+    fn fat32_volume() -> Vec<u8> {
+        const FAT: u64 = 32 * 512;
+        const DATA: u64 = FAT + 2 * 512 * 512;
+        let cluster = |cluster: u64| DATA + (cluster - 2) * 512;
+        let mut volume = vec![0; (DATA + 66000 * 512) as usize];
+        boot_sector(
+            &mut volume,
+            &[
+                (3, b"MSWIN4.1"),
+                (11, &512u16.to_le_bytes()),
+                (13, &[1]),
+                (14, &32u16.to_le_bytes()),
+                (16, &[2]),
+                (32, &(32u32 + 1024 + 66000).to_le_bytes()),
+                (36, &512u32.to_le_bytes()),
+                (44, &2u32.to_le_bytes()),
+            ],
+        );
+        for (entry, value) in [
+            (0, 0x0FFF_FFF8u32),
+            (1, 0x0FFF_FFFF),
+            (2, 0x0FFF_FFFF),
+            (3, 0x0FFF_FFFF),
+            (4, 0x0FFF_FFFF),
+            (5, 7),
+            (6, 0x0FFF_FFFF),
+            (7, 0x0FFF_FFFF),
+        ] {
+            put(&mut volume, FAT + entry * 4, &value.to_le_bytes());
+        }
+
+        let mut root = short_entry(b"ESP        ", VOLUME_ID as u8, 0, 0, 0);
+        root.extend(short_entry(b"EFI        ", DIRECTORY as u8, 0, 3, 0));
+        put(&mut volume, cluster(2), &root);
+        let mut efi = short_entry(b".          ", DIRECTORY as u8, 0, 3, 0);
+        efi.extend(short_entry(b"..         ", DIRECTORY as u8, 0, 0, 0));
+        efi.extend(short_entry(b"BOOT       ", DIRECTORY as u8, 0, 4, 0));
+        put(&mut volume, cluster(3), &efi);
+        let mut boot = short_entry(b"\xE5OLD    TXT", ARCHIVE as u8, 0, 0, 0);
+        boot.extend(long_entries("Microsoft Boot Manager.efi", b"MICROS~1EFI"));
+        boot.extend(short_entry(b"MICROS~1EFI", ARCHIVE as u8, 0, 5, 600));
+        boot.extend(short_entry(b"GRUB    CFG", ARCHIVE as u8, 0x18, 6, 10));
+        put(&mut volume, cluster(4), &boot);
+
+        put(&mut volume, cluster(5), &[0xAA; 512]);
+        put(&mut volume, cluster(6), b"set root=x");
+        put(&mut volume, cluster(7), &[0xBB; 88]);
+        volume
+    }
+
+    #[test]
+    fn should_browse_and_extract_files_of_a_fat32_volume() {
+        let mut filesystem = FatFileSystem::new(Cursor::new(fat32_volume())).unwrap();
+
+        let root: Vec<String> = filesystem
+            .read_dir("/")
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        let boot = filesystem.read_dir(r"\efi\boot").unwrap();
+        let mut manager = Vec::new();
+        filesystem
+            .open("/EFI/BOOT/microsoft boot manager.EFI")
+            .unwrap()
+            .read_to_end(&mut manager)
+            .unwrap();
+        let mut grub = Vec::new();
+        filesystem
+            .open("/EFI/BOOT/MICROS~1.EFI/../grub.cfg")
+            .map(|_| ())
+            .unwrap_err();
+        filesystem
+            .open("EFI/BOOT/GRUB.CFG")
+            .unwrap()
+            .read_to_end(&mut grub)
+            .unwrap();
+
+        assert_eq!(FatType::Fat32, filesystem.fat_type());
+        assert_eq!(vec!["EFI".to_string()], root);
+        assert_eq!(
+            vec![
+                ("Microsoft Boot Manager.efi", Some("MICROS~1.EFI"), 600),
+                ("grub.cfg", Some("grub.cfg"), 10),
+            ],
+            boot.iter()
+                .map(|entry| (entry.name.as_str(), entry.short_name.as_deref(), entry.size))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            FatDateTime {
+                year: 2024,
+                month: 3,
+                day: 15,
+                hour: 10,
+                minute: 30,
+                second: 20,
+            },
+            boot[0].modified
+        );
+        assert!(manager[..512].iter().all(|b| *b == 0xAA));
+        assert!(manager[512..].iter().all(|b| *b == 0xBB));
+        assert_eq!(600, manager.len());
+        assert_eq!(b"set root=x".to_vec(), grub);
+        assert!(matches!(
+            filesystem.metadata("/EFI/missing"),
+            Err(VhdxError::FileNotFound(_))
+        ));
+        assert!(matches!(
+            filesystem.open("/EFI"),
+            Err(VhdxError::IsADirectory(_))
+        ));
+    }
+
+    #[test]
+    fn should_read_exfat_file_sets_and_unwritten_tails() {
+        const FAT: u64 = 24 * 512;
+        const HEAP: u64 = 32 * 512;
+        let cluster = |cluster: u64| HEAP + (cluster - 2) * 512;
+        let mut volume = vec![0; (HEAP + 100 * 512) as usize];
+        boot_sector(
+            &mut volume,
+            &[
+                (3, b"EXFAT   "),
+                (72, &132u64.to_le_bytes()),
+                (80, &24u32.to_le_bytes()),
+                (84, &8u32.to_le_bytes()),
+                (88, &32u32.to_le_bytes()),
+                (92, &100u32.to_le_bytes()),
+                (96, &2u32.to_le_bytes()),
+                (108, &[9, 0]),
+            ],
+        );
+        for (entry, value) in [(2, u32::MAX), (3, u32::MAX)] {
+            put(&mut volume, FAT + entry * 4, &value.to_le_bytes());
+        }
+
+        // A label, a file in clusters 4 and 5 without a FAT chain whose last 100 bytes were
+        // never written, and a directory.
+        let file_set = |name: &str, attributes: u16, flags: u8, first: u32, length: u64| {
+            let units: Vec<u16> = name.encode_utf16().collect();
+            let names = units.len().div_ceil(15);
+            let mut set = vec![0; 64 + 32 * names];
+            set[0] = 0x85;
+            set[1] = 1 + names as u8;
+            put(&mut set, 4, &attributes.to_le_bytes());
+            put(
+                &mut set,
+                12,
+                &(44u32 << 25 | 3 << 21 | 15 << 16).to_le_bytes(),
+            );
+            set[32] = 0xC0;
+            set[33] = flags;
+            set[35] = units.len() as u8;
+            put(&mut set, 40, &length.saturating_sub(100).to_le_bytes());
+            put(&mut set, 52, &first.to_le_bytes());
+            put(&mut set, 56, &length.to_le_bytes());
+            for (i, part) in units.chunks(15).enumerate() {
+                set[64 + 32 * i] = 0xC1;
+                for (j, unit) in part.iter().enumerate() {
+                    put(&mut set, (66 + 32 * i + 2 * j) as u64, &unit.to_le_bytes());
+                }
+            }
+            set
+        };
+        let mut root = vec![0x83, 3, b'E', 0, b'S', 0, b'P', 0];
+        root.resize(32, 0);
+        root.extend(file_set(
+            "A file with a long name.txt",
+            ARCHIVE,
+            0x03,
+            4,
+            700,
+        ));
+        root.extend(file_set("EFI", DIRECTORY, 0x01, 3, 612));
+        put(&mut volume, cluster(2), &root);
+        put(&mut volume, cluster(4), &[0x11; 512]);
+        put(&mut volume, cluster(5), &[0x22; 512]);
+
+        let mut filesystem = FatFileSystem::new(Cursor::new(volume)).unwrap();
+        let entries = filesystem.read_dir("").unwrap();
+        let mut data = Vec::new();
+        filesystem
+            .open("/a file with a long name.TXT")
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+
+        assert_eq!(FatType::ExFat, filesystem.fat_type());
+        assert_eq!(
+            vec![("A file with a long name.txt", false), ("EFI", true)],
+            entries
+                .iter()
+                .map(|entry| (entry.name.as_str(), entry.is_dir()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(2024, entries[0].modified.year);
+        assert_eq!(700, data.len());
+        assert!(data[..512].iter().all(|b| *b == 0x11));
+        assert!(data[512..600].iter().all(|b| *b == 0x22));
+        assert!(data[600..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn should_follow_packed_fat12_chains() {
+        let mut volume = vec![0; 100 * 512];
+        boot_sector(
+            &mut volume,
+            &[
+                (11, &512u16.to_le_bytes()),
+                (13, &[1]),
+                (14, &1u16.to_le_bytes()),
+                (16, &[1]),
+                (17, &16u16.to_le_bytes()),
+                (19, &100u16.to_le_bytes()),
+                (22, &1u16.to_le_bytes()),
+            ],
+        );
+        // Entries 2 and 3 share the bytes 3 to 5 of the FAT: 2 -> 3 -> end of chain.
+        put(&mut volume, 512, &[0xF8, 0xFF, 0xFF, 0x03, 0xF0, 0xFF]);
+        put(
+            &mut volume,
+            1024,
+            &short_entry(b"A       TXT", 0, 0, 2, 1000),
+        );
+        put(&mut volume, 1536, &[b'a'; 512]);
+        put(&mut volume, 2048, &[b'b'; 488]);
+
+        let mut filesystem = FatFileSystem::new(Cursor::new(volume)).unwrap();
+        let mut data = Vec::new();
+        let mut file = filesystem.open("a.txt").unwrap();
+        file.seek(SeekFrom::Start(510)).unwrap();
+        file.read_to_end(&mut data).unwrap();
+
+        assert_eq!(FatType::Fat12, filesystem.fat_type());
+        assert_eq!(b"aabbbbbbbb".to_vec(), data[..10].to_vec());
+        assert_eq!(490, data.len());
+    }
+
+    #[test]
+    fn should_refuse_corrupt_cluster_chains() {
+        const FAT: u64 = 32 * 512;
+        const BOOT: u64 = FAT + 2 * 512 * 512 + 2 * 512;
+        let corrupt = |offset: u64, bytes: &[u8], path: &str| {
+            let mut volume = fat32_volume();
+            put(&mut volume, offset, bytes);
+            let mut filesystem = FatFileSystem::new(Cursor::new(volume)).unwrap();
+            filesystem.open(path).map(|_| ()).unwrap_err()
+        };
+        let grub = BOOT + 32 * 4;
+
+        // No first cluster for 10 bytes, 1000 bytes over a chain of one cluster, a cluster pointing
+        // to itself, a size larger than the volume, and a chain leaving the volume.
+        let errors = [
+            corrupt(grub + 26, &0u16.to_le_bytes(), "/EFI/BOOT/GRUB.CFG"),
+            corrupt(grub + 28, &1000u32.to_le_bytes(), "/EFI/BOOT/GRUB.CFG"),
+            corrupt(FAT + 5 * 4, &5u32.to_le_bytes(), "/EFI/BOOT/MICROS~1.EFI"),
+            corrupt(grub + 28, &u32::MAX.to_le_bytes(), "/EFI/BOOT/GRUB.CFG"),
+            corrupt(
+                FAT + 5 * 4,
+                &70000u32.to_le_bytes(),
+                "/EFI/BOOT/MICROS~1.EFI",
+            ),
+        ];
+
+        assert!(matches!(
+            errors[0],
+            VhdxError::InvalidFat("File without clusters")
+        ));
+        assert!(matches!(
+            errors[1],
+            VhdxError::InvalidFat("Cluster chain shorter than the file")
+        ));
+        assert!(matches!(
+            errors[2],
+            VhdxError::InvalidFat("Cluster chain loops")
+        ));
+        assert!(matches!(
+            errors[3],
+            VhdxError::InvalidFat("File larger than the volume")
+        ));
+        assert!(matches!(
+            errors[4],
+            VhdxError::InvalidFat("Cluster chain leads out of the volume")
+        ));
+    }
+
+    #[test]
+    fn should_reach_the_last_cluster_of_the_largest_volume() {
+        let mut filesystem = FatFileSystem::new(Cursor::new(fat32_volume())).unwrap();
+        filesystem.cluster_count = u32::MAX;
+
+        let contiguous = Allocation::Clusters {
+            first: u32::MAX,
+            contiguous: true,
+        };
+        let chain = Allocation::Clusters {
+            first: u32::MAX,
+            contiguous: false,
+        };
+
+        assert_eq!(
+            vec![u32::MAX],
+            filesystem.clusters(contiguous, Some(512)).unwrap()
+        );
+        assert_eq!(
+            vec![u32::MAX],
+            filesystem.clusters(chain, Some(512)).unwrap()
+        );
+        assert!(matches!(
+            filesystem.clusters(contiguous, Some(1024)),
+            Err(VhdxError::InvalidFat("File past the end of the volume"))
+        ));
+    }
+}
@@ -0,0 +1,844 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use uuid::Uuid;
+
+use crate::{
+    error::VhdxError,
+    partition::{read_partitions, Partition, PartitionReader},
+    vhdx::Vhdx,
+};
+
+// Every signature probed lies in the first 68 KB of a volume: the Btrfs superblock at 64 KB, and
+// the swap signature at the end of the first page, of up to 64 KB.
+const PROBE_LENGTH: u64 = 0x11000;
+
+// Directories read to find a volume label are cut at this length.
+const MAX_LABEL_DIRECTORY_LENGTH: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileSystemType {
+    Ntfs,
+    Fat12,
+    Fat16,
+    Fat32,
+    ExFat,
+    Ext2,
+    Ext3,
+    Ext4,
+    Xfs,
+    Btrfs,
+    ReFs,
+    LvmPhysicalVolume,
+    Luks,
+    BitLocker,
+    Swap,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSystemInfo {
+    pub filesystem_type: FileSystemType,
+    pub label: Option<String>,
+
+    // Formatted the way blkid does: a UUID, or the serial number of FAT, exFAT, NTFS and ReFS
+    // volumes.
+    pub uuid: Option<String>,
+
+    // The size the filesystem records for itself in bytes, which may be less than its partition.
+    // Encrypted volumes only record it once unlocked.
+    pub size: Option<u64>,
+}
+
+// A partition, or the whole disk when it isn't partitioned, and what it holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Volume {
+    pub partition: Option<Partition>,
+    pub filesystem: Option<FileSystemInfo>,
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    // Detects the filesystem of every partition of the guest disk. A partition table that can't
+    // be read leaves the whole disk to probe, and a partition that can't be read is reported
+    // without a filesystem, so one damaged structure doesn't hide the others.
+    pub fn volumes(&mut self) -> Result<Vec<Volume>, VhdxError> {
+        let sector_size = self.meta_data.logical_sector_size as u64;
+        let partitions = read_partitions(&mut self.virtual_disk(), sector_size).unwrap_or_default();
+        if partitions.is_empty() {
+            return Ok(vec![Volume {
+                partition: None,
+                filesystem: detect_filesystem(&mut self.virtual_disk())?,
+            }]);
+        }
+
+        let mut volumes = Vec::new();
+        for partition in partitions {
+            let filesystem =
+                detect_filesystem(&mut PartitionReader::new(self.virtual_disk(), &partition))
+                    .ok()
+                    .flatten();
+            volumes.push(Volume {
+                partition: Some(partition),
+                filesystem,
+            });
+        }
+        Ok(volumes)
+    }
+}
+
+// Tells what the volume holds from the signatures of the filesystems, encrypted volumes and
+// volume managers known here, None when none of them is found.
+pub fn detect_filesystem<R>(volume: &mut R) -> Result<Option<FileSystemInfo>, VhdxError>
+where
+    R: Read + Seek,
+{
+    let probe = read_at(volume, 0, PROBE_LENGTH)?;
+
+    let info = if let Some(info) = probe_luks(&probe) {
+        info
+    } else if let Some(info) = probe_bitlocker(&probe) {
+        info
+    } else if let Some(info) = probe_ntfs(&probe) {
+        FileSystemInfo {
+            label: ntfs_label(volume, &probe).ok().flatten(),
+            ..info
+        }
+    } else if let Some((info, root)) = probe_exfat(&probe) {
+        let label = read_at(volume, root.0, root.1)
+            .ok()
+            .and_then(|root| exfat_label(&root));
+        FileSystemInfo { label, ..info }
+    } else if let Some(info) = probe_refs(&probe)
+        .or_else(|| probe_xfs(&probe))
+        .or_else(|| probe_btrfs(&probe))
+        .or_else(|| probe_ext(&probe))
+        .or_else(|| probe_lvm(&probe))
+        .or_else(|| probe_swap(&probe))
+    {
+        info
+    } else if let Some((info, root)) = probe_fat(&probe) {
+        // The label of the root directory is the one Windows shows, the one of the boot sector
+        // is often left as it was formatted.
+        let label = read_at(volume, root.0, root.1)
+            .ok()
+            .and_then(|root| fat_label(&root))
+            .or(info.label);
+        FileSystemInfo { label, ..info }
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(info))
+}
+
+fn probe_luks(probe: &[u8]) -> Option<FileSystemInfo> {
+    if &probe[..6] != b"LUKS\xBA\xBE" {
+        return None;
+    }
+    // LUKS2 headers have a label, both versions keep the UUID at the same place.
+    let label = match be16(probe, 6) {
+        2 => ascii(&probe[24..72]),
+        _ => None,
+    };
+    Some(FileSystemInfo {
+        filesystem_type: FileSystemType::Luks,
+        label,
+        uuid: ascii(&probe[168..208]),
+        size: None,
+    })
+}
+
+fn probe_bitlocker(probe: &[u8]) -> Option<FileSystemInfo> {
+    if &probe[3..11] != b"-FVE-FS-" {
+        return None;
+    }
+    let identifier = Uuid::from_slice_le(&probe[160..176]).unwrap();
+    Some(FileSystemInfo {
+        filesystem_type: FileSystemType::BitLocker,
+        label: None,
+        uuid: (!identifier.is_nil()).then(|| identifier.to_string()),
+        size: None,
+    })
+}
+
+fn probe_ntfs(probe: &[u8]) -> Option<FileSystemInfo> {
+    if &probe[3..11] != b"NTFS    " {
+        return None;
+    }
+    let bytes_per_sector = le16(probe, 0x0B) as u64;
+    Some(FileSystemInfo {
+        filesystem_type: FileSystemType::Ntfs,
+        label: None,
+        uuid: Some(format!("{:016X}", le64(probe, 0x48))),
+        size: Some(le64(probe, 0x28).saturating_mul(bytes_per_sector)),
+    })
+}
+
+// The label is the value of the VOLUME_NAME attribute of $Volume, the fourth record of the MFT.
+// The first records of the MFT are always stored together.
+fn ntfs_label<R>(volume: &mut R, boot: &[u8]) -> Result<Option<String>, VhdxError>
+where
+    R: Read + Seek,
+{
+    const VOLUME_RECORD: u64 = 3;
+    const VOLUME_NAME: u32 = 0x60;
+    const END: u32 = 0xFFFF_FFFF;
+    // Update sequence numbers protect the end of every 512 bytes of a record.
+    const STRIDE: usize = 512;
+
+    let cluster_size = le16(boot, 0x0B) as u64 * boot[0x0D] as u64;
+    let record_size = match boot[0x40] as i8 {
+        shift if shift < 0 => 1u64.checked_shl(shift.unsigned_abs() as u32).unwrap_or(0),
+        clusters => clusters as u64 * cluster_size,
+    };
+    if !(STRIDE as u64..=64 * 1024).contains(&record_size) {
+        return Ok(None);
+    }
+    let offset = le64(boot, 0x30)
+        .saturating_mul(cluster_size)
+        .saturating_add(VOLUME_RECORD * record_size);
+    let mut record = read_at(volume, offset, record_size)?;
+    if &record[..4] != b"FILE" {
+        return Ok(None);
+    }
+
+    let sequence_offset = le16(&record, 4) as usize;
+    let sequence_count = le16(&record, 6) as usize;
+    if sequence_count == 0
+        || sequence_offset + 2 * sequence_count > record.len()
+        || (sequence_count - 1) * STRIDE > record.len()
+    {
+        return Ok(None);
+    }
+    for i in 1..sequence_count {
+        let end = i * STRIDE - 2;
+        let original = sequence_offset + 2 * i;
+        record.copy_within(original..original + 2, end);
+    }
+
+    let mut offset = le16(&record, 0x14) as usize;
+    while offset + 0x18 <= record.len() {
+        let attribute_type = le32(&record, offset);
+        let length = le32(&record, offset + 4) as usize;
+        if attribute_type == END || length == 0 {
+            break;
+        }
+        let resident = record[offset + 8] == 0;
+        if attribute_type == VOLUME_NAME && resident {
+            let value_length = le32(&record, offset + 0x10) as usize;
+            let value_offset = offset + le16(&record, offset + 0x14) as usize;
+            return Ok(record
+                .get(value_offset..value_offset + value_length)
+                .and_then(utf16));
+        }
+        offset += length;
+    }
+    Ok(None)
+}
+
+// Returns the offset and length of the first cluster of the root directory along with what
+// the boot sector says.
+fn probe_exfat(probe: &[u8]) -> Option<(FileSystemInfo, (u64, u64))> {
+    if &probe[3..11] != b"EXFAT   " {
+        return None;
+    }
+    let sector_shift = probe[108] as u32;
+    let cluster_shift = sector_shift + probe[109] as u32;
+    if !(9..=12).contains(&sector_shift) || cluster_shift > 25 {
+        return None;
+    }
+    let heap_offset = (le32(probe, 88) as u64) << sector_shift;
+    let root_cluster = le32(probe, 96) as u64;
+    let root_offset = heap_offset + (root_cluster.saturating_sub(2) << cluster_shift);
+    let serial = le32(probe, 100);
+
+    let info = FileSystemInfo {
+        filesystem_type: FileSystemType::ExFat,
+        label: None,
+        uuid: Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF)),
+        size: Some(le64(probe, 72) << sector_shift),
+    };
+    let root_length = (1 << cluster_shift).min(MAX_LABEL_DIRECTORY_LENGTH);
+    Some((info, (root_offset, root_length)))
+}
+
+fn exfat_label(root: &[u8]) -> Option<String> {
+    const END_OF_DIRECTORY: u8 = 0x00;
+    const VOLUME_LABEL: u8 = 0x83;
+
+    for entry in root.chunks_exact(32) {
+        match entry[0] {
+            END_OF_DIRECTORY => break,
+            VOLUME_LABEL => {
+                let length = (entry[1] as usize).min(11);
+                return utf16(&entry[2..2 + 2 * length]);
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn probe_refs(probe: &[u8]) -> Option<FileSystemInfo> {
+    if &probe[3..11] != b"ReFS\0\0\0\0" || &probe[16..20] != b"FSRS" {
+        return None;
+    }
+    Some(FileSystemInfo {
+        filesystem_type: FileSystemType::ReFs,
+        label: None,
+        uuid: Some(format!("{:016X}", le64(probe, 0x38))),
+        size: Some(le64(probe, 0x18).saturating_mul(le32(probe, 0x20) as u64)),
+    })
+}
+
+fn probe_xfs(probe: &[u8]) -> Option<FileSystemInfo> {
+    if &probe[..4] != b"XFSB" {
+        return None;
+    }
+    Some(FileSystemInfo {
+        filesystem_type: FileSystemType::Xfs,
+        label: ascii(&probe[108..120]),
+        uuid: Some(Uuid::from_slice(&probe[32..48]).unwrap().to_string()),
+        size: Some(be64(probe, 8).saturating_mul(be32(probe, 4) as u64)),
+    })
+}
+
+fn probe_btrfs(probe: &[u8]) -> Option<FileSystemInfo> {
+    const SUPERBLOCK: usize = 0x10000;
+
+    let superblock = &probe[SUPERBLOCK..SUPERBLOCK + 0x1000];
+    if &superblock[0x40..0x48] != b"_BHRfS_M" {
+        return None;
+    }
+    Some(FileSystemInfo {
+        filesystem_type: FileSystemType::Btrfs,
+        label: ascii(&superblock[0x12B..0x22B]),
+        uuid: Some(
+            Uuid::from_slice(&superblock[0x20..0x30])
+                .unwrap()
+                .to_string(),
+        ),
+        size: Some(le64(superblock, 0x70)),
+    })
+}
+
+// ext2, ext3 and ext4 share their superblock, they are told apart by their features the way
+// blkid does: a journal makes ext3, and any feature ext3 doesn't know of makes ext4.
+fn probe_ext(probe: &[u8]) -> Option<FileSystemInfo> {
+    const SUPERBLOCK: usize = 1024;
+    const MAGIC: u16 = 0xEF53;
+    const COMPAT_HAS_JOURNAL: u32 = 0x4;
+    const INCOMPAT_64BIT: u32 = 0x80;
+    // Filetype, recover and meta_bg.
+    const INCOMPAT_EXT3: u32 = 0x2 | 0x4 | 0x10;
+    // Sparse_super, large_file and btree_dir.
+    const RO_COMPAT_EXT3: u32 = 0x1 | 0x2 | 0x4;
+
+    let superblock = &probe[SUPERBLOCK..SUPERBLOCK + 1024];
+    if le16(superblock, 56) != MAGIC {
+        return None;
+    }
+    let compat = le32(superblock, 92);
+    let incompat = le32(superblock, 96);
+    let ro_compat = le32(superblock, 100);
+    let filesystem_type = if incompat & !INCOMPAT_EXT3 != 0 || ro_compat & !RO_COMPAT_EXT3 != 0 {
+        FileSystemType::Ext4
+    } else if compat & COMPAT_HAS_JOURNAL != 0 {
+        FileSystemType::Ext3
+    } else {
+        FileSystemType::Ext2
+    };
+
+    let mut blocks = le32(superblock, 4) as u64;
+    if incompat & INCOMPAT_64BIT != 0 {
+        blocks |= (le32(superblock, 0x150) as u64) << 32;
+    }
+    let block_size = 1024u64.checked_shl(le32(superblock, 24))?;
+    Some(FileSystemInfo {
+        filesystem_type,
+        label: ascii(&superblock[120..136]),
+        uuid: Some(Uuid::from_slice(&superblock[104..120]).unwrap().to_string()),
+        size: Some(blocks.saturating_mul(block_size)),
+    })
+}
+
+// The label of a physical volume can be in any of the first four sectors, it points to the PV
+// header holding the UUID and the size of the device.
+fn probe_lvm(probe: &[u8]) -> Option<FileSystemInfo> {
+    let label = (0..4)
+        .map(|sector| &probe[sector * 512..(sector + 1) * 512])
+        .find(|label| &label[..8] == b"LABELONE" && &label[24..32] == b"LVM2 001")?;
+    let offset = le32(label, 20) as usize;
+    let header = label.get(offset..offset.checked_add(40)?)?;
+    let uuid = std::str::from_utf8(&header[..32]).ok()?;
+    let uuid = [0..6, 6..10, 10..14, 14..18, 18..22, 22..26, 26..32]
+        .into_iter()
+        .map(|range| &uuid[range])
+        .collect::<Vec<_>>()
+        .join("-");
+    Some(FileSystemInfo {
+        filesystem_type: FileSystemType::LvmPhysicalVolume,
+        label: None,
+        uuid: Some(uuid),
+        size: Some(le64(header, 32)),
+    })
+}
+
+// The signature ends the first page, whose size depends on the architecture that made it.
+fn probe_swap(probe: &[u8]) -> Option<FileSystemInfo> {
+    const HEADER: usize = 1024;
+
+    let page_size = [4096, 8192, 16384, 65536].into_iter().find(|page_size| {
+        matches!(
+            &probe[page_size - 10..*page_size],
+            b"SWAPSPACE2" | b"SWAP-SPACE"
+        )
+    })?;
+    let version_1 = &probe[page_size - 10..page_size] == b"SWAPSPACE2";
+    let header = &probe[HEADER..HEADER + 44];
+    Some(FileSystemInfo {
+        filesystem_type: FileSystemType::Swap,
+        label: version_1.then(|| ascii(&header[28..44])).flatten(),
+        uuid: version_1.then(|| Uuid::from_slice(&header[12..28]).unwrap().to_string()),
+        size: version_1.then(|| (le32(header, 4) as u64 + 1) * page_size as u64),
+    })
+}
+
+// Returns the offset and length of the root directory, or of its first cluster on FAT32, along
+// with what the boot sector says.
+fn probe_fat(probe: &[u8]) -> Option<(FileSystemInfo, (u64, u64))> {
+    if probe[510..512] != [0x55, 0xAA] || !matches!(probe[0], 0xEB | 0xE9) {
+        return None;
+    }
+    let bytes_per_sector = le16(probe, 11) as u64;
+    let sectors_per_cluster = probe[13] as u64;
+    let reserved_sectors = le16(probe, 14) as u64;
+    let fats = probe[16] as u64;
+    let root_entries = le16(probe, 17) as u64;
+    if !(512..=4096).contains(&bytes_per_sector)
+        || !bytes_per_sector.is_power_of_two()
+        || !sectors_per_cluster.is_power_of_two()
+        || reserved_sectors == 0
+        || fats == 0
+    {
+        return None;
+    }
+
+    let total_sectors = match le16(probe, 19) as u64 {
+        0 => le32(probe, 32) as u64,
+        sectors => sectors,
+    };
+    let fat_sectors = match le16(probe, 22) as u64 {
+        0 => le32(probe, 36) as u64,
+        sectors => sectors,
+    };
+    let root_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+    let data_start = reserved_sectors + fats * fat_sectors + root_sectors;
+    let clusters = total_sectors.checked_sub(data_start)? / sectors_per_cluster;
+    let filesystem_type = match clusters {
+        0..4085 => FileSystemType::Fat12,
+        4085..65525 => FileSystemType::Fat16,
+        _ => FileSystemType::Fat32,
+    };
+
+    // The extended boot record sits after the FAT32 fields on FAT32 volumes.
+    let extended = match filesystem_type {
+        FileSystemType::Fat32 => 64,
+        _ => 36,
+    };
+    let (uuid, label) = match probe[extended + 2] {
+        0x29 => {
+            let serial = le32(probe, extended + 3);
+            (
+                Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF)),
+                fat_name(&probe[extended + 7..extended + 18]),
+            )
+        }
+        _ => (None, None),
+    };
+
+    let cluster_size = sectors_per_cluster * bytes_per_sector;
+    let root = match filesystem_type {
+        FileSystemType::Fat32 => {
+            let root_cluster = le32(probe, 44) as u64;
+            let offset =
+                data_start * bytes_per_sector + (root_cluster.saturating_sub(2)) * cluster_size;
+            (offset, cluster_size)
+        }
+        _ => (
+            (reserved_sectors + fats * fat_sectors) * bytes_per_sector,
+            root_sectors * bytes_per_sector,
+        ),
+    };
+    let info = FileSystemInfo {
+        filesystem_type,
+        label,
+        uuid,
+        size: Some(total_sectors * bytes_per_sector),
+    };
+    Some((info, (root.0, root.1.min(MAX_LABEL_DIRECTORY_LENGTH))))
+}
+
+fn fat_label(root: &[u8]) -> Option<String> {
+    const END_OF_DIRECTORY: u8 = 0x00;
+    const DELETED: u8 = 0xE5;
+    const VOLUME_ID: u8 = 0x08;
+    const DIRECTORY: u8 = 0x10;
+    const LONG_NAME: u8 = 0x0F;
+
+    for entry in root.chunks_exact(32) {
+        let attributes = entry[11];
+        match entry[0] {
+            END_OF_DIRECTORY => break,
+            DELETED => continue,
+            _ if attributes & LONG_NAME == LONG_NAME => continue,
+            _ if attributes & (VOLUME_ID | DIRECTORY) == VOLUME_ID => {
+                return fat_name(&entry[..11])
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Labels are padded with spaces, and formatting tools write NO NAME when there's none.
+fn fat_name(name: &[u8]) -> Option<String> {
+    let name = String::from_utf8_lossy(name).trim_end().to_string();
+    (!name.is_empty() && name != "NO NAME").then_some(name)
+}
+
+// A string padded with NULs, None when empty.
+fn ascii(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    let string = String::from_utf8_lossy(&bytes[..end]).into_owned();
+    (!string.is_empty()).then_some(string)
+}
+
+fn utf16(bytes: &[u8]) -> Option<String> {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|unit| *unit != 0)
+        .collect();
+    (!units.is_empty()).then(|| String::from_utf16_lossy(&units))
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn be16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn be64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// Reads a range of the volume, the part of it past the end of the volume reads as zeros.
+fn read_at<R>(volume: &mut R, offset: u64, length: u64) -> Result<Vec<u8>, VhdxError>
+where
+    R: Read + Seek,
+{
+    let mut buffer = Vec::with_capacity(length as usize);
+    volume.seek(SeekFrom::Start(offset))?;
+    volume.take(length).read_to_end(&mut buffer)?;
+    buffer.resize(length as usize, 0);
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::create::{CreateOptions, DynamicWriter};
+    use pretty_assertions::assert_eq;
+
+    fn put(volume: &mut [u8], offset: usize, bytes: &[u8]) {
+        volume[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn detect(volume: Vec<u8>) -> Option<FileSystemInfo> {
+        detect_filesystem(&mut Cursor::new(volume)).unwrap()
+    }
+
+    fn ext_superblock(compat: u32, incompat: u32) -> Vec<u8> {
+        let mut volume = vec![0; 4096];
+        put(&mut volume, 1024 + 4, &1000u32.to_le_bytes());
+        put(&mut volume, 1024 + 24, &2u32.to_le_bytes());
+        put(&mut volume, 1024 + 56, &0xEF53u16.to_le_bytes());
+        put(&mut volume, 1024 + 92, &compat.to_le_bytes());
+        put(&mut volume, 1024 + 96, &incompat.to_le_bytes());
+        put(&mut volume, 1024 + 104, &Uuid::from_u128(0xE4).into_bytes());
+        put(&mut volume, 1024 + 120, b"rootfs");
+        volume
+    }
+
+    #[test]
+    fn should_detect_linux_filesystems() {
+        let mut xfs = vec![0; 512];
+        put(&mut xfs, 0, b"XFSB");
+        put(&mut xfs, 4, &4096u32.to_be_bytes());
+        put(&mut xfs, 8, &256u64.to_be_bytes());
+        put(&mut xfs, 32, &Uuid::from_u128(0xF5).into_bytes());
+        put(&mut xfs, 108, b"data");
+        let mut btrfs = vec![0; 0x11000];
+        put(&mut btrfs, 0x10020, &Uuid::from_u128(0xB7).into_bytes());
+        put(&mut btrfs, 0x10040, b"_BHRfS_M");
+        put(&mut btrfs, 0x10070, &(1u64 << 30).to_le_bytes());
+        put(&mut btrfs, 0x1012B, b"pool");
+
+        assert_eq!(
+            Some(FileSystemInfo {
+                filesystem_type: FileSystemType::Ext4,
+                label: Some("rootfs".to_string()),
+                uuid: Some(Uuid::from_u128(0xE4).to_string()),
+                size: Some(1000 * 4096),
+            }),
+            detect(ext_superblock(0x4, 0x2 | 0x40))
+        );
+        assert_eq!(
+            FileSystemType::Ext3,
+            detect(ext_superblock(0x4, 0x2)).unwrap().filesystem_type
+        );
+        assert_eq!(
+            FileSystemType::Ext2,
+            detect(ext_superblock(0, 0x2)).unwrap().filesystem_type
+        );
+        assert_eq!(
+            Some(FileSystemInfo {
+                filesystem_type: FileSystemType::Xfs,
+                label: Some("data".to_string()),
+                uuid: Some(Uuid::from_u128(0xF5).to_string()),
+                size: Some(256 * 4096),
+            }),
+            detect(xfs)
+        );
+        assert_eq!(
+            Some(FileSystemInfo {
+                filesystem_type: FileSystemType::Btrfs,
+                label: Some("pool".to_string()),
+                uuid: Some(Uuid::from_u128(0xB7).to_string()),
+                size: Some(1 << 30),
+            }),
+            detect(btrfs)
+        );
+    }
+
+    #[test]
+    fn should_read_fat_and_exfat_labels_from_the_root_directory() {
+        let mut fat = vec![0; 0x11000];
+        put(&mut fat, 0, &[0xEB, 0x3C, 0x90]);
+        put(&mut fat, 11, &512u16.to_le_bytes());
+        fat[13] = 4;
+        put(&mut fat, 14, &1u16.to_le_bytes());
+        fat[16] = 2;
+        put(&mut fat, 17, &512u16.to_le_bytes());
+        put(&mut fat, 19, &40000u16.to_le_bytes());
+        put(&mut fat, 22, &40u16.to_le_bytes());
+        fat[38] = 0x29;
+        put(&mut fat, 39, &0x1234ABCDu32.to_le_bytes());
+        put(&mut fat, 43, b"NO NAME    ");
+        put(&mut fat, 510, &[0x55, 0xAA]);
+        put(&mut fat, 81 * 512, b"MY DISK    \x08");
+
+        let mut exfat = vec![0; 80 * 1024];
+        put(&mut exfat, 3, b"EXFAT   ");
+        put(&mut exfat, 72, &0x10000u64.to_le_bytes());
+        put(&mut exfat, 88, &128u32.to_le_bytes());
+        put(&mut exfat, 96, &4u32.to_le_bytes());
+        put(&mut exfat, 100, &0xCAFE0001u32.to_le_bytes());
+        exfat[108] = 9;
+        exfat[109] = 3;
+        put(&mut exfat, 73728, &[0x81; 32]);
+        put(
+            &mut exfat,
+            73728 + 32,
+            &[0x83, 4, b'D', 0, b'a', 0, b't', 0, b'a', 0],
+        );
+
+        assert_eq!(
+            Some(FileSystemInfo {
+                filesystem_type: FileSystemType::Fat16,
+                label: Some("MY DISK".to_string()),
+                uuid: Some("1234-ABCD".to_string()),
+                size: Some(40000 * 512),
+            }),
+            detect(fat)
+        );
+        assert_eq!(
+            Some(FileSystemInfo {
+                filesystem_type: FileSystemType::ExFat,
+                label: Some("Data".to_string()),
+                uuid: Some("CAFE-0001".to_string()),
+                size: Some(0x10000 * 512),
+            }),
+            detect(exfat)
+        );
+    }
+
+    #[test]
+    fn should_read_the_ntfs_label_from_the_volume_record() {
+        const RECORD: usize = 4 * 4096 + 3 * 1024;
+        let mut ntfs = vec![0; 0x11000];
+        put(&mut ntfs, 3, b"NTFS    ");
+        put(&mut ntfs, 0x0B, &512u16.to_le_bytes());
+        ntfs[0x0D] = 8;
+        put(&mut ntfs, 0x28, &100000u64.to_le_bytes());
+        put(&mut ntfs, 0x30, &4u64.to_le_bytes());
+        ntfs[0x40] = -10i8 as u8;
+        put(&mut ntfs, 0x48, &0x0123456789ABCDEFu64.to_le_bytes());
+
+        // The label straddles the end of the first 512 bytes of the record, which hold the
+        // update sequence number on disk.
+        let mut record = vec![0; 1024];
+        put(&mut record, 0, b"FILE");
+        put(&mut record, 4, &0x30u16.to_le_bytes());
+        put(&mut record, 6, &3u16.to_le_bytes());
+        put(&mut record, 0x14, &0x1E0u16.to_le_bytes());
+        put(&mut record, 0x1E0, &0x60u32.to_le_bytes());
+        put(&mut record, 0x1E4, &0x28u32.to_le_bytes());
+        put(&mut record, 0x1F0, &14u32.to_le_bytes());
+        put(&mut record, 0x1F4, &0x18u16.to_le_bytes());
+        let label: Vec<u8> = "Windows"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        put(&mut record, 0x1F8, &label);
+        put(&mut record, 0x208, &0xFFFF_FFFFu32.to_le_bytes());
+        let original = [record[510], record[511], record[1022], record[1023]];
+        put(&mut record, 0x30, &[0x01, 0x00]);
+        put(&mut record, 0x32, &original);
+        put(&mut record, 510, &[0x01, 0x00]);
+        put(&mut record, 1022, &[0x01, 0x00]);
+        put(&mut ntfs, RECORD, &record);
+
+        assert_eq!(
+            Some(FileSystemInfo {
+                filesystem_type: FileSystemType::Ntfs,
+                label: Some("Windows".to_string()),
+                uuid: Some("0123456789ABCDEF".to_string()),
+                size: Some(100000 * 512),
+            }),
+            detect(ntfs)
+        );
+    }
+
+    #[test]
+    fn should_detect_containers_and_swap() {
+        let mut luks = vec![0; 4096];
+        put(&mut luks, 0, b"LUKS\xBA\xBE\x00\x02");
+        put(&mut luks, 24, b"secret");
+        put(&mut luks, 168, Uuid::from_u128(0x1C).to_string().as_bytes());
+        let mut lvm = vec![0; 4096];
+        put(&mut lvm, 512, b"LABELONE");
+        put(&mut lvm, 512 + 20, &32u32.to_le_bytes());
+        put(&mut lvm, 512 + 24, b"LVM2 001");
+        put(&mut lvm, 512 + 32, b"abcdefghijklmnopqrstuvwxyz012345");
+        put(&mut lvm, 512 + 64, &(1u64 << 32).to_le_bytes());
+        let mut swap = vec![0; 8192];
+        put(&mut swap, 1024, &1u32.to_le_bytes());
+        put(&mut swap, 1028, &1023u32.to_le_bytes());
+        put(&mut swap, 1036, &Uuid::from_u128(0x5A).into_bytes());
+        put(&mut swap, 4086, b"SWAPSPACE2");
+        let mut bitlocker = vec![0; 512];
+        put(&mut bitlocker, 3, b"-FVE-FS-");
+        put(&mut bitlocker, 160, &Uuid::from_u128(0xB1).to_bytes_le());
+
+        let luks = detect(luks).unwrap();
+        let lvm = detect(lvm).unwrap();
+        let swap = detect(swap).unwrap();
+        let bitlocker = detect(bitlocker).unwrap();
+
+        assert_eq!(
+            (FileSystemType::Luks, Some("secret".to_string())),
+            (luks.filesystem_type, luks.label)
+        );
+        assert_eq!(Some(Uuid::from_u128(0x1C).to_string()), luks.uuid);
+        assert_eq!(
+            Some("abcdef-ghij-klmn-opqr-stuv-wxyz-012345".to_string()),
+            lvm.uuid
+        );
+        assert_eq!(Some(1 << 32), lvm.size);
+        assert_eq!(FileSystemType::Swap, swap.filesystem_type);
+        assert_eq!(Some(1024 * 4096), swap.size);
+        assert_eq!(Some(Uuid::from_u128(0xB1).to_string()), bitlocker.uuid);
+        assert_eq!(None, detect(vec![0; 4096]));
+    }
+
+    #[test]
+    fn should_ignore_lvm_labels_pointing_past_the_sector() {
+        for offset in [500, u32::MAX] {
+            let mut lvm = vec![0; 4096];
+            put(&mut lvm, 512, b"LABELONE");
+            put(&mut lvm, 512 + 20, &offset.to_le_bytes());
+            put(&mut lvm, 512 + 24, b"LVM2 001");
+
+            assert_eq!(None, detect(lvm));
+        }
+    }
+
+    #[test]
+    fn should_detect_the_filesystem_of_an_unpartitioned_vhdx() {
+        let options = CreateOptions {
+            block_size: Vhdx::MB as u32,
+            ..CreateOptions::default()
+        };
+        let mut writer = DynamicWriter::new(Cursor::new(Vec::new()), Vhdx::MB, options).unwrap();
+        writer.write_block(0, &ext_superblock(0, 0x2)).unwrap();
+        let mut vhdx = Vhdx::from_reader(writer.finish().unwrap()).unwrap();
+
+        let volumes = vhdx.volumes().unwrap();
+
+        assert_eq!(1, volumes.len());
+        assert_eq!(None, volumes[0].partition);
+        assert_eq!(
+            Some(FileSystemType::Ext2),
+            volumes[0]
+                .filesystem
+                .as_ref()
+                .map(|info| info.filesystem_type)
+        );
+    }
+
+    #[test]
+    fn should_probe_the_whole_disk_when_the_partition_table_is_corrupt() {
+        let options = CreateOptions {
+            block_size: Vhdx::MB as u32,
+            ..CreateOptions::default()
+        };
+        // A protective MBR without any GPT behind it.
+        let mut disk = ext_superblock(0, 0x2);
+        disk[446 + 4] = 0xEE;
+        put(&mut disk, 510, &[0x55, 0xAA]);
+        let mut writer = DynamicWriter::new(Cursor::new(Vec::new()), Vhdx::MB, options).unwrap();
+        writer.write_block(0, &disk).unwrap();
+        let mut vhdx = Vhdx::from_reader(writer.finish().unwrap()).unwrap();
+        assert!(vhdx.partitions().is_err());
+
+        let volumes = vhdx.volumes().unwrap();
+
+        assert_eq!(1, volumes.len());
+        assert_eq!(None, volumes[0].partition);
+        assert_eq!(
+            Some(FileSystemType::Ext2),
+            volumes[0]
+                .filesystem
+                .as_ref()
+                .map(|info| info.filesystem_type)
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::{
+    bat::BatEntryState,
+    create::{CreateOptions, DynamicWriter},
+    error::VhdxError,
+    image_store::{FileSystem, ImageStore},
+    vhdx::Vhdx,
+    virtual_disk::Allocation,
+    SyncData,
+};
+
+impl Vhdx {
+    // Writes the whole chain ending in this disk as a new standalone dynamic disk at the path and
+    // opens it.
+    pub fn flatten(&mut self, path: &impl AsRef<Path>) -> Result<Vhdx, VhdxError> {
+        self.flatten_in(&FileSystem, path)
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    // The same for a new disk of the store.
+    pub fn flatten_in<S>(
+        &mut self,
+        store: &S,
+        path: &impl AsRef<Path>,
+    ) -> Result<Vhdx<S::Image>, VhdxError>
+    where
+        S: ImageStore,
+    {
+        let image = store.create(path.as_ref())?;
+        self.flatten_to(image)?.sync_data()?;
+        Vhdx::from_reader(store.open(path.as_ref(), true)?)
+    }
+
+    // Writes the chain ending in this disk as a dynamic disk without a parent. Only the blocks
+    // holding anything but zeros once the chain is merged are allocated. The new disk keeps the
+    // virtual disk id, the block size and the sector sizes of this one, it is the same disk
+    // without its history.
+    pub fn flatten_to<W>(&mut self, writer: W) -> Result<W, VhdxError>
+    where
+        W: Write + Seek,
+    {
+        let options = CreateOptions {
+            block_size: self.meta_data.file_parameters.block_size as u32,
+            logical_sector_size: self.meta_data.logical_sector_size,
+            physical_sector_size: self.meta_data.physical_sector_size,
+            zero_block_state: BatEntryState::NotPresent,
+        };
+        let size = self.virtual_disk_size();
+        let block_size = self.block_size();
+        let mut writer = DynamicWriter::new(writer, size, options)?;
+        writer.set_virtual_disk_id(self.meta_data.virtual_disk_id);
+
+        let mut disk = self.virtual_disk();
+        let mut buffer = vec![0; block_size as usize];
+        for block in 0..writer.payload_blocks_count() {
+            let start = block * block_size;
+            let length = block_size.min(size - start);
+            if disk.allocation(start, length)? == Allocation::Zero {
+                continue;
+            }
+            let data = &mut buffer[..length as usize];
+            disk.seek(SeekFrom::Start(start))?;
+            disk.read_exact(data)?;
+            writer.write_block(block, data)?;
+        }
+        writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_utils::{
+        differencing_image, dynamic_image, parent_locator, TempDir, BAT_OFFSET, BLOCK_SIZE,
+    };
+    use pretty_assertions::assert_eq;
+
+    fn read_all<T: Read + Seek>(vhdx: &mut Vhdx<T>) -> Vec<u8> {
+        let mut data = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn should_flatten_a_chain_into_a_standalone_disk() {
+        let parent = dynamic_image(4 * BLOCK_SIZE, &[(1, 0x11), (2, 0x22), (3, 0)]);
+        let child = differencing_image(
+            4 * BLOCK_SIZE,
+            parent_locator("parent.vhdx"),
+            &[(0, 0xAA, None), (2, 0xBB, Some(vec![0x0F]))],
+        );
+        let mut leaf = Vhdx::from_reader(child)
+            .unwrap()
+            .with_parent(Vhdx::from_reader(parent).unwrap())
+            .unwrap();
+        let expected = read_all(&mut leaf);
+
+        let image = leaf.flatten_to(Cursor::new(Vec::new())).unwrap();
+        let mut flat = Vhdx::from_reader(image).unwrap();
+
+        assert!(!flat.has_parent());
+        assert_eq!(
+            leaf.meta_data.virtual_disk_id,
+            flat.meta_data.virtual_disk_id
+        );
+        assert_eq!(
+            leaf.meta_data.logical_sector_size,
+            flat.meta_data.logical_sector_size
+        );
+        assert_eq!(leaf.block_size(), flat.block_size());
+        let states: Vec<_> = (0..4)
+            .map(|block| flat.payload_entry(block).unwrap().state())
+            .collect();
+        assert_eq!(
+            vec![
+                BatEntryState::FullyPresent,
+                BatEntryState::FullyPresent,
+                BatEntryState::FullyPresent,
+                BatEntryState::NotPresent,
+            ],
+            states
+        );
+        assert!(read_all(&mut flat) == expected);
+    }
+
+    #[test]
+    fn should_flatten_zero_and_partially_present_blocks_over_the_parent() {
+        let parent = dynamic_image(2 * BLOCK_SIZE, &[(0, 0x11), (1, 0x22)]);
+        let mut child = differencing_image(
+            2 * BLOCK_SIZE,
+            parent_locator("parent.vhdx"),
+            &[(1, 0xBB, Some(vec![0x0F]))],
+        );
+        child.seek(SeekFrom::Start(BAT_OFFSET)).unwrap();
+        child
+            .write_all(&(BatEntryState::Zero as u64).to_le_bytes())
+            .unwrap();
+        let mut leaf = Vhdx::from_reader(child)
+            .unwrap()
+            .with_parent(Vhdx::from_reader(parent).unwrap())
+            .unwrap();
+
+        let image = leaf.flatten_to(Cursor::new(Vec::new())).unwrap();
+        let mut flat = Vhdx::from_reader(image).unwrap();
+
+        assert_eq!(
+            BatEntryState::NotPresent,
+            flat.payload_entry(0).unwrap().state()
+        );
+        let data = read_all(&mut flat);
+        assert!(data[..BLOCK_SIZE].iter().all(|b| *b == 0));
+        assert!(data[BLOCK_SIZE..BLOCK_SIZE + 4 * 512]
+            .iter()
+            .all(|b| *b == 0xBB));
+        assert!(data[BLOCK_SIZE + 4 * 512..].iter().all(|b| *b == 0x22));
+    }
+
+    #[test]
+    fn should_refuse_to_overwrite_an_existing_destination() {
+        let directory = TempDir::new("flatten");
+        let source = directory.join("source.vhdx");
+        let destination = directory.join("destination.vhdx");
+        std::fs::write(
+            &source,
+            dynamic_image(BLOCK_SIZE, &[(0, 0x11)]).into_inner(),
+        )
+        .unwrap();
+        std::fs::write(&destination, b"keep").unwrap();
+
+        let result = Vhdx::new(&source).unwrap().flatten(&destination);
+        let kept = std::fs::read(&destination).unwrap();
+
+        assert!(
+            matches!(result, Err(VhdxError::IoError(e)) if e.kind() == std::io::ErrorKind::AlreadyExists)
+        );
+        assert_eq!(b"keep".to_vec(), kept);
+    }
+}
@@ -0,0 +1,493 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::{error::VhdxError, SetLen, SyncData};
+
+// Where images are opened from whenever one is found by path, such as the parents of a
+// differencing disk. Parent resolution only goes through the store, so it decides what a locator
+// can reach.
+pub trait ImageStore {
+    type Image: Read + Write + Seek + SyncData;
+
+    // Opens an existing image, writable when asked to.
+    fn open(&self, path: &Path, write: bool) -> Result<Self::Image, VhdxError>;
+
+    // Creates a new image, failing when there's one at the path already.
+    fn create(&self, path: &Path) -> Result<Self::Image, VhdxError>;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    // The names of the entries of a directory.
+    fn read_dir(&self, path: &Path) -> Result<Vec<String>, VhdxError>;
+
+    // The one path all the paths leading to an image share.
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, VhdxError>;
+}
+
+// The file system of the host, with no restriction.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileSystem;
+
+impl ImageStore for FileSystem {
+    type Image = File;
+
+    fn open(&self, path: &Path, write: bool) -> Result<File, VhdxError> {
+        Ok(File::options().read(true).write(write).open(path)?)
+    }
+
+    fn create(&self, path: &Path) -> Result<File, VhdxError> {
+        Ok(File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<String>, VhdxError> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(path)? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, VhdxError> {
+        Ok(fs::canonicalize(path)?)
+    }
+}
+
+// The file system under a root directory, for images that can't be trusted, such as uploaded
+// ones. Paths are taken relative to the root and anything leading out of it, through .. or a
+// symbolic link, is refused.
+#[derive(Debug, Clone)]
+pub struct SandboxedFileSystem {
+    root: PathBuf,
+}
+
+impl SandboxedFileSystem {
+    pub fn new(root: &impl AsRef<Path>) -> Result<Self, VhdxError> {
+        Ok(Self {
+            root: fs::canonicalize(root)?,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // The path on the host, once every symbolic link on the way is followed. A path that doesn't
+    // exist yet is checked through its directory, which has to exist.
+    fn confine(&self, path: &Path) -> Result<PathBuf, VhdxError> {
+        let path = normalize(&self.root.join(path));
+        if !path.starts_with(&self.root) {
+            return Err(VhdxError::OutsideImageStore(path));
+        }
+        let resolved = match fs::canonicalize(&path) {
+            Ok(resolved) => resolved,
+            Err(error) => match (path.parent(), path.file_name()) {
+                (Some(directory), Some(name)) => fs::canonicalize(directory)?.join(name),
+                _ => return Err(error.into()),
+            },
+        };
+        match resolved.starts_with(&self.root) {
+            true => Ok(resolved),
+            false => Err(VhdxError::OutsideImageStore(path)),
+        }
+    }
+
+    // A link swapped in on the way after the path was confined is only followed when the file is
+    // opened, so what was opened is checked again. Where the host doesn't tell, confine is all
+    // there is.
+    fn check_opened(&self, file: File, path: &Path) -> Result<File, VhdxError> {
+        match opened_path(&file) {
+            Some(opened) if !opened.starts_with(&self.root) => {
+                Err(VhdxError::OutsideImageStore(path.to_path_buf()))
+            }
+            _ => Ok(file),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn opened_path(file: &File) -> Option<PathBuf> {
+    use std::os::unix::io::AsRawFd;
+
+    fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn opened_path(_: &File) -> Option<PathBuf> {
+    None
+}
+
+impl ImageStore for SandboxedFileSystem {
+    type Image = File;
+
+    fn open(&self, path: &Path, write: bool) -> Result<File, VhdxError> {
+        let path = self.confine(path)?;
+        self.check_opened(FileSystem.open(&path, write)?, &path)
+    }
+
+    fn create(&self, path: &Path) -> Result<File, VhdxError> {
+        let path = self.confine(path)?;
+        self.check_opened(FileSystem.create(&path)?, &path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.confine(path).is_ok_and(|path| path.is_file())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.confine(path).is_ok_and(|path| path.is_dir())
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<String>, VhdxError> {
+        FileSystem.read_dir(&self.confine(path)?)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, VhdxError> {
+        FileSystem.canonicalize(&self.confine(path)?)
+    }
+}
+
+type Images = BTreeMap<PathBuf, Arc<Mutex<Vec<u8>>>>;
+
+// Images kept in memory by path, directories being implied by the paths of the images.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    images: Arc<Mutex<Images>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds an image, replacing the one at the path if any.
+    pub fn insert(&self, path: &impl AsRef<Path>, data: Vec<u8>) {
+        self.images()
+            .insert(normalize(path.as_ref()), Arc::new(Mutex::new(data)));
+    }
+
+    // A copy of what the image holds.
+    pub fn get(&self, path: &impl AsRef<Path>) -> Option<Vec<u8>> {
+        let images = self.images();
+        let data = images.get(&normalize(path.as_ref()))?;
+        let data = lock(data).clone();
+        Some(data)
+    }
+
+    pub fn remove(&self, path: &impl AsRef<Path>) -> Option<Vec<u8>> {
+        let data = self.images().remove(&normalize(path.as_ref()))?;
+        let data = lock(&data).clone();
+        Some(data)
+    }
+
+    fn images(&self) -> MutexGuard<'_, Images> {
+        lock(&self.images)
+    }
+}
+
+impl ImageStore for MemoryStore {
+    type Image = MemoryImage;
+
+    fn open(&self, path: &Path, write: bool) -> Result<MemoryImage, VhdxError> {
+        let data = self
+            .images()
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, path.display().to_string()))?;
+        Ok(MemoryImage {
+            data,
+            position: 0,
+            write,
+        })
+    }
+
+    fn create(&self, path: &Path) -> Result<MemoryImage, VhdxError> {
+        let mut images = self.images();
+        let path = normalize(path);
+        if images.contains_key(&path) {
+            return Err(
+                io::Error::new(io::ErrorKind::AlreadyExists, path.display().to_string()).into(),
+            );
+        }
+        let data = Arc::new(Mutex::new(Vec::new()));
+        images.insert(path, data.clone());
+        Ok(MemoryImage {
+            data,
+            position: 0,
+            write: true,
+        })
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.images().contains_key(&normalize(path))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        let path = normalize(path);
+        self.images()
+            .keys()
+            .any(|image| image != &path && image.starts_with(&path))
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<String>, VhdxError> {
+        let path = normalize(path);
+        let names: BTreeSet<String> = self
+            .images()
+            .keys()
+            .filter_map(|image| image.strip_prefix(&path).ok()?.components().next())
+            .map(|name| name.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        Ok(names.into_iter().collect())
+    }
+
+    // Any directory can hold images, even one that holds none yet.
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, VhdxError> {
+        Ok(normalize(path))
+    }
+}
+
+// An image of a memory store. Writes are seen by every other handle to the same image.
+#[derive(Debug)]
+pub struct MemoryImage {
+    data: Arc<Mutex<Vec<u8>>>,
+    position: u64,
+    write: bool,
+}
+
+impl Read for MemoryImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = lock(&self.data);
+        let start = (self.position as usize).min(data.len());
+        let length = buf.len().min(data.len() - start);
+        buf[..length].copy_from_slice(&data[start..start + length]);
+        drop(data);
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl Write for MemoryImage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.write {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "image is opened read only",
+            ));
+        }
+        let mut data = lock(&self.data);
+        let start = self.position as usize;
+        if data.len() < start + buf.len() {
+            data.resize(start + buf.len(), 0);
+        }
+        data[start..start + buf.len()].copy_from_slice(buf);
+        drop(data);
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryImage {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match position {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::End(offset) => (lock(&self.data).len() as u64, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        self.position = base
+            .checked_add_signed(offset)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.position)
+    }
+}
+
+impl SetLen for MemoryImage {
+    fn set_len(&mut self, length: u64) -> io::Result<()> {
+        if !self.write {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "image is opened read only",
+            ));
+        }
+        lock(&self.data).resize(length as usize, 0);
+        Ok(())
+    }
+}
+
+impl SyncData for MemoryImage {
+    fn sync_data(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Whoever panicked holding the lock left the data as whole as any writer would have.
+fn lock<V>(mutex: &Mutex<V>) -> MutexGuard<'_, V> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Drops the . components of the path and resolves its .. components without looking at what the
+// path leads to. There's nothing above the root.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta_data::ParentLocator;
+    use crate::parent_resolver::ParentResolver;
+    use crate::test_utils::{
+        differencing_image, dynamic_image, parent_locator, TempDir, BLOCK_SIZE,
+    };
+    use crate::vhdx::Vhdx;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_create_and_open_a_chain_held_in_memory() {
+        let store = MemoryStore::new();
+        store.insert(
+            &"/images/base.vhdx",
+            dynamic_image(4 * BLOCK_SIZE, &[(1, 0x11)]).into_inner(),
+        );
+
+        let mut child = Vhdx::create_differencing_in(
+            &store,
+            &"/images/children/./child.vhdx",
+            &"/images/children/../base.vhdx",
+            &ParentResolver::default(),
+        )
+        .unwrap();
+
+        let locator = child.meta_data.parent_locator.clone().unwrap();
+        assert_eq!(
+            Some(r"..\base.vhdx"),
+            locator.get(ParentLocator::RELATIVE_PATH)
+        );
+        assert!(child.parent().is_some());
+        let mut data = vec![0; BLOCK_SIZE];
+        let mut disk = child.virtual_disk();
+        disk.seek(SeekFrom::Start(BLOCK_SIZE as u64)).unwrap();
+        disk.read_exact(&mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 0x11));
+
+        let report = Vhdx::verify_chain_in(
+            &store,
+            &"/images/children/child.vhdx",
+            &ParentResolver::default(),
+        )
+        .unwrap();
+        assert!(report.is_valid());
+    }
+
+    #[test]
+    fn should_keep_locators_inside_the_sandbox() {
+        let directory = TempDir::new("sandbox");
+        fs::create_dir_all(directory.join("root/uploads")).unwrap();
+        fs::create_dir_all(directory.join("outside")).unwrap();
+        fs::write(
+            directory.join("outside/secret.vhdx"),
+            dynamic_image(4 * BLOCK_SIZE, &[]).into_inner(),
+        )
+        .unwrap();
+        fs::write(
+            directory.join("root/uploads/child.vhdx"),
+            differencing_image(
+                4 * BLOCK_SIZE,
+                parent_locator(r"..\..\outside\secret.vhdx"),
+                &[],
+            )
+            .into_inner(),
+        )
+        .unwrap();
+
+        let unrestricted = Vhdx::open_in(
+            &FileSystem,
+            &directory.join("root/uploads/child.vhdx"),
+            &ParentResolver::default(),
+        );
+        let sandbox = SandboxedFileSystem::new(&directory.join("root")).unwrap();
+        let sandboxed = Vhdx::open_in(&sandbox, &"uploads/child.vhdx", &ParentResolver::default());
+        let escaped = sandbox.open(Path::new("../outside/secret.vhdx"), false);
+
+        assert!(unrestricted.is_ok());
+        assert!(matches!(sandboxed, Err(VhdxError::ParentNotFound(_))));
+        assert!(matches!(escaped, Err(VhdxError::OutsideImageStore(_))));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn should_refuse_links_and_missing_directories_leading_out_of_the_sandbox() {
+        let directory = TempDir::new("links");
+        fs::create_dir_all(directory.join("root")).unwrap();
+        fs::create_dir_all(directory.join("outside")).unwrap();
+        fs::write(directory.join("outside/secret.vhdx"), b"secret").unwrap();
+        std::os::unix::fs::symlink(
+            directory.join("outside/secret.vhdx"),
+            directory.join("root/link.vhdx"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(directory.join("outside"), directory.join("root/dir")).unwrap();
+
+        let sandbox = SandboxedFileSystem::new(&directory.join("root")).unwrap();
+        let linked = sandbox.open(Path::new("link.vhdx"), false);
+        let created = sandbox.create(Path::new("dir/new.vhdx"));
+        let missing = sandbox.create(Path::new("missing/new.vhdx"));
+        let escaped = directory.join("outside/new.vhdx").exists();
+
+        assert!(matches!(linked, Err(VhdxError::OutsideImageStore(_))));
+        assert!(matches!(created, Err(VhdxError::OutsideImageStore(_))));
+        assert!(matches!(missing, Err(VhdxError::IoError(_))));
+        assert!(!escaped);
+    }
+
+    #[test]
+    fn should_share_writes_between_handles_of_an_image() {
+        let store = MemoryStore::new();
+        let mut image = store.create(Path::new("/a/image.vhdx")).unwrap();
+        image.write_all(b"vhdxfile").unwrap();
+        let mut read_only = store.open(Path::new("/a/b/../image.vhdx"), false).unwrap();
+
+        let mut data = Vec::new();
+        read_only.read_to_end(&mut data).unwrap();
+
+        assert_eq!(b"vhdxfile".to_vec(), data);
+        assert!(read_only.write_all(b"x").is_err());
+        assert_eq!(
+            vec!["image.vhdx".to_string()],
+            store.read_dir(Path::new("/a")).unwrap()
+        );
+        assert!(store.is_dir(Path::new("/")));
+        assert!(store.create(Path::new("/a/image.vhdx")).is_err());
+    }
+}
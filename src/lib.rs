@@ -1,14 +1,38 @@
 use error::VhdxError;
-use std::io::{Read, Seek};
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, Write};
 
 pub mod bat;
 pub mod bits_parsers;
+pub mod chain;
+pub mod compact;
+pub mod create;
 pub mod error;
+pub mod ext4;
+pub mod fat;
+pub mod filesystem;
+pub mod flatten;
+pub mod image_store;
 pub mod log;
+pub mod log_history;
+pub mod merge;
 pub mod meta_data;
+pub mod parent_resolver;
 pub mod parse_utils;
+pub mod partition;
+pub mod qcow2;
+pub mod raw;
+pub mod rebase;
+pub mod recovery;
+pub mod resize;
+#[cfg(test)]
+mod test_utils;
+pub mod vdi;
+pub mod vhd;
 pub mod vhdx;
 pub mod vhdx_header;
+pub mod virtual_disk;
+pub mod vmdk;
 
 pub trait DeSerialise<T> {
     type Item;
@@ -18,6 +42,12 @@ pub trait DeSerialise<T> {
         T: Read + Seek;
 }
 
+pub trait Serialise<T> {
+    fn serialize(&self, writer: &mut T) -> Result<(), VhdxError>
+    where
+        T: Write + Seek;
+}
+
 pub trait Crc32 {
     fn crc32(&self) -> u32;
     fn crc32_from_digest(&self, digest: &mut crc::Digest<u32>);
@@ -27,6 +57,42 @@ pub trait Validation {
     fn validate(&self) -> Result<(), VhdxError>;
 }
 
+// Storage whose length can be changed, for the operations that give space back to the host.
+pub trait SetLen {
+    fn set_len(&mut self, length: u64) -> io::Result<()>;
+}
+
+impl SetLen for File {
+    fn set_len(&mut self, length: u64) -> io::Result<()> {
+        File::set_len(self, length)
+    }
+}
+
+impl SetLen for Cursor<Vec<u8>> {
+    fn set_len(&mut self, length: u64) -> io::Result<()> {
+        self.get_mut().resize(length as usize, 0);
+        Ok(())
+    }
+}
+
+// Storage that can be made durable. Data and log entries are synced before anything pointing to
+// them, a BAT entry or a header, is written, as flushing a file doesn't reach the disk.
+pub trait SyncData {
+    fn sync_data(&mut self) -> io::Result<()>;
+}
+
+impl SyncData for File {
+    fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+impl SyncData for Cursor<Vec<u8>> {
+    fn sync_data(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Ord, PartialOrd)]
 pub enum Signature {
     Vhdxfile,
@@ -1,8 +1,8 @@
 use crc::{Crc, CRC_32_ISCSI};
 use nom::Finish;
 use std::{
-    io::{Read, Seek},
-    iter, usize,
+    collections::BTreeMap,
+    io::{Cursor, Read, Seek, Write},
 };
 use uuid::Uuid;
 
@@ -17,22 +17,63 @@ use crate::{
     error::VhdxError,
     parse_utils::{t_guid, t_sign_u32, t_u32, t_u64},
     vhdx::Vhdx,
-    Crc32, DeSerialise, Signature, Validation,
+    Crc32, DeSerialise, Serialise, Signature, Validation,
 };
 
 #[derive(Debug)]
 pub struct Log {
+    // Every entry found in the log region, in the order they are stored, whatever log guid they
+    // were written with.
     pub log_entries: Vec<LogEntry>,
     pub log_sequence: LogSequence,
 }
 
 impl Log {
-    pub(crate) fn new(log_entries: Vec<LogEntry>) -> Self {
-        let entries = log_entries.clone();
-        Self {
-            log_entries,
-            log_sequence: Vhdx::try_get_log_sequence(&entries).unwrap(),
+    pub(crate) fn new(log: &[u8], log_guid: Uuid) -> Result<Self, VhdxError> {
+        let log_length = log.len() as u64;
+        let entries = Log::scan(log);
+
+        // Entries of an earlier log guid are left overs of a log that has already been replayed,
+        // and a nil log guid means there is nothing to replay at all.
+        let candidates: BTreeMap<u64, &LogEntry> = entries
+            .iter()
+            .filter(|(_, entry)| {
+                !log_guid.is_nil() && entry.header.log_guid == log_guid && entry.validate().is_ok()
+            })
+            .map(|(offset, entry)| (*offset, entry))
+            .collect();
+        let log_sequence = Vhdx::try_get_log_sequence(&candidates, log_length)?;
+
+        Ok(Self {
+            log_entries: entries.into_values().collect(),
+            log_sequence,
+        })
+    }
+
+    // Scans the log region for entries at every 4 KB boundary, keyed by their offset in the
+    // region. The log is a circular buffer, an entry running past the end of the region
+    // continues at its start. The entries aren't validated.
+    pub(crate) fn scan(log: &[u8]) -> BTreeMap<u64, LogEntry> {
+        let log_length = log.len() as u64;
+        let wrapped = [log, log].concat();
+
+        let mut entries = BTreeMap::new();
+        for offset in (0..log.len()).step_by(LogEntry::SECTOR_SIZE) {
+            if !log[offset..].starts_with(LogHeader::SIGN) {
+                continue;
+            }
+
+            // Whatever fails to parse is not an entry, just data that happens to start with the
+            // signature.
+            let Ok(entry) = LogEntry::deserialize(&mut Cursor::new(&wrapped[offset..])) else {
+                continue;
+            };
+            if entry.header.entry_length == 0 || entry.header.entry_length as u64 > log_length {
+                continue;
+            }
+            entries.insert(offset as u64, entry);
         }
+        entries
     }
 }
 
@@ -41,22 +82,191 @@ impl Log {
 pub struct LogEntry {
     pub(crate) header: LogHeader,
     descriptors: Vec<Descriptor>,
+
+    // CRC-32C of the entry as it was read, or as it would be written, with the checksum field
+    // taken as zero.
+    crc: u32,
 }
 
 impl LogEntry {
-    const SECTOR_SIZE: usize = 4096;
+    pub(crate) const SECTOR_SIZE: usize = 4096;
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
 
-    fn new(header: LogHeader, descriptors: Vec<Descriptor>) -> Self {
-        Self {
+    pub(crate) fn new(header: LogHeader, descriptors: Vec<Descriptor>) -> Self {
+        let mut entry = Self {
             header,
             descriptors,
-        }
+            crc: 0,
+        };
+        entry.crc = entry.crc32();
+        entry
+    }
+
+    // Recomputes the checksum after the header or the descriptors have been changed.
+    pub(crate) fn update_checksum(&mut self) {
+        self.crc = self.crc32();
+        self.header.checksum = self.crc;
+    }
+
+    // Length of the descriptor sectors and the data sectors that follow them.
+    fn expected_length(&self) -> u64 {
+        let descriptors = (64 + 32 * self.descriptors.len() as u64)
+            .div_ceil(LogEntry::SECTOR_SIZE as u64)
+            * LogEntry::SECTOR_SIZE as u64;
+        let data_sectors = self
+            .descriptors
+            .iter()
+            .filter(|desc| matches!(desc, Descriptor::Data(_)))
+            .count() as u64;
+        descriptors + data_sectors * LogEntry::SECTOR_SIZE as u64
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let header = &self.header;
+        let mut buffer = Vec::with_capacity(header.entry_length as usize);
+        buffer.extend_from_slice(LogHeader::SIGN);
+        buffer.extend_from_slice(&header.checksum.to_le_bytes());
+        buffer.extend_from_slice(&header.entry_length.to_le_bytes());
+        buffer.extend_from_slice(&header.tail.to_le_bytes());
+        buffer.extend_from_slice(&header.seq_number.to_le_bytes());
+        buffer.extend_from_slice(&header.descript_count.to_le_bytes());
+        buffer.extend_from_slice(&[0; 4]);
+        buffer.extend_from_slice(&header.log_guid.to_bytes_le());
+        buffer.extend_from_slice(&header.flushed_file_offset.to_le_bytes());
+        buffer.extend_from_slice(&header.last_file_offset.to_le_bytes());
+
+        self.descriptors.iter().for_each(|desc| match desc {
+            Descriptor::Zero(zero) => {
+                buffer.extend_from_slice(ZeroDesc::SIGN);
+                buffer.extend_from_slice(&[0; 4]);
+                buffer.extend_from_slice(&zero.zero_length.to_le_bytes());
+                buffer.extend_from_slice(&zero.file_offset.to_le_bytes());
+                buffer.extend_from_slice(&zero.seq_number.to_le_bytes());
+            }
+            Descriptor::Data(data) => {
+                buffer.extend_from_slice(DataDesc::SIGN);
+                buffer.extend_from_slice(&data.trailing_bytes);
+                buffer.extend_from_slice(&data.leading_bytes);
+                buffer.extend_from_slice(&data.file_offset.to_le_bytes());
+                buffer.extend_from_slice(&data.seq_number.to_le_bytes());
+            }
+        });
+        buffer.resize(
+            buffer.len().div_ceil(LogEntry::SECTOR_SIZE) * LogEntry::SECTOR_SIZE,
+            0,
+        );
+
+        self.descriptors
+            .iter()
+            .filter_map(|desc| match desc {
+                Descriptor::Data(data) => data.data_sector.as_ref(),
+                Descriptor::Zero(_) => None,
+            })
+            .for_each(|sector| {
+                buffer.extend_from_slice(DataSector::SIGN);
+                buffer.extend_from_slice(&sector.seq_high.to_le_bytes());
+                buffer.extend_from_slice(&sector.data);
+                buffer.extend_from_slice(&sector.seq_low.to_le_bytes());
+            });
+        buffer
+    }
+
+    pub fn header(&self) -> &LogHeader {
+        &self.header
+    }
+
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
     }
 }
 
 impl Validation for LogEntry {
     fn validate(&self) -> Result<(), VhdxError> {
+        self.header.validate()?;
+
+        if self.header.checksum != self.crc {
+            return Err(VhdxError::Crc32Error(self.header.checksum, self.crc));
+        }
+
+        let expected_length = self.expected_length();
+        if self.header.descript_count as usize != self.descriptors.len()
+            || self.header.entry_length as u64 != expected_length
+        {
+            return Err(VhdxError::LogEntryLengthError(
+                expected_length,
+                self.header.entry_length as u64,
+            ));
+        }
+
+        let seq_number = self.header.seq_number;
+        for descriptor in &self.descriptors {
+            if !descriptor.file_offset().is_multiple_of(Vhdx::KB * 4) {
+                return Err(VhdxError::NotDivisbleBy4KB(
+                    "Descriptor File Offset",
+                    descriptor.file_offset(),
+                ));
+            }
+
+            match descriptor {
+                Descriptor::Zero(zero) => {
+                    if zero.signature != Signature::Zero {
+                        return Err(VhdxError::SignatureError(
+                            Signature::Zero,
+                            zero.signature.clone(),
+                        ));
+                    }
+                    if !zero.zero_length.is_multiple_of(Vhdx::KB * 4) {
+                        return Err(VhdxError::NotDivisbleBy4KB(
+                            "Zero Descriptor Length",
+                            zero.zero_length,
+                        ));
+                    }
+                    if zero.seq_number != seq_number {
+                        return Err(VhdxError::LogSequenceNumberError(
+                            "Zero Descriptor",
+                            seq_number,
+                            zero.seq_number,
+                        ));
+                    }
+                }
+                Descriptor::Data(data) => {
+                    if data.signature != Signature::Desc {
+                        return Err(VhdxError::SignatureError(
+                            Signature::Desc,
+                            data.signature.clone(),
+                        ));
+                    }
+                    if data.seq_number != seq_number {
+                        return Err(VhdxError::LogSequenceNumberError(
+                            "Data Descriptor",
+                            seq_number,
+                            data.seq_number,
+                        ));
+                    }
+
+                    let Some(sector) = &data.data_sector else {
+                        return Err(VhdxError::LogEntryLengthError(
+                            expected_length,
+                            self.header.entry_length as u64,
+                        ));
+                    };
+                    if sector.signature != Signature::Data {
+                        return Err(VhdxError::SignatureError(
+                            Signature::Data,
+                            sector.signature.clone(),
+                        ));
+                    }
+                    if sector.sequence_number() != seq_number {
+                        return Err(VhdxError::LogSequenceNumberError(
+                            "Data Sector",
+                            seq_number,
+                            sector.sequence_number(),
+                        ));
+                    }
+                }
+            }
+        }
+
         Ok(())
     }
 }
@@ -71,7 +281,25 @@ impl<T> DeSerialise<T> for LogEntry {
         let start_pos = reader.stream_position()?;
 
         let header = LogHeader::deserialize(reader)?;
-        let mut descriptors = Vec::with_capacity(header.descript_count as usize);
+
+        // The checksum covers the whole entry as it is stored, padding included.
+        reader.seek(std::io::SeekFrom::Start(start_pos))?;
+        let mut raw = Vec::new();
+        reader
+            .by_ref()
+            .take(header.entry_length as u64)
+            .read_to_end(&mut raw)?;
+        let mut digest = LogEntry::CRC.digest();
+        digest.update(&raw[..raw.len().min(4)]);
+        digest.update(&[0; 4]);
+        digest.update(raw.get(8..).unwrap_or_default());
+        let crc = digest.finalize();
+        reader.seek(std::io::SeekFrom::Start(start_pos + 64))?;
+
+        // A garbage descriptor count must not be trusted for the allocation.
+        let mut descriptors = Vec::with_capacity(
+            (header.descript_count as usize).min(header.entry_length as usize / 32),
+        );
         if header.descript_count != 0 {
             for _ in 0..header.descript_count {
                 let mut buffer = [0; 4];
@@ -82,25 +310,40 @@ impl<T> DeSerialise<T> for LogEntry {
                 let desc = match signature {
                     Signature::Desc => Descriptor::Data(DataDesc::deserialize(reader)?),
                     Signature::Zero => Descriptor::Zero(ZeroDesc::deserialize(reader)?),
-                    _ => panic!("Fix this error"),
+                    _ => return Err(VhdxError::SignatureError(Signature::Desc, signature)),
                 };
                 descriptors.push(desc);
             }
         }
 
+        // The descriptors are padded to a whole number of sectors, the data sectors follow.
         let current_pos = reader.stream_position()?;
-        let offset = LogEntry::SECTOR_SIZE as u64 - (current_pos - start_pos);
-        reader.seek(std::io::SeekFrom::Current(offset as i64))?;
-
-        descriptors.iter_mut().for_each(|v| match v {
-            Descriptor::Data(desc) => {
-                let d_sector = DataSector::deserialize(reader).unwrap();
-                desc.data_sector = Some(d_sector);
+        let descriptors_length = (current_pos - start_pos).div_ceil(LogEntry::SECTOR_SIZE as u64)
+            * LogEntry::SECTOR_SIZE as u64;
+        reader.seek(std::io::SeekFrom::Start(start_pos + descriptors_length))?;
+
+        for descriptor in descriptors.iter_mut() {
+            // Only data descriptors have a data sector, zero descriptors describe their range
+            // entirely in the descriptor itself.
+            if let Descriptor::Data(desc) = descriptor {
+                desc.data_sector = Some(DataSector::deserialize(reader)?);
             }
-            Descriptor::Zero(_) => todo!(),
-        });
-        let log_entry = LogEntry::new(header, descriptors);
-        Ok(log_entry)
+        }
+        Ok(LogEntry {
+            header,
+            descriptors,
+            crc,
+        })
+    }
+}
+
+impl<T> Serialise<T> for LogEntry {
+    fn serialize(&self, writer: &mut T) -> Result<(), VhdxError>
+    where
+        T: Write + Seek,
+    {
+        writer.write_all(&self.to_bytes())?;
+        Ok(())
     }
 }
 
@@ -112,33 +355,10 @@ impl Crc32 for LogEntry {
     }
 
     fn crc32_from_digest(&self, digest: &mut crc::Digest<u32>) {
-        self.header.crc32_from_digest(digest);
-        self.descriptors.crc32_from_digest(digest);
-    }
-}
-
-impl Crc32 for Vec<Descriptor> {
-    fn crc32(&self) -> u32 {
-        todo!()
-    }
-
-    fn crc32_from_digest(&self, digest: &mut crc::Digest<u32>) {
-        self.iter().for_each(|desc| {
-            desc.crc32_from_digest(digest);
-        });
-
-        let zeros: Vec<u8> = iter::repeat(0)
-            .take(4096 - ((64 + (self.len() * 32)) % 4096))
-            .collect();
-        digest.update(&zeros);
-
-        self.iter().for_each(|desc| {
-            if let Descriptor::Data(desc) = desc {
-                if let Some(data) = &desc.data_sector {
-                    data.crc32_from_digest(digest);
-                }
-            }
-        });
+        let mut bytes = self.to_bytes();
+        bytes[4..8].fill(0);
+        bytes.resize(self.header.entry_length as usize, 0);
+        digest.update(&bytes);
     }
 }
 
@@ -198,7 +418,8 @@ pub struct LogHeader {
 impl LogHeader {
     pub const SIGN: &'static [u8] = &[0x6C, 0x6F, 0x67, 0x65];
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
-    fn new(
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
         signature: Signature,
         checksum: u32,
         entry_length: u32,
@@ -297,35 +518,35 @@ impl Validation for LogHeader {
             ));
         }
 
-        // TODO: Calc checksum
+        // The checksum covers the whole entry, it is checked by the entry itself.
 
-        if self.entry_length as u64 % (Vhdx::KB * 4) != 0 {
-            return Err(VhdxError::NotDivisbleByMB(
+        if self.entry_length == 0 {
+            return Err(VhdxError::NotAllowedToBeZero("Log Entry Length"));
+        }
+
+        if !(self.entry_length as u64).is_multiple_of(Vhdx::KB * 4) {
+            return Err(VhdxError::NotDivisbleBy4KB(
                 "Log Entry Length",
                 self.entry_length as u64,
             ));
         }
 
-        if self.tail as u64 % (Vhdx::KB * 4) != 0 {
-            return Err(VhdxError::NotDivisbleByMB("Log Tail", self.tail as u64));
+        if !(self.tail as u64).is_multiple_of(Vhdx::KB * 4) {
+            return Err(VhdxError::NotDivisbleBy4KB("Log Tail", self.tail as u64));
         }
 
         if self.seq_number == 0 {
             return Err(VhdxError::NotAllowedToBeZero("Log Sequence Number"));
         }
 
-        if self.descript_count == 0 {
-            return Err(VhdxError::NotAllowedToBeZero("Log Description Count"));
-        }
-
-        if self.flushed_file_offset % Vhdx::MB != 0 {
+        if !self.flushed_file_offset.is_multiple_of(Vhdx::MB) {
             return Err(VhdxError::NotDivisbleByMB(
                 "Flushed File Offset",
                 self.flushed_file_offset,
             ));
         }
 
-        if self.last_file_offset % Vhdx::MB != 0 {
+        if !self.last_file_offset.is_multiple_of(Vhdx::MB) {
             return Err(VhdxError::NotDivisbleByMB(
                 "Last File Offset",
                 self.last_file_offset,
@@ -338,17 +559,32 @@ impl Validation for LogHeader {
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
-pub(crate) enum Descriptor {
+pub enum Descriptor {
     Zero(ZeroDesc),
     Data(DataDesc),
 }
 
 impl Descriptor {
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+    pub fn file_offset(&self) -> u64 {
+        match self {
+            Descriptor::Zero(zero) => zero.file_offset,
+            Descriptor::Data(data) => data.file_offset,
+        }
+    }
+
+    // Number of bytes of the file the descriptor updates.
+    pub fn length(&self) -> u64 {
+        match self {
+            Descriptor::Zero(zero) => zero.zero_length,
+            Descriptor::Data(_) => LogEntry::SECTOR_SIZE as u64,
+        }
+    }
 }
 
 #[derive(Clone)]
-pub(crate) struct ZeroDesc {
+pub struct ZeroDesc {
     // ZeroSignature (4 bytes): MUST be 0x6F72657A ("zero" as ASCII).
     signature: Signature,
 
@@ -364,8 +600,20 @@ pub(crate) struct ZeroDesc {
     seq_number: u64,
 }
 impl ZeroDesc {
-    pub(crate) const SIGN: &'static [u8] = &[0x7A, 0x65, 0x72, 0x67];
+    pub(crate) const SIGN: &'static [u8] = &[0x7A, 0x65, 0x72, 0x6F];
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+    pub fn zero_length(&self) -> u64 {
+        self.zero_length
+    }
+
+    pub fn file_offset(&self) -> u64 {
+        self.file_offset
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.seq_number
+    }
 }
 
 impl Crc32 for Descriptor {
@@ -433,7 +681,7 @@ impl Crc32 for ZeroDesc {
 }
 
 #[derive(Clone)]
-pub(crate) struct DataDesc {
+pub struct DataDesc {
     signature: Signature,
 
     // TrailingBytes (4 bytes): Contains the four trailing bytes that were removed from the
@@ -460,6 +708,44 @@ pub(crate) struct DataDesc {
 impl DataDesc {
     pub(crate) const SIGN: &'static [u8] = &[0x64, 0x65, 0x73, 0x63];
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+    // Splits a 4 KB update into the descriptor and its data sector.
+    pub(crate) fn new(file_offset: u64, seq_number: u64, page: &[u8]) -> Self {
+        let data_sector = DataSector::new(
+            Signature::Data,
+            (seq_number >> 32) as u32,
+            &page[8..4092],
+            seq_number as u32,
+        );
+        Self {
+            signature: Signature::Desc,
+            trailing_bytes: page[4092..].to_vec(),
+            leading_bytes: page[..8].to_vec(),
+            file_offset,
+            seq_number,
+            data_sector: Some(data_sector),
+        }
+    }
+
+    pub fn file_offset(&self) -> u64 {
+        self.file_offset
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.seq_number
+    }
+
+    // Puts the leading and trailing bytes back around the data of the data sector, giving the
+    // 4 KB update as it is to be written to the file.
+    pub fn page(&self) -> Option<Vec<u8>> {
+        self.data_sector.as_ref().map(|sector| {
+            let mut page = Vec::with_capacity(LogEntry::SECTOR_SIZE);
+            page.extend_from_slice(&self.leading_bytes);
+            page.extend_from_slice(&sector.data);
+            page.extend_from_slice(&self.trailing_bytes);
+            page
+        })
+    }
 }
 
 impl<T> DeSerialise<T> for DataDesc {
@@ -596,27 +882,23 @@ impl std::fmt::Debug for DataSector {
     }
 }
 
-#[derive(Debug)]
+// A run of log entries with consecutive sequence numbers, from the tail entry to the head entry.
+#[derive(Debug, Default)]
 pub struct LogSequence {
+    // Sequence number of the head entry.
     pub sequence_number: u64,
     pub entries: Vec<LogEntry>,
+
+    // Offsets of the head and tail entries from the start of the log region.
     pub head_value: u64,
     pub tail_value: u64,
 }
 impl LogSequence {
-    pub(crate) fn is_empty(&self) -> bool {
+    pub fn is_empty(&self) -> bool {
         self.entries.is_empty()
     }
 
-    pub(crate) fn is_valid(&self) -> bool {
-        self.head()
-            .map(|v| {
-                self.tail_value <= v.header.tail as u64 && self.head_value >= v.header.tail as u64
-            })
-            .unwrap_or(false)
-    }
-
-    fn head(&self) -> Option<&LogEntry> {
+    pub fn head(&self) -> Option<&LogEntry> {
         self.entries.last()
     }
 }
@@ -627,6 +909,39 @@ mod tests {
     use std::io::Cursor;
 
     use super::*;
+    use crate::test_utils::{
+        dynamic_image, page_log_entry, set_log_guid, write_log_entry, BAT_OFFSET, BLOCK_SIZE,
+        LOG_GUID, LOG_OFFSET,
+    };
+    use pretty_assertions::assert_eq;
+
+    const LOG_LENGTH: usize = 64 * 1024;
+
+    // Writes the 8 KB page entries into a 64 KB log, each entry is (log offset, seq, tail).
+    fn log_with(entries: &[(usize, u64, u32)], log_guid: Uuid) -> Vec<u8> {
+        let mut log = vec![0; LOG_LENGTH];
+        for (offset, seq, tail) in entries {
+            let mut entry = page_log_entry(*seq, BAT_OFFSET, &[*seq as u8; 4096]);
+            entry.header.tail = *tail;
+            entry.header.log_guid = log_guid;
+            entry.update_checksum();
+
+            let mut bytes = Cursor::new(Vec::new());
+            entry.serialize(&mut bytes).unwrap();
+            for (i, byte) in bytes.into_inner().into_iter().enumerate() {
+                log[(offset + i) % LOG_LENGTH] = byte;
+            }
+        }
+        log
+    }
+
+    fn sequence_numbers(sequence: &LogSequence) -> Vec<u64> {
+        sequence
+            .entries
+            .iter()
+            .map(|e| e.header.seq_number)
+            .collect()
+    }
 
     #[test]
     fn should_deserialize_entry_header() {
@@ -647,4 +962,153 @@ mod tests {
 
         assert_eq!(Signature::Loge, entry_header.signature);
     }
+
+    #[test]
+    fn should_follow_sequence_wrapping_around_the_log() {
+        // The head entry starts in the last sector and has its data sector at the start of the
+        // log. An older sequence is left further in the log.
+        let log = log_with(
+            &[
+                (16 * 1024, 2, 16 * 1024),
+                (44 * 1024, 5, 44 * 1024),
+                (52 * 1024, 6, 44 * 1024),
+                (60 * 1024, 7, 44 * 1024),
+            ],
+            LOG_GUID,
+        );
+
+        let log = Log::new(&log, LOG_GUID).unwrap();
+
+        assert_eq!(vec![5, 6, 7], sequence_numbers(&log.log_sequence));
+        assert_eq!(60 * 1024, log.log_sequence.head_value);
+        assert_eq!(44 * 1024, log.log_sequence.tail_value);
+        let head = log.log_sequence.head().unwrap();
+        let Descriptor::Data(desc) = &head.descriptors()[0] else {
+            panic!("Expected a data descriptor");
+        };
+        assert_eq!(Some(vec![7; 4096]), desc.page());
+    }
+
+    #[test]
+    fn should_start_sequence_at_the_tail_of_the_head() {
+        let log = log_with(
+            &[(0, 3, 0), (8 * 1024, 4, 0), (16 * 1024, 5, 8 * 1024)],
+            LOG_GUID,
+        );
+
+        let log = Log::new(&log, LOG_GUID).unwrap();
+
+        assert_eq!(vec![4, 5], sequence_numbers(&log.log_sequence));
+        assert_eq!(3, log.log_entries.len());
+    }
+
+    #[test]
+    fn should_reject_sequence_missing_its_tail() {
+        // The entry the head points to has been overwritten.
+        let log = log_with(&[(8 * 1024, 6, 0), (16 * 1024, 7, 0)], LOG_GUID);
+
+        let log = Log::new(&log, LOG_GUID).unwrap();
+
+        assert!(log.log_sequence.is_empty());
+    }
+
+    #[test]
+    fn should_reject_entries_of_another_log_guid() {
+        let mut log = log_with(&[(0, 1, 0), (8 * 1024, 9, 8 * 1024)], Uuid::from_u128(99));
+        let current = log_with(&[(32 * 1024, 3, 32 * 1024)], LOG_GUID);
+        log[32 * 1024..40 * 1024].copy_from_slice(&current[32 * 1024..40 * 1024]);
+
+        let found = Log::new(&log, LOG_GUID).unwrap();
+        assert_eq!(vec![3], sequence_numbers(&found.log_sequence));
+        assert_eq!(3, found.log_entries.len());
+
+        let found = Log::new(&log, Uuid::nil()).unwrap();
+        assert!(found.log_sequence.is_empty());
+    }
+
+    #[test]
+    fn should_load_wrapped_log_of_a_file() {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[]);
+        set_log_guid(&mut image, LOG_GUID);
+
+        // The first entry starts in the last sector of the 1 MB log, its data sector wraps to the
+        // start of the log and the next entry follows it.
+        let tail = (Vhdx::MB - 4 * Vhdx::KB) as u32;
+        let mut first = page_log_entry(20, BAT_OFFSET, &[0; 4096]);
+        first.header.tail = tail;
+        first.update_checksum();
+        let mut bytes = Cursor::new(Vec::new());
+        first.serialize(&mut bytes).unwrap();
+        let (start, end) = bytes.get_ref().split_at(4096);
+        image.get_mut()[(LOG_OFFSET + tail as u64) as usize..][..4096].copy_from_slice(start);
+        image.get_mut()[LOG_OFFSET as usize..][..4096].copy_from_slice(end);
+
+        let mut second = page_log_entry(21, BAT_OFFSET, &[0; 4096]);
+        second.header.tail = tail;
+        second.update_checksum();
+        write_log_entry(&mut image, 4 * Vhdx::KB, &second);
+
+        let vhdx = Vhdx::from_reader(image).unwrap();
+
+        assert_eq!(vec![20, 21], sequence_numbers(&vhdx.log.log_sequence));
+        assert_eq!(4 * Vhdx::KB, vhdx.log.log_sequence.head_value);
+    }
+
+    fn read_back(entry: &LogEntry) -> Vec<u8> {
+        let mut bytes = Cursor::new(Vec::new());
+        entry.serialize(&mut bytes).unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn should_validate_checksum_over_the_whole_entry() {
+        let entry = page_log_entry(3, BAT_OFFSET, &[1; 4096]);
+        let mut bytes = read_back(&entry);
+        let parsed = LogEntry::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert!(parsed.validate().is_ok());
+
+        // Padding after the descriptors isn't parsed but still covered by the checksum.
+        bytes[1024] = 0xFF;
+        let parsed = LogEntry::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert!(matches!(
+            parsed.validate(),
+            Err(VhdxError::Crc32Error(_, _))
+        ));
+    }
+
+    #[test]
+    fn should_reject_data_sector_of_another_sequence() {
+        let mut entry = page_log_entry(3, BAT_OFFSET, &[1; 4096]);
+        let Descriptor::Data(desc) = &mut entry.descriptors[0] else {
+            panic!("Expected a data descriptor");
+        };
+        desc.data_sector.as_mut().unwrap().seq_low = 2;
+        entry.update_checksum();
+
+        assert!(matches!(
+            entry.validate(),
+            Err(VhdxError::LogSequenceNumberError("Data Sector", 3, 2))
+        ));
+    }
+
+    #[test]
+    fn should_reject_entry_length_not_fitting_descriptors() {
+        let mut entry = page_log_entry(3, BAT_OFFSET, &[1; 4096]);
+        entry.header.entry_length = 12 * 1024;
+        entry.update_checksum();
+
+        assert!(matches!(
+            entry.validate(),
+            Err(VhdxError::LogEntryLengthError(8192, 12288))
+        ));
+
+        let mut entry = page_log_entry(3, BAT_OFFSET, &[1; 4096]);
+        entry.header.last_file_offset += 4096;
+        entry.update_checksum();
+
+        assert!(matches!(
+            entry.validate(),
+            Err(VhdxError::NotDivisbleByMB("Last File Offset", _))
+        ));
+    }
 }
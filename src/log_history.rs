@@ -0,0 +1,417 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Cursor, Read, Seek};
+
+use uuid::Uuid;
+
+use crate::{
+    bat::{payload_block, BatEntry, BatEntryState},
+    error::VhdxError,
+    log::{Descriptor, Log, LogEntry},
+    meta_data::MetaData,
+    vhdx::Vhdx,
+    vhdx_header::KnowRegion,
+    DeSerialise, Validation,
+};
+
+// A log entry found anywhere in the log region, whether or not it belongs to the active sequence.
+#[derive(Debug)]
+pub struct LogRecord {
+    // Offset of the entry from the start of the log region.
+    pub log_offset: u64,
+
+    pub sequence_number: u64,
+
+    // Whether the entry carries the log guid of the current header. Entries with another guid are
+    // left over from an earlier use of the log.
+    pub matches_log_guid: bool,
+
+    // Whether the entry is part of the active sequence, the one that gets replayed.
+    pub active: bool,
+
+    // Whether the entry is whole: its checksum, its descriptors and the data sectors they use.
+    pub validation: Result<(), VhdxError>,
+
+    pub entry: LogEntry,
+}
+
+// The contents of a region as they were right after the log entry with the given sequence number
+// was written.
+#[derive(Debug)]
+pub struct RegionSnapshot {
+    pub region: KnowRegion,
+    pub sequence_number: u64,
+    pub data: Vec<u8>,
+
+    // Region relative offsets of the 4 KB pages taken from the log, mapped to the sequence number
+    // of the entry that wrote them.
+    pub pages_from_log: BTreeMap<u64, u64>,
+
+    // Region relative offsets of the pages only written by entries after the sequence number.
+    // What they held before is unknown, so they keep the current contents of the file.
+    pub newer_pages: BTreeSet<u64>,
+}
+
+impl RegionSnapshot {
+    pub fn bat_entries(&self) -> Result<Vec<BatEntry>, VhdxError> {
+        let mut reader = Cursor::new(&self.data);
+        (0..self.data.len() / 8)
+            .map(|_| BatEntry::deserialize(&mut reader))
+            .collect()
+    }
+
+    pub fn meta_data(&self) -> Result<MetaData, VhdxError> {
+        MetaData::deserialize(&mut Cursor::new(&self.data))
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    // Parses every log entry in the log region, including the ones superseded by later sequences,
+    // in the order they are stored in the log. Entries are found the way the log is scanned for
+    // replay, so an entry wrapping around the end of the log is found too.
+    pub fn log_history(&mut self) -> Result<Vec<LogRecord>, VhdxError> {
+        let header = self.header().clone();
+        let log = self.read_at(header.log_offset, header.log_length as u64)?;
+
+        let active: BTreeSet<u64> = self
+            .log
+            .log_sequence
+            .entries
+            .iter()
+            .map(|e| e.header.seq_number)
+            .collect();
+
+        let records = Log::scan(&log)
+            .into_iter()
+            .map(|(log_offset, entry)| {
+                let matches_log_guid =
+                    !header.log_guid.is_nil() && entry.header.log_guid == header.log_guid;
+                LogRecord {
+                    log_offset,
+                    sequence_number: entry.header.seq_number,
+                    matches_log_guid,
+                    active: matches_log_guid && active.contains(&entry.header.seq_number),
+                    validation: entry.validate(),
+                    entry,
+                }
+            })
+            .collect();
+        Ok(records)
+    }
+
+    // Materializes a region as it was at the given sequence number of the log guid by applying, in
+    // sequence order, every update the log holds for it up to and including that sequence number.
+    // Sequence numbers only order the entries of one log guid, this crate starts them over at 1
+    // with a new log guid every time it writes to the log.
+    pub fn region_at_sequence(
+        &mut self,
+        region: KnowRegion,
+        log_guid: Uuid,
+        sequence_number: u64,
+    ) -> Result<RegionSnapshot, VhdxError> {
+        let entry = self
+            .region(region)
+            .ok_or(VhdxError::MissingKnownRegion(match region {
+                KnowRegion::Bat => "Bat",
+                KnowRegion::MetaData => "MetaData",
+            }))?
+            .clone();
+        let mut data = self.read_at(entry.file_offset, entry.length as u64)?;
+
+        let page_size = LogEntry::SECTOR_SIZE as u64;
+        let region_start = entry.file_offset;
+        let region_end = region_start.saturating_add(entry.length as u64);
+        let mut pages_from_log = BTreeMap::new();
+        let mut newer_pages = BTreeSet::new();
+
+        for record in self.history_of(log_guid)? {
+            for descriptor in record.entry.descriptors() {
+                let page = match descriptor {
+                    Descriptor::Data(desc) => desc.page(),
+                    Descriptor::Zero(_) => None,
+                };
+
+                // Only the pages of the update inside the region.
+                let start = descriptor.file_offset();
+                let Some(end) = start.checked_add(descriptor.length()) else {
+                    continue;
+                };
+                if end <= region_start || start >= region_end {
+                    continue;
+                }
+                let first = start
+                    + region_start
+                        .saturating_sub(start)
+                        .next_multiple_of(page_size);
+                for file_offset in (first..end.min(region_end)).step_by(page_size as usize) {
+                    let offset = file_offset - region_start;
+                    let Some(target) = data.get_mut(offset as usize..(offset + page_size) as usize)
+                    else {
+                        continue;
+                    };
+
+                    if record.sequence_number > sequence_number {
+                        if !pages_from_log.contains_key(&offset) {
+                            newer_pages.insert(offset);
+                        }
+                        continue;
+                    }
+
+                    match &page {
+                        Some(page) => target.copy_from_slice(page),
+                        None => target.fill(0),
+                    }
+                    pages_from_log.insert(offset, record.sequence_number);
+                }
+            }
+        }
+
+        Ok(RegionSnapshot {
+            region,
+            sequence_number,
+            data,
+            pages_from_log,
+            newer_pages,
+        })
+    }
+
+    // The sequence number of the first log entry of the log guid recording each payload block as
+    // present, which tells when the block was allocated. Blocks allocated before the oldest entry
+    // still in the log are missing.
+    pub fn block_allocations(&mut self, log_guid: Uuid) -> Result<BTreeMap<u64, u64>, VhdxError> {
+        let bat = self
+            .region(KnowRegion::Bat)
+            .ok_or(VhdxError::MissingKnownRegion("Bat"))?
+            .clone();
+        let bat_range = bat.file_offset..bat.file_offset.saturating_add(bat.length as u64);
+        let chunk_ratio = self.meta_data.chunk_ratio;
+
+        let mut allocations = BTreeMap::new();
+        for record in self.history_of(log_guid)? {
+            for descriptor in record.entry.descriptors() {
+                let Descriptor::Data(desc) = descriptor else {
+                    continue;
+                };
+                let Some(page) = desc.page() else {
+                    continue;
+                };
+                if !bat_range.contains(&desc.file_offset()) {
+                    continue;
+                }
+
+                let first_index = (desc.file_offset() - bat.file_offset) / 8;
+                for (i, raw) in page.chunks_exact(8).enumerate() {
+                    let Some(block) = payload_block(first_index + i as u64, chunk_ratio) else {
+                        continue;
+                    };
+                    let entry = BatEntry::deserialize(&mut Cursor::new(raw))?;
+                    if matches!(
+                        entry.state(),
+                        BatEntryState::FullyPresent | BatEntryState::PartiallyPresent
+                    ) {
+                        allocations.entry(block).or_insert(record.sequence_number);
+                    }
+                }
+            }
+        }
+        Ok(allocations)
+    }
+
+    // The entries of the log guid that pass validation, in sequence order. Damaged entries are
+    // left out rather than applied.
+    fn history_of(&mut self, log_guid: Uuid) -> Result<Vec<LogRecord>, VhdxError> {
+        let mut records: Vec<LogRecord> = self
+            .log_history()?
+            .into_iter()
+            .filter(|r| r.entry.header.log_guid == log_guid && r.validation.is_ok())
+            .collect();
+        records.sort_by_key(|r| r.sequence_number);
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        dynamic_image, page_log_entry, set_log_guid, write_log_entry, BAT_OFFSET, BLOCK_SIZE,
+        LOG_GUID, LOG_OFFSET, PAYLOAD_OFFSET,
+    };
+    use crate::{
+        log::{LogHeader, ZeroDesc},
+        Serialise, Signature,
+    };
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    // An image with blocks 0 and 1 allocated, whose log recorded the BAT page with only block 0
+    // allocated at sequence 10 and with both blocks at sequence 11. Further in the log is an entry
+    // of an earlier log guid.
+    fn history_image() -> Cursor<Vec<u8>> {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0xAA), (1, 0xBB)]);
+        set_log_guid(&mut image, LOG_GUID);
+
+        let page = |blocks: u64| {
+            let mut page = vec![0; 4096];
+            for block in 0..blocks {
+                let entry = (6 | ((PAYLOAD_OFFSET / Vhdx::MB + block) << 20)).to_le_bytes();
+                page[block as usize * 8..block as usize * 8 + 8].copy_from_slice(&entry);
+            }
+            page
+        };
+
+        write_log_entry(&mut image, 0, &page_log_entry(10, BAT_OFFSET, &page(1)));
+        write_log_entry(&mut image, 8192, &page_log_entry(11, BAT_OFFSET, &page(2)));
+
+        let mut stale = page_log_entry(5, BAT_OFFSET, &page(0));
+        stale.header.log_guid = Uuid::from_u128(99);
+        stale.update_checksum();
+        write_log_entry(&mut image, 64 * 1024, &stale);
+        image
+    }
+
+    fn image_with_history() -> Vhdx<Cursor<Vec<u8>>> {
+        Vhdx::from_reader(history_image()).unwrap()
+    }
+
+    #[test]
+    fn should_list_every_log_entry() {
+        let mut vhdx = image_with_history();
+
+        let records: Vec<(u64, u64, bool, bool)> = vhdx
+            .log_history()
+            .unwrap()
+            .into_iter()
+            .map(|r| {
+                (
+                    r.log_offset,
+                    r.sequence_number,
+                    r.matches_log_guid,
+                    r.active,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            vec![
+                (0, 10, true, true),
+                (8192, 11, true, true),
+                (65536, 5, false, false)
+            ],
+            records
+        );
+    }
+
+    #[test]
+    fn should_list_wrapped_entries_and_report_damaged_ones() {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0xAA)]);
+        set_log_guid(&mut image, LOG_GUID);
+
+        // The descriptor sector is the last sector of the log, the data sector its first one.
+        let mut bytes = Cursor::new(Vec::new());
+        page_log_entry(3, BAT_OFFSET, &[0; 4096])
+            .serialize(&mut bytes)
+            .unwrap();
+        let bytes = bytes.into_inner();
+        let log = &mut image.get_mut()[LOG_OFFSET as usize..(LOG_OFFSET + Vhdx::MB) as usize];
+        log[Vhdx::MB as usize - 4096..].copy_from_slice(&bytes[..4096]);
+        log[..4096].copy_from_slice(&bytes[4096..]);
+
+        write_log_entry(
+            &mut image,
+            64 * 1024,
+            &page_log_entry(4, BAT_OFFSET, &[0; 4096]),
+        );
+        image.get_mut()[(LOG_OFFSET + 68 * 1024 + 8) as usize] = 0xFF;
+
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        let records: Vec<(u64, u64, bool)> = vhdx
+            .log_history()
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.log_offset, r.sequence_number, r.validation.is_ok()))
+            .collect();
+
+        assert_eq!(vec![(65536, 4, false), (Vhdx::MB - 4096, 3, true)], records);
+    }
+
+    #[test]
+    fn should_materialize_bat_at_sequence() {
+        let mut vhdx = image_with_history();
+
+        let snapshot = vhdx
+            .region_at_sequence(KnowRegion::Bat, LOG_GUID, 10)
+            .unwrap();
+        let entries = snapshot.bat_entries().unwrap();
+
+        assert_eq!(BatEntryState::FullyPresent, entries[0].state());
+        assert_eq!(BatEntryState::NotPresent, entries[1].state());
+        assert_eq!(Some(&10), snapshot.pages_from_log.get(&0));
+        assert!(snapshot.newer_pages.is_empty());
+
+        // The entry of sequence 5 belongs to another log guid.
+        let snapshot = vhdx
+            .region_at_sequence(KnowRegion::Bat, LOG_GUID, 7)
+            .unwrap();
+        assert!(snapshot.pages_from_log.is_empty());
+        assert!(snapshot.newer_pages.contains(&0));
+
+        let snapshot = vhdx
+            .region_at_sequence(KnowRegion::Bat, Uuid::from_u128(99), 5)
+            .unwrap();
+        assert_eq!(Some(&5), snapshot.pages_from_log.get(&0));
+        assert_eq!(
+            BatEntryState::NotPresent,
+            snapshot.bat_entries().unwrap()[0].state()
+        );
+    }
+
+    #[test]
+    fn should_clip_zero_descriptors_to_the_region() {
+        let mut image = history_image();
+
+        // Zeroing a terabyte from the start of the file, and the last pages of the address space.
+        let zero = |file_offset: u64, length: u64| {
+            let mut bytes = b"zero".to_vec();
+            bytes.extend([0; 4]);
+            bytes.extend(length.to_le_bytes());
+            bytes.extend(file_offset.to_le_bytes());
+            bytes.extend(12u64.to_le_bytes());
+            Descriptor::Zero(ZeroDesc::deserialize(&mut Cursor::new(bytes)).unwrap())
+        };
+        let header = LogHeader::new(
+            Signature::Loge,
+            0,
+            4096,
+            0,
+            12,
+            2,
+            LOG_GUID,
+            PAYLOAD_OFFSET,
+            PAYLOAD_OFFSET,
+        );
+        let mut entry = LogEntry::new(header, vec![zero(0, 1 << 40), zero(u64::MAX - 4095, 8192)]);
+        entry.update_checksum();
+        write_log_entry(&mut image, 128 * 1024, &entry);
+
+        let snapshot = Vhdx::from_reader(image)
+            .unwrap()
+            .region_at_sequence(KnowRegion::Bat, LOG_GUID, 12)
+            .unwrap();
+
+        assert_eq!(256, snapshot.pages_from_log.len());
+        assert_eq!(Some(&12), snapshot.pages_from_log.get(&0));
+        assert!(snapshot.data.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn should_tell_when_blocks_were_allocated() {
+        let mut vhdx = image_with_history();
+
+        let allocations = vhdx.block_allocations(LOG_GUID).unwrap();
+
+        assert_eq!(BTreeMap::from([(0, 10), (1, 11)]), allocations);
+    }
+}
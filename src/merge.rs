@@ -0,0 +1,339 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use uuid::Uuid;
+
+use crate::{
+    bat::{payload_bat_index, BatEntry, BatEntryState},
+    error::VhdxError,
+    image_store::{FileSystem, ImageStore},
+    meta_data::ParentLocator,
+    parent_resolver::ParentResolver,
+    vhdx::Vhdx,
+    SyncData,
+};
+
+// Blocks allocated in the parent are committed to its BAT in batches of this many.
+const COMMIT_BATCH: usize = 256;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MergeReport {
+    // Bytes of the virtual disk the child held on its own and that were copied to the parent.
+    pub merged_bytes: u64,
+
+    // Blocks of the parent updated where they are stored.
+    pub updated_blocks: u64,
+
+    // Blocks of the parent that had to be allocated, or moved to hold all of their data.
+    pub allocated_blocks: u64,
+}
+
+impl Vhdx {
+    // Merges the differencing disk at the path into its parent, the offline equivalent of
+    // deleting a Hyper-V checkpoint. The child is deleted afterwards when asked to, otherwise it
+    // stays linked to its parent and reads the same through it.
+    pub fn merge_into_parent(
+        path: &impl AsRef<Path>,
+        delete_child: bool,
+    ) -> Result<MergeReport, VhdxError> {
+        let report = Vhdx::merge_into_parent_in(&FileSystem, path, &ParentResolver::default())?;
+        if delete_child {
+            fs::remove_file(path)?;
+        }
+        Ok(report)
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Write + Seek + SyncData,
+{
+    // Merges the differencing disk of the store at the path into its parent, found in the same
+    // store.
+    pub fn merge_into_parent_in<S>(
+        store: &S,
+        path: &impl AsRef<Path>,
+        resolver: &ParentResolver,
+    ) -> Result<MergeReport, VhdxError>
+    where
+        S: ImageStore<Image = T>,
+    {
+        let path = path.as_ref();
+        let mut child = Vhdx::from_reader(store.open(path, true)?)?;
+        child.replay_log()?;
+
+        let locator = child
+            .meta_data
+            .parent_locator
+            .as_ref()
+            .ok_or(VhdxError::MissingMetaDataItem("Parent Locator"))?;
+        let parent_path = resolver.resolve_in(store, path, locator)?.path;
+        let mut parent = Vhdx::open_in(store, &parent_path, resolver)?;
+        child.merge_into(&mut parent)
+    }
+
+    // Copies everything this differencing disk holds on its own into its parent: fully present
+    // blocks, the present sectors of partially present blocks and zero blocks. A differencing
+    // parent needs its own parent attached to fill the blocks it allocates.
+    //
+    // The parent changes, so it gets a new data write guid before any of its data is touched.
+    // The child records that guid as its second linkage first, so it keeps linking to the parent
+    // whichever of the two a crash leaves it with. Data goes either where the parent already
+    // stores it or to space no BAT entry points to, the entries of new blocks being committed
+    // through the log once their data is synced. As long as the child overlays the parent the
+    // chain reads the same at any point, so a merge cut short by a crash can be run again.
+    pub fn merge_into<P>(&mut self, parent: &mut Vhdx<P>) -> Result<MergeReport, VhdxError>
+    where
+        P: Read + Write + Seek + SyncData,
+    {
+        let data_write_guid = parent.header().data_write_guid();
+        let locator = self
+            .meta_data
+            .parent_locator
+            .as_ref()
+            .ok_or(VhdxError::MissingMetaDataItem("Parent Locator"))?;
+        let linkage = locator
+            .parent_linkage()
+            .ok_or(VhdxError::MissingMetaDataItem("Parent Linkage"))?;
+        if !locator.links_to(data_write_guid) {
+            return Err(VhdxError::ParentLinkageError(linkage, data_write_guid));
+        }
+
+        let new_data_write_guid = Uuid::new_v4();
+        let mut locator = locator.clone();
+        locator.entries.insert(
+            ParentLocator::PARENT_LINKAGE2.to_string(),
+            format!("{{{new_data_write_guid}}}"),
+        );
+        self.write_parent_locator(&locator)?;
+        let header = parent
+            .header()
+            .successor(Uuid::nil())
+            .with_data_write_guid(new_data_write_guid);
+        parent.write_header(header)?;
+
+        // The ranges the child holds, grouped by the parent block they fall in, since the two
+        // disks may have different block sizes.
+        let parent_block_size = parent.block_size();
+        let parent_size = parent.virtual_disk_size();
+        let payload_blocks_count = self.meta_data.payload_blocks_count;
+        let mut disk = self.virtual_disk();
+        let mut ranges: BTreeMap<u64, Vec<(u64, u64)>> = BTreeMap::new();
+        for block in 0..payload_blocks_count {
+            for (mut offset, length) in disk.own_ranges(block)? {
+                let end = offset + length;
+                if end > parent_size {
+                    return Err(VhdxError::BlockOutOfRange(block));
+                }
+                while offset < end {
+                    let parent_block = offset / parent_block_size;
+                    let run = end.min((parent_block + 1) * parent_block_size) - offset;
+                    ranges.entry(parent_block).or_default().push((offset, run));
+                    offset += run;
+                }
+            }
+        }
+
+        let mut report = MergeReport::default();
+        let mut pending = Vec::new();
+        for (block, ranges) in ranges {
+            let block_start = block * parent_block_size;
+            let entry = parent
+                .payload_entry(block)
+                .ok_or(VhdxError::BlockOutOfRange(block))?
+                .clone();
+
+            if entry.state() == BatEntryState::FullyPresent {
+                for (offset, length) in ranges {
+                    let data = read_disk(&mut disk, offset, length)?;
+                    parent
+                        .file
+                        .seek(SeekFrom::Start(entry.file_offset() + offset - block_start))?;
+                    parent.file.write_all(&data)?;
+                    report.merged_bytes += length;
+                }
+                report.updated_blocks += 1;
+                continue;
+            }
+
+            // The whole block is written anew, with what the parent reads for the sectors the
+            // child leaves to it.
+            let length = parent_block_size.min(parent_size - block_start);
+            let mut data = read_disk(&mut parent.virtual_disk(), block_start, length)?;
+            for (offset, run) in ranges {
+                let start = (offset - block_start) as usize;
+                data[start..start + run as usize]
+                    .copy_from_slice(&read_disk(&mut disk, offset, run)?);
+                report.merged_bytes += run;
+            }
+            data.resize(parent_block_size as usize, 0);
+
+            let destination = parent
+                .file
+                .seek(SeekFrom::End(0))?
+                .next_multiple_of(Vhdx::MB);
+            parent.file.seek(SeekFrom::Start(destination))?;
+            parent.file.write_all(&data)?;
+            pending.push((
+                payload_bat_index(block, parent.meta_data.chunk_ratio),
+                BatEntry::new(
+                    BatEntryState::FullyPresent,
+                    (destination / Vhdx::MB) as usize,
+                ),
+            ));
+            report.allocated_blocks += 1;
+
+            if pending.len() == COMMIT_BATCH {
+                parent.file.sync_data()?;
+                parent.update_bat(&std::mem::take(&mut pending))?;
+            }
+        }
+        parent.file.sync_data()?;
+        if !pending.is_empty() {
+            parent.update_bat(&pending)?;
+        }
+        Ok(report)
+    }
+}
+
+fn read_disk<R>(disk: &mut R, offset: u64, length: u64) -> Result<Vec<u8>, VhdxError>
+where
+    R: Read + Seek,
+{
+    let mut data = vec![0; length as usize];
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.read_exact(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::image_store::MemoryStore;
+    use crate::test_utils::{
+        differencing_image, dynamic_image, parent_locator, TempDir, BAT_OFFSET, BLOCK_SIZE,
+        DATA_WRITE_GUID,
+    };
+    use pretty_assertions::assert_eq;
+
+    fn read_all<T: Read + Seek>(vhdx: &mut Vhdx<T>) -> Vec<u8> {
+        let mut data = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    // A child overwriting block 0, the first sectors of block 2 and zeroing block 1 of a parent
+    // holding blocks 1 and 2.
+    fn chain() -> (Cursor<Vec<u8>>, Cursor<Vec<u8>>) {
+        let parent = dynamic_image(4 * BLOCK_SIZE, &[(1, 0x11), (2, 0x22)]);
+        let mut child = differencing_image(
+            4 * BLOCK_SIZE,
+            parent_locator("parent.vhdx"),
+            &[(0, 0xAA, None), (2, 0xBB, Some(vec![0x0F]))],
+        );
+        child.seek(SeekFrom::Start(BAT_OFFSET + 8)).unwrap();
+        child
+            .write_all(&(BatEntryState::Zero as u64).to_le_bytes())
+            .unwrap();
+        (parent, child)
+    }
+
+    #[test]
+    fn should_merge_the_child_view_into_the_parent() {
+        let (parent, child) = chain();
+        let mut expected = Vhdx::from_reader(child.clone())
+            .unwrap()
+            .with_parent(Vhdx::from_reader(parent.clone()).unwrap())
+            .unwrap();
+        let expected = read_all(&mut expected);
+
+        let mut parent = Vhdx::from_reader(parent).unwrap();
+        let report = Vhdx::from_reader(child)
+            .unwrap()
+            .merge_into(&mut parent)
+            .unwrap();
+
+        assert_eq!(1, report.allocated_blocks);
+        assert_eq!(2, report.updated_blocks);
+        assert_eq!(2 * BLOCK_SIZE as u64 + 4 * 512, report.merged_bytes);
+        assert_ne!(DATA_WRITE_GUID, parent.header().data_write_guid());
+
+        let mut reopened = Vhdx::from_reader(parent.file).unwrap();
+        assert!(!reopened.replay_log().unwrap());
+        assert_eq!(expected, read_all(&mut reopened));
+    }
+
+    #[test]
+    fn should_keep_the_child_linked_so_a_merge_can_run_again() {
+        let (parent, child) = chain();
+        let mut expected = Vhdx::from_reader(child.clone())
+            .unwrap()
+            .with_parent(Vhdx::from_reader(parent.clone()).unwrap())
+            .unwrap();
+        let expected = read_all(&mut expected);
+
+        let mut parent = Vhdx::from_reader(parent).unwrap();
+        let mut child = Vhdx::from_reader(child).unwrap();
+        child.merge_into(&mut parent).unwrap();
+
+        let data_write_guid = parent.header().data_write_guid();
+        let locator = child.meta_data.parent_locator.clone().unwrap();
+        assert_eq!(Some(DATA_WRITE_GUID), locator.parent_linkage());
+        assert_eq!(Some(data_write_guid), locator.parent_linkage2());
+
+        child.merge_into(&mut parent).unwrap();
+        assert_eq!(expected, read_all(&mut parent));
+    }
+
+    #[test]
+    fn should_find_the_parent_in_the_store_of_the_child() {
+        let (parent, child) = chain();
+        let store = MemoryStore::new();
+        store.insert(&"/images/parent.vhdx", parent.into_inner());
+        store.insert(&"/images/child.vhdx", child.into_inner());
+        let resolver = ParentResolver::default();
+        let expected =
+            read_all(&mut Vhdx::open_in(&store, &"/images/child.vhdx", &resolver).unwrap());
+
+        Vhdx::merge_into_parent_in(&store, &"/images/child.vhdx", &resolver).unwrap();
+
+        let mut parent = Vhdx::open_in(&store, &"/images/parent.vhdx", &resolver).unwrap();
+        assert!(read_all(&mut parent) == expected);
+    }
+
+    #[test]
+    fn should_merge_a_chain_on_disk_and_delete_the_child() {
+        let directory = TempDir::new("merge");
+        let (parent, child) = chain();
+        std::fs::write(directory.join("parent.vhdx"), parent.into_inner()).unwrap();
+        std::fs::write(directory.join("child.vhdx"), child.into_inner()).unwrap();
+        let expected = read_all(&mut Vhdx::open_chain(&directory.join("child.vhdx")).unwrap());
+
+        Vhdx::merge_into_parent(&directory.join("child.vhdx"), true).unwrap();
+
+        let child_exists = directory.join("child.vhdx").exists();
+        let merged = read_all(&mut Vhdx::new(&directory.join("parent.vhdx")).unwrap());
+
+        assert!(!child_exists);
+        assert!(merged == expected);
+    }
+
+    #[test]
+    fn should_refuse_a_parent_the_child_isnt_linked_to() {
+        let (parent, child) = chain();
+        let mut parent = Vhdx::from_reader(parent).unwrap();
+        let header = parent
+            .header()
+            .successor(Uuid::nil())
+            .with_new_data_write_guid();
+        parent.write_header(header).unwrap();
+
+        let result = Vhdx::from_reader(child).unwrap().merge_into(&mut parent);
+
+        assert!(matches!(result, Err(VhdxError::ParentLinkageError(..))));
+    }
+}
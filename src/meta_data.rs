@@ -1,4 +1,7 @@
-use std::{collections::HashMap, io::SeekFrom};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Seek, SeekFrom, Write},
+};
 
 use super::Signature;
 use nom::{
@@ -18,7 +21,8 @@ use crate::{
         calc_total_bat_entries_differencing, calc_total_bat_entries_fixed_dynamic,
     },
     error::{VhdxError, VhdxParseError},
-    DeSerialise,
+    vhdx::Vhdx,
+    DeSerialise, Serialise,
 };
 
 use super::{
@@ -46,6 +50,7 @@ pub struct MetaData {
     pub sector_bitmaps_blocks_count: u64,
     pub total_bat_entries_fixed_dynamic: u64,
     pub total_bat_entries_differencing: u64,
+    pub parent_locator: Option<ParentLocator>,
     pub(crate) entries: HashMap<Uuid, Entry>,
 }
 
@@ -59,6 +64,13 @@ impl MetaData {
     pub const PHYSICAL_SECTOR_SIZE: Uuid = uuid!("CDA348C7445D44719CC9E9885251C556");
     pub const PARENT_LOCATOR: Uuid = uuid!("A8D35F2DB30B454DABF7D3D84834AB0C");
 
+    // EntryCount must be less than or equal to 2047.
+    const MAX_ENTRIES: u16 = 2047;
+
+    // The metadata table occupies the first 64 KB of the region, items are stored after it.
+    const ITEMS_OFFSET: usize = 64 * Vhdx::KB as usize;
+
+    #[allow(clippy::too_many_arguments)]
     fn new(
         signature: Signature,
         entry_count: u16,
@@ -73,6 +85,7 @@ impl MetaData {
         sector_bitmaps_blocks_count: u64,
         total_bat_entries_fixed_dynamic: u64,
         total_bat_entries_differencing: u64,
+        parent_locator: Option<ParentLocator>,
     ) -> Self {
         Self {
             signature,
@@ -88,8 +101,72 @@ impl MetaData {
             sector_bitmaps_blocks_count,
             total_bat_entries_fixed_dynamic,
             total_bat_entries_differencing,
+            parent_locator,
+        }
+    }
+
+    // Differencing disks have a sector bitmap entry for every chunk, the BAT of other disks ends
+    // right after the last payload block.
+    pub fn total_bat_entries(&self) -> u64 {
+        if self.file_parameters.has_parent {
+            self.total_bat_entries_differencing
+        } else {
+            self.total_bat_entries_fixed_dynamic
         }
     }
+
+    // Builds the metadata of a new virtual disk, laying the system metadata items out back to back
+    // at the start of the item area the same way Hyper-V does.
+    pub(crate) fn create(
+        file_parameters: FileParameters,
+        virtual_disk_size: usize,
+        virtual_disk_id: Uuid,
+        logical_sector_size: SectorSize,
+        physical_sector_size: SectorSize,
+        parent_locator: Option<ParentLocator>,
+    ) -> Self {
+        let mut items = vec![
+            (MetaData::FILE_PARAMETERS, 8, false),
+            (MetaData::VIRTUAL_DISK_SIZE, 8, true),
+            (MetaData::LOGICAL_SECTOR_SIZE, 4, true),
+            (MetaData::PHYSICAL_SECTOR_SIZE, 4, true),
+            (MetaData::VIRTUAL_DISK_ID, 16, true),
+        ];
+        if let Some(locator) = &parent_locator {
+            items.push((MetaData::PARENT_LOCATOR, locator.to_bytes().len(), false));
+        }
+
+        let mut entries = HashMap::new();
+        let mut offset = MetaData::ITEMS_OFFSET;
+        for (item_id, length, is_virtual_disk) in items {
+            let entry = Entry::new(item_id, offset, length, false, is_virtual_disk, true);
+            entries.insert(item_id, entry);
+            offset += length;
+        }
+
+        let chunk_ratio = calc_chunk_ratio(logical_sector_size, file_parameters.block_size);
+        let payload_blocks_count =
+            calc_payload_blocks_count(virtual_disk_size, file_parameters.block_size);
+        let sector_bitmaps_blocks_count =
+            calc_sector_bitmap_blocks_count(payload_blocks_count as usize, chunk_ratio as usize);
+
+        MetaData::new(
+            Signature::MetaData,
+            entries.len() as u16,
+            entries,
+            file_parameters,
+            virtual_disk_size,
+            virtual_disk_id,
+            logical_sector_size,
+            physical_sector_size,
+            chunk_ratio,
+            payload_blocks_count,
+            sector_bitmaps_blocks_count,
+            calc_total_bat_entries_fixed_dynamic(payload_blocks_count, chunk_ratio),
+            calc_total_bat_entries_differencing(sector_bitmaps_blocks_count, chunk_ratio),
+            parent_locator,
+        )
+    }
 }
 
 impl<T> DeSerialise<T> for MetaData {
@@ -106,66 +183,77 @@ impl<T> DeSerialise<T> for MetaData {
         let (_, (signature, entry_count)) = parse_header(&buffer).unwrap();
 
         let mut entries = HashMap::new();
-        for _ in 0..5 {
+        for _ in 0..entry_count.min(MetaData::MAX_ENTRIES) {
             let mut buffer = [0; 32];
             reader.read_exact(&mut buffer)?;
 
-            let (_, (signature, offset, length, a, b, c)) = parse_entry(&buffer).unwrap();
-
-            let start_next = reader.stream_position()?;
+            let (_, (signature, offset, length, a, b, c)) = parse_entry(&buffer)?;
 
             let entry = Entry::new(signature, offset, length, a, b, c);
             match signature {
-                MetaData::FILE_PARAMETERS => {
-                    entries.insert(MetaData::FILE_PARAMETERS, entry);
-                }
-                MetaData::VIRTUAL_DISK_SIZE => {
-                    entries.insert(MetaData::VIRTUAL_DISK_SIZE, entry);
+                MetaData::FILE_PARAMETERS
+                | MetaData::VIRTUAL_DISK_SIZE
+                | MetaData::VIRTUAL_DISK_ID
+                | MetaData::LOGICAL_SECTOR_SIZE
+                | MetaData::PHYSICAL_SECTOR_SIZE
+                | MetaData::PARENT_LOCATOR => {
+                    entries.insert(signature, entry);
                 }
-                MetaData::VIRTUAL_DISK_ID => {
-                    entries.insert(MetaData::VIRTUAL_DISK_ID, entry);
+                // Unknown items can be skipped unless the file says they are needed to read it.
+                _ if entry.is_required && !entry.is_user => {
+                    return Err(VhdxError::UnknownMetaDataItem(signature));
                 }
-                MetaData::LOGICAL_SECTOR_SIZE => {
-                    entries.insert(MetaData::LOGICAL_SECTOR_SIZE, entry);
-                }
-                MetaData::PHYSICAL_SECTOR_SIZE => {
-                    entries.insert(MetaData::PHYSICAL_SECTOR_SIZE, entry);
-                }
-                _ => panic!("Could not identify signature for read metadata entry"),
+                _ => {}
             }
-            reader.seek(SeekFrom::Start(start_next))?;
         }
 
-        let entry = entries[&MetaData::FILE_PARAMETERS];
+        let item = |item_id: Uuid, name: &'static str| {
+            entries
+                .get(&item_id)
+                .copied()
+                .ok_or(VhdxError::MissingMetaDataItem(name))
+        };
+
+        let entry = item(MetaData::FILE_PARAMETERS, "File Parameters")?;
         reader.seek(SeekFrom::Start(start_pos + entry.offset as u64))?;
         let mut buffer = [0; 8];
         reader.read_exact(&mut buffer)?;
         let (_, file_parameters) = parse_file_params(&buffer).unwrap();
 
-        let entry = entries[&MetaData::VIRTUAL_DISK_SIZE];
+        let entry = item(MetaData::VIRTUAL_DISK_SIZE, "Virtual Disk Size")?;
         reader.seek(SeekFrom::Start(start_pos + entry.offset as u64))?;
         let mut buffer = [0; 8];
         reader.read_exact(&mut buffer)?;
         let (_, virtual_disk_size) = t_v_disk_size(&buffer).unwrap();
 
-        let entry = entries[&MetaData::VIRTUAL_DISK_ID];
+        let entry = item(MetaData::VIRTUAL_DISK_ID, "Virtual Disk Id")?;
         reader.seek(SeekFrom::Start(start_pos + entry.offset as u64))?;
         let mut buffer = [0; 16];
         reader.read_exact(&mut buffer)?;
         let (_, virtual_disk_id) = t_guid(&buffer).unwrap();
 
-        let entry = entries[&MetaData::LOGICAL_SECTOR_SIZE];
+        let entry = item(MetaData::LOGICAL_SECTOR_SIZE, "Logical Sector Size")?;
         reader.seek(SeekFrom::Start(start_pos + entry.offset as u64))?;
         let mut buffer = [0; 4];
         reader.read_exact(&mut buffer)?;
         let (_, logical_sector_size) = t_sector_size(&buffer).unwrap();
 
-        let entry = entries[&MetaData::PHYSICAL_SECTOR_SIZE];
+        let entry = item(MetaData::PHYSICAL_SECTOR_SIZE, "Physical Sector Size")?;
         reader.seek(SeekFrom::Start(start_pos + entry.offset as u64))?;
         let mut buffer = [0; 4];
         reader.read_exact(&mut buffer)?;
         let (_, physical_sector_size) = t_sector_size(&buffer).unwrap();
 
+        let parent_locator = match entries.get(&MetaData::PARENT_LOCATOR) {
+            Some(entry) => {
+                reader.seek(SeekFrom::Start(start_pos + entry.offset as u64))?;
+                let mut buffer = vec![0; entry.length];
+                reader.read_exact(&mut buffer)?;
+                Some(ParentLocator::from_bytes(&buffer)?)
+            }
+            None => None,
+        };
+
         let chunk_ratio = calc_chunk_ratio(logical_sector_size, file_parameters.block_size);
 
         let payload_blocks_count =
@@ -193,10 +281,59 @@ impl<T> DeSerialise<T> for MetaData {
             sector_bitmaps_blocks_count,
             total_bat_entries_fixed_dynamic,
             total_bat_entries_differencing,
+            parent_locator,
         ))
     }
 }
 
+impl<T> Serialise<T> for MetaData {
+    fn serialize(&self, writer: &mut T) -> Result<(), VhdxError>
+    where
+        T: Write + Seek,
+    {
+        let start_pos = writer.stream_position()?;
+
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.offset);
+
+        let mut buffer = Vec::with_capacity(MetaData::ITEMS_OFFSET);
+        buffer.extend_from_slice(MetaData::SIGN);
+        buffer.extend_from_slice(&[0; 2]);
+        buffer.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        buffer.extend_from_slice(&[0; 20]);
+        entries
+            .iter()
+            .for_each(|entry| buffer.extend_from_slice(&entry.to_bytes()));
+        buffer.resize(MetaData::ITEMS_OFFSET, 0);
+        writer.write_all(&buffer)?;
+
+        for entry in entries {
+            let item = match entry.item_id {
+                MetaData::FILE_PARAMETERS => self.file_parameters.to_bytes(),
+                MetaData::VIRTUAL_DISK_SIZE => {
+                    (self.virtual_disk_size as u64).to_le_bytes().to_vec()
+                }
+                MetaData::VIRTUAL_DISK_ID => self.virtual_disk_id.to_bytes_le().to_vec(),
+                MetaData::LOGICAL_SECTOR_SIZE => {
+                    (self.logical_sector_size as u32).to_le_bytes().to_vec()
+                }
+                MetaData::PHYSICAL_SECTOR_SIZE => {
+                    (self.physical_sector_size as u32).to_le_bytes().to_vec()
+                }
+                MetaData::PARENT_LOCATOR => match &self.parent_locator {
+                    Some(locator) => locator.to_bytes(),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            writer.seek(SeekFrom::Start(start_pos + entry.offset as u64))?;
+            writer.write_all(&item)?;
+        }
+
+        Ok(())
+    }
+}
+
 fn t_sector_size(buffer: &[u8]) -> IResult<&[u8], SectorSize> {
     map(le_u32, |v: u32| match v.try_into() {
         Ok(SectorSize::Sector512) => SectorSize::Sector512,
@@ -244,11 +381,24 @@ impl Entry {
             is_required,
         }
     }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let flags = self.is_user as u32
+            | (self.is_virtual_disk as u32) << 1
+            | (self.is_required as u32) << 2;
+        let mut buffer = Vec::with_capacity(32);
+        buffer.extend_from_slice(&self.item_id.to_bytes_le());
+        buffer.extend_from_slice(&(self.offset as u32).to_le_bytes());
+        buffer.extend_from_slice(&(self.length as u32).to_le_bytes());
+        buffer.extend_from_slice(&flags.to_le_bytes());
+        buffer.extend_from_slice(&[0; 4]);
+        buffer
+    }
 }
 
-fn parse_entry(
-    buffer: &[u8],
-) -> IResult<&[u8], (Uuid, usize, usize, bool, bool, bool), VhdxParseError<&[u8]>> {
+type RawEntry = (Uuid, usize, usize, bool, bool, bool);
+
+fn parse_entry(buffer: &[u8]) -> IResult<&[u8], RawEntry, VhdxParseError<&[u8]>> {
     map(
         tuple((t_guid, le_u32, le_u32, bits(t_3_flags_u32), take(7usize))),
         |(guid, offset, length, (is_user, is_virtual_disk, is_required), _)| {
@@ -275,7 +425,7 @@ fn parse_file_params(buffer: &[u8]) -> IResult<&[u8], FileParameters, VhdxParseE
     )(buffer)
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SectorSize {
     Sector512 = 512,
     Sector4096 = 4096,
@@ -287,12 +437,125 @@ impl TryFrom<u32> for SectorSize {
     fn try_from(v: u32) -> Result<Self, Self::Error> {
         match v {
             x if x == SectorSize::Sector512 as u32 => Ok(SectorSize::Sector512),
-            x if x == SectorSize::Sector4096 as u32 => Ok(SectorSize::Sector512),
+            x if x == SectorSize::Sector4096 as u32 => Ok(SectorSize::Sector4096),
             _ => Err(()),
         }
     }
 }
 
+// The parent locator of a differencing disk. It is a set of key value pairs telling where the
+// parent can be found and which version of it the disk was created against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParentLocator {
+    // LocatorType (16 bytes): Identifies the kind of locator, the only one defined for VHDX
+    // parents is B04AEFB7-D19E-4A81-B789-25B8E9445913.
+    pub locator_type: Uuid,
+
+    // Keys and values are stored as UTF-16 strings, neither of them can be empty.
+    pub entries: BTreeMap<String, String>,
+}
+
+impl ParentLocator {
+    pub const VHDX_LOCATOR: Uuid = uuid!("B04AEFB7D19E4A81B78925B8E9445913");
+
+    pub const PARENT_LINKAGE: &'static str = "parent_linkage";
+    pub const PARENT_LINKAGE2: &'static str = "parent_linkage2";
+    pub const RELATIVE_PATH: &'static str = "relative_path";
+    pub const VOLUME_PATH: &'static str = "volume_path";
+    pub const ABSOLUTE_WIN32_PATH: &'static str = "absolute_win32_path";
+
+    pub fn new(entries: BTreeMap<String, String>) -> Self {
+        Self {
+            locator_type: ParentLocator::VHDX_LOCATOR,
+            entries,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    // The DataWriteGuid the parent had when the differencing disk was created, stored as a GUID
+    // string in braces.
+    pub fn parent_linkage(&self) -> Option<Uuid> {
+        self.get(ParentLocator::PARENT_LINKAGE)
+            .and_then(|linkage| Uuid::parse_str(linkage.trim_matches(['{', '}'])).ok())
+    }
+
+    // A second DataWriteGuid the parent may be matched with, written by tools that change the
+    // identity of a parent without changing what it reads.
+    pub fn parent_linkage2(&self) -> Option<Uuid> {
+        self.get(ParentLocator::PARENT_LINKAGE2)
+            .and_then(|linkage| Uuid::parse_str(linkage.trim_matches(['{', '}'])).ok())
+    }
+
+    // Whether a parent with the DataWriteGuid is the one this locator links to.
+    pub fn links_to(&self, data_write_guid: Uuid) -> bool {
+        self.parent_linkage() == Some(data_write_guid)
+            || self.parent_linkage2() == Some(data_write_guid)
+    }
+
+    fn from_bytes(buffer: &[u8]) -> Result<Self, VhdxError> {
+        let (mut rest, (locator_type, _, key_value_count)) =
+            tuple((t_guid, le_u16, le_u16))(buffer)?;
+
+        let string = |offset: u32, length: u16| -> Result<String, VhdxError> {
+            let bytes = buffer
+                .get(offset as usize..offset as usize + length as usize)
+                .ok_or(VhdxError::ParseError(
+                    "Parent locator entry out of bounds".to_string(),
+                ))?;
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .collect();
+            String::from_utf16(&units).map_err(|e| VhdxError::ParseError(e.to_string()))
+        };
+
+        let mut entries = BTreeMap::new();
+        for _ in 0..key_value_count {
+            let (next, (key_offset, value_offset, key_length, value_length)) =
+                tuple((le_u32, le_u32, le_u16, le_u16))(rest)?;
+            rest = next;
+            entries.insert(
+                string(key_offset, key_length)?,
+                string(value_offset, value_length)?,
+            );
+        }
+
+        Ok(Self {
+            locator_type,
+            entries,
+        })
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let utf16 = |s: &str| -> Vec<u8> { s.encode_utf16().flat_map(u16::to_le_bytes).collect() };
+
+        let mut table = Vec::new();
+        table.extend_from_slice(&self.locator_type.to_bytes_le());
+        table.extend_from_slice(&[0; 2]);
+        table.extend_from_slice(&(self.entries.len() as u16).to_le_bytes());
+
+        let mut strings = Vec::new();
+        let strings_offset = 20 + 12 * self.entries.len();
+        for (key, value) in &self.entries {
+            let (key, value) = (utf16(key), utf16(value));
+            let key_offset = strings_offset + strings.len();
+            strings.extend_from_slice(&key);
+            let value_offset = strings_offset + strings.len();
+            strings.extend_from_slice(&value);
+
+            table.extend_from_slice(&(key_offset as u32).to_le_bytes());
+            table.extend_from_slice(&(value_offset as u32).to_le_bytes());
+            table.extend_from_slice(&(key.len() as u16).to_le_bytes());
+            table.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        }
+        table.extend_from_slice(&strings);
+        table
+    }
+}
+
 #[derive(Debug)]
 pub enum LocatorTypeEntry {
     Guid(Uuid),
@@ -305,3 +568,13 @@ pub struct FileParameters {
     pub leave_block_allocated: bool,
     pub has_parent: bool,
 }
+
+impl FileParameters {
+    fn to_bytes(&self) -> Vec<u8> {
+        let flags = self.leave_block_allocated as u32 | (self.has_parent as u32) << 1;
+        let mut buffer = Vec::with_capacity(8);
+        buffer.extend_from_slice(&(self.block_size as u32).to_le_bytes());
+        buffer.extend_from_slice(&flags.to_le_bytes());
+        buffer
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use crate::{
+    error::VhdxError,
+    image_store::{FileSystem, ImageStore},
+    meta_data::ParentLocator,
+};
+
+// Finds the parent of a differencing disk from the Windows paths of its locator on a host where
+// they don't mean anything as they are. Drive letters and volumes are mapped to the directories
+// they are mounted at, and file names can be matched regardless of case since NTFS doesn't care
+// about it either.
+#[derive(Debug, Clone)]
+pub struct ParentResolver {
+    // Directory every drive letter is mounted at, keyed by the upper case letter.
+    pub drives: BTreeMap<char, PathBuf>,
+
+    // Directory every volume is mounted at, keyed by the GUID of \\?\Volume{GUID}\ paths.
+    pub volumes: BTreeMap<Uuid, PathBuf>,
+
+    // Directories searched for the file name of the parent when none of the locator paths lead
+    // to it, in order.
+    pub search_directories: Vec<PathBuf>,
+
+    pub case_insensitive: bool,
+}
+
+impl Default for ParentResolver {
+    fn default() -> Self {
+        Self {
+            drives: BTreeMap::new(),
+            volumes: BTreeMap::new(),
+            search_directories: Vec::new(),
+            case_insensitive: true,
+        }
+    }
+}
+
+// What led to the parent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolvedBy {
+    RelativePath,
+    VolumePath,
+    AbsoluteWin32Path,
+    SearchDirectory(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedParent {
+    pub path: PathBuf,
+    pub resolved_by: ResolvedBy,
+}
+
+impl ParentResolver {
+    // Tries the locator paths in the order Hyper-V does, relative path, volume path and then
+    // absolute path, and falls back to the search directories.
+    pub fn resolve(
+        &self,
+        child_path: &Path,
+        locator: &ParentLocator,
+    ) -> Result<ResolvedParent, VhdxError> {
+        self.resolve_in(&FileSystem, child_path, locator)
+    }
+
+    // The same, only looking at what the store holds.
+    pub fn resolve_in<S>(
+        &self,
+        store: &S,
+        child_path: &Path,
+        locator: &ParentLocator,
+    ) -> Result<ResolvedParent, VhdxError>
+    where
+        S: ImageStore,
+    {
+        let directory = child_path.parent().unwrap_or(Path::new(""));
+
+        // Stops at the first path leading to the parent, what the later ones lead to, or fail
+        // to, doesn't matter then.
+        for (key, resolved_by) in [
+            (ParentLocator::RELATIVE_PATH, ResolvedBy::RelativePath),
+            (ParentLocator::VOLUME_PATH, ResolvedBy::VolumePath),
+            (
+                ParentLocator::ABSOLUTE_WIN32_PATH,
+                ResolvedBy::AbsoluteWin32Path,
+            ),
+        ] {
+            let Some(value) = locator.get(key) else {
+                continue;
+            };
+            let path = match resolved_by {
+                ResolvedBy::RelativePath => self.find(store, directory, &components(value))?,
+                ResolvedBy::VolumePath => self.find_volume_path(store, value)?,
+                _ => self.find_absolute_path(store, value)?,
+            };
+            if let Some(path) = path {
+                return Ok(ResolvedParent { path, resolved_by });
+            }
+        }
+
+        let file_name = [
+            ParentLocator::RELATIVE_PATH,
+            ParentLocator::VOLUME_PATH,
+            ParentLocator::ABSOLUTE_WIN32_PATH,
+        ]
+        .into_iter()
+        .find_map(|key| components(locator.get(key)?).last().copied());
+        if let Some(file_name) = file_name {
+            for directory in &self.search_directories {
+                if let Some(path) = self.find(store, directory, &[file_name])? {
+                    return Ok(ResolvedParent {
+                        path,
+                        resolved_by: ResolvedBy::SearchDirectory(directory.clone()),
+                    });
+                }
+            }
+        }
+
+        Err(VhdxError::ParentNotFound(
+            locator
+                .get(ParentLocator::RELATIVE_PATH)
+                .or(locator.get(ParentLocator::ABSOLUTE_WIN32_PATH))
+                .or(locator.get(ParentLocator::VOLUME_PATH))
+                .unwrap_or_default()
+                .to_string(),
+        ))
+    }
+
+    // \\?\Volume{GUID}\path, through the mount point of the volume.
+    fn find_volume_path(
+        &self,
+        store: &impl ImageStore,
+        volume_path: &str,
+    ) -> Result<Option<PathBuf>, VhdxError> {
+        let mount_point = volume_path
+            .strip_prefix(r"\\?\Volume{")
+            .and_then(|rest| rest.split_once('}'))
+            .and_then(|(guid, rest)| Some((self.volumes.get(&Uuid::parse_str(guid).ok()?)?, rest)));
+        match mount_point {
+            Some((mount_point, rest)) => self.find(store, mount_point, &components(rest)),
+            None => Ok(None),
+        }
+    }
+
+    // C:\path through the mount point of the drive. Paths that aren't Windows paths, such as the
+    // ones written on this host, are taken as they are.
+    fn find_absolute_path(
+        &self,
+        store: &impl ImageStore,
+        absolute_path: &str,
+    ) -> Result<Option<PathBuf>, VhdxError> {
+        let path = absolute_path.strip_prefix(r"\\?\").unwrap_or(absolute_path);
+        let mut chars = path.chars();
+        match (chars.next(), chars.next()) {
+            (Some(letter), Some(':')) if letter.is_ascii_alphabetic() => {
+                match self.drives.get(&letter.to_ascii_uppercase()) {
+                    Some(mount_point) => self.find(store, mount_point, &components(&path[2..])),
+                    None => Ok(None),
+                }
+            }
+            _ if path.starts_with('/') => self.find(store, Path::new("/"), &components(path)),
+            _ => Ok(None),
+        }
+    }
+
+    // Follows the components from the base directory, matching every one of them regardless of
+    // case when there is no exact match. A component matching several names is an error rather
+    // than a guess at which of them is the parent.
+    fn find(
+        &self,
+        store: &impl ImageStore,
+        base: &Path,
+        components: &[&str],
+    ) -> Result<Option<PathBuf>, VhdxError> {
+        let mut path = base.to_path_buf();
+        for component in components {
+            if *component == ".." {
+                path.push("..");
+                continue;
+            }
+            let exact = path.join(component);
+            if store.is_file(&exact) || store.is_dir(&exact) || !self.case_insensitive {
+                path = exact;
+                continue;
+            }
+            let lower = component.to_lowercase();
+            let Ok(names) = store.read_dir(&path) else {
+                return Ok(None);
+            };
+            let mut matching = names
+                .into_iter()
+                .filter(|name| name.to_lowercase() == lower);
+            let Some(name) = matching.next() else {
+                return Ok(None);
+            };
+            if matching.next().is_some() {
+                return Err(VhdxError::AmbiguousParent(
+                    path.join(component).to_string_lossy().into_owned(),
+                ));
+            }
+            path.push(name);
+        }
+        Ok(store.is_file(&path).then_some(path))
+    }
+}
+
+// The components of a path with either separator, without the ones that don't lead anywhere.
+fn components(path: &str) -> Vec<&str> {
+    path.split(['\\', '/'])
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_utils::TempDir;
+    use pretty_assertions::assert_eq;
+
+    fn locator(entries: &[(&str, &str)]) -> ParentLocator {
+        ParentLocator::new(
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    // A copy of the files of a Hyper-V host: the child in one directory and the parent, with
+    // its name in another case, in a directory standing in for a drive or a volume.
+    fn host(name: &str) -> TempDir {
+        let directory = TempDir::new(&format!("resolver-{}", name));
+        fs::create_dir_all(directory.join("children")).unwrap();
+        fs::create_dir_all(directory.join("mnt/Hyper-V/Base")).unwrap();
+        fs::write(directory.join("mnt/Hyper-V/Base/Parent.VHDX"), b"").unwrap();
+        directory
+    }
+
+    #[test]
+    fn should_map_drive_letters_and_ignore_case() {
+        let directory = host("drive");
+        let resolver = ParentResolver {
+            drives: BTreeMap::from([('D', directory.join("mnt"))]),
+            ..ParentResolver::default()
+        };
+
+        let resolved = resolver.resolve(
+            &directory.join("children/child.vhdx"),
+            &locator(&[
+                (ParentLocator::RELATIVE_PATH, r"..\Base\parent.vhdx"),
+                (
+                    ParentLocator::ABSOLUTE_WIN32_PATH,
+                    r"d:\hyper-v\base\parent.vhdx",
+                ),
+            ]),
+        );
+
+        assert_eq!(
+            ResolvedParent {
+                path: directory.join("mnt/Hyper-V/Base/Parent.VHDX"),
+                resolved_by: ResolvedBy::AbsoluteWin32Path,
+            },
+            resolved.unwrap()
+        );
+    }
+
+    #[test]
+    fn should_map_volumes_before_absolute_paths() {
+        let directory = host("volume");
+        let guid = Uuid::from_u128(0x1234);
+        let resolver = ParentResolver {
+            volumes: BTreeMap::from([(guid, directory.join("mnt"))]),
+            ..ParentResolver::default()
+        };
+        let volume_path = format!(r"\\?\Volume{{{}}}\Hyper-V\Base\Parent.vhdx", guid);
+
+        let resolved = resolver.resolve(
+            &directory.join("children/child.vhdx"),
+            &locator(&[
+                (ParentLocator::VOLUME_PATH, &volume_path),
+                (
+                    ParentLocator::ABSOLUTE_WIN32_PATH,
+                    r"C:\Hyper-V\Base\Parent.vhdx",
+                ),
+            ]),
+        );
+
+        assert_eq!(ResolvedBy::VolumePath, resolved.unwrap().resolved_by);
+    }
+
+    #[test]
+    fn should_fall_back_to_search_directories() {
+        let directory = host("search");
+        let resolver = ParentResolver {
+            search_directories: vec![
+                directory.join("children"),
+                directory.join("mnt/Hyper-V/Base"),
+            ],
+            ..ParentResolver::default()
+        };
+
+        let resolved = resolver.resolve(
+            &directory.join("children/child.vhdx"),
+            &locator(&[(ParentLocator::ABSOLUTE_WIN32_PATH, r"E:\Gone\parent.vhdx")]),
+        );
+        let strict = ParentResolver {
+            case_insensitive: false,
+            ..resolver.clone()
+        }
+        .resolve(
+            &directory.join("children/child.vhdx"),
+            &locator(&[(ParentLocator::ABSOLUTE_WIN32_PATH, r"E:\Gone\parent.vhdx")]),
+        );
+
+        assert_eq!(
+            ResolvedBy::SearchDirectory(directory.join("mnt/Hyper-V/Base")),
+            resolved.unwrap().resolved_by
+        );
+        assert!(matches!(strict, Err(VhdxError::ParentNotFound(_))));
+    }
+
+    #[test]
+    fn should_refuse_to_pick_between_names_differing_in_case() {
+        let directory = host("ambiguous");
+        fs::write(directory.join("mnt/Hyper-V/Base/PARENT.vhdx"), b"").unwrap();
+        let resolver = ParentResolver {
+            drives: BTreeMap::from([('D', directory.join("mnt"))]),
+            ..ParentResolver::default()
+        };
+
+        let resolved = resolver.resolve(
+            &directory.join("children/child.vhdx"),
+            &locator(&[(
+                ParentLocator::ABSOLUTE_WIN32_PATH,
+                r"D:\Hyper-V\Base\parent.vhdx",
+            )]),
+        );
+
+        assert!(matches!(resolved, Err(VhdxError::AmbiguousParent(_))));
+    }
+
+    #[test]
+    fn should_stop_at_the_first_path_leading_to_the_parent() {
+        let directory = host("first");
+        fs::write(directory.join("mnt/Hyper-V/Base/PARENT.vhdx"), b"").unwrap();
+        let resolver = ParentResolver {
+            drives: BTreeMap::from([('D', directory.join("mnt"))]),
+            ..ParentResolver::default()
+        };
+
+        let resolved = resolver.resolve(
+            &directory.join("children/child.vhdx"),
+            &locator(&[
+                (
+                    ParentLocator::RELATIVE_PATH,
+                    r"..\mnt\Hyper-V\Base\Parent.VHDX",
+                ),
+                (
+                    ParentLocator::ABSOLUTE_WIN32_PATH,
+                    r"D:\Hyper-V\Base\parent.vhdx",
+                ),
+            ]),
+        );
+
+        assert_eq!(
+            ResolvedParent {
+                path: directory.join("children/../mnt/Hyper-V/Base/Parent.VHDX"),
+                resolved_by: ResolvedBy::RelativePath,
+            },
+            resolved.unwrap()
+        );
+    }
+}
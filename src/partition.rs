@@ -0,0 +1,657 @@
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use nom::{
+    bytes::complete::take,
+    number::complete::{le_u32, le_u64},
+    sequence::tuple,
+    Finish,
+};
+use uuid::{uuid, Uuid};
+
+use crate::{
+    error::{VhdxError, VhdxParseError},
+    parse_utils::t_guid,
+    vhdx::Vhdx,
+    virtual_disk::VirtualDisk,
+};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES_OFFSET: usize = 446;
+
+// Partition types of the MBR with a special meaning: the protective partition of a GPT disk and
+// the extended partitions holding a chain of extended boot records.
+const PROTECTIVE: u8 = 0xEE;
+const EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+// Logical partitions followed before the chain of extended boot records is taken for a loop.
+const MAX_LOGICAL_PARTITIONS: usize = 256;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_HEADER_LENGTH: u32 = 92;
+const GPT_ENTRY_LENGTH: u32 = 128;
+
+// Partition entry arrays larger than this are taken for corruption, Windows and Linux create
+// 16 KB ones.
+const MAX_GPT_ENTRIES_LENGTH: u64 = 16 * Vhdx::MB;
+
+// GPT checksums are the CRC32 of zlib, not the CRC32C of VHDX.
+const GPT_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Gpt(Uuid),
+    Mbr(u8),
+}
+
+impl PartitionType {
+    pub const EFI_SYSTEM: Uuid = uuid!("C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+    pub const MICROSOFT_RESERVED: Uuid = uuid!("E3C9E316-0B5C-4DB8-817D-F92DF00215AE");
+    pub const MICROSOFT_BASIC_DATA: Uuid = uuid!("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7");
+    pub const WINDOWS_RECOVERY: Uuid = uuid!("DE94BBA4-06D1-4D40-A16A-BFD50179D6AC");
+    pub const LINUX_FILESYSTEM: Uuid = uuid!("0FC63DAF-8483-4772-8E79-3D69D8477DE4");
+    pub const LINUX_SWAP: Uuid = uuid!("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F");
+    pub const LINUX_LVM: Uuid = uuid!("E6D6D379-F507-44C2-A23C-238F2A3DF928");
+
+    // What the well known types hold.
+    pub fn description(&self) -> Option<&'static str> {
+        Some(match *self {
+            PartitionType::Gpt(Self::EFI_SYSTEM) => "EFI system partition",
+            PartitionType::Gpt(Self::MICROSOFT_RESERVED) => "Microsoft reserved partition",
+            PartitionType::Gpt(Self::MICROSOFT_BASIC_DATA) => "Microsoft basic data",
+            PartitionType::Gpt(Self::WINDOWS_RECOVERY) => "Windows recovery environment",
+            PartitionType::Gpt(Self::LINUX_FILESYSTEM) => "Linux filesystem",
+            PartitionType::Gpt(Self::LINUX_SWAP) => "Linux swap",
+            PartitionType::Gpt(Self::LINUX_LVM) => "Linux LVM",
+            PartitionType::Mbr(0x01) => "FAT12",
+            PartitionType::Mbr(0x04 | 0x06 | 0x0E) => "FAT16",
+            PartitionType::Mbr(0x05 | 0x0F | 0x85) => "Extended partition",
+            PartitionType::Mbr(0x07) => "NTFS or exFAT",
+            PartitionType::Mbr(0x0B | 0x0C) => "FAT32",
+            PartitionType::Mbr(0x27) => "Windows recovery environment",
+            PartitionType::Mbr(0x82) => "Linux swap",
+            PartitionType::Mbr(0x83) => "Linux filesystem",
+            PartitionType::Mbr(0x8E) => "Linux LVM",
+            PartitionType::Mbr(0xEF) => "EFI system partition",
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    // The index of the GPT entry counted from 1. On MBR disks primary partitions are numbered 1
+    // to 4 by their entry and logical partitions from 5 on, the way Linux does.
+    pub number: u32,
+    pub partition_type: PartitionType,
+
+    // The GUID of a GPT partition.
+    pub unique_id: Option<Uuid>,
+
+    // The name of a GPT partition, MBR partitions have none.
+    pub name: String,
+
+    // Where the partition lies in the disk, in bytes.
+    pub start: u64,
+    pub length: u64,
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    // The partitions of the guest disk, an empty list when it isn't partitioned.
+    pub fn partitions(&mut self) -> Result<Vec<Partition>, VhdxError> {
+        let sector_size = self.meta_data.logical_sector_size as u64;
+        read_partitions(&mut self.virtual_disk(), sector_size)
+    }
+
+    // Reads a partition of the guest disk as a disk of its own.
+    pub fn open_partition(&mut self, partition: &Partition) -> PartitionReader<VirtualDisk<'_, T>> {
+        PartitionReader::new(self.virtual_disk(), partition)
+    }
+}
+
+// Reads the partition table of a disk with the given logical sector size. A protective MBR
+// leads to the GPT, whose backup at the end of the disk is used when the primary one doesn't
+// check out. Any other MBR is read as a classic partition table.
+pub fn read_partitions<R>(disk: &mut R, sector_size: u64) -> Result<Vec<Partition>, VhdxError>
+where
+    R: Read + Seek,
+{
+    let disk_size = disk.seek(SeekFrom::End(0))?;
+    if disk_size < sector_size.max(512) {
+        return Ok(Vec::new());
+    }
+    let mbr = read_at(disk, 0, 512)?;
+    if mbr[510..] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let entries = mbr_entries(&mbr);
+    if entries
+        .iter()
+        .any(|entry| entry.partition_type == PROTECTIVE)
+    {
+        let last_lba = disk_size / sector_size - 1;
+        let entries = read_gpt(disk, 1, sector_size)
+            .or_else(|error| read_gpt(disk, last_lba, sector_size).map_err(|_| error))?;
+        return gpt_partitions(&entries, sector_size, disk_size);
+    }
+    mbr_partitions(disk, &entries, sector_size, disk_size)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MbrEntry {
+    partition_type: u8,
+    first_lba: u64,
+    sectors: u64,
+}
+
+fn mbr_entries(sector: &[u8]) -> Vec<MbrEntry> {
+    sector[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + 64]
+        .chunks_exact(16)
+        .map(|entry| MbrEntry {
+            partition_type: entry[4],
+            first_lba: u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64,
+            sectors: u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64,
+        })
+        .collect()
+}
+
+fn mbr_partitions<R>(
+    disk: &mut R,
+    entries: &[MbrEntry],
+    sector_size: u64,
+    disk_size: u64,
+) -> Result<Vec<Partition>, VhdxError>
+where
+    R: Read + Seek,
+{
+    let partition = |number: u32, entry: &MbrEntry, base_lba: u64| {
+        let start = (base_lba + entry.first_lba) * sector_size;
+        let length = entry.sectors * sector_size;
+        if start + length > disk_size {
+            return Err(VhdxError::InvalidPartitionTable(
+                "MBR partition past the end of the disk",
+            ));
+        }
+        Ok(Partition {
+            number,
+            partition_type: PartitionType::Mbr(entry.partition_type),
+            unique_id: None,
+            name: String::new(),
+            start,
+            length,
+        })
+    };
+
+    // Only the first extended partition holds logical partitions, whether it has any or not.
+    let mut partitions = Vec::new();
+    let mut logical = Vec::new();
+    let mut extended = false;
+    for (number, entry) in (1..).zip(entries) {
+        if entry.partition_type == 0 || entry.sectors == 0 {
+            continue;
+        }
+        partitions.push(partition(number, entry, 0)?);
+        if !EXTENDED.contains(&entry.partition_type) || extended {
+            continue;
+        }
+        extended = true;
+
+        // Every extended boot record holds a logical partition, relative to the record, and a
+        // link to the next record, relative to the extended partition.
+        let mut record_lba = entry.first_lba;
+        let mut visited = BTreeSet::new();
+        while visited.insert(record_lba) && logical.len() < MAX_LOGICAL_PARTITIONS {
+            let record = read_at(disk, record_lba * sector_size, 512)?;
+            if record[510..] != MBR_SIGNATURE {
+                return Err(VhdxError::InvalidPartitionTable(
+                    "Extended boot record signature not found",
+                ));
+            }
+            let record_entries = mbr_entries(&record);
+            if record_entries[0].partition_type != 0 && record_entries[0].sectors != 0 {
+                let number = 5 + logical.len() as u32;
+                logical.push(partition(number, &record_entries[0], record_lba)?);
+            }
+            if record_entries[1].partition_type == 0 || record_entries[1].sectors == 0 {
+                break;
+            }
+            record_lba = entry.first_lba + record_entries[1].first_lba;
+        }
+    }
+    partitions.extend(logical);
+    Ok(partitions)
+}
+
+// Reads the GPT header at the LBA and the partition entry array it points to, checking both.
+fn read_gpt<R>(disk: &mut R, lba: u64, sector_size: u64) -> Result<Vec<u8>, VhdxError>
+where
+    R: Read + Seek,
+{
+    let mut header = read_at(disk, lba * sector_size, sector_size)?;
+    let (
+        _,
+        (
+            signature,
+            _revision,
+            header_length,
+            checksum,
+            _,
+            my_lba,
+            _alternate_lba,
+            _first_usable_lba,
+            _last_usable_lba,
+            _disk_guid,
+            entries_lba,
+            entries_count,
+            entry_length,
+            entries_checksum,
+        ),
+    ) = tuple((
+        take(8usize),
+        le_u32,
+        le_u32,
+        le_u32,
+        take(4usize),
+        le_u64,
+        le_u64,
+        le_u64,
+        le_u64,
+        t_guid,
+        le_u64,
+        le_u32,
+        le_u32,
+        le_u32,
+    ))(header.as_slice())
+    .finish()
+    .map_err(|e: VhdxParseError<&[u8]>| VhdxError::from(e))?;
+
+    if signature != GPT_SIGNATURE {
+        return Err(VhdxError::InvalidPartitionTable("GPT signature not found"));
+    }
+    if header_length < GPT_HEADER_LENGTH || header_length as u64 > sector_size {
+        return Err(VhdxError::InvalidPartitionTable("Invalid GPT header size"));
+    }
+    header[16..20].fill(0);
+    if GPT_CRC.checksum(&header[..header_length as usize]) != checksum {
+        return Err(VhdxError::InvalidPartitionTable(
+            "GPT header checksum doesn't match",
+        ));
+    }
+    if my_lba != lba {
+        return Err(VhdxError::InvalidPartitionTable(
+            "GPT header isn't where it says it is",
+        ));
+    }
+    let entries_length = entries_count as u64 * entry_length as u64;
+    if entry_length < GPT_ENTRY_LENGTH
+        || !entry_length.is_power_of_two()
+        || entries_length > MAX_GPT_ENTRIES_LENGTH
+    {
+        return Err(VhdxError::InvalidPartitionTable(
+            "Invalid GPT partition entry array",
+        ));
+    }
+
+    let entries_offset =
+        entries_lba
+            .checked_mul(sector_size)
+            .ok_or(VhdxError::InvalidPartitionTable(
+                "GPT partition entry array past the end of the disk",
+            ))?;
+    let entries = read_at(disk, entries_offset, entries_length)?;
+    if GPT_CRC.checksum(&entries) != entries_checksum {
+        return Err(VhdxError::InvalidPartitionTable(
+            "GPT partition entries checksum doesn't match",
+        ));
+    }
+    Ok(entries
+        .chunks_exact(entry_length as usize)
+        .flat_map(|entry| &entry[..GPT_ENTRY_LENGTH as usize])
+        .copied()
+        .collect())
+}
+
+// The used entries of a checked partition entry array, cut down to 128 bytes each.
+fn gpt_partitions(
+    entries: &[u8],
+    sector_size: u64,
+    disk_size: u64,
+) -> Result<Vec<Partition>, VhdxError> {
+    let mut partitions = Vec::new();
+    for (number, entry) in (1..).zip(entries.chunks_exact(GPT_ENTRY_LENGTH as usize)) {
+        let partition_type = Uuid::from_slice_le(&entry[..16]).unwrap();
+        if partition_type.is_nil() {
+            continue;
+        }
+        let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        let end = last_lba
+            .checked_add(1)
+            .and_then(|end| end.checked_mul(sector_size));
+        if first_lba > last_lba || end.is_none_or(|end| end > disk_size) {
+            return Err(VhdxError::InvalidPartitionTable(
+                "GPT partition past the end of the disk",
+            ));
+        }
+        let name: Vec<u16> = entry[56..]
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|unit| *unit != 0)
+            .collect();
+
+        partitions.push(Partition {
+            number,
+            partition_type: PartitionType::Gpt(partition_type),
+            unique_id: Some(Uuid::from_slice_le(&entry[16..32]).unwrap()),
+            name: String::from_utf16_lossy(&name),
+            start: first_lba * sector_size,
+            length: (last_lba - first_lba + 1) * sector_size,
+        });
+    }
+    Ok(partitions)
+}
+
+fn read_at<R>(disk: &mut R, offset: u64, length: u64) -> Result<Vec<u8>, VhdxError>
+where
+    R: Read + Seek,
+{
+    let mut buffer = vec![0; length as usize];
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+// A partition read as a disk of its own, its first byte at offset 0.
+pub struct PartitionReader<R> {
+    disk: R,
+    start: u64,
+    length: u64,
+    position: u64,
+}
+
+impl<R> PartitionReader<R>
+where
+    R: Read + Seek,
+{
+    pub fn new(disk: R, partition: &Partition) -> Self {
+        Self {
+            disk,
+            start: partition.start,
+            length: partition.length,
+            position: 0,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.length
+    }
+
+    pub fn into_inner(self) -> R {
+        self.disk
+    }
+}
+
+impl<R> Read for PartitionReader<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.length || buf.is_empty() {
+            return Ok(0);
+        }
+        let length = (buf.len() as u64).min(self.length - self.position) as usize;
+        self.disk
+            .seek(SeekFrom::Start(self.start + self.position))?;
+        let read = self.disk.read(&mut buf[..length])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R> Seek for PartitionReader<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::create::{CreateOptions, DynamicWriter};
+    use pretty_assertions::assert_eq;
+
+    const SIZE: u64 = 4 * Vhdx::MB;
+    const LAST_LBA: u64 = SIZE / 512 - 1;
+
+    fn mbr_entry(disk: &mut [u8], sector: u64, index: usize, entry: (u8, u32, u32)) {
+        let offset = (sector * 512) as usize + MBR_ENTRIES_OFFSET + index * 16;
+        disk[offset + 4] = entry.0;
+        disk[offset + 8..offset + 12].copy_from_slice(&entry.1.to_le_bytes());
+        disk[offset + 12..offset + 16].copy_from_slice(&entry.2.to_le_bytes());
+        let signature = (sector * 512) as usize + 510;
+        disk[signature..signature + 2].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    // A disk with a protective MBR and a GPT holding (type, name, first LBA, last LBA) entries,
+    // the partition entry array following the primary header and preceding the backup one.
+    fn gpt_disk(partitions: &[(Uuid, &str, u64, u64)]) -> Vec<u8> {
+        let mut disk = vec![0; SIZE as usize];
+        mbr_entry(&mut disk, 0, 0, (PROTECTIVE, 1, LAST_LBA as u32));
+
+        let mut entries = vec![0; 128 * GPT_ENTRY_LENGTH as usize];
+        for (i, (partition_type, name, first_lba, last_lba)) in partitions.iter().enumerate() {
+            let entry = &mut entries[i * GPT_ENTRY_LENGTH as usize..][..GPT_ENTRY_LENGTH as usize];
+            entry[..16].copy_from_slice(&partition_type.to_bytes_le());
+            entry[16..32].copy_from_slice(&Uuid::from_u128(i as u128 + 1).to_bytes_le());
+            entry[32..40].copy_from_slice(&first_lba.to_le_bytes());
+            entry[40..48].copy_from_slice(&last_lba.to_le_bytes());
+            for (j, unit) in name.encode_utf16().enumerate() {
+                entry[56 + 2 * j..58 + 2 * j].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+
+        for (lba, alternate_lba, entries_lba) in [(1, LAST_LBA, 2), (LAST_LBA, 1, LAST_LBA - 32)] {
+            let mut header = GPT_SIGNATURE.to_vec();
+            header.extend_from_slice(&0x0001_0000u32.to_le_bytes());
+            header.extend_from_slice(&GPT_HEADER_LENGTH.to_le_bytes());
+            header.extend_from_slice(&[0; 8]);
+            for value in [lba, alternate_lba, 34, LAST_LBA - 33] {
+                header.extend_from_slice(&value.to_le_bytes());
+            }
+            header.extend_from_slice(&Uuid::from_u128(0xD15C).to_bytes_le());
+            header.extend_from_slice(&entries_lba.to_le_bytes());
+            header.extend_from_slice(&128u32.to_le_bytes());
+            header.extend_from_slice(&GPT_ENTRY_LENGTH.to_le_bytes());
+            header.extend_from_slice(&GPT_CRC.checksum(&entries).to_le_bytes());
+            let checksum = GPT_CRC.checksum(&header);
+            header[16..20].copy_from_slice(&checksum.to_le_bytes());
+
+            disk[(lba * 512) as usize..][..header.len()].copy_from_slice(&header);
+            disk[(entries_lba * 512) as usize..][..entries.len()].copy_from_slice(&entries);
+        }
+        disk
+    }
+
+    #[test]
+    fn should_read_the_gpt_of_a_vhdx_and_open_its_partitions() {
+        let mut disk = gpt_disk(&[
+            (PartitionType::EFI_SYSTEM, "EFI system partition", 34, 2047),
+            (
+                PartitionType::MICROSOFT_BASIC_DATA,
+                "Basic data",
+                2048,
+                8157,
+            ),
+        ]);
+        disk[2048 * 512..8158 * 512].fill(0xAB);
+        let options = CreateOptions {
+            block_size: Vhdx::MB as u32,
+            ..CreateOptions::default()
+        };
+        let mut writer = DynamicWriter::new(Cursor::new(Vec::new()), SIZE, options).unwrap();
+        for (block, data) in disk.chunks(Vhdx::MB as usize).enumerate() {
+            writer.write_block(block as u64, data).unwrap();
+        }
+        let mut vhdx = Vhdx::from_reader(writer.finish().unwrap()).unwrap();
+
+        let partitions = vhdx.partitions().unwrap();
+
+        assert_eq!(
+            vec![
+                Partition {
+                    number: 1,
+                    partition_type: PartitionType::Gpt(PartitionType::EFI_SYSTEM),
+                    unique_id: Some(Uuid::from_u128(1)),
+                    name: "EFI system partition".to_string(),
+                    start: 34 * 512,
+                    length: 2014 * 512,
+                },
+                Partition {
+                    number: 2,
+                    partition_type: PartitionType::Gpt(PartitionType::MICROSOFT_BASIC_DATA),
+                    unique_id: Some(Uuid::from_u128(2)),
+                    name: "Basic data".to_string(),
+                    start: 2048 * 512,
+                    length: 6110 * 512,
+                },
+            ],
+            partitions
+        );
+        let mut data = Vec::new();
+        let mut reader = vhdx.open_partition(&partitions[1]);
+        reader.seek(SeekFrom::End(-512)).unwrap();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(vec![0xAB; 512], data);
+    }
+
+    #[test]
+    fn should_fall_back_to_the_backup_gpt() {
+        let mut disk = gpt_disk(&[(PartitionType::LINUX_FILESYSTEM, "root", 2048, 8157)]);
+        disk[512 + 24] ^= 0xFF;
+
+        let partitions = read_partitions(&mut Cursor::new(disk.clone()), 512).unwrap();
+        disk[(LAST_LBA * 512) as usize + 24] ^= 0xFF;
+        let corrupted = read_partitions(&mut Cursor::new(disk), 512);
+
+        assert_eq!(1, partitions.len());
+        assert_eq!("root", partitions[0].name);
+        assert!(matches!(
+            corrupted,
+            Err(VhdxError::InvalidPartitionTable(
+                "GPT header checksum doesn't match"
+            ))
+        ));
+    }
+
+    // Rewrites a field of the GPT header at the LBA, updating its checksum.
+    fn patch_gpt_header(disk: &mut [u8], lba: u64, offset: usize, bytes: &[u8]) {
+        let header = &mut disk[(lba * 512) as usize..][..GPT_HEADER_LENGTH as usize];
+        header[offset..offset + bytes.len()].copy_from_slice(bytes);
+        header[16..20].fill(0);
+        let checksum = GPT_CRC.checksum(header);
+        header[16..20].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    #[test]
+    fn should_refuse_gpt_offsets_out_of_range() {
+        let mut disk = gpt_disk(&[(PartitionType::LINUX_FILESYSTEM, "root", 2048, 8157)]);
+        for lba in [1, LAST_LBA] {
+            patch_gpt_header(&mut disk, lba, 72, &(u64::MAX / 256).to_le_bytes());
+        }
+        let entries = read_partitions(&mut Cursor::new(disk), 512);
+
+        let disk = gpt_disk(&[(PartitionType::LINUX_FILESYSTEM, "root", 2048, u64::MAX)]);
+        let partition = read_partitions(&mut Cursor::new(disk), 512);
+
+        assert!(matches!(
+            entries,
+            Err(VhdxError::InvalidPartitionTable(
+                "GPT partition entry array past the end of the disk"
+            ))
+        ));
+        assert!(matches!(
+            partition,
+            Err(VhdxError::InvalidPartitionTable(
+                "GPT partition past the end of the disk"
+            ))
+        ));
+    }
+
+    #[test]
+    fn should_only_follow_the_first_extended_partition() {
+        let mut disk = vec![0; SIZE as usize];
+        mbr_entry(&mut disk, 0, 0, (0x05, 2048, 2048));
+        mbr_entry(&mut disk, 0, 1, (0x0F, 4096, 4096));
+        mbr_entry(&mut disk, 2048, 0, (0, 0, 0));
+        mbr_entry(&mut disk, 4096, 0, (0x07, 63, 1000));
+
+        let partitions = read_partitions(&mut Cursor::new(disk), 512).unwrap();
+
+        assert_eq!(
+            vec![PartitionType::Mbr(0x05), PartitionType::Mbr(0x0F)],
+            partitions
+                .into_iter()
+                .map(|partition| partition.partition_type)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn should_read_the_logical_partitions_of_an_extended_partition() {
+        let mut disk = vec![0; SIZE as usize];
+        mbr_entry(&mut disk, 0, 0, (0x83, 2048, 2048));
+        mbr_entry(&mut disk, 0, 1, (0x05, 4096, 4096));
+        mbr_entry(&mut disk, 4096, 0, (0x07, 63, 1000));
+        mbr_entry(&mut disk, 4096, 1, (0x05, 2048, 2048));
+        mbr_entry(&mut disk, 6144, 0, (0x0B, 63, 1000));
+
+        let partitions: Vec<_> = read_partitions(&mut Cursor::new(disk), 512)
+            .unwrap()
+            .into_iter()
+            .map(|partition| {
+                (
+                    partition.number,
+                    partition.partition_type,
+                    partition.start / 512,
+                    partition.length / 512,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            vec![
+                (1, PartitionType::Mbr(0x83), 2048, 2048),
+                (2, PartitionType::Mbr(0x05), 4096, 4096),
+                (5, PartitionType::Mbr(0x07), 4096 + 63, 1000),
+                (6, PartitionType::Mbr(0x0B), 6144 + 63, 1000),
+            ],
+            partitions
+        );
+        assert_eq!(Some("FAT32"), PartitionType::Mbr(0x0B).description());
+    }
+}
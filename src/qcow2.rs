@@ -0,0 +1,982 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use nom::{
+    number::complete::{be_u32, be_u64},
+    sequence::tuple,
+    Finish,
+};
+
+use crate::{
+    bat::BatEntryState,
+    create::{CreateOptions, DynamicWriter},
+    error::{VhdxError, VhdxParseError},
+    image_store::{FileSystem, ImageStore},
+    vhdx::Vhdx,
+    virtual_disk::Allocation,
+};
+
+const MAGIC: u32 = 0x5146_49FB;
+
+// Length of the version 2 header and of the version 3 header written here.
+const V2_HEADER_LENGTH: u32 = 72;
+const V3_HEADER_LENGTH: u32 = 104;
+
+// Bits of the host offset in L1 and L2 entries.
+const OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+
+// Set in L1 and L2 entries whose cluster has a refcount of exactly one.
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+
+// Set in version 3 L2 entries of clusters reading as zeros.
+const ZERO: u64 = 1;
+
+const BACKING_FORMAT_EXTENSION: u32 = 0xE279_2ACA;
+
+// The specification limits backing file names to 1023 bytes.
+const MAX_BACKING_FILE_NAME: u32 = 1023;
+
+// Incompatible features a reader can ignore: the dirty and corrupt bits and the compression type,
+// compressed clusters are rejected anyway.
+const IGNORABLE_INCOMPATIBLE_FEATURES: u64 = 0b1011;
+
+// Refcounts are written with 16 bits.
+const REFCOUNT_ORDER: u32 = 4;
+
+// Options for qcow2 exports.
+#[derive(Debug, Clone)]
+pub struct Qcow2Options {
+    // Log2 of the cluster size, from 512 bytes up to 1 MB so a cluster never spans payload blocks.
+    pub cluster_bits: u32,
+
+    // Name of the qcow2 image of the parent. When set, a differencing disk is written as an overlay
+    // holding only its own data, the rest is left unallocated to be read from the backing file.
+    // Without it the chain is flattened into the one image.
+    pub backing_file: Option<String>,
+}
+
+impl Default for Qcow2Options {
+    // The 64 KB clusters of qemu-img.
+    fn default() -> Self {
+        Self {
+            cluster_bits: 16,
+            backing_file: None,
+        }
+    }
+}
+
+// The qcow2 header, all fields are big endian. Only the fields of version 3 are kept, a version 2
+// header reads as if refcount_order was 4 and no features were set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Qcow2Header {
+    pub version: u32,
+    pub backing_file_offset: u64,
+    pub backing_file_size: u32,
+    pub cluster_bits: u32,
+
+    // Size of the virtual disk in bytes.
+    pub size: u64,
+    pub crypt_method: u32,
+
+    // Number of entries in the L1 table and its offset.
+    pub l1_size: u32,
+    pub l1_table_offset: u64,
+
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u32,
+    pub nb_snapshots: u32,
+    pub snapshots_offset: u64,
+
+    pub incompatible_features: u64,
+    pub compatible_features: u64,
+    pub autoclear_features: u64,
+    pub refcount_order: u32,
+    pub header_length: u32,
+}
+
+impl Qcow2Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(V3_HEADER_LENGTH as usize);
+        buffer.extend_from_slice(&MAGIC.to_be_bytes());
+        buffer.extend_from_slice(&self.version.to_be_bytes());
+        buffer.extend_from_slice(&self.backing_file_offset.to_be_bytes());
+        buffer.extend_from_slice(&self.backing_file_size.to_be_bytes());
+        buffer.extend_from_slice(&self.cluster_bits.to_be_bytes());
+        buffer.extend_from_slice(&self.size.to_be_bytes());
+        buffer.extend_from_slice(&self.crypt_method.to_be_bytes());
+        buffer.extend_from_slice(&self.l1_size.to_be_bytes());
+        buffer.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        buffer.extend_from_slice(&self.refcount_table_offset.to_be_bytes());
+        buffer.extend_from_slice(&self.refcount_table_clusters.to_be_bytes());
+        buffer.extend_from_slice(&self.nb_snapshots.to_be_bytes());
+        buffer.extend_from_slice(&self.snapshots_offset.to_be_bytes());
+        buffer.extend_from_slice(&self.incompatible_features.to_be_bytes());
+        buffer.extend_from_slice(&self.compatible_features.to_be_bytes());
+        buffer.extend_from_slice(&self.autoclear_features.to_be_bytes());
+        buffer.extend_from_slice(&self.refcount_order.to_be_bytes());
+        buffer.extend_from_slice(&self.header_length.to_be_bytes());
+        buffer
+    }
+
+    fn parse(buffer: &[u8]) -> Result<Self, VhdxError> {
+        let (rest, header) = tuple((
+            be_u32, be_u32, be_u64, be_u32, be_u32, be_u64, be_u32, be_u32, be_u64, be_u64, be_u32,
+            be_u32, be_u64,
+        ))(buffer)
+        .finish()
+        .map_err(|e: VhdxParseError<&[u8]>| VhdxError::from(e))?;
+
+        if header.0 != MAGIC {
+            return Err(VhdxError::InvalidQcow2("Magic not found"));
+        }
+        let (
+            incompatible_features,
+            compatible_features,
+            autoclear_features,
+            refcount_order,
+            length,
+        ) = match header.1 {
+            2 => (0, 0, 0, REFCOUNT_ORDER, V2_HEADER_LENGTH),
+            3 => {
+                tuple((be_u64, be_u64, be_u64, be_u32, be_u32))(rest)
+                    .finish()
+                    .map_err(|e: VhdxParseError<&[u8]>| VhdxError::from(e))?
+                    .1
+            }
+            _ => return Err(VhdxError::UnsupportedQcow2("version other than 2 or 3")),
+        };
+
+        let header = Self {
+            version: header.1,
+            backing_file_offset: header.2,
+            backing_file_size: header.3,
+            cluster_bits: header.4,
+            size: header.5,
+            crypt_method: header.6,
+            l1_size: header.7,
+            l1_table_offset: header.8,
+            refcount_table_offset: header.9,
+            refcount_table_clusters: header.10,
+            nb_snapshots: header.11,
+            snapshots_offset: header.12,
+            incompatible_features,
+            compatible_features,
+            autoclear_features,
+            refcount_order,
+            header_length: length,
+        };
+
+        if !(9..=21).contains(&header.cluster_bits) {
+            return Err(VhdxError::InvalidQcow2("Cluster size out of range"));
+        }
+        if header.crypt_method != 0 {
+            return Err(VhdxError::UnsupportedQcow2("encryption"));
+        }
+        if header.incompatible_features & !IGNORABLE_INCOMPATIBLE_FEATURES != 0 {
+            return Err(VhdxError::UnsupportedQcow2("incompatible features"));
+        }
+        Ok(header)
+    }
+}
+
+// The allocation of one cluster of a qcow2 image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cluster {
+    // Stored at the offset in the image.
+    Data(u64),
+    Zero,
+    // Read from the backing file, or as zeros without one.
+    Unallocated,
+}
+
+// A qcow2 image opened for reading, through its backing files when they are attached. It reads as
+// the virtual disk.
+pub struct Qcow2<R> {
+    reader: R,
+    header: Qcow2Header,
+    backing_file: Option<String>,
+    backing_format: Option<String>,
+    l1: Vec<u64>,
+    backing: Option<Box<Qcow2<R>>>,
+    position: u64,
+}
+
+impl Qcow2<File> {
+    // Opens the image and its chain of backing files, found relative to the image.
+    pub fn open(path: &impl AsRef<Path>) -> Result<Self, VhdxError> {
+        Qcow2::open_in(&FileSystem, path)
+    }
+}
+
+impl<R> Qcow2<R>
+where
+    R: Read + Seek,
+{
+    // Opens an image of the store and its chain of backing files, found relative to the image in
+    // the same store.
+    pub fn open_in<S>(store: &S, path: &impl AsRef<Path>) -> Result<Self, VhdxError>
+    where
+        S: ImageStore<Image = R>,
+    {
+        Qcow2::open_chain_in(store, path.as_ref(), &mut BTreeSet::new())
+    }
+
+    // A backing file already part of the chain would make it endless.
+    fn open_chain_in<S>(
+        store: &S,
+        path: &Path,
+        visited: &mut BTreeSet<PathBuf>,
+    ) -> Result<Self, VhdxError>
+    where
+        S: ImageStore<Image = R>,
+    {
+        if !visited.insert(store.canonicalize(path)?) {
+            return Err(VhdxError::ParentCycle(path.to_string_lossy().into_owned()));
+        }
+        let qcow2 = Qcow2::from_reader(store.open(path, false)?)?;
+        let Some(backing_file) = qcow2.backing_file() else {
+            return Ok(qcow2);
+        };
+        if qcow2
+            .backing_format
+            .as_deref()
+            .is_some_and(|format| format != "qcow2")
+        {
+            return Err(VhdxError::UnsupportedQcow2(
+                "backing file not in qcow2 format",
+            ));
+        }
+
+        let backing_path = path.parent().unwrap_or(Path::new("")).join(backing_file);
+        if !store.is_file(&backing_path) {
+            return Err(VhdxError::ParentNotFound(backing_file.to_string()));
+        }
+        let backing = Qcow2::open_chain_in(store, &backing_path, visited)?;
+        Ok(qcow2.with_backing(backing))
+    }
+
+    pub fn from_reader(mut reader: R) -> Result<Self, VhdxError> {
+        let file_length = reader.seek(SeekFrom::End(0))?;
+        let mut buffer = vec![0; V3_HEADER_LENGTH as usize];
+        reader.rewind()?;
+        reader.read_exact(&mut buffer)?;
+        let header = Qcow2Header::parse(&buffer)?;
+        let cluster_size = 1u64 << header.cluster_bits;
+
+        // The extensions follow the header up to the end of its cluster.
+        let mut backing_format = None;
+        let mut offset = header.header_length as u64;
+        while offset + 8 <= cluster_size {
+            let mut extension = [0; 8];
+            reader.seek(SeekFrom::Start(offset))?;
+            reader.read_exact(&mut extension)?;
+            let extension_type = u32::from_be_bytes(extension[..4].try_into().unwrap());
+            let length = u32::from_be_bytes(extension[4..].try_into().unwrap()) as u64;
+            if extension_type == 0 {
+                break;
+            }
+            if offset + 8 + length > cluster_size {
+                return Err(VhdxError::InvalidQcow2(
+                    "Header extension past the end of the header cluster",
+                ));
+            }
+            if extension_type == BACKING_FORMAT_EXTENSION {
+                let mut format = vec![0; length as usize];
+                reader.read_exact(&mut format)?;
+                backing_format = Some(String::from_utf8_lossy(&format).into_owned());
+            }
+            offset += 8 + length.next_multiple_of(8);
+        }
+
+        let backing_file = match header.backing_file_offset {
+            0 => None,
+            _ if header.backing_file_size > MAX_BACKING_FILE_NAME => {
+                return Err(VhdxError::InvalidQcow2("Backing file name too long"))
+            }
+            offset => {
+                let mut name = vec![0; header.backing_file_size as usize];
+                reader.seek(SeekFrom::Start(offset))?;
+                reader.read_exact(&mut name)?;
+                Some(
+                    String::from_utf8(name)
+                        .map_err(|_| VhdxError::InvalidQcow2("Backing file name isn't UTF-8"))?,
+                )
+            }
+        };
+
+        // Only the entries covering the virtual disk are read, whatever the header says, and they
+        // have to be in the file.
+        let l2_entries = cluster_size / 8;
+        let l1_entries =
+            (header.l1_size as u64).min(header.size.div_ceil(cluster_size * l2_entries));
+        if header
+            .l1_table_offset
+            .checked_add(l1_entries * 8)
+            .is_none_or(|end| end > file_length)
+        {
+            return Err(VhdxError::InvalidQcow2("L1 table past the end of the file"));
+        }
+
+        let mut table = vec![0; l1_entries as usize * 8];
+        reader.seek(SeekFrom::Start(header.l1_table_offset))?;
+        reader.read_exact(&mut table)?;
+        let l1 = table
+            .chunks_exact(8)
+            .map(|entry| u64::from_be_bytes(entry.try_into().unwrap()))
+            .collect();
+
+        Ok(Self {
+            reader,
+            header,
+            backing_file,
+            backing_format,
+            l1,
+            backing: None,
+            position: 0,
+        })
+    }
+
+    // Attaches the image of the backing file, read where this image is unallocated.
+    pub fn with_backing(mut self, backing: Qcow2<R>) -> Self {
+        self.backing = Some(Box::new(backing));
+        self
+    }
+
+    pub fn header(&self) -> &Qcow2Header {
+        &self.header
+    }
+
+    pub fn backing_file(&self) -> Option<&str> {
+        self.backing_file.as_deref()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.header.cluster_bits
+    }
+
+    // Looks the cluster up through the L1 and L2 tables.
+    fn cluster(&mut self, index: u64) -> io::Result<Cluster> {
+        let l2_entries = self.cluster_size() / 8;
+        let l1_entry = self
+            .l1
+            .get((index / l2_entries) as usize)
+            .copied()
+            .unwrap_or(0);
+        let l2_offset = l1_entry & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+
+        let mut entry = [0; 8];
+        self.reader
+            .seek(SeekFrom::Start(l2_offset + index % l2_entries * 8))?;
+        self.reader.read_exact(&mut entry)?;
+        let entry = u64::from_be_bytes(entry);
+
+        if entry & COMPRESSED != 0 {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("Cluster {} is compressed", index),
+            ));
+        }
+        Ok(match entry & OFFSET_MASK {
+            _ if self.header.version >= 3 && entry & ZERO != 0 => Cluster::Zero,
+            0 => Cluster::Unallocated,
+            offset => Cluster::Data(offset),
+        })
+    }
+
+    // The allocation of a range through the backing chain. Stored data wins over zeros, zeros win
+    // over ranges not allocated anywhere.
+    fn allocation(&mut self, offset: u64, length: u64) -> io::Result<Allocation> {
+        let cluster_size = self.cluster_size();
+        let end = (offset + length).min(self.size());
+        let mut allocation = Allocation::Parent;
+
+        let mut position = offset;
+        while position < end {
+            let next = ((position / cluster_size + 1) * cluster_size).min(end);
+            let cluster = match self.cluster(position / cluster_size)? {
+                Cluster::Data(_) => Allocation::Data,
+                Cluster::Zero => Allocation::Zero,
+                Cluster::Unallocated => match self.backing.as_deref_mut() {
+                    Some(backing) => backing.allocation(position, next - position)?,
+                    None => Allocation::Parent,
+                },
+            };
+            if cluster == Allocation::Data {
+                return Ok(cluster);
+            }
+            if cluster == Allocation::Zero {
+                allocation = cluster;
+            }
+            position = next;
+        }
+        Ok(allocation)
+    }
+
+    // Reads the same range of the backing file, which reads as zeros past its end.
+    fn read_backing(&mut self, position: u64, buf: &mut [u8]) -> io::Result<()> {
+        let Some(backing) = self.backing.as_deref_mut() else {
+            buf.fill(0);
+            return Ok(());
+        };
+
+        backing.seek(SeekFrom::Start(position))?;
+        let mut read = 0;
+        while read < buf.len() {
+            match backing.read(&mut buf[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        buf[read..].fill(0);
+        Ok(())
+    }
+}
+
+impl<R> Read for Qcow2<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.size();
+        if self.position >= size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let cluster_size = self.cluster_size();
+        let offset_in_cluster = self.position % cluster_size;
+        let length = (buf.len() as u64)
+            .min(cluster_size - offset_in_cluster)
+            .min(size - self.position) as usize;
+        let buf = &mut buf[..length];
+
+        match self.cluster(self.position / cluster_size)? {
+            Cluster::Data(offset) => {
+                self.reader
+                    .seek(SeekFrom::Start(offset + offset_in_cluster))?;
+                self.reader.read_exact(buf)?;
+            }
+            Cluster::Zero => buf.fill(0),
+            Cluster::Unallocated => self.read_backing(self.position, buf)?,
+        }
+
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<R> Seek for Qcow2<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    pub fn export_qcow2(
+        &mut self,
+        dest: &impl AsRef<Path>,
+        options: Qcow2Options,
+    ) -> Result<(), VhdxError> {
+        let mut file = File::create(dest)?;
+        self.export_qcow2_to(&mut file, options)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    // Writes the disk as a qcow2 version 3 image. Clusters stored in the VHDX are allocated, Zero
+    // blocks become zero clusters and NotPresent blocks are left unallocated, so L2 tables mapping
+    // nothing are left out. The header and the L1 table come first, L2 tables follow the data they
+    // map and the refcounts close the image.
+    pub fn export_qcow2_to<W>(
+        &mut self,
+        writer: &mut W,
+        options: Qcow2Options,
+    ) -> Result<(), VhdxError>
+    where
+        W: Write + Seek,
+    {
+        if !(9..=20).contains(&options.cluster_bits) {
+            return Err(VhdxError::InvalidClusterSize(options.cluster_bits));
+        }
+        let size = self.virtual_disk_size();
+        let cluster_size = 1u64 << options.cluster_bits;
+        let l2_entries = cluster_size / 8;
+        let l1_size = size.div_ceil(cluster_size * l2_entries);
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+
+        let header_cluster = header_cluster(cluster_size, options.backing_file.as_deref())?;
+        let overlay = options.backing_file.is_some();
+
+        let mut next_cluster = 1 + l1_clusters;
+        let mut l1 = vec![0u64; l1_size as usize];
+        let mut buffer = vec![0; cluster_size as usize];
+        let mut disk = self.virtual_disk();
+
+        for (l1_index, l1_entry) in l1.iter_mut().enumerate() {
+            let mut l2 = vec![0u64; l2_entries as usize];
+            for (l2_index, l2_entry) in l2.iter_mut().enumerate() {
+                let offset = (l1_index as u64 * l2_entries + l2_index as u64) * cluster_size;
+                if offset >= size {
+                    break;
+                }
+                let length = cluster_size.min(size - offset);
+
+                let allocation = disk.allocation(offset, length)?;
+                if allocation == Allocation::Zero || (allocation == Allocation::Parent && overlay) {
+                    // Over a backing file any block reading as zeros has to hide it.
+                    let zeroed = allocation == Allocation::Zero && overlay;
+                    if zeroed || disk.state(offset)? == BatEntryState::Zero {
+                        *l2_entry = ZERO;
+                    }
+                    continue;
+                }
+
+                buffer.fill(0);
+                disk.seek(SeekFrom::Start(offset))?;
+                disk.read_exact(&mut buffer[..length as usize])?;
+                // Flattened data of the parent is only allocated where it isn't all zeros, there's
+                // no backing file for the rest to show through.
+                if allocation == Allocation::Parent && buffer.iter().all(|b| *b == 0) {
+                    continue;
+                }
+
+                writer.seek(SeekFrom::Start(next_cluster * cluster_size))?;
+                writer.write_all(&buffer)?;
+                *l2_entry = (next_cluster * cluster_size) | COPIED;
+                next_cluster += 1;
+            }
+
+            if l2.iter().any(|entry| *entry != 0) {
+                let table: Vec<u8> = l2.iter().flat_map(|entry| entry.to_be_bytes()).collect();
+                writer.seek(SeekFrom::Start(next_cluster * cluster_size))?;
+                writer.write_all(&table)?;
+                *l1_entry = (next_cluster * cluster_size) | COPIED;
+                next_cluster += 1;
+            }
+        }
+
+        // The refcount blocks and table count themselves, grow them until they cover every cluster.
+        let refcounts_per_block = cluster_size * 8 / (1 << REFCOUNT_ORDER);
+        let (mut blocks, mut table_clusters) = (0, 0);
+        loop {
+            let clusters = next_cluster + table_clusters + blocks;
+            let needed_blocks = clusters.div_ceil(refcounts_per_block);
+            let needed_table_clusters = (needed_blocks * 8).div_ceil(cluster_size);
+            if (needed_blocks, needed_table_clusters) == (blocks, table_clusters) {
+                break;
+            }
+            (blocks, table_clusters) = (needed_blocks, needed_table_clusters);
+        }
+        let clusters = next_cluster + table_clusters + blocks;
+
+        let mut table = vec![0; (table_clusters * cluster_size) as usize];
+        for block in 0..blocks {
+            let offset = (next_cluster + table_clusters + block) * cluster_size;
+            table[block as usize * 8..][..8].copy_from_slice(&offset.to_be_bytes());
+        }
+        let mut refcounts = vec![0; (blocks * cluster_size) as usize];
+        for cluster in 0..clusters as usize {
+            refcounts[cluster * 2..][..2].copy_from_slice(&1u16.to_be_bytes());
+        }
+        writer.seek(SeekFrom::Start(next_cluster * cluster_size))?;
+        writer.write_all(&table)?;
+        writer.write_all(&refcounts)?;
+
+        let mut l1_table: Vec<u8> = l1.iter().flat_map(|entry| entry.to_be_bytes()).collect();
+        l1_table.resize((l1_clusters * cluster_size) as usize, 0);
+        let header = Qcow2Header {
+            version: 3,
+            backing_file_offset: header_cluster.backing_file_offset,
+            backing_file_size: options
+                .backing_file
+                .as_ref()
+                .map_or(0, |name| name.len() as u32),
+            cluster_bits: options.cluster_bits,
+            size,
+            crypt_method: 0,
+            l1_size: l1_size as u32,
+            l1_table_offset: cluster_size,
+            refcount_table_offset: next_cluster * cluster_size,
+            refcount_table_clusters: table_clusters as u32,
+            nb_snapshots: 0,
+            snapshots_offset: 0,
+            incompatible_features: 0,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: REFCOUNT_ORDER,
+            header_length: V3_HEADER_LENGTH,
+        };
+        let mut first_cluster = header.to_bytes();
+        first_cluster.extend_from_slice(&header_cluster.extensions);
+        first_cluster.resize(cluster_size as usize, 0);
+
+        writer.rewind()?;
+        writer.write_all(&first_cluster)?;
+        writer.write_all(&l1_table)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// What follows the header in the first cluster: the header extensions and the name of the
+// backing file.
+struct HeaderCluster {
+    extensions: Vec<u8>,
+    backing_file_offset: u64,
+}
+
+fn header_cluster(
+    cluster_size: u64,
+    backing_file: Option<&str>,
+) -> Result<HeaderCluster, VhdxError> {
+    let mut extensions = Vec::new();
+    if backing_file.is_some() {
+        let format = b"qcow2";
+        extensions.extend_from_slice(&BACKING_FORMAT_EXTENSION.to_be_bytes());
+        extensions.extend_from_slice(&(format.len() as u32).to_be_bytes());
+        extensions.extend_from_slice(format);
+        extensions.resize(extensions.len().next_multiple_of(8), 0);
+    }
+    extensions.extend_from_slice(&[0; 8]);
+
+    let backing_file_offset = match backing_file {
+        Some(name) => {
+            let offset = V3_HEADER_LENGTH as u64 + extensions.len() as u64;
+            extensions.extend_from_slice(name.as_bytes());
+            offset
+        }
+        None => 0,
+    };
+
+    if V3_HEADER_LENGTH as u64 + extensions.len() as u64 > cluster_size {
+        return Err(VhdxError::InvalidQcow2(
+            "Backing file name doesn't fit the header cluster",
+        ));
+    }
+    Ok(HeaderCluster {
+        extensions,
+        backing_file_offset,
+    })
+}
+
+impl Vhdx {
+    // Builds a dynamic VHDX at dest from a qcow2 image, flattening its backing files into it.
+    // Blocks allocated nowhere in the chain stay NotPresent, blocks only made of zero clusters
+    // become Zero and all other blocks are stored.
+    pub fn import_qcow2(
+        src: &impl AsRef<Path>,
+        dest: &impl AsRef<Path>,
+        options: CreateOptions,
+    ) -> Result<Self, VhdxError> {
+        let mut qcow2 = Qcow2::open(src)?;
+        let size = qcow2.size();
+        let virtual_disk_size = size.next_multiple_of(options.logical_sector_size as u64);
+
+        let mut vhdx = DynamicWriter::new(File::create(dest)?, virtual_disk_size, options)?;
+        let block_size = vhdx.block_size();
+        let mut buffer = vec![0; block_size as usize];
+
+        for block in 0..vhdx.payload_blocks_count() {
+            let start = block * block_size;
+            let length = block_size.min(size.saturating_sub(start));
+            match qcow2.allocation(start, length)? {
+                Allocation::Parent => vhdx.set_state(block, BatEntryState::NotPresent)?,
+                Allocation::Zero => vhdx.set_state(block, BatEntryState::Zero)?,
+                Allocation::Data => {
+                    let data = &mut buffer[..length as usize];
+                    qcow2.seek(SeekFrom::Start(start))?;
+                    qcow2.read_exact(data)?;
+                    vhdx.write_block(block, data)?;
+                }
+            }
+        }
+
+        vhdx.finish()?.sync_all()?;
+        Vhdx::new(dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::image_store::MemoryStore;
+    use crate::test_utils::{
+        differencing_image, dynamic_image, parent_locator, TempDir, BAT_OFFSET, BLOCK_SIZE,
+    };
+    use pretty_assertions::assert_eq;
+
+    const CLUSTER_SIZE: u64 = 64 * 1024;
+    const CLUSTERS_PER_BLOCK: u64 = BLOCK_SIZE as u64 / CLUSTER_SIZE;
+
+    fn export(vhdx: &mut Vhdx<Cursor<Vec<u8>>>, backing_file: Option<&str>) -> Vec<u8> {
+        let mut image = Cursor::new(Vec::new());
+        let options = Qcow2Options {
+            backing_file: backing_file.map(str::to_string),
+            ..Qcow2Options::default()
+        };
+        vhdx.export_qcow2_to(&mut image, options).unwrap();
+        image.into_inner()
+    }
+
+    fn read_u64(image: &[u8], offset: u64) -> u64 {
+        u64::from_be_bytes(image[offset as usize..][..8].try_into().unwrap())
+    }
+
+    // Every cluster of the image is referenced exactly once, by the header, the L1 table, an L2
+    // table or the refcounts, and has a refcount of one.
+    fn assert_refcounts(image: &[u8]) {
+        let qcow2 = Qcow2::from_reader(Cursor::new(image.to_vec())).unwrap();
+        let header = qcow2.header().clone();
+        let mut referenced = vec![0];
+        referenced.extend(
+            (0..(header.l1_size as u64 * 8).div_ceil(CLUSTER_SIZE))
+                .map(|i| header.l1_table_offset / CLUSTER_SIZE + i),
+        );
+        for l1_entry in &qcow2.l1 {
+            let l2_offset = l1_entry & OFFSET_MASK;
+            if l2_offset == 0 {
+                continue;
+            }
+            referenced.push(l2_offset / CLUSTER_SIZE);
+            for i in 0..CLUSTER_SIZE / 8 {
+                let data = read_u64(image, l2_offset + i * 8) & OFFSET_MASK;
+                if data != 0 {
+                    referenced.push(data / CLUSTER_SIZE);
+                }
+            }
+        }
+        let table_offset = header.refcount_table_offset;
+        for i in 0..header.refcount_table_clusters as u64 {
+            referenced.push(table_offset / CLUSTER_SIZE + i);
+        }
+        let mut refcounts = Vec::new();
+        for i in 0..header.refcount_table_clusters as u64 * CLUSTER_SIZE / 8 {
+            let block = read_u64(image, table_offset + i * 8);
+            if block != 0 {
+                referenced.push(block / CLUSTER_SIZE);
+                refcounts.extend(
+                    image[block as usize..][..CLUSTER_SIZE as usize]
+                        .chunks(2)
+                        .map(|refcount| u16::from_be_bytes([refcount[0], refcount[1]])),
+                );
+            }
+        }
+
+        let clusters = image.len() as u64 / CLUSTER_SIZE;
+        referenced.sort();
+        assert_eq!((0..clusters).collect::<Vec<u64>>(), referenced);
+        assert!(refcounts[..clusters as usize].iter().all(|r| *r == 1));
+        assert!(refcounts[clusters as usize..].iter().all(|r| *r == 0));
+    }
+
+    #[test]
+    fn should_export_allocation_as_clusters() {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0xAA), (2, 0xBB), (3, 0xCC)]);
+        // Block 3 is zeroed, block 1 not present.
+        image.get_mut()[BAT_OFFSET as usize + 3 * 8..][..8]
+            .copy_from_slice(&(BatEntryState::Zero as u64).to_le_bytes());
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        let mut expected = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut expected).unwrap();
+
+        let image = export(&mut vhdx, None);
+
+        assert_refcounts(&image);
+        let mut qcow2 = Qcow2::from_reader(Cursor::new(image)).unwrap();
+        assert_eq!(3, qcow2.header().version);
+        assert_eq!(4 * BLOCK_SIZE as u64, qcow2.size());
+        let clusters: Vec<Cluster> = (0..4)
+            .map(|block| qcow2.cluster(block * CLUSTERS_PER_BLOCK).unwrap())
+            .collect();
+        assert!(matches!(clusters[0], Cluster::Data(_)));
+        assert_eq!(Cluster::Unallocated, clusters[1]);
+        assert!(matches!(clusters[2], Cluster::Data(_)));
+        assert_eq!(Cluster::Zero, clusters[3]);
+        let mut content = Vec::new();
+        qcow2.read_to_end(&mut content).unwrap();
+        assert!(content == expected);
+    }
+
+    #[test]
+    fn should_leave_out_l2_tables_of_blocks_not_present() {
+        // One L2 table of 64 KB clusters maps 512 MB.
+        let mut vhdx = Vhdx::from_reader(dynamic_image(1024 * BLOCK_SIZE, &[(0, 0xAA)])).unwrap();
+
+        let image = export(&mut vhdx, None);
+
+        assert_refcounts(&image);
+        let qcow2 = Qcow2::from_reader(Cursor::new(image)).unwrap();
+        assert_eq!(2, qcow2.l1.len());
+        assert_ne!(0, qcow2.l1[0]);
+        assert_eq!(0, qcow2.l1[1]);
+    }
+
+    #[test]
+    fn should_bound_the_l1_table_by_the_disk_and_the_file() {
+        let mut vhdx = Vhdx::from_reader(dynamic_image(2 * BLOCK_SIZE, &[(0, 0xAA)])).unwrap();
+        let image = export(&mut vhdx, None);
+        let patched = |offset: usize, value: &[u8]| {
+            let mut image = image.clone();
+            image[offset..offset + value.len()].copy_from_slice(value);
+            Cursor::new(image)
+        };
+
+        let mut huge_l1 = Qcow2::from_reader(patched(36, &u32::MAX.to_be_bytes())).unwrap();
+        let mut content = Vec::new();
+        huge_l1.read_to_end(&mut content).unwrap();
+
+        assert_eq!(1, huge_l1.l1.len());
+        assert!(content[..BLOCK_SIZE].iter().all(|b| *b == 0xAA));
+        assert!(matches!(
+            Qcow2::from_reader(patched(40, &(u64::MAX - 7).to_be_bytes())),
+            Err(VhdxError::InvalidQcow2("L1 table past the end of the file"))
+        ));
+        assert!(matches!(
+            Qcow2::from_reader(patched(
+                8,
+                &[0, 0, 0, 0, 0, 0, 2, 0, 0xFF, 0xFF, 0xFF, 0xFF]
+            )),
+            Err(VhdxError::InvalidQcow2("Backing file name too long"))
+        ));
+    }
+
+    #[test]
+    fn should_export_differencing_disk_over_backing_file() {
+        let parent = dynamic_image(4 * BLOCK_SIZE, &[(0, 0xAA), (1, 0xBB)]);
+        let child = differencing_image(
+            4 * BLOCK_SIZE,
+            parent_locator("parent.vhdx"),
+            &[(0, 0xCC, Some(vec![0x0F])), (2, 0xDD, None)],
+        );
+        let parent = Vhdx::from_reader(parent).unwrap();
+        let mut child = Vhdx::from_reader(child)
+            .unwrap()
+            .with_parent(parent)
+            .unwrap();
+        let mut expected = Vec::new();
+        child.virtual_disk().read_to_end(&mut expected).unwrap();
+
+        let overlay = export(&mut child, Some("parent.qcow2"));
+        let backing = export(child.parent.as_deref_mut().unwrap(), None);
+
+        assert_refcounts(&overlay);
+        let mut overlay = Qcow2::from_reader(Cursor::new(overlay)).unwrap();
+        assert_eq!(Some("parent.qcow2"), overlay.backing_file());
+        assert_eq!(Some("qcow2"), overlay.backing_format.as_deref());
+        // Only the first cluster of block 0 holds sectors of the child.
+        assert!(matches!(overlay.cluster(0).unwrap(), Cluster::Data(_)));
+        assert_eq!(Cluster::Unallocated, overlay.cluster(1).unwrap());
+        assert_eq!(
+            Cluster::Unallocated,
+            overlay.cluster(CLUSTERS_PER_BLOCK).unwrap()
+        );
+
+        let backing = Qcow2::from_reader(Cursor::new(backing)).unwrap();
+        let mut overlay = overlay.with_backing(backing);
+        let mut content = Vec::new();
+        overlay.read_to_end(&mut content).unwrap();
+        assert!(content == expected);
+    }
+
+    #[test]
+    fn should_open_backing_files_through_the_store_and_refuse_loops() {
+        let mut base = Vhdx::from_reader(dynamic_image(4 * BLOCK_SIZE, &[(1, 0x11)])).unwrap();
+        let store = MemoryStore::new();
+        store.insert(&"/images/base.qcow2", export(&mut base, None));
+        store.insert(&"/images/top.qcow2", export(&mut base, Some("base.qcow2")));
+        store.insert(&"/images/loop.qcow2", export(&mut base, Some("loop.qcow2")));
+
+        let mut top = Qcow2::open_in(&store, &"/images/top.qcow2").unwrap();
+        let looped = Qcow2::open_in(&store, &"/images/loop.qcow2");
+
+        assert!(top.backing.is_some());
+        let mut content = Vec::new();
+        top.read_to_end(&mut content).unwrap();
+        assert!(content[BLOCK_SIZE..2 * BLOCK_SIZE]
+            .iter()
+            .all(|b| *b == 0x11));
+        assert!(matches!(looped, Err(VhdxError::ParentCycle(_))));
+    }
+
+    #[test]
+    fn should_import_qcow2_chain() {
+        let directory = TempDir::new("qcow2");
+        let mut base = Vhdx::from_reader(dynamic_image(4 * BLOCK_SIZE, &[(1, 0x11)])).unwrap();
+        std::fs::write(directory.join("base.qcow2"), export(&mut base, None)).unwrap();
+        let mut top = Vhdx::from_reader(differencing_image(
+            4 * BLOCK_SIZE,
+            parent_locator("base.vhdx"),
+            &[(2, 0x22, None)],
+        ))
+        .unwrap()
+        .with_parent(base)
+        .unwrap();
+        std::fs::write(
+            directory.join("top.qcow2"),
+            export(&mut top, Some("base.qcow2")),
+        )
+        .unwrap();
+
+        let options = CreateOptions {
+            block_size: BLOCK_SIZE as u32,
+            ..CreateOptions::default()
+        };
+        let mut vhdx = Vhdx::import_qcow2(
+            &directory.join("top.qcow2"),
+            &directory.join("disk.vhdx"),
+            options,
+        )
+        .unwrap();
+        let states: Vec<BatEntryState> = (0..4)
+            .map(|block| vhdx.payload_entry(block).unwrap().state())
+            .collect();
+        let mut content = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut content).unwrap();
+
+        use BatEntryState::*;
+        assert_eq!(
+            vec![NotPresent, FullyPresent, FullyPresent, NotPresent],
+            states
+        );
+        assert!(content[..BLOCK_SIZE].iter().all(|b| *b == 0));
+        assert!(content[BLOCK_SIZE..2 * BLOCK_SIZE]
+            .iter()
+            .all(|b| *b == 0x11));
+        assert!(content[2 * BLOCK_SIZE..3 * BLOCK_SIZE]
+            .iter()
+            .all(|b| *b == 0x22));
+        assert!(content[3 * BLOCK_SIZE..].iter().all(|b| *b == 0));
+    }
+}
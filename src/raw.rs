@@ -0,0 +1,514 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::{
+    create::{CreateOptions, DynamicWriter},
+    error::VhdxError,
+    vhdx::Vhdx,
+    virtual_disk::Allocation,
+};
+
+// Zeros are detected at this granularity, the size of a page and of most file system blocks.
+const HOLE_SIZE: usize = 4096;
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    // Writes the guest visible contents of the disk to a raw image file. Ranges reading as zeros,
+    // such as blocks that are not present, are left as holes so the file stays sparse on file
+    // systems supporting it. Differencing disks are read through their parents, which flattens
+    // the chain into the one image.
+    pub fn export_raw(&mut self, dest: &impl AsRef<Path>) -> Result<(), VhdxError> {
+        let mut file = File::create(dest)?;
+        self.export_raw_to(&mut file)?;
+        file.set_len(self.virtual_disk_size())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    // Writes the raw image to any seekable writer, seeking over the ranges reading as zeros. The
+    // writer is extended to the size of the disk even when it ends with zeros. Returns the number
+    // of bytes actually written.
+    pub fn export_raw_to<W>(&mut self, writer: &mut W) -> Result<u64, VhdxError>
+    where
+        W: Write + Seek,
+    {
+        let size = self.virtual_disk_size();
+        let chunk_size = self.block_size().min(Vhdx::MB) as usize;
+        let mut buffer = vec![0; chunk_size];
+        let mut disk = self.virtual_disk();
+        let mut written = 0;
+
+        let mut position = 0;
+        while position < size {
+            let length = (chunk_size as u64).min(size - position) as usize;
+            disk.read_exact(&mut buffer[..length])?;
+
+            for (i, page) in buffer[..length].chunks(HOLE_SIZE).enumerate() {
+                if page.iter().all(|b| *b == 0) {
+                    continue;
+                }
+                writer.seek(SeekFrom::Start(position + (i * HOLE_SIZE) as u64))?;
+                writer.write_all(page)?;
+                written += page.len() as u64;
+            }
+            position += length as u64;
+        }
+
+        // A trailing hole only extends the output once something is written at its end.
+        if size > 0 && writer.seek(SeekFrom::End(0))? < size {
+            writer.seek(SeekFrom::Start(size - 1))?;
+            writer.write_all(&[0])?;
+        }
+        writer.flush()?;
+
+        Ok(written)
+    }
+
+    // Writes the raw image to a writer that can't seek, such as a pipe. The disk is walked in
+    // virtual order, ranges without data are written as zeros without reading anything. Returns
+    // the number of bytes written, the size of the disk.
+    pub fn export_raw_stream<W>(&mut self, writer: &mut W) -> Result<u64, VhdxError>
+    where
+        W: Write,
+    {
+        let size = self.virtual_disk_size();
+        let chunk_size = self.block_size().min(Vhdx::MB);
+        let zeros = vec![0; chunk_size as usize];
+        let mut buffer = vec![0; chunk_size as usize];
+        let mut disk = self.virtual_disk();
+
+        let mut position = 0;
+        while position < size {
+            let length = chunk_size.min(size - position);
+            let chunk = &mut buffer[..length as usize];
+            if disk.allocation(position, length)? == Allocation::Zero {
+                writer.write_all(&zeros[..length as usize])?;
+            } else {
+                disk.seek(SeekFrom::Start(position))?;
+                disk.read_exact(chunk)?;
+                writer.write_all(chunk)?;
+            }
+            position += length;
+        }
+        writer.flush()?;
+
+        Ok(size)
+    }
+}
+
+impl Vhdx {
+    // Builds a dynamic VHDX at dest from a raw image. Blocks reading as zeros are not allocated,
+    // and the holes of a sparse source are skipped without being read. The size of the raw image
+    // is rounded up to whole logical sectors.
+    pub fn import_raw(
+        src: &impl AsRef<Path>,
+        dest: &impl AsRef<Path>,
+        options: CreateOptions,
+    ) -> Result<Self, VhdxError> {
+        let mut source = File::open(src)?;
+        let size = source.metadata()?.len();
+        let virtual_disk_size = size.next_multiple_of(options.logical_sector_size as u64);
+
+        let mut vhdx = DynamicWriter::new(File::create(dest)?, virtual_disk_size, options)?;
+        let block_size = vhdx.block_size();
+        let mut buffer = vec![0; block_size as usize];
+
+        for block in 0..vhdx.payload_blocks_count() {
+            let start = block * block_size;
+            let end = (start + block_size).min(size);
+            let data = &mut buffer[..end.saturating_sub(start) as usize];
+            if start >= end || !read_data(&mut source, start, data)? {
+                vhdx.write_block(block, &[])?;
+                continue;
+            }
+            vhdx.write_block(block, data)?;
+        }
+
+        vhdx.finish()?.sync_all()?;
+        Vhdx::new(dest)
+    }
+}
+
+impl Vhdx {
+    // Builds a dynamic VHDX at dest from a raw image read from a stream, such as stdin, without
+    // knowing its size up front.
+    pub fn import_raw_stream<R>(
+        reader: R,
+        dest: &impl AsRef<Path>,
+        options: CreateOptions,
+    ) -> Result<Self, VhdxError>
+    where
+        R: Read,
+    {
+        import_raw_stream_to(reader, File::create(dest)?, options)?.sync_all()?;
+        Vhdx::new(dest)
+    }
+}
+
+// Writes a dynamic VHDX holding the raw image read from the stream. The size of the disk grows with
+// every block read and is fixed up at the end, rounded up to whole logical sectors. Blocks only
+// holding zeros are detected as they are read and not allocated.
+pub fn import_raw_stream_to<R, W>(
+    mut reader: R,
+    writer: W,
+    options: CreateOptions,
+) -> Result<W, VhdxError>
+where
+    R: Read,
+    W: Write + Seek,
+{
+    let sector_size = options.logical_sector_size as u64;
+    let mut vhdx = DynamicWriter::provisional(writer, options)?;
+    let mut buffer = vec![0; vhdx.block_size() as usize];
+    let mut size = 0;
+
+    for block in 0.. {
+        let length = read_block(&mut reader, &mut buffer)?;
+        if length == 0 {
+            break;
+        }
+        size += length as u64;
+        vhdx.set_virtual_disk_size(size.next_multiple_of(sector_size))?;
+        vhdx.write_block(block, &buffer[..length])?;
+        if length < buffer.len() {
+            break;
+        }
+    }
+
+    vhdx.finish()
+}
+
+// Fills the buffer from the stream, short only at its end. Pipes hand data over in small pieces.
+fn read_block<R>(reader: &mut R, buffer: &mut [u8]) -> Result<usize, VhdxError>
+where
+    R: Read,
+{
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(read)
+}
+
+// Fills the buffer from the file at the offset, reading only the data runs and leaving the holes
+// between them as zeros. False when the range is all a hole.
+fn read_data(file: &mut File, offset: u64, buffer: &mut [u8]) -> Result<bool, VhdxError> {
+    buffer.fill(0);
+    let end = offset + buffer.len() as u64;
+    let mut position = offset;
+    let mut found = false;
+    while position < end {
+        let Some((start, hole)) = data_run(file, position)? else {
+            break;
+        };
+        if start >= end {
+            break;
+        }
+        let hole = hole.min(end);
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buffer[(start - offset) as usize..(hole - offset) as usize])?;
+        found = true;
+        position = hole;
+    }
+    Ok(found)
+}
+
+// The first data run at or after the offset, as its start and the start of the hole ending it.
+// None when only a hole follows. Only Linux is asked through SEEK_DATA and SEEK_HOLE, elsewhere
+// all of the file is taken as data.
+#[cfg(target_os = "linux")]
+fn data_run(file: &mut File, offset: u64) -> Result<Option<(u64, u64)>, VhdxError> {
+    use std::os::fd::AsRawFd;
+
+    let seek = |offset: u64, whence: libc::c_int| {
+        let result = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+        match result {
+            0.. => Ok(result as u64),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    };
+
+    let start = match seek(offset, libc::SEEK_DATA) {
+        Ok(start) => start,
+        Err(error) => {
+            return match error.raw_os_error() {
+                Some(libc::ENXIO) => Ok(None),
+                // File systems without support for it fail, which just means reading everything.
+                Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => Ok(Some((offset, u64::MAX))),
+                _ => Err(error.into()),
+            };
+        }
+    };
+    // The end of the file counts as a hole, so there always is one after the data.
+    let hole = seek(start, libc::SEEK_HOLE)?;
+    Ok(Some((start, hole)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn data_run(_file: &mut File, offset: u64) -> Result<Option<(u64, u64)>, VhdxError> {
+    Ok(Some((offset, u64::MAX)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::bat::BatEntryState;
+    use crate::meta_data::{ParentLocator, SectorSize};
+    use crate::test_utils::{
+        differencing_image, dynamic_image, parent_locator, TempDir, BLOCK_SIZE, DATA_WRITE_GUID,
+    };
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    // A child of the two block parent. Block 0 holds its first four sectors, block 2 is fully in
+    // the child and the rest comes from the parent.
+    fn chain() -> (Cursor<Vec<u8>>, Cursor<Vec<u8>>) {
+        let parent = dynamic_image(4 * BLOCK_SIZE, &[(0, 0xAA), (1, 0xBB)]);
+        let child = differencing_image(
+            4 * BLOCK_SIZE,
+            parent_locator("parent.vhdx"),
+            &[(0, 0xCC, Some(vec![0x0F])), (2, 0xDD, None)],
+        );
+        (parent, child)
+    }
+
+    fn expected_chain_content() -> Vec<u8> {
+        let mut content = vec![0xAA; BLOCK_SIZE];
+        content[..4 * 512].fill(0xCC);
+        content.extend(vec![0xBB; BLOCK_SIZE]);
+        content.extend(vec![0xDD; BLOCK_SIZE]);
+        content.extend(vec![0; BLOCK_SIZE]);
+        content
+    }
+
+    #[test]
+    fn should_export_only_allocated_data() {
+        let image = dynamic_image(4 * BLOCK_SIZE, &[(1, 0x11)]);
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+
+        let mut raw = Cursor::new(Vec::new());
+        let written = vhdx.export_raw_to(&mut raw).unwrap();
+
+        assert_eq!(BLOCK_SIZE as u64, written);
+        let raw = raw.into_inner();
+        assert_eq!(4 * BLOCK_SIZE, raw.len());
+        assert!(raw[..BLOCK_SIZE].iter().all(|b| *b == 0));
+        assert!(raw[BLOCK_SIZE..2 * BLOCK_SIZE].iter().all(|b| *b == 0x11));
+        assert!(raw[2 * BLOCK_SIZE..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn should_flatten_differencing_chain() {
+        let (parent, child) = chain();
+        let parent = Vhdx::from_reader(parent).unwrap();
+        let mut vhdx = Vhdx::from_reader(child)
+            .unwrap()
+            .with_parent(parent)
+            .unwrap();
+
+        let mut raw = Cursor::new(Vec::new());
+        vhdx.export_raw_to(&mut raw).unwrap();
+
+        assert!(raw.into_inner() == expected_chain_content());
+    }
+
+    #[test]
+    fn should_stream_chain_without_seeking() {
+        let (parent, child) = chain();
+        let parent = Vhdx::from_reader(parent).unwrap();
+        let mut vhdx = Vhdx::from_reader(child)
+            .unwrap()
+            .with_parent(parent)
+            .unwrap();
+
+        // A Vec can't seek, like a pipe.
+        let mut raw: Vec<u8> = Vec::new();
+        let written = vhdx.export_raw_stream(&mut raw).unwrap();
+
+        assert_eq!(4 * BLOCK_SIZE as u64, written);
+        assert!(raw == expected_chain_content());
+    }
+
+    #[test]
+    fn should_import_raw_stream_of_unknown_size() {
+        // 3 blocks and 100 bytes, the second block only holds zeros.
+        let size = 3 * BLOCK_SIZE + 100;
+        let mut content = vec![0; size];
+        content[..BLOCK_SIZE].fill(0x11);
+        content[2 * BLOCK_SIZE..].fill(0x33);
+        let options = CreateOptions {
+            block_size: BLOCK_SIZE as u32,
+            ..CreateOptions::default()
+        };
+
+        // A slice can't seek, like stdin.
+        let image = import_raw_stream_to(&content[..], Cursor::new(Vec::new()), options).unwrap();
+
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        assert_eq!(
+            (size as u64).next_multiple_of(512),
+            vhdx.virtual_disk_size()
+        );
+        let states: Vec<BatEntryState> = (0..vhdx.meta_data.payload_blocks_count)
+            .map(|block| vhdx.payload_entry(block).unwrap().state())
+            .collect();
+        use BatEntryState::*;
+        assert_eq!(
+            vec![FullyPresent, NotPresent, FullyPresent, FullyPresent],
+            states
+        );
+        let mut imported = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut imported).unwrap();
+        assert!(imported[..size] == content[..]);
+        assert!(imported[size..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn should_import_empty_raw_stream() {
+        let image = import_raw_stream_to(
+            std::io::empty(),
+            Cursor::new(Vec::new()),
+            CreateOptions::default(),
+        )
+        .unwrap();
+
+        let vhdx = Vhdx::from_reader(image).unwrap();
+        assert_eq!(0, vhdx.virtual_disk_size());
+    }
+
+    #[test]
+    fn should_reject_parent_of_another_version() {
+        let mut locator = parent_locator("parent.vhdx");
+        locator.entries.insert(
+            ParentLocator::PARENT_LINKAGE.to_string(),
+            format!("{{{}}}", Uuid::from_u128(77)),
+        );
+        let child = differencing_image(4 * BLOCK_SIZE, locator, &[]);
+        let parent = Vhdx::from_reader(dynamic_image(4 * BLOCK_SIZE, &[])).unwrap();
+
+        let result = Vhdx::from_reader(child).unwrap().with_parent(parent);
+
+        assert!(matches!(
+            result,
+            Err(VhdxError::ParentLinkageError(linkage, parent))
+                if linkage == Uuid::from_u128(77) && parent == DATA_WRITE_GUID
+        ));
+    }
+
+    #[test]
+    fn should_export_sparse_file_of_chain_on_disk() {
+        let directory = TempDir::new("raw");
+        let (parent, child) = chain();
+        std::fs::write(directory.join("parent.vhdx"), parent.into_inner()).unwrap();
+        std::fs::write(directory.join("child.vhdx"), child.into_inner()).unwrap();
+
+        let mut vhdx = Vhdx::open_chain(&directory.join("child.vhdx")).unwrap();
+        vhdx.export_raw(&directory.join("disk.raw")).unwrap();
+
+        let raw = std::fs::read(directory.join("disk.raw")).unwrap();
+        #[cfg(target_os = "linux")]
+        let allocated = {
+            use std::os::unix::fs::MetadataExt;
+            std::fs::metadata(directory.join("disk.raw"))
+                .unwrap()
+                .blocks()
+                * 512
+        };
+
+        assert!(raw == expected_chain_content());
+        #[cfg(target_os = "linux")]
+        assert!(allocated < raw.len() as u64);
+    }
+
+    #[test]
+    fn should_read_data_runs_and_leave_holes_as_zeros() {
+        let directory = TempDir::new("data-runs");
+        let path = directory.join("sparse.raw");
+        let mut file = File::create(&path).unwrap();
+        file.set_len(4 * BLOCK_SIZE as u64).unwrap();
+        file.write_all(&[0x11; 4096]).unwrap();
+        file.seek(SeekFrom::Start(3 * BLOCK_SIZE as u64)).unwrap();
+        file.write_all(&[0x33; 4096]).unwrap();
+        drop(file);
+
+        let mut file = File::open(&path).unwrap();
+        let mut buffer = vec![0xFF; 4 * BLOCK_SIZE];
+        let found = read_data(&mut file, 0, &mut buffer).unwrap();
+
+        let mut expected = vec![0; 4 * BLOCK_SIZE];
+        expected[..4096].fill(0x11);
+        expected[3 * BLOCK_SIZE..][..4096].fill(0x33);
+        assert!(found);
+        assert!(buffer == expected);
+    }
+
+    #[test]
+    fn should_import_sparse_raw_image() {
+        let directory = TempDir::new("import");
+
+        // 7.5 MB with data in blocks 1 and 5, zeros written to block 6 and holes elsewhere.
+        let size = 7 * BLOCK_SIZE + BLOCK_SIZE / 2;
+        let mut content = vec![0; size];
+        content[BLOCK_SIZE + 10..BLOCK_SIZE + 20].fill(0x11);
+        content[5 * BLOCK_SIZE..6 * BLOCK_SIZE].fill(0x55);
+        content[size - 1] = 0x77;
+        let mut raw = File::create(directory.join("disk.raw")).unwrap();
+        raw.set_len(size as u64).unwrap();
+        for range in [
+            BLOCK_SIZE..2 * BLOCK_SIZE,
+            5 * BLOCK_SIZE..7 * BLOCK_SIZE,
+            size - 1..size,
+        ] {
+            raw.seek(SeekFrom::Start(range.start as u64)).unwrap();
+            raw.write_all(&content[range]).unwrap();
+        }
+        drop(raw);
+
+        let options = CreateOptions {
+            block_size: BLOCK_SIZE as u32,
+            logical_sector_size: SectorSize::Sector4096,
+            zero_block_state: BatEntryState::Zero,
+            ..CreateOptions::default()
+        };
+        let mut vhdx = Vhdx::import_raw(
+            &directory.join("disk.raw"),
+            &directory.join("disk.vhdx"),
+            options,
+        )
+        .unwrap();
+
+        let states: Vec<BatEntryState> = (0..vhdx.meta_data.payload_blocks_count)
+            .map(|block| vhdx.payload_entry(block).unwrap().state())
+            .collect();
+        let mut imported = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut imported).unwrap();
+
+        use BatEntryState::*;
+        assert_eq!(
+            vec![
+                Zero,
+                FullyPresent,
+                Zero,
+                Zero,
+                Zero,
+                FullyPresent,
+                Zero,
+                FullyPresent
+            ],
+            states
+        );
+        assert_eq!(SectorSize::Sector4096, vhdx.meta_data.logical_sector_size);
+        assert_eq!(size.next_multiple_of(4096), imported.len());
+        assert!(imported[..size] == content[..]);
+    }
+}
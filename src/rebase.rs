@@ -0,0 +1,429 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path};
+
+use crate::{
+    bat::{payload_bat_index, BatEntry, BatEntryState},
+    error::VhdxError,
+    image_store::{FileSystem, ImageStore},
+    meta_data::{MetaData, ParentLocator},
+    parent_resolver::ParentResolver,
+    vhdx::Vhdx,
+    vhdx_header::KnowRegion,
+    SyncData,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebaseMode {
+    // Copies into the child whatever reads differently through the new parent, so the child
+    // reads the same before and after.
+    Safe,
+    // Only points the child to the new parent, for a parent known to hold the same data, such as
+    // a copy of the old one.
+    Unsafe,
+}
+
+impl Vhdx {
+    // Rebases the differencing disk at the path onto the disk at the new parent path and opens it
+    // with its new chain. The locator gets the path of the new parent relative to the child and
+    // its absolute path when that is a Windows one. The old parent only has to be found in safe
+    // mode.
+    pub fn rebase(
+        path: &impl AsRef<Path>,
+        new_parent_path: &impl AsRef<Path>,
+        mode: RebaseMode,
+    ) -> Result<Vhdx, VhdxError> {
+        Vhdx::rebase_in(
+            &FileSystem,
+            path,
+            new_parent_path,
+            &ParentResolver::default(),
+            mode,
+        )
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Write + Seek + SyncData,
+{
+    // The same for a disk of the store, both parents being found in the same store.
+    pub fn rebase_in<S>(
+        store: &S,
+        path: &impl AsRef<Path>,
+        new_parent_path: &impl AsRef<Path>,
+        resolver: &ParentResolver,
+        mode: RebaseMode,
+    ) -> Result<Self, VhdxError>
+    where
+        S: ImageStore<Image = T>,
+    {
+        let path = path.as_ref();
+        let new_parent_path = store.canonicalize(new_parent_path.as_ref())?;
+
+        let mut child = match mode {
+            RebaseMode::Safe => Vhdx::open_in(store, &path, resolver)?,
+            RebaseMode::Unsafe => {
+                let mut child = Vhdx::from_reader(store.open(path, true)?)?;
+                child.replay_log()?;
+                child
+            }
+        };
+        let mut new_parent = Vhdx::open_read_only_in(store, &new_parent_path, resolver)?;
+
+        let directory = store.canonicalize(path)?;
+        let directory = directory.parent().unwrap_or(Path::new(""));
+        let relative_path = relative_path(directory, &new_parent_path);
+        child.rebase_onto(
+            &mut new_parent,
+            &relative_path,
+            &new_parent_path.to_string_lossy(),
+            mode,
+        )?;
+
+        drop(child);
+        Vhdx::open_in(store, &path, resolver)
+    }
+
+    // Points this differencing disk to a new parent, found at the given relative and absolute
+    // paths. In safe mode the old parent has to be attached. Returns the number of blocks copied
+    // into the child.
+    //
+    // Copied blocks are written to space nothing points to before their BAT entries are
+    // committed through the log, the space of a partially present block they replace staying in
+    // the file until it is compacted. The locator is committed last, so a crash leaves the child
+    // reading the same through its old parent. The absolute path is only stored when it is a
+    // Windows one, which is what the locator holds.
+    pub fn rebase_onto<P>(
+        &mut self,
+        new_parent: &mut Vhdx<P>,
+        relative_path: &str,
+        absolute_path: &str,
+        mode: RebaseMode,
+    ) -> Result<u64, VhdxError>
+    where
+        P: Read + Seek,
+    {
+        let mut locator = self
+            .meta_data
+            .parent_locator
+            .clone()
+            .ok_or(VhdxError::MissingMetaDataItem("Parent Locator"))?;
+        if new_parent.meta_data.logical_sector_size != self.meta_data.logical_sector_size {
+            return Err(VhdxError::IncompatibleParent("logical sector size"));
+        }
+
+        let copied_blocks = match mode {
+            RebaseMode::Safe => self.copy_differing_blocks(new_parent)?,
+            RebaseMode::Unsafe => 0,
+        };
+
+        // Paths and linkages of the old parent mean nothing for the new one.
+        locator.entries.retain(|key, _| {
+            ![
+                ParentLocator::PARENT_LINKAGE2,
+                ParentLocator::VOLUME_PATH,
+                ParentLocator::ABSOLUTE_WIN32_PATH,
+            ]
+            .contains(&key.as_str())
+        });
+        let linkage = format!("{{{}}}", new_parent.header().data_write_guid());
+        locator
+            .entries
+            .insert(ParentLocator::PARENT_LINKAGE.to_string(), linkage);
+        locator.entries.insert(
+            ParentLocator::RELATIVE_PATH.to_string(),
+            relative_path.to_string(),
+        );
+        if is_absolute_win32_path(absolute_path) {
+            locator.entries.insert(
+                ParentLocator::ABSOLUTE_WIN32_PATH.to_string(),
+                absolute_path.to_string(),
+            );
+        }
+        self.write_parent_locator(&locator)?;
+
+        // The old parent no longer applies, the caller attaches the new one.
+        self.parent = None;
+        Ok(copied_blocks)
+    }
+
+    fn copy_differing_blocks<P>(&mut self, new_parent: &mut Vhdx<P>) -> Result<u64, VhdxError>
+    where
+        P: Read + Seek,
+    {
+        if self.parent.is_none() {
+            return Err(VhdxError::ParentNotFound(
+                "the old parent has to be attached for a safe rebase".to_string(),
+            ));
+        }
+        let block_size = self.block_size();
+        let size = self.virtual_disk_size();
+        let chunk_ratio = self.meta_data.chunk_ratio;
+
+        let mut updates = Vec::new();
+        for block in 0..self.meta_data.payload_blocks_count {
+            let start = block * block_size;
+            let length = block_size.min(size - start);
+            let own_ranges = self.virtual_disk().own_ranges(block)?;
+
+            // Only the ranges the child leaves to its parent can read differently.
+            let mut differs = false;
+            let mut position = start;
+            for (offset, run) in own_ranges
+                .iter()
+                .copied()
+                .chain(std::iter::once((start + length, 0)))
+            {
+                let old_parent = self.parent.as_deref_mut().unwrap();
+                if offset > position
+                    && read_disk(old_parent, position, offset - position)?
+                        != read_disk(new_parent, position, offset - position)?
+                {
+                    differs = true;
+                    break;
+                }
+                position = offset + run;
+            }
+            if !differs {
+                continue;
+            }
+
+            let mut data = vec![0; block_size as usize];
+            let mut disk = self.virtual_disk();
+            disk.seek(SeekFrom::Start(start))?;
+            disk.read_exact(&mut data[..length as usize])?;
+
+            let destination = self.file.seek(SeekFrom::End(0))?.next_multiple_of(Vhdx::MB);
+            self.file.seek(SeekFrom::Start(destination))?;
+            self.file.write_all(&data)?;
+            updates.push((
+                payload_bat_index(block, chunk_ratio),
+                BatEntry::new(
+                    BatEntryState::FullyPresent,
+                    (destination / Vhdx::MB) as usize,
+                ),
+            ));
+        }
+
+        self.file.sync_data()?;
+        if !updates.is_empty() {
+            self.update_bat(&updates)?;
+        }
+        Ok(updates.len() as u64)
+    }
+
+    // Replaces the parent locator item through the log. The item stays where it is when the new
+    // one fits, otherwise it goes after the last item of the metadata region.
+    pub(crate) fn write_parent_locator(
+        &mut self,
+        locator: &ParentLocator,
+    ) -> Result<(), VhdxError> {
+        let region = self
+            .region(KnowRegion::MetaData)
+            .ok_or(VhdxError::MissingKnownRegion("MetaData"))?
+            .clone();
+        let table = self.read_at(region.file_offset, 64 * Vhdx::KB)?;
+        let entry_count = u16::from_le_bytes([table[10], table[11]]) as usize;
+
+        let item = locator.to_bytes();
+        let mut index = None;
+        let mut items_end = 64 * Vhdx::KB;
+        for i in 0..entry_count.min(2047) {
+            let entry = &table[32 + i * 32..64 + i * 32];
+            let offset = u32::from_le_bytes(entry[16..20].try_into().unwrap()) as u64;
+            let length = u32::from_le_bytes(entry[20..24].try_into().unwrap()) as u64;
+            items_end = items_end.max(offset + length);
+            if entry[..16] == MetaData::PARENT_LOCATOR.to_bytes_le() {
+                index = Some((i, offset, length));
+            }
+        }
+        let (index, offset, length) =
+            index.ok_or(VhdxError::MissingMetaDataItem("Parent Locator"))?;
+
+        let offset = match item.len() as u64 <= length {
+            true => offset,
+            false => items_end,
+        };
+        if offset + item.len() as u64 > region.length as u64 {
+            return Err(VhdxError::MetaDataRegionFull("Parent Locator"));
+        }
+
+        let mut entry = (offset as u32).to_le_bytes().to_vec();
+        entry.extend_from_slice(&(item.len() as u32).to_le_bytes());
+        let pages = self.patched_pages(&[
+            (region.file_offset + offset, item),
+            (region.file_offset + 32 + index as u64 * 32 + 16, entry),
+        ])?;
+        self.write_through_log(&pages)
+    }
+}
+
+// Reads a range of the virtual disk, the part past its end reading as zeros the way a parent
+// smaller than its child does.
+fn read_disk<T>(vhdx: &mut Vhdx<T>, offset: u64, length: u64) -> Result<Vec<u8>, VhdxError>
+where
+    T: Read + Seek,
+{
+    let mut data = vec![0; length as usize];
+    let size = vhdx.virtual_disk_size();
+    let length = (data.len() as u64).min(size.saturating_sub(offset)) as usize;
+    let mut disk = vhdx.virtual_disk();
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.read_exact(&mut data[..length])?;
+    Ok(data)
+}
+
+// The path of the target relative to the directory, with Windows separators the way Hyper-V
+// stores it.
+pub(crate) fn relative_path(directory: &Path, target: &Path) -> String {
+    let directory: Vec<Component> = directory.components().collect();
+    let target: Vec<Component> = target.components().collect();
+    let common = directory
+        .iter()
+        .zip(&target)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut parts: Vec<String> = vec!["..".to_string(); directory.len() - common];
+    parts.extend(
+        target[common..]
+            .iter()
+            .map(|part| part.as_os_str().to_string_lossy().into_owned()),
+    );
+    parts.join("\\")
+}
+
+// Whether the path is an absolute Windows one, on a drive letter or a UNC share.
+pub(crate) fn is_absolute_win32_path(path: &str) -> bool {
+    match path.as_bytes() {
+        [drive, b':', b'\\' | b'/', ..] => drive.is_ascii_alphabetic(),
+        [b'\\', b'\\', ..] => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::test_utils::{differencing_image, dynamic_image, parent_locator, BLOCK_SIZE};
+    use pretty_assertions::assert_eq;
+
+    fn read_all<T: Read + Seek>(vhdx: &mut Vhdx<T>) -> Vec<u8> {
+        let mut data = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    // A new parent that differs from the old one in block 1, with its own data write guid.
+    fn new_parent() -> Vhdx<Cursor<Vec<u8>>> {
+        let image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0x11), (1, 0x33)]);
+        let mut parent = Vhdx::from_reader(image).unwrap();
+        let header = parent
+            .header()
+            .successor(Uuid::nil())
+            .with_new_data_write_guid();
+        parent.write_header(header).unwrap();
+        parent
+    }
+
+    fn child() -> Vhdx<Cursor<Vec<u8>>> {
+        let old_parent = dynamic_image(4 * BLOCK_SIZE, &[(0, 0x11), (1, 0x22)]);
+        let child = differencing_image(
+            4 * BLOCK_SIZE,
+            parent_locator("old.vhdx"),
+            &[(2, 0xAA, None), (1, 0xBB, Some(vec![0x0F]))],
+        );
+        Vhdx::from_reader(child)
+            .unwrap()
+            .with_parent(Vhdx::from_reader(old_parent).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn should_keep_the_child_view_in_safe_mode() {
+        let mut child = child();
+        let expected = read_all(&mut child);
+        let mut new_parent = new_parent();
+
+        let copied = child
+            .rebase_onto(
+                &mut new_parent,
+                "new.vhdx",
+                "/images/new.vhdx",
+                RebaseMode::Safe,
+            )
+            .unwrap();
+
+        assert_eq!(1, copied);
+        let mut rebased = Vhdx::from_reader(child.file)
+            .unwrap()
+            .with_parent(new_parent)
+            .unwrap();
+        let locator = rebased.meta_data.parent_locator.clone().unwrap();
+        assert_eq!(Some("new.vhdx"), locator.get(ParentLocator::RELATIVE_PATH));
+        assert_eq!(None, locator.get(ParentLocator::ABSOLUTE_WIN32_PATH));
+        assert!(read_all(&mut rebased) == expected);
+    }
+
+    #[test]
+    fn should_only_rewrite_the_locator_in_unsafe_mode() {
+        let mut child = child();
+        let mut new_parent = new_parent();
+        let bat_before: Vec<_> = child.bat_table.iter().map(BatEntry::file_offset).collect();
+
+        let copied = child
+            .rebase_onto(
+                &mut new_parent,
+                "new.vhdx",
+                r"D:\images\new.vhdx",
+                RebaseMode::Unsafe,
+            )
+            .unwrap();
+
+        assert_eq!(0, copied);
+        let rebased = Vhdx::from_reader(child.file).unwrap();
+        let bat_after: Vec<_> = rebased
+            .bat_table
+            .iter()
+            .map(BatEntry::file_offset)
+            .collect();
+        assert_eq!(bat_before, bat_after);
+        let locator = rebased.meta_data.parent_locator.clone().unwrap();
+        assert_eq!(
+            Some(new_parent.header().data_write_guid()),
+            locator.parent_linkage()
+        );
+        assert_eq!(
+            Some(r"D:\images\new.vhdx"),
+            locator.get(ParentLocator::ABSOLUTE_WIN32_PATH)
+        );
+        rebased.with_parent(new_parent).unwrap();
+    }
+
+    #[test]
+    fn should_only_take_windows_paths_as_absolute_win32_paths() {
+        assert!(is_absolute_win32_path(r"C:\images\parent.vhdx"));
+        assert!(is_absolute_win32_path(r"\\server\share\parent.vhdx"));
+        assert!(!is_absolute_win32_path("/images/parent.vhdx"));
+        assert!(!is_absolute_win32_path(r"..\parent.vhdx"));
+    }
+
+    #[test]
+    fn should_give_relative_paths_with_windows_separators() {
+        assert_eq!(
+            "..\\base\\parent.vhdx",
+            relative_path(
+                Path::new("/images/children"),
+                Path::new("/images/base/parent.vhdx")
+            )
+        );
+        assert_eq!(
+            "parent.vhdx",
+            relative_path(Path::new("/images"), Path::new("/images/parent.vhdx"))
+        );
+    }
+}
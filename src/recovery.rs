@@ -0,0 +1,835 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use uuid::Uuid;
+
+use crate::{
+    bat::{payload_bat_index, payload_block, BatEntry, BatEntryState},
+    error::VhdxError,
+    filesystem::{detect_filesystem, FileSystemType},
+    log::{Descriptor, Log, LogEntry},
+    parse_utils::{t_sign_u32, t_sign_u64},
+    partition::{read_partitions, PartitionReader},
+    vhdx::{check_sign_and_crc, Vhdx},
+    vhdx_header::{FileTypeIdentifier, Header, KnowRegion, RegionTable, VhdxHeader},
+    DeSerialise, Signature, Validation,
+};
+
+// A structure signature found while scanning a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureHit {
+    pub offset: u64,
+    pub signature: Signature,
+}
+
+#[derive(Debug)]
+pub struct RecoveryReport {
+    // Every signature found in the file, in file order.
+    pub hits: Vec<SignatureHit>,
+
+    // File offset of the header that the recovered file is based on. None when no header
+    // survived and a header without a log had to be synthesised.
+    pub header_offset: Option<u64>,
+
+    // File offset of the region table used to locate the metadata and the BAT.
+    pub region_table_offset: u64,
+}
+
+impl Vhdx {
+    // Opens a file whose headers are destroyed by scanning it for the structures that survived.
+    // The file is opened read only and the result is meant to get the data out, not to be
+    // modified.
+    pub fn recover(path: &impl AsRef<Path>) -> Result<(Self, RecoveryReport), VhdxError> {
+        let reader = File::open(path)?;
+        Vhdx::recover_from_reader(reader)
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    pub fn recover_from_reader(mut reader: T) -> Result<(Self, RecoveryReport), VhdxError> {
+        let hits = scan_signatures(&mut reader)?;
+
+        let fti = match hits.first() {
+            Some(SignatureHit {
+                offset: 0,
+                signature: Signature::Vhdxfile,
+            }) => {
+                reader.rewind()?;
+                FileTypeIdentifier::deserialize(&mut reader)?
+            }
+            _ => FileTypeIdentifier::new(Signature::Vhdxfile, String::new()),
+        };
+
+        let (region_table_offset, region_table) =
+            find_region_table(&mut reader, &hits)?.ok_or(VhdxError::NoRegionTable)?;
+
+        let (header_offset, header) = match find_header(&mut reader, &hits)? {
+            Some((offset, header)) => (Some(offset), header),
+            None => (None, synthesise_header(&mut reader, &hits, &region_table)?),
+        };
+
+        let vhdx_header = VhdxHeader::new(
+            fti,
+            header.clone(),
+            header,
+            region_table.clone(),
+            region_table,
+        );
+        let vhdx = Vhdx::load(reader, vhdx_header)?;
+
+        let report = RecoveryReport {
+            hits,
+            header_offset,
+            region_table_offset,
+        };
+        Ok((vhdx, report))
+    }
+}
+
+// Scans the file for structure signatures. Headers and region tables are aligned to 64 KB while
+// the log and metadata regions are aligned to 1 MB, only looking at these alignments keeps guest
+// data that happens to contain a signature from being picked up.
+pub fn scan_signatures<T>(reader: &mut T) -> Result<Vec<SignatureHit>, VhdxError>
+where
+    T: Read + Seek,
+{
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    let mut hits = Vec::new();
+
+    let mut offset = 0;
+    while offset + 8 <= file_size {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut buffer = [0; 8];
+        reader.read_exact(&mut buffer)?;
+
+        let (_, long) = t_sign_u64(&buffer)?;
+        let (_, short) = t_sign_u32(&buffer)?;
+        let on_mb = offset % Vhdx::MB == 0;
+
+        let signature = match (long, short) {
+            (Signature::Vhdxfile, _) if offset == 0 => Some(Signature::Vhdxfile),
+            (Signature::MetaData, _) if on_mb => Some(Signature::MetaData),
+            (_, Signature::Loge) if on_mb => Some(Signature::Loge),
+            (_, signature @ (Signature::Head | Signature::Regi)) => Some(signature),
+            _ => None,
+        };
+
+        if let Some(signature) = signature {
+            hits.push(SignatureHit { offset, signature });
+        }
+
+        offset += 64 * Vhdx::KB;
+    }
+
+    Ok(hits)
+}
+
+// Picks the valid header with the highest sequence number among all found headers.
+fn find_header<T>(reader: &mut T, hits: &[SignatureHit]) -> Result<Option<(u64, Header)>, VhdxError>
+where
+    T: Read + Seek,
+{
+    let mut current: Option<(u64, Header)> = None;
+    for hit in hits.iter().filter(|h| h.signature == Signature::Head) {
+        reader.seek(SeekFrom::Start(hit.offset))?;
+        let header = match Header::deserialize(reader) {
+            Ok(header) => header,
+            Err(_) => continue,
+        };
+
+        if check_sign_and_crc(&header).is_err() || header.validate().is_err() {
+            continue;
+        }
+
+        if current
+            .as_ref()
+            .map(|(_, c)| header.sequence_number() > c.sequence_number())
+            .unwrap_or(true)
+        {
+            current = Some((hit.offset, header));
+        }
+    }
+    Ok(current)
+}
+
+// Without a header the log is located through the first valid log entry found. The log region
+// is 1 MB aligned and the entry is taken as its start, its log guid as the one of the header, and
+// the log as running up to the next region. When no entry is found, or the log they make up
+// doesn't hold together, the header describes an empty log which keeps the log from being read or
+// replayed.
+fn synthesise_header<T>(
+    reader: &mut T,
+    hits: &[SignatureHit],
+    region_table: &RegionTable,
+) -> Result<Header, VhdxError>
+where
+    T: Read + Seek,
+{
+    let mut log = (Uuid::nil(), 0, 0);
+    for hit in hits.iter().filter(|h| h.signature == Signature::Loge) {
+        reader.seek(SeekFrom::Start(hit.offset))?;
+        let entry = match LogEntry::deserialize(reader) {
+            Ok(entry) if entry.validate().is_ok() => entry,
+            _ => continue,
+        };
+
+        let log_end = region_table
+            .table_entries
+            .values()
+            .map(|entry| entry.file_offset)
+            .filter(|offset| *offset > hit.offset)
+            .min()
+            .unwrap_or(hit.offset + Vhdx::MB);
+        let log_length = ((log_end - hit.offset) / Vhdx::MB * Vhdx::MB).min(u32::MAX as u64);
+
+        reader.seek(SeekFrom::Start(hit.offset))?;
+        let mut log_region = Vec::with_capacity(log_length as usize);
+        (&mut *reader)
+            .take(log_length)
+            .read_to_end(&mut log_region)?;
+        log_region.resize(log_length as usize, 0);
+        if log_length > 0 && Log::new(&log_region, entry.header.log_guid).is_ok() {
+            log = (entry.header.log_guid, hit.offset, log_length as u32);
+        }
+        break;
+    }
+
+    let (log_guid, log_offset, log_length) = log;
+    let mut header = Header::new(
+        Signature::Head,
+        0,
+        0,
+        Uuid::nil(),
+        Uuid::nil(),
+        log_guid,
+        0,
+        1,
+        log_length,
+        log_offset,
+    );
+    header.update_checksum();
+    Ok(header)
+}
+
+// Picks the first valid region table, preferring one whose metadata region still starts with a
+// metadata signature.
+fn find_region_table<T>(
+    reader: &mut T,
+    hits: &[SignatureHit],
+) -> Result<Option<(u64, RegionTable)>, VhdxError>
+where
+    T: Read + Seek,
+{
+    let mut fallback = None;
+    for hit in hits.iter().filter(|h| h.signature == Signature::Regi) {
+        reader.seek(SeekFrom::Start(hit.offset))?;
+        let region_table = match RegionTable::deserialize(reader) {
+            Ok(region_table) if region_table.validate().is_ok() => region_table,
+            _ => continue,
+        };
+
+        let meta_data_found = region_table
+            .table_entries
+            .get(&KnowRegion::MetaData)
+            .map(|entry| {
+                hits.iter()
+                    .any(|h| h.offset == entry.file_offset && h.signature == Signature::MetaData)
+            })
+            .unwrap_or(false);
+
+        if meta_data_found {
+            return Ok(Some((hit.offset, region_table)));
+        }
+
+        if fallback.is_none() {
+            fallback = Some((hit.offset, region_table));
+        }
+    }
+    Ok(fallback)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Evidence {
+    // The entry was part of a BAT page recorded by the log entry with this sequence number.
+    Log(u64),
+
+    // The block starts with a MBR or a protective MBR followed by a GPT header, which only the
+    // first block of the disk does.
+    PartitionTable,
+
+    // The block holds the backup GPT header at the end of the disk.
+    BackupGptHeader,
+
+    // A partition listed in the partition table starts in this block with this filesystem.
+    Superblock(FileSystemType),
+
+    // Nothing points at the block, it was placed after the block allocated before it in the file.
+    AllocationOrder,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecoveredBlock {
+    pub block: u64,
+    pub state: BatEntryState,
+    pub file_offset: u64,
+    pub confidence: Confidence,
+    pub evidence: Evidence,
+}
+
+#[derive(Debug, Default)]
+pub struct BatReport {
+    // Every payload block given a location, ordered by block number. Blocks missing from the
+    // report are not present.
+    pub blocks: Vec<RecoveredBlock>,
+
+    // File offsets of chunks holding data that could not be given a place on the virtual disk.
+    pub unplaced: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatRecoveryOptions {
+    // Use the BAT pages recorded in the log.
+    pub use_log: bool,
+
+    // Look for partition tables and filesystem superblocks in the payload.
+    pub guest_hints: bool,
+}
+
+impl Default for BatRecoveryOptions {
+    fn default() -> Self {
+        Self {
+            use_log: true,
+            guest_hints: true,
+        }
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    // Rebuilds a best effort BAT for a file whose BAT region is lost but whose payload survived.
+    // The rebuilt BAT replaces the loaded one in memory only, the file itself is never written.
+    pub fn reconstruct_bat(&mut self, options: BatRecoveryOptions) -> Result<BatReport, VhdxError> {
+        let blocks_count = self.meta_data.payload_blocks_count;
+
+        let mut placed = BTreeMap::new();
+        if options.use_log {
+            self.blocks_from_log(&mut placed)?;
+        }
+
+        let mut unplaced = self.payload_chunks(&placed)?;
+
+        if options.guest_hints {
+            self.blocks_from_guest(&mut placed, &mut unplaced)?;
+        }
+
+        // Whatever is left is placed after the block allocated right before it in the file, which
+        // is what happens when a guest writes the disk front to back.
+        let mut leftover = Vec::new();
+        for chunk in unplaced {
+            let previous = placed
+                .values()
+                .filter(|b: &&RecoveredBlock| b.file_offset < chunk)
+                .max_by_key(|b| b.file_offset)
+                .map(|b| b.block + 1)
+                .unwrap_or(0);
+
+            match (previous..blocks_count).find(|block| !placed.contains_key(block)) {
+                Some(block) => {
+                    placed.insert(
+                        block,
+                        RecoveredBlock {
+                            block,
+                            state: BatEntryState::FullyPresent,
+                            file_offset: chunk,
+                            confidence: Confidence::Low,
+                            evidence: Evidence::AllocationOrder,
+                        },
+                    );
+                }
+                None => leftover.push(chunk),
+            }
+        }
+
+        self.set_bat(&placed);
+        Ok(BatReport {
+            blocks: placed.into_values().collect(),
+            unplaced: leftover,
+        })
+    }
+
+    // Points the BAT at the placed blocks, the others are not present.
+    fn set_bat(&mut self, placed: &BTreeMap<u64, RecoveredBlock>) {
+        let mut bat_table = vec![
+            BatEntry::new(BatEntryState::NotPresent, 0);
+            self.meta_data.total_bat_entries() as usize
+        ];
+        for block in placed.values() {
+            if let Some(entry) =
+                bat_table.get_mut(payload_bat_index(block.block, self.meta_data.chunk_ratio))
+            {
+                *entry = BatEntry::new(block.state, (block.file_offset / Vhdx::MB) as usize);
+            }
+        }
+        self.bat_table = bat_table;
+    }
+
+    // Replays every BAT page recorded in the log in sequence order, so later pages win. Only the
+    // entries of the log the newest valid entry belongs to are used, the others are left overs
+    // of logs replayed long ago.
+    fn blocks_from_log(&self, placed: &mut BTreeMap<u64, RecoveredBlock>) -> Result<(), VhdxError> {
+        let bat = self
+            .region(KnowRegion::Bat)
+            .ok_or(VhdxError::MissingKnownRegion("Bat"))?;
+        let bat_range = bat.file_offset..bat.file_offset + bat.length as u64;
+        let chunk_ratio = self.meta_data.chunk_ratio;
+
+        let mut entries: Vec<&LogEntry> = self
+            .log
+            .log_entries
+            .iter()
+            .filter(|e| e.header.signature == Signature::Loge && e.validate().is_ok())
+            .collect();
+        entries.sort_by_key(|e| e.header.seq_number);
+        let Some(log_guid) = entries.last().map(|e| e.header.log_guid) else {
+            return Ok(());
+        };
+        entries.retain(|e| e.header.log_guid == log_guid);
+
+        for entry in entries {
+            for descriptor in entry.descriptors() {
+                let Descriptor::Data(desc) = descriptor else {
+                    continue;
+                };
+                if !bat_range.contains(&desc.file_offset()) {
+                    continue;
+                }
+                let Some(page) = desc.page() else {
+                    continue;
+                };
+
+                let first_index = (desc.file_offset() - bat.file_offset) / 8;
+                for (i, raw) in page.chunks_exact(8).enumerate() {
+                    let Some(block) = payload_block(first_index + i as u64, chunk_ratio) else {
+                        continue;
+                    };
+                    if block >= self.meta_data.payload_blocks_count {
+                        continue;
+                    }
+
+                    let bat_entry = BatEntry::deserialize(&mut Cursor::new(raw))?;
+                    match bat_entry.state() {
+                        BatEntryState::FullyPresent | BatEntryState::PartiallyPresent => {
+                            placed.insert(
+                                block,
+                                RecoveredBlock {
+                                    block,
+                                    state: bat_entry.state(),
+                                    file_offset: bat_entry.file_offset(),
+                                    confidence: Confidence::High,
+                                    evidence: Evidence::Log(entry.header.seq_number),
+                                },
+                            );
+                        }
+                        _ => {
+                            placed.remove(&block);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Walks the payload area of the file in block size steps and returns the offsets of the
+    // chunks holding any data. All zero chunks read the same as unallocated blocks, so they are
+    // left out.
+    fn payload_chunks(
+        &mut self,
+        placed: &BTreeMap<u64, RecoveredBlock>,
+    ) -> Result<Vec<u64>, VhdxError> {
+        let block_size = self.block_size();
+        let header = self.header();
+        let mut payload_start = header.log_offset + header.log_length as u64;
+        for region in [KnowRegion::Bat, KnowRegion::MetaData] {
+            if let Some(entry) = self.region(region) {
+                payload_start = payload_start.max(entry.file_offset + entry.length as u64);
+            }
+        }
+        payload_start = payload_start.div_ceil(Vhdx::MB) * Vhdx::MB;
+
+        let mut claimed: Vec<u64> = placed.values().map(|b| b.file_offset).collect();
+        claimed.sort_unstable();
+
+        let file_size = self.file.seek(SeekFrom::End(0))?;
+        let mut chunks = Vec::new();
+        let mut offset = payload_start;
+        while offset < file_size {
+            let overlapping = claimed
+                .iter()
+                .find(|c| **c < offset + block_size && **c + block_size > offset);
+            if let Some(claimed) = overlapping {
+                offset = claimed + block_size;
+                continue;
+            }
+
+            let length = block_size.min(file_size - offset);
+            if !self.is_zero(offset, length)? {
+                chunks.push(offset);
+            }
+            offset += block_size;
+        }
+        Ok(chunks)
+    }
+
+    fn blocks_from_guest(
+        &mut self,
+        placed: &mut BTreeMap<u64, RecoveredBlock>,
+        unplaced: &mut Vec<u64>,
+    ) -> Result<(), VhdxError> {
+        let block_size = self.block_size();
+        let sector_size = self.meta_data.logical_sector_size as u64;
+        let disk_size = self.virtual_disk_size();
+
+        if !placed.contains_key(&0) {
+            for (i, chunk) in unplaced.iter().enumerate() {
+                let sectors = self.read_at(*chunk, 2 * sector_size)?;
+                if has_partition_table(&sectors, sector_size) {
+                    placed.insert(0, guest_block(0, *chunk, Evidence::PartitionTable));
+                    unplaced.remove(i);
+                    break;
+                }
+            }
+        }
+
+        // The backup GPT header lives in the last sector of the disk
+        let Some(last_sector) = disk_size.checked_sub(sector_size) else {
+            return Ok(());
+        };
+        let last_block = last_sector / block_size;
+        if !placed.contains_key(&last_block) {
+            for (i, chunk) in unplaced.iter().enumerate() {
+                let sector = self.read_at(chunk + last_sector % block_size, sector_size)?;
+                if sector.starts_with(GPT_SIGNATURE) {
+                    placed.insert(
+                        last_block,
+                        guest_block(last_block, *chunk, Evidence::BackupGptHeader),
+                    );
+                    unplaced.remove(i);
+                    break;
+                }
+            }
+        }
+
+        // The partition table is read through the blocks placed so far, and every chunk left is
+        // tried as the block a partition starts in until a filesystem shows up there.
+        if !placed.contains_key(&0) {
+            return Ok(());
+        }
+        self.set_bat(placed);
+        let partitions = read_partitions(&mut self.virtual_disk(), sector_size).unwrap_or_default();
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        for partition in partitions {
+            let block = partition.start / block_size;
+            if block >= self.meta_data.payload_blocks_count || placed.contains_key(&block) {
+                continue;
+            }
+            for (i, chunk) in unplaced.iter().enumerate() {
+                self.bat_table[payload_bat_index(block, chunk_ratio)] =
+                    BatEntry::new(BatEntryState::FullyPresent, (chunk / Vhdx::MB) as usize);
+                let filesystem =
+                    detect_filesystem(&mut PartitionReader::new(self.virtual_disk(), &partition))
+                        .ok()
+                        .flatten();
+                if let Some(filesystem) = filesystem {
+                    placed.insert(
+                        block,
+                        guest_block(
+                            block,
+                            *chunk,
+                            Evidence::Superblock(filesystem.filesystem_type),
+                        ),
+                    );
+                    unplaced.remove(i);
+                    break;
+                }
+            }
+            self.set_bat(placed);
+        }
+        Ok(())
+    }
+
+    fn is_zero(&mut self, offset: u64, length: u64) -> Result<bool, VhdxError> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut buffer = vec![0; Vhdx::MB.min(length) as usize];
+        let mut remaining = length;
+        while remaining > 0 {
+            let read = self
+                .file
+                .read(&mut buffer[..remaining.min(Vhdx::MB) as usize])?;
+            if read == 0 {
+                break;
+            }
+            if buffer[..read].iter().any(|b| *b != 0) {
+                return Ok(false);
+            }
+            remaining -= read as u64;
+        }
+        Ok(true)
+    }
+}
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+fn guest_block(block: u64, file_offset: u64, evidence: Evidence) -> RecoveredBlock {
+    RecoveredBlock {
+        block,
+        state: BatEntryState::FullyPresent,
+        file_offset,
+        confidence: Confidence::Medium,
+        evidence,
+    }
+}
+
+fn has_partition_table(sectors: &[u8], sector_size: u64) -> bool {
+    let sector_size = sector_size as usize;
+    let mbr = sectors.len() >= 512 && sectors[510..512] == [0x55, 0xAA];
+    let gpt = sectors.len() >= sector_size + 8 && sectors[sector_size..].starts_with(GPT_SIGNATURE);
+    mbr || gpt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        dynamic_image, page_log_entry, wipe, write_log_entry, BAT_OFFSET, BLOCK_SIZE, LOG_GUID,
+        LOG_OFFSET, META_DATA_OFFSET, PAYLOAD_OFFSET,
+    };
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_find_signatures() {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0xAA)]);
+
+        let hits = scan_signatures(&mut image).unwrap();
+        let signatures: Vec<(u64, Signature)> =
+            hits.into_iter().map(|h| (h.offset, h.signature)).collect();
+
+        assert_eq!(
+            vec![
+                (0, Signature::Vhdxfile),
+                (64 * Vhdx::KB, Signature::Head),
+                (128 * Vhdx::KB, Signature::Head),
+                (192 * Vhdx::KB, Signature::Regi),
+                (256 * Vhdx::KB, Signature::Regi),
+                (META_DATA_OFFSET, Signature::MetaData),
+            ],
+            signatures
+        );
+    }
+
+    #[test]
+    fn should_recover_when_both_headers_are_destroyed() {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0xAA), (3, 0xBB)]);
+        wipe(&mut image, 64 * Vhdx::KB, 128 * Vhdx::KB);
+
+        assert!(matches!(
+            Vhdx::from_reader(image.clone()),
+            Err(VhdxError::VhdxHeaderError)
+        ));
+
+        let (mut vhdx, report) = Vhdx::recover_from_reader(image).unwrap();
+        assert_eq!(None, report.header_offset);
+        assert_eq!(192 * Vhdx::KB, report.region_table_offset);
+
+        let mut content = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut content).unwrap();
+        assert!(content[..BLOCK_SIZE].iter().all(|b| *b == 0xAA));
+        assert!(content[BLOCK_SIZE..3 * BLOCK_SIZE].iter().all(|b| *b == 0));
+        assert!(content[3 * BLOCK_SIZE..].iter().all(|b| *b == 0xBB));
+    }
+
+    #[test]
+    fn should_locate_the_log_without_a_header() {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0xAA)]);
+        write_log_entry(
+            &mut image,
+            0,
+            &page_log_entry(5, PAYLOAD_OFFSET, &[0xEE; 4096]),
+        );
+        wipe(&mut image, 64 * Vhdx::KB, 128 * Vhdx::KB);
+
+        let (mut vhdx, report) = Vhdx::recover_from_reader(image).unwrap();
+        let header = vhdx.header().clone();
+
+        assert_eq!(None, report.header_offset);
+        assert_eq!(LOG_GUID, header.log_guid);
+        assert_eq!(LOG_OFFSET, header.log_offset);
+        assert_eq!(Vhdx::MB as u32, header.log_length);
+        assert!(vhdx.replay_log().unwrap());
+        let mut content = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut content).unwrap();
+        assert!(content[..4096].iter().all(|b| *b == 0xEE));
+        assert!(content[4096..BLOCK_SIZE].iter().all(|b| *b == 0xAA));
+    }
+
+    #[test]
+    fn should_use_surviving_header_and_region_table() {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[(1, 0xCC)]);
+        wipe(&mut image, 64 * Vhdx::KB, 64 * Vhdx::KB);
+        wipe(&mut image, 192 * Vhdx::KB, 64 * Vhdx::KB);
+
+        let (vhdx, report) = Vhdx::recover_from_reader(image).unwrap();
+
+        assert_eq!(Some(128 * Vhdx::KB), report.header_offset);
+        assert_eq!(256 * Vhdx::KB, report.region_table_offset);
+        assert_eq!(4 * BLOCK_SIZE as u64, vhdx.virtual_disk_size());
+    }
+
+    #[test]
+    fn should_fail_without_region_table() {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[]);
+        wipe(&mut image, 64 * Vhdx::KB, 256 * Vhdx::KB);
+
+        assert!(matches!(
+            Vhdx::recover_from_reader(image),
+            Err(VhdxError::NoRegionTable)
+        ));
+    }
+
+    fn block_offsets(report: &BatReport) -> Vec<(u64, u64, Confidence)> {
+        report
+            .blocks
+            .iter()
+            .map(|b| (b.block, b.file_offset, b.confidence))
+            .collect()
+    }
+
+    #[test]
+    fn should_rebuild_bat_from_allocation_order() {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0xAA), (1, 0xBB), (2, 0xCC)]);
+        wipe(&mut image, BAT_OFFSET, Vhdx::MB);
+
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        let report = vhdx.reconstruct_bat(BatRecoveryOptions::default()).unwrap();
+
+        let chunk = |i: u64| PAYLOAD_OFFSET + i * BLOCK_SIZE as u64;
+        assert_eq!(
+            vec![
+                (0, chunk(0), Confidence::Low),
+                (1, chunk(1), Confidence::Low),
+                (2, chunk(2), Confidence::Low),
+            ],
+            block_offsets(&report)
+        );
+        assert!(report.unplaced.is_empty());
+
+        let mut content = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut content).unwrap();
+        assert!(content[BLOCK_SIZE..2 * BLOCK_SIZE]
+            .iter()
+            .all(|b| *b == 0xBB));
+    }
+
+    #[test]
+    fn should_prefer_bat_pages_recorded_in_the_log() {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[(2, 0xAA), (0, 0xBB)]);
+        let bat_page =
+            image.get_ref()[BAT_OFFSET as usize..(BAT_OFFSET + 4 * Vhdx::KB) as usize].to_vec();
+        wipe(&mut image, BAT_OFFSET, Vhdx::MB);
+
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        vhdx.log
+            .log_entries
+            .push(page_log_entry(7, BAT_OFFSET, &bat_page));
+        let report = vhdx.reconstruct_bat(BatRecoveryOptions::default()).unwrap();
+
+        let chunk = |i: u64| PAYLOAD_OFFSET + i * BLOCK_SIZE as u64;
+        assert_eq!(
+            vec![
+                (0, chunk(1), Confidence::High),
+                (2, chunk(0), Confidence::High),
+            ],
+            block_offsets(&report)
+        );
+        assert_eq!(Evidence::Log(7), report.blocks[0].evidence);
+    }
+
+    #[test]
+    fn should_only_use_the_log_of_the_newest_entry() {
+        let mut image = dynamic_image(1024 * BLOCK_SIZE, &[(2, 0xAA), (0, 0xBB)]);
+        let bat_page =
+            image.get_ref()[BAT_OFFSET as usize..(BAT_OFFSET + 4 * Vhdx::KB) as usize].to_vec();
+        wipe(&mut image, BAT_OFFSET, Vhdx::MB);
+
+        // An older log had block 600, in the next page of the BAT, at the place of block 2.
+        let mut stale_page = vec![0; 4 * Vhdx::KB as usize];
+        stale_page[(600 - 512) * 8..][..8].copy_from_slice(&bat_page[2 * 8..3 * 8]);
+        let mut stale = page_log_entry(3, BAT_OFFSET + 4 * Vhdx::KB, &stale_page);
+        stale.header.log_guid = Uuid::from_u128(0x5555);
+        stale.update_checksum();
+
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        vhdx.log.log_entries.push(stale);
+        vhdx.log
+            .log_entries
+            .push(page_log_entry(7, BAT_OFFSET, &bat_page));
+        let report = vhdx.reconstruct_bat(BatRecoveryOptions::default()).unwrap();
+
+        let chunk = |i: u64| PAYLOAD_OFFSET + i * BLOCK_SIZE as u64;
+        assert_eq!(
+            vec![
+                (0, chunk(1), Confidence::High),
+                (2, chunk(0), Confidence::High),
+            ],
+            block_offsets(&report)
+        );
+    }
+
+    #[test]
+    fn should_place_blocks_using_guest_hints() {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[(1, 0x11), (0, 0x00)]);
+        let first = PAYLOAD_OFFSET as usize;
+        let second = first + BLOCK_SIZE;
+
+        // A MBR in the second chunk with a linux partition of 1 MB starting at 1 MB, and an ext
+        // superblock at the start of the first chunk.
+        let bytes = image.get_mut();
+        bytes[second + 446 + 4] = 0x83;
+        bytes[second + 446 + 8..second + 446 + 12].copy_from_slice(&2048u32.to_le_bytes());
+        bytes[second + 446 + 12..second + 446 + 16].copy_from_slice(&2048u32.to_le_bytes());
+        bytes[second + 510..second + 512].copy_from_slice(&[0x55, 0xAA]);
+        bytes[first + 1024..first + 2048].fill(0);
+        bytes[first + 1080..first + 1082].copy_from_slice(&[0x53, 0xEF]);
+        wipe(&mut image, BAT_OFFSET, Vhdx::MB);
+
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        let report = vhdx.reconstruct_bat(BatRecoveryOptions::default()).unwrap();
+
+        assert_eq!(
+            vec![
+                (0, second as u64, Confidence::Medium),
+                (1, first as u64, Confidence::Medium),
+            ],
+            block_offsets(&report)
+        );
+        assert_eq!(Evidence::PartitionTable, report.blocks[0].evidence);
+        assert_eq!(
+            Evidence::Superblock(FileSystemType::Ext2),
+            report.blocks[1].evidence
+        );
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use uuid::Uuid;
+
+use crate::{
+    bat::{
+        calc_payload_blocks_count, calc_sector_bitmap_blocks_count,
+        calc_total_bat_entries_differencing, calc_total_bat_entries_fixed_dynamic,
+        payload_bat_index, payload_block, BatEntry, BatEntryState,
+    },
+    error::VhdxError,
+    log::LogEntry,
+    meta_data::MetaData,
+    vhdx::Vhdx,
+    vhdx_header::{KnowRegion, RegionTable},
+    Serialise, SyncData,
+};
+
+// The largest virtual disk the format allows, 64 TB.
+const MAX_VIRTUAL_DISK_SIZE: u64 = 64 * Vhdx::MB * Vhdx::MB;
+
+impl<T> Vhdx<T>
+where
+    T: Read + Write + Seek + SyncData,
+{
+    // Changes the size of the virtual disk. Shrinking is refused when it would discard data, a
+    // block past the new end holding some or the part of the last block past it not being
+    // zeros, see force_resize to discard it anyway.
+    pub fn resize(&mut self, virtual_disk_size: u64) -> Result<(), VhdxError> {
+        self.resize_to(virtual_disk_size, false)
+    }
+
+    // Changes the size of the virtual disk, discarding the blocks past the new end. Their space
+    // stays in the file until it is compacted.
+    pub fn force_resize(&mut self, virtual_disk_size: u64) -> Result<(), VhdxError> {
+        self.resize_to(virtual_disk_size, true)
+    }
+
+    // The new size, the BAT entries it takes and the region holding them are committed together
+    // through the log. Everything done before that only touches space nothing points to, is
+    // itself committed through the log, or zeroes data being discarded, so a crash leaves the
+    // disk at its old or its new size.
+    fn resize_to(&mut self, virtual_disk_size: u64, force: bool) -> Result<(), VhdxError> {
+        let logical_sector_size = self.meta_data.logical_sector_size as u64;
+        if virtual_disk_size == 0
+            || virtual_disk_size > MAX_VIRTUAL_DISK_SIZE
+            || !virtual_disk_size.is_multiple_of(logical_sector_size)
+        {
+            return Err(VhdxError::InvalidVirtualDiskSize(virtual_disk_size));
+        }
+        let old_size = self.virtual_disk_size();
+        if virtual_disk_size == old_size {
+            return Ok(());
+        }
+
+        let total_bat_entries = self.total_bat_entries_for(virtual_disk_size);
+        let cleared = self.cleared_entries(virtual_disk_size, total_bat_entries, force)?;
+        let tail = match virtual_disk_size < old_size {
+            true => self.discarded_tail(virtual_disk_size, force)?,
+            false => Vec::new(),
+        };
+
+        // The disk reads differently from now on. The new data write guid goes first: a crash
+        // before the commit then only unlinks the children of a disk that didn't change, where
+        // writing it last could leave them linked to a disk that did.
+        let header = self
+            .header()
+            .successor(Uuid::nil())
+            .with_new_data_write_guid();
+        self.write_header(header)?;
+
+        for (offset, length) in tail {
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(&vec![0; length as usize])?;
+        }
+        self.file.sync_data()?;
+
+        if virtual_disk_size > old_size {
+            self.zero_past_end(old_size, virtual_disk_size)?;
+        }
+        let bat = self.grow_bat(total_bat_entries)?;
+
+        // The size and the region table go last, so they are committed together even when the
+        // update takes several log sequences.
+        let mut pages = self.bat_pages(&cleared)?;
+        let patch = self.virtual_disk_size_patch(virtual_disk_size)?;
+        pages.extend(self.patched_pages(&[patch])?);
+        if let Some((bat_offset, bat_length)) = bat {
+            pages.extend(self.region_table_pages(bat_offset, bat_length)?);
+        }
+        self.write_through_log(&pages)
+    }
+
+    fn total_bat_entries_for(&self, virtual_disk_size: u64) -> u64 {
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        let payload_blocks_count =
+            calc_payload_blocks_count(virtual_disk_size as usize, self.block_size() as usize);
+        if self.has_parent() {
+            let sector_bitmap_blocks_count = calc_sector_bitmap_blocks_count(
+                payload_blocks_count as usize,
+                chunk_ratio as usize,
+            );
+            calc_total_bat_entries_differencing(sector_bitmap_blocks_count, chunk_ratio)
+        } else {
+            calc_total_bat_entries_fixed_dynamic(payload_blocks_count, chunk_ratio)
+        }
+    }
+
+    // The entries of the payload blocks past a shrunk end and of the sector bitmap blocks past
+    // the end of a shrunk BAT, to be cleared. A differencing BAT keeps its last chunk whole, so
+    // the payload blocks are walked by block rather than by entry. Only fully and partially
+    // present payload blocks hold data of their own, the others read as zeros or from the parent.
+    fn cleared_entries(
+        &self,
+        virtual_disk_size: u64,
+        total_bat_entries: u64,
+        force: bool,
+    ) -> Result<Vec<(usize, BatEntry)>, VhdxError> {
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        let payload_blocks_count =
+            calc_payload_blocks_count(virtual_disk_size as usize, self.block_size() as usize);
+        let mut cleared = Vec::new();
+        for block in payload_blocks_count..self.meta_data.payload_blocks_count {
+            let index = payload_bat_index(block, chunk_ratio);
+            let Some(entry) = self.bat_table.get(index) else {
+                break;
+            };
+            if entry.state() == BatEntryState::NotPresent {
+                continue;
+            }
+            if !force && entry.state().is_stored() {
+                return Err(VhdxError::ShrinkDiscardsData(block));
+            }
+            cleared.push((index, BatEntry::new(BatEntryState::NotPresent, 0)));
+        }
+        for (index, entry) in self
+            .bat_table
+            .iter()
+            .enumerate()
+            .skip(total_bat_entries as usize)
+        {
+            if payload_block(index as u64, chunk_ratio).is_none()
+                && entry.state() != BatEntryState::NotPresent
+            {
+                cleared.push((index, BatEntry::new(BatEntryState::NotPresent, 0)));
+            }
+        }
+        Ok(cleared)
+    }
+
+    // The parts of the last block past a shrunk end holding data of the block's own, as ranges
+    // of the file. They would show again once the disk grows, so they are zeroed.
+    fn discarded_tail(
+        &mut self,
+        virtual_disk_size: u64,
+        force: bool,
+    ) -> Result<Vec<(u64, u64)>, VhdxError> {
+        let block_size = self.block_size();
+        let block = virtual_disk_size / block_size;
+        let block_start = block * block_size;
+        let Some(entry) = self.payload_entry(block).cloned() else {
+            return Ok(Vec::new());
+        };
+        if virtual_disk_size == block_start || !entry.state().is_stored() {
+            return Ok(Vec::new());
+        }
+
+        let mut tail = Vec::new();
+        for (offset, length) in self.virtual_disk().own_ranges(block)? {
+            let start = offset.max(virtual_disk_size);
+            let end = offset + length;
+            if start >= end {
+                continue;
+            }
+            let file_offset = entry.file_offset() + start - block_start;
+            if self
+                .read_at(file_offset, end - start)?
+                .iter()
+                .any(|b| *b != 0)
+            {
+                if !force {
+                    return Err(VhdxError::ShrinkDiscardsData(block));
+                }
+                tail.push((file_offset, end - start));
+            }
+        }
+        Ok(tail)
+    }
+
+    // A stored last block only partly inside the old size may hold stale data past the old end,
+    // which would show once the disk grows.
+    fn zero_past_end(&mut self, old_size: u64, virtual_disk_size: u64) -> Result<(), VhdxError> {
+        let block_size = self.block_size();
+        let start = old_size % block_size;
+        let Some(entry) = self.payload_entry(old_size / block_size) else {
+            return Ok(());
+        };
+        if start == 0 || entry.state() != BatEntryState::FullyPresent {
+            return Ok(());
+        }
+        let length = block_size.min(virtual_disk_size - old_size / block_size * block_size);
+
+        self.file
+            .seek(SeekFrom::Start(entry.file_offset() + start))?;
+        self.file.write_all(&vec![0; (length - start) as usize])?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // Makes room for the BAT when it outgrows its region, returning the new region. The region
+    // grows in place when only blocks are in the way, they are moved to the end of the file
+    // first. When other structures are in the way, the BAT is copied to the end of the file.
+    // Either way the new part of the region is zeroed before the region table points to it.
+    fn grow_bat(&mut self, total_bat_entries: u64) -> Result<Option<(u64, u64)>, VhdxError> {
+        let region = self
+            .region(KnowRegion::Bat)
+            .ok_or(VhdxError::MissingKnownRegion("Bat"))?
+            .clone();
+        let bat_length = (total_bat_entries * 8).div_ceil(Vhdx::MB) * Vhdx::MB;
+        if bat_length <= region.length as u64 {
+            return Ok(None);
+        }
+
+        let grown = (region.file_offset, region.file_offset + bat_length);
+        let header = self.header();
+        let mut structures = vec![(0, Vhdx::MB)];
+        structures.push((
+            header.log_offset,
+            header.log_offset + header.log_length as u64,
+        ));
+        if let Some(meta_data) = self.region(KnowRegion::MetaData) {
+            structures.push((
+                meta_data.file_offset,
+                meta_data.file_offset + meta_data.length as u64,
+            ));
+        }
+        let blocked = structures
+            .iter()
+            .any(|(start, end)| *start < grown.1 && grown.0 < *end);
+
+        let mut end = self.file.seek(SeekFrom::End(0))?.next_multiple_of(Vhdx::MB);
+        if blocked {
+            let mut bat = self.read_at(region.file_offset, region.length as u64)?;
+            bat.resize(bat_length as usize, 0);
+            self.file.seek(SeekFrom::Start(end))?;
+            self.file.write_all(&bat)?;
+            self.file.sync_data()?;
+            return Ok(Some((end, bat_length)));
+        }
+
+        end = end.max(grown.1);
+        let block_size = self.block_size();
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        let mut moves = Vec::new();
+        for (index, entry) in self.bat_table.iter().enumerate() {
+            let length = match payload_block(index as u64, chunk_ratio) {
+                Some(_) if entry.state().is_stored() => block_size,
+                None if entry.state() == BatEntryState::FullyPresent => Vhdx::MB,
+                _ => continue,
+            };
+            let offset = entry.file_offset();
+            if offset < grown.1 && grown.0 < offset + length {
+                moves.push((index, entry.clone(), length, end));
+                end += length;
+            }
+        }
+
+        let mut updates = Vec::new();
+        for (index, entry, length, destination) in moves {
+            let data = self.read_at(entry.file_offset(), length)?;
+            self.file.seek(SeekFrom::Start(destination))?;
+            self.file.write_all(&data)?;
+            updates.push((
+                index,
+                BatEntry::new(entry.state(), (destination / Vhdx::MB) as usize),
+            ));
+        }
+        self.file.sync_data()?;
+        if !updates.is_empty() {
+            self.update_bat(&updates)?;
+        }
+
+        let start = region.file_offset + region.length as u64;
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.write_all(&vec![0; (grown.1 - start) as usize])?;
+        self.file.sync_data()?;
+        Ok(Some((region.file_offset, bat_length)))
+    }
+
+    // The new value of the virtual disk size item, as a patch of the metadata region.
+    fn virtual_disk_size_patch(&self, virtual_disk_size: u64) -> Result<(u64, Vec<u8>), VhdxError> {
+        let region_offset = self
+            .region(KnowRegion::MetaData)
+            .ok_or(VhdxError::MissingKnownRegion("MetaData"))?
+            .file_offset;
+        let item = self
+            .meta_data
+            .entries
+            .get(&MetaData::VIRTUAL_DISK_SIZE)
+            .ok_or(VhdxError::MissingMetaDataItem("Virtual Disk Size"))?;
+        Ok((
+            region_offset + item.offset as u64,
+            virtual_disk_size.to_le_bytes().to_vec(),
+        ))
+    }
+
+    // The first page of both region tables with the BAT at its new place. The rest of a table
+    // is zeros, which doesn't change.
+    fn region_table_pages(
+        &self,
+        bat_offset: u64,
+        bat_length: u64,
+    ) -> Result<Vec<(u64, Vec<u8>)>, VhdxError> {
+        let mut entries = BTreeMap::new();
+        for region in [KnowRegion::MetaData, KnowRegion::Bat] {
+            let entry = self
+                .region(region)
+                .ok_or(VhdxError::MissingKnownRegion("MetaData or Bat"))?;
+            entries.insert(region, entry.clone());
+        }
+        let bat = entries
+            .get_mut(&KnowRegion::Bat)
+            .ok_or(VhdxError::MissingKnownRegion("Bat"))?;
+        bat.file_offset = bat_offset;
+        bat.length = bat_length as u32;
+
+        let mut table = Cursor::new(Vec::new());
+        RegionTable::with_entries(entries).serialize(&mut table)?;
+        let page = table.into_inner()[..LogEntry::SECTOR_SIZE].to_vec();
+        Ok(vec![(192 * Vhdx::KB, page.clone()), (256 * Vhdx::KB, page)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        differencing_image, dynamic_image, parent_locator, BAT_OFFSET, BLOCK_SIZE, DATA_WRITE_GUID,
+        PAYLOAD_OFFSET,
+    };
+    use pretty_assertions::assert_eq;
+
+    fn read_disk<T: Read + Seek>(vhdx: &mut Vhdx<T>) -> Vec<u8> {
+        let mut data = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn should_expand_within_the_bat_region() {
+        let image = dynamic_image(2 * BLOCK_SIZE, &[(1, 0x11)]);
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+
+        vhdx.resize(5 * BLOCK_SIZE as u64).unwrap();
+
+        assert_eq!(5 * BLOCK_SIZE as u64, vhdx.virtual_disk_size());
+        assert_eq!(5, vhdx.meta_data.payload_blocks_count);
+        assert_eq!(5, vhdx.bat_table.len());
+        assert_ne!(DATA_WRITE_GUID, vhdx.header().data_write_guid());
+
+        let mut reopened = Vhdx::from_reader(vhdx.file).unwrap();
+        let data = read_disk(&mut reopened);
+        assert_eq!(5 * BLOCK_SIZE, data.len());
+        assert!(data[BLOCK_SIZE..2 * BLOCK_SIZE].iter().all(|b| *b == 0x11));
+        assert!(data[2 * BLOCK_SIZE..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn should_move_blocks_out_of_the_way_of_a_growing_bat() {
+        let image = dynamic_image(2 * BLOCK_SIZE, &[(0, 0x22), (1, 0x11)]);
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+
+        // 200 GB of 1 MB blocks take more than the 1 MB the BAT has.
+        let size = 200 * 1024 * BLOCK_SIZE as u64;
+        vhdx.resize(size).unwrap();
+
+        let bat = vhdx.region(KnowRegion::Bat).unwrap().clone();
+        assert_eq!(
+            (BAT_OFFSET, 2 * Vhdx::MB),
+            (bat.file_offset, bat.length as u64)
+        );
+        assert!(vhdx.bat_table[0].file_offset() >= PAYLOAD_OFFSET + Vhdx::MB);
+
+        let mut reopened = Vhdx::from_reader(vhdx.file).unwrap();
+        assert_eq!(size, reopened.virtual_disk_size());
+        let mut disk = reopened.virtual_disk();
+        let mut data = vec![0; 2 * BLOCK_SIZE];
+        disk.read_exact(&mut data).unwrap();
+        assert!(data[..BLOCK_SIZE].iter().all(|b| *b == 0x22));
+        assert!(data[BLOCK_SIZE..].iter().all(|b| *b == 0x11));
+    }
+
+    #[test]
+    fn should_refuse_to_shrink_over_allocated_blocks_unless_forced() {
+        let image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0x22), (3, 0x11)]);
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+
+        assert!(matches!(
+            vhdx.resize(2 * BLOCK_SIZE as u64),
+            Err(VhdxError::ShrinkDiscardsData(3))
+        ));
+        assert_eq!(4 * BLOCK_SIZE as u64, vhdx.virtual_disk_size());
+
+        vhdx.force_resize(3 * BLOCK_SIZE as u64).unwrap();
+
+        let mut reopened = Vhdx::from_reader(vhdx.file).unwrap();
+        assert_eq!(3, reopened.bat_table.len());
+        let data = read_disk(&mut reopened);
+        assert!(data[..BLOCK_SIZE].iter().all(|b| *b == 0x22));
+        assert!(data[BLOCK_SIZE..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn should_shrink_over_blocks_without_data_of_their_own() {
+        let image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0x22)]);
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        vhdx.update_bat(&[(3, BatEntry::new(BatEntryState::Zero, 0))])
+            .unwrap();
+
+        vhdx.resize(2 * BLOCK_SIZE as u64).unwrap();
+
+        assert_eq!(2 * BLOCK_SIZE as u64, vhdx.virtual_disk_size());
+        assert_eq!(2, vhdx.bat_table.len());
+    }
+
+    #[test]
+    fn should_refuse_to_shrink_a_differencing_disk_within_its_last_chunk() {
+        let image = differencing_image(
+            4 * BLOCK_SIZE,
+            parent_locator("parent.vhdx"),
+            &[
+                (0, 0x22, None),
+                (2, 0x33, Some(vec![0x0F])),
+                (3, 0x11, None),
+            ],
+        );
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+
+        assert!(matches!(
+            vhdx.resize(3 * BLOCK_SIZE as u64),
+            Err(VhdxError::ShrinkDiscardsData(3))
+        ));
+        assert!(matches!(
+            vhdx.resize(2 * BLOCK_SIZE as u64),
+            Err(VhdxError::ShrinkDiscardsData(2))
+        ));
+        assert_eq!(4 * BLOCK_SIZE as u64, vhdx.virtual_disk_size());
+
+        vhdx.force_resize(2 * BLOCK_SIZE as u64).unwrap();
+
+        let reopened = Vhdx::from_reader(vhdx.file).unwrap();
+        assert_eq!(2 * BLOCK_SIZE as u64, reopened.virtual_disk_size());
+        let states: Vec<BatEntryState> = (0..4)
+            .map(|block| reopened.payload_entry(block).unwrap().state())
+            .collect();
+        assert_eq!(
+            vec![
+                BatEntryState::FullyPresent,
+                BatEntryState::NotPresent,
+                BatEntryState::NotPresent,
+                BatEntryState::NotPresent
+            ],
+            states
+        );
+    }
+
+    #[test]
+    fn should_zero_the_tail_of_a_block_cut_by_a_shrink() {
+        let image = dynamic_image(2 * BLOCK_SIZE, &[(1, 0x11)]);
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        let size = BLOCK_SIZE as u64 + 4096;
+
+        assert!(matches!(
+            vhdx.resize(size),
+            Err(VhdxError::ShrinkDiscardsData(1))
+        ));
+        vhdx.force_resize(size).unwrap();
+        let offset = vhdx.bat_table[1].file_offset();
+        let block = vhdx.read_at(offset, BLOCK_SIZE as u64).unwrap();
+        assert!(block[4096..].iter().all(|b| *b == 0));
+
+        vhdx.resize(2 * BLOCK_SIZE as u64).unwrap();
+
+        let mut reopened = Vhdx::from_reader(vhdx.file).unwrap();
+        let data = read_disk(&mut reopened);
+        assert!(data[BLOCK_SIZE..BLOCK_SIZE + 4096]
+            .iter()
+            .all(|b| *b == 0x11));
+        assert!(data[BLOCK_SIZE + 4096..].iter().all(|b| *b == 0));
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use crate::{
+    bat::{payload_bat_index, sector_bitmap_bat_index, BatEntry, BatEntryState},
+    log::{DataDesc, Descriptor, LogEntry, LogHeader},
+    meta_data::{FileParameters, MetaData, ParentLocator, SectorSize},
+    vhdx::Vhdx,
+    vhdx_header::{FileTypeIdentifier, Header, KnowRegion, RTEntry, RegionTable, VhdxHeader},
+    DeSerialise, Serialise, Signature,
+};
+
+pub(crate) const BLOCK_SIZE: usize = Vhdx::MB as usize;
+
+pub(crate) const LOG_OFFSET: u64 = Vhdx::MB;
+pub(crate) const META_DATA_OFFSET: u64 = 2 * Vhdx::MB;
+pub(crate) const BAT_OFFSET: u64 = 3 * Vhdx::MB;
+pub(crate) const PAYLOAD_OFFSET: u64 = 4 * Vhdx::MB;
+
+pub(crate) const DATA_WRITE_GUID: Uuid = Uuid::from_u128(2);
+pub(crate) const LOG_GUID: Uuid = Uuid::from_u128(4);
+
+// Builds a dynamic VHDX in memory with 1 MB blocks. Every (block, fill) pair allocates the given
+// virtual block, in order, right after the BAT region and fills it with the byte value.
+pub(crate) fn dynamic_image(virtual_disk_size: usize, blocks: &[(u64, u8)]) -> Cursor<Vec<u8>> {
+    let blocks: Vec<(u64, u8, Option<Vec<u8>>)> = blocks
+        .iter()
+        .map(|(block, fill)| (*block, *fill, None))
+        .collect();
+    image(virtual_disk_size, None, &blocks)
+}
+
+// The locator of a differencing disk whose parent is an image built here, found at the given
+// relative path.
+pub(crate) fn parent_locator(relative_path: &str) -> ParentLocator {
+    ParentLocator::new(BTreeMap::from([
+        (
+            ParentLocator::PARENT_LINKAGE.to_string(),
+            format!("{{{}}}", DATA_WRITE_GUID),
+        ),
+        (
+            ParentLocator::RELATIVE_PATH.to_string(),
+            relative_path.to_string(),
+        ),
+    ]))
+}
+
+// Builds a differencing VHDX the same way. Blocks given a sector bitmap are partially present,
+// the bitmap covering the sectors of the block, the others are fully present.
+pub(crate) fn differencing_image(
+    virtual_disk_size: usize,
+    locator: ParentLocator,
+    blocks: &[(u64, u8, Option<Vec<u8>>)],
+) -> Cursor<Vec<u8>> {
+    image(virtual_disk_size, Some(locator), blocks)
+}
+
+fn image(
+    virtual_disk_size: usize,
+    parent_locator: Option<ParentLocator>,
+    blocks: &[(u64, u8, Option<Vec<u8>>)],
+) -> Cursor<Vec<u8>> {
+    let mut image = Cursor::new(vec![0; PAYLOAD_OFFSET as usize]);
+
+    let mut header = Header::new(
+        Signature::Head,
+        0,
+        1,
+        Uuid::from_u128(1),
+        DATA_WRITE_GUID,
+        Uuid::nil(),
+        0,
+        1,
+        Vhdx::MB as u32,
+        LOG_OFFSET,
+    );
+    header.update_checksum();
+
+    let mut regions = BTreeMap::new();
+    regions.insert(
+        KnowRegion::Bat,
+        RTEntry::new(RegionTable::BAT_ENTRY, BAT_OFFSET, Vhdx::MB as u32, true),
+    );
+    regions.insert(
+        KnowRegion::MetaData,
+        RTEntry::new(
+            RegionTable::META_DATA_ENTRY,
+            META_DATA_OFFSET,
+            Vhdx::MB as u32,
+            true,
+        ),
+    );
+    let region_table = RegionTable::with_entries(regions);
+
+    VhdxHeader::new(
+        FileTypeIdentifier::new(Signature::Vhdxfile, "vhdx-rs".to_string()),
+        header.clone(),
+        header,
+        region_table.clone(),
+        region_table,
+    )
+    .serialize(&mut image)
+    .unwrap();
+
+    let file_parameters = FileParameters {
+        block_size: BLOCK_SIZE,
+        leave_block_allocated: false,
+        has_parent: parent_locator.is_some(),
+    };
+    let meta_data = MetaData::create(
+        file_parameters,
+        virtual_disk_size,
+        Uuid::from_u128(3),
+        SectorSize::Sector512,
+        SectorSize::Sector512,
+        parent_locator,
+    );
+    image.seek(SeekFrom::Start(META_DATA_OFFSET)).unwrap();
+    meta_data.serialize(&mut image).unwrap();
+
+    let mut bat =
+        vec![BatEntry::new(BatEntryState::NotPresent, 0); meta_data.total_bat_entries() as usize];
+    for (i, (block, fill, _)) in blocks.iter().enumerate() {
+        let file_offset = PAYLOAD_OFFSET + (i * BLOCK_SIZE) as u64;
+        bat[payload_bat_index(*block, meta_data.chunk_ratio)] = BatEntry::new(
+            BatEntryState::FullyPresent,
+            (file_offset / Vhdx::MB) as usize,
+        );
+        image.seek(SeekFrom::Start(file_offset)).unwrap();
+        image.write_all(&vec![*fill; BLOCK_SIZE]).unwrap();
+    }
+
+    // All blocks of the image are in the first chunk, so one sector bitmap block covers them.
+    let partial: Vec<_> = blocks
+        .iter()
+        .filter_map(|(block, _, bitmap)| bitmap.as_ref().map(|bitmap| (*block, bitmap)))
+        .collect();
+    if !partial.is_empty() {
+        let file_offset = PAYLOAD_OFFSET + (blocks.len() * BLOCK_SIZE) as u64;
+        let bytes_per_block = BLOCK_SIZE / 512 / 8;
+        let mut sector_bitmap = vec![0; Vhdx::MB as usize];
+        for (block, bitmap) in partial {
+            bat[payload_bat_index(block, meta_data.chunk_ratio)] = BatEntry::new(
+                BatEntryState::PartiallyPresent,
+                bat[payload_bat_index(block, meta_data.chunk_ratio)].file_offset() as usize
+                    / Vhdx::MB as usize,
+            );
+            sector_bitmap[block as usize * bytes_per_block..][..bitmap.len()]
+                .copy_from_slice(bitmap);
+        }
+        bat[sector_bitmap_bat_index(0, meta_data.chunk_ratio)] = BatEntry::new(
+            BatEntryState::FullyPresent,
+            (file_offset / Vhdx::MB) as usize,
+        );
+        image.seek(SeekFrom::Start(file_offset)).unwrap();
+        image.write_all(&sector_bitmap).unwrap();
+    }
+
+    image.seek(SeekFrom::Start(BAT_OFFSET)).unwrap();
+    bat.iter()
+        .for_each(|entry| entry.serialize(&mut image).unwrap());
+
+    image.rewind().unwrap();
+    image
+}
+
+// Overwrites a range of the image with zeros, simulating a damaged structure.
+pub(crate) fn wipe(image: &mut Cursor<Vec<u8>>, offset: u64, length: u64) {
+    image.get_mut()[offset as usize..(offset + length) as usize].fill(0);
+}
+
+// A log entry updating a single 4 KB page of the file.
+pub(crate) fn page_log_entry(seq_number: u64, file_offset: u64, page: &[u8]) -> LogEntry {
+    let header = LogHeader::new(
+        Signature::Loge,
+        0,
+        8 * Vhdx::KB as u32,
+        0,
+        seq_number,
+        1,
+        LOG_GUID,
+        PAYLOAD_OFFSET,
+        PAYLOAD_OFFSET,
+    );
+    let desc = DataDesc::new(file_offset, seq_number, page);
+    let mut entry = LogEntry::new(header, vec![Descriptor::Data(desc)]);
+    entry.update_checksum();
+    entry
+}
+
+// Writes a log entry at the given offset of the log region.
+pub(crate) fn write_log_entry(image: &mut Cursor<Vec<u8>>, log_offset: u64, entry: &LogEntry) {
+    image
+        .seek(SeekFrom::Start(LOG_OFFSET + log_offset))
+        .unwrap();
+    entry.serialize(image).unwrap();
+}
+
+// Rewrites both headers with a new log guid.
+pub(crate) fn set_log_guid(image: &mut Cursor<Vec<u8>>, log_guid: Uuid) {
+    for offset in [64 * Vhdx::KB, 128 * Vhdx::KB] {
+        image.seek(SeekFrom::Start(offset)).unwrap();
+        let mut header = Header::deserialize(image).unwrap();
+        header.log_guid = log_guid;
+        header.update_checksum();
+        image.seek(SeekFrom::Start(offset)).unwrap();
+        header.serialize(image).unwrap();
+    }
+}
+
+// A directory of the system temporary directory named after the test and the process. It's
+// removed with its contents when dropped, so a failing assertion doesn't leave it behind.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("vhdx-rs-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,584 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use nom::{
+    bytes::complete::take,
+    number::complete::{le_u32, le_u64},
+    sequence::tuple,
+    Finish,
+};
+use uuid::Uuid;
+
+use crate::{
+    bat::BatEntryState,
+    create::{CreateOptions, DynamicWriter},
+    error::{VhdxError, VhdxParseError},
+    vhdx::Vhdx,
+    virtual_disk::Allocation,
+};
+
+const PRE_HEADER_TEXT: &[u8] = b"<<< Oracle VM VirtualBox Disk Image >>>\n";
+const SIGNATURE: u32 = 0xBEDA_107F;
+const VERSION: u32 = 0x0001_0001;
+
+// The pre-header takes 72 bytes, the version 1.1 header follows it.
+const HEADER_OFFSET: u64 = 72;
+const HEADER_LENGTH: u32 = 0x180;
+
+// The block map starts at the first sector after the headers, blocks start at the next MB.
+const BLOCK_MAP_OFFSET: u64 = 512;
+const BLOCK_SIZE: u64 = 1024 * 1024;
+
+// Block map entries of blocks without data, free blocks read as zeros or through the parent of a
+// differencing image, zero blocks always read as zeros.
+const BLOCK_FREE: u32 = u32::MAX;
+const BLOCK_ZERO: u32 = u32::MAX - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VdiImageType {
+    Normal = 1,
+    Fixed = 2,
+    Undo = 3,
+    Diff = 4,
+}
+
+// The version 1.1 header, all fields are little endian.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VdiHeader {
+    pub header_length: u32,
+    pub image_type: VdiImageType,
+    pub flags: u32,
+    pub comment: String,
+
+    // Offsets of the block map and of the first block in the file.
+    pub block_map_offset: u32,
+    pub data_offset: u32,
+
+    pub sector_size: u32,
+    pub disk_size: u64,
+
+    // Size of a block and of the data kept in front of each block.
+    pub block_size: u32,
+    pub block_extra: u32,
+    pub blocks: u32,
+    pub blocks_allocated: u32,
+
+    pub uuid_create: Uuid,
+    pub uuid_modify: Uuid,
+    pub uuid_linkage: Uuid,
+    pub uuid_parent_modify: Uuid,
+}
+
+impl VdiHeader {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(BLOCK_MAP_OFFSET as usize);
+        buffer.extend_from_slice(PRE_HEADER_TEXT);
+        buffer.resize(64, 0);
+        buffer.extend_from_slice(&SIGNATURE.to_le_bytes());
+        buffer.extend_from_slice(&VERSION.to_le_bytes());
+
+        buffer.extend_from_slice(&self.header_length.to_le_bytes());
+        buffer.extend_from_slice(&(self.image_type as u32).to_le_bytes());
+        buffer.extend_from_slice(&self.flags.to_le_bytes());
+        let mut comment = self.comment.as_bytes().to_vec();
+        comment.resize(256, 0);
+        buffer.extend_from_slice(&comment[..256]);
+        buffer.extend_from_slice(&self.block_map_offset.to_le_bytes());
+        buffer.extend_from_slice(&self.data_offset.to_le_bytes());
+        // The legacy geometry is left to be computed by VirtualBox, only its sector size is set.
+        buffer.extend_from_slice(&[0; 12]);
+        buffer.extend_from_slice(&self.sector_size.to_le_bytes());
+        buffer.extend_from_slice(&[0; 4]);
+        buffer.extend_from_slice(&self.disk_size.to_le_bytes());
+        buffer.extend_from_slice(&self.block_size.to_le_bytes());
+        buffer.extend_from_slice(&self.block_extra.to_le_bytes());
+        buffer.extend_from_slice(&self.blocks.to_le_bytes());
+        buffer.extend_from_slice(&self.blocks_allocated.to_le_bytes());
+        for uuid in [
+            self.uuid_create,
+            self.uuid_modify,
+            self.uuid_linkage,
+            self.uuid_parent_modify,
+        ] {
+            buffer.extend_from_slice(&uuid.to_bytes_le());
+        }
+        buffer.resize(BLOCK_MAP_OFFSET as usize, 0);
+        buffer
+    }
+
+    fn parse(buffer: &[u8]) -> Result<Self, VhdxError> {
+        let (_, (signature, version)) = tuple((le_u32, le_u32))(&buffer[64..])
+            .finish()
+            .map_err(|e: VhdxParseError<&[u8]>| VhdxError::from(e))?;
+        if signature != SIGNATURE {
+            return Err(VhdxError::InvalidVdi("Signature not found"));
+        }
+        if version >> 16 != 1 || version & 0xFFFF < 1 {
+            return Err(VhdxError::UnsupportedVdi("version other than 1.1"));
+        }
+
+        let (rest, header) = tuple((
+            le_u32,
+            le_u32,
+            le_u32,
+            take(256usize),
+            le_u32,
+            le_u32,
+            take(12usize),
+            le_u32,
+            le_u32,
+            le_u64,
+            le_u32,
+            le_u32,
+            le_u32,
+            le_u32,
+        ))(&buffer[HEADER_OFFSET as usize..])
+        .finish()
+        .map_err(|e: VhdxParseError<&[u8]>| VhdxError::from(e))?;
+        let uuid = |i: usize| Uuid::from_slice_le(&rest[i * 16..][..16]).unwrap();
+
+        let image_type = match header.1 {
+            1 => VdiImageType::Normal,
+            2 => VdiImageType::Fixed,
+            3 => VdiImageType::Undo,
+            4 => VdiImageType::Diff,
+            _ => return Err(VhdxError::InvalidVdi("Unknown image type")),
+        };
+        if header.10 == 0 || header.10 % 512 != 0 {
+            return Err(VhdxError::InvalidVdi(
+                "Block size is not a multiple of sectors",
+            ));
+        }
+        if (header.12 as u64) < header.9.div_ceil(header.10 as u64) {
+            return Err(VhdxError::InvalidVdi("Block map doesn't cover the disk"));
+        }
+
+        Ok(Self {
+            header_length: header.0,
+            image_type,
+            flags: header.2,
+            comment: String::from_utf8_lossy(header.3)
+                .trim_end_matches('\0')
+                .to_string(),
+            block_map_offset: header.4,
+            data_offset: header.5,
+            sector_size: header.7,
+            disk_size: header.9,
+            block_size: header.10,
+            block_extra: header.11,
+            blocks: header.12,
+            blocks_allocated: header.13,
+            uuid_create: uuid(0),
+            uuid_modify: uuid(1),
+            uuid_linkage: uuid(2),
+            uuid_parent_modify: uuid(3),
+        })
+    }
+}
+
+// A VDI opened for reading. It reads as the virtual disk, free and zero blocks read as zeros.
+pub struct Vdi<R> {
+    reader: R,
+    header: VdiHeader,
+    block_map: Vec<u32>,
+    position: u64,
+}
+
+impl Vdi<File> {
+    pub fn open(path: &impl AsRef<Path>) -> Result<Self, VhdxError> {
+        Vdi::from_reader(File::open(path)?)
+    }
+}
+
+impl<R> Vdi<R>
+where
+    R: Read + Seek,
+{
+    // Differencing images need their parent, which isn't supported.
+    pub fn from_reader(mut reader: R) -> Result<Self, VhdxError> {
+        let mut buffer = vec![0; BLOCK_MAP_OFFSET as usize];
+        reader.rewind()?;
+        reader.read_exact(&mut buffer)?;
+        let header = VdiHeader::parse(&buffer)?;
+        if header.image_type == VdiImageType::Diff {
+            return Err(VhdxError::UnsupportedVdi("differencing images"));
+        }
+
+        // Only the entries covering the virtual disk are read, whatever the header says. They and
+        // the allocated blocks they point to have to be in the file.
+        let file_length = reader.seek(SeekFrom::End(0))?;
+        let block_size = header.block_size as u64;
+        let entries = (header.blocks as u64).min(header.disk_size.div_ceil(block_size));
+        if header.block_map_offset as u64 + entries * 4 > file_length {
+            return Err(VhdxError::InvalidVdi("Block map past the end of the file"));
+        }
+        let stride = block_size + header.block_extra as u64;
+        if (header.blocks_allocated as u64)
+            .checked_mul(stride)
+            .and_then(|length| length.checked_add(header.data_offset as u64))
+            .is_none_or(|end| end > file_length)
+        {
+            return Err(VhdxError::InvalidVdi("Blocks past the end of the file"));
+        }
+
+        let mut block_map = vec![0; entries as usize * 4];
+        reader.seek(SeekFrom::Start(header.block_map_offset as u64))?;
+        reader.read_exact(&mut block_map)?;
+        let block_map: Vec<u32> = block_map
+            .chunks_exact(4)
+            .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+            .collect();
+        if block_map.iter().any(|index| {
+            *index != BLOCK_FREE && *index != BLOCK_ZERO && *index >= header.blocks_allocated
+        }) {
+            return Err(VhdxError::InvalidVdi(
+                "Block map entry past the allocated blocks",
+            ));
+        }
+
+        Ok(Self {
+            reader,
+            header,
+            block_map,
+            position: 0,
+        })
+    }
+
+    pub fn header(&self) -> &VdiHeader {
+        &self.header
+    }
+
+    pub fn size(&self) -> u64 {
+        self.header.disk_size
+    }
+
+    // The allocation of a range, stored data wins over zero blocks, zero blocks over free ones.
+    fn allocation(&self, offset: u64, length: u64) -> Allocation {
+        let block_size = self.header.block_size as u64;
+        let first = offset / block_size;
+        let last = (offset + length).div_ceil(block_size);
+
+        let mut allocation = Allocation::Parent;
+        for block in first..last {
+            match self
+                .block_map
+                .get(block as usize)
+                .copied()
+                .unwrap_or(BLOCK_FREE)
+            {
+                BLOCK_FREE => {}
+                BLOCK_ZERO => allocation = Allocation::Zero,
+                _ => return Allocation::Data,
+            }
+        }
+        allocation
+    }
+}
+
+impl<R> Read for Vdi<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.size();
+        if self.position >= size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block_size = self.header.block_size as u64;
+        let block = self.position / block_size;
+        let offset_in_block = self.position % block_size;
+        let length = (buf.len() as u64)
+            .min(block_size - offset_in_block)
+            .min(size - self.position) as usize;
+        let buf = &mut buf[..length];
+
+        match self.block_map[block as usize] {
+            BLOCK_FREE | BLOCK_ZERO => buf.fill(0),
+            index => {
+                let stride = block_size + self.header.block_extra as u64;
+                let offset = self.header.data_offset as u64
+                    + index as u64 * stride
+                    + self.header.block_extra as u64
+                    + offset_in_block;
+                self.reader.seek(SeekFrom::Start(offset))?;
+                self.reader.read_exact(buf)?;
+            }
+        }
+
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<R> Seek for Vdi<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    pub fn export_vdi(&mut self, dest: &impl AsRef<Path>) -> Result<(), VhdxError> {
+        let mut file = File::create(dest)?;
+        self.export_vdi_to(&mut file)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    // Writes the disk as a dynamic VDI with 1 MB blocks. Zero blocks of the VHDX become zero
+    // blocks and blocks not present stay free, differencing disks are flattened.
+    pub fn export_vdi_to<W>(&mut self, writer: &mut W) -> Result<(), VhdxError>
+    where
+        W: Write + Seek,
+    {
+        let size = self.virtual_disk_size();
+        let blocks = size.div_ceil(BLOCK_SIZE);
+        let data_offset = (BLOCK_MAP_OFFSET + blocks * 4).next_multiple_of(BLOCK_SIZE);
+
+        let mut block_map = vec![BLOCK_FREE; blocks as usize];
+        let mut allocated = 0;
+        let mut buffer = vec![0; BLOCK_SIZE as usize];
+        let mut disk = self.virtual_disk();
+
+        for (block, entry) in block_map.iter_mut().enumerate() {
+            let offset = block as u64 * BLOCK_SIZE;
+            let length = BLOCK_SIZE.min(size - offset);
+            let allocation = disk.allocation(offset, length)?;
+            if allocation == Allocation::Zero {
+                *entry = match disk.state(offset)? {
+                    BatEntryState::Zero => BLOCK_ZERO,
+                    _ => BLOCK_FREE,
+                };
+                continue;
+            }
+
+            buffer.fill(0);
+            disk.seek(SeekFrom::Start(offset))?;
+            disk.read_exact(&mut buffer[..length as usize])?;
+            // Data of a parent is flattened into the image, unless it only holds zeros.
+            if allocation == Allocation::Parent && buffer.iter().all(|b| *b == 0) {
+                continue;
+            }
+
+            writer.seek(SeekFrom::Start(data_offset + allocated as u64 * BLOCK_SIZE))?;
+            writer.write_all(&buffer)?;
+            *entry = allocated;
+            allocated += 1;
+        }
+
+        let header = VdiHeader {
+            header_length: HEADER_LENGTH,
+            image_type: VdiImageType::Normal,
+            flags: 0,
+            comment: String::new(),
+            block_map_offset: BLOCK_MAP_OFFSET as u32,
+            data_offset: data_offset as u32,
+            sector_size: 512,
+            disk_size: size,
+            block_size: BLOCK_SIZE as u32,
+            block_extra: 0,
+            blocks: blocks as u32,
+            blocks_allocated: allocated,
+            uuid_create: self.meta_data.virtual_disk_id,
+            uuid_modify: Uuid::new_v4(),
+            uuid_linkage: Uuid::nil(),
+            uuid_parent_modify: Uuid::nil(),
+        };
+        let mut map: Vec<u8> = block_map
+            .iter()
+            .flat_map(|entry| entry.to_le_bytes())
+            .collect();
+        map.resize((data_offset - BLOCK_MAP_OFFSET) as usize, 0);
+
+        writer.rewind()?;
+        writer.write_all(&header.to_bytes())?;
+        writer.write_all(&map)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Vhdx {
+    // Builds a dynamic VHDX at dest from a normal or fixed VDI. Blocks free in the VDI stay
+    // NotPresent, blocks made of zero blocks become Zero and all other blocks are stored.
+    pub fn import_vdi(
+        src: &impl AsRef<Path>,
+        dest: &impl AsRef<Path>,
+        options: CreateOptions,
+    ) -> Result<Self, VhdxError> {
+        let mut vdi = Vdi::open(src)?;
+        let size = vdi.size();
+        let virtual_disk_size = size.next_multiple_of(options.logical_sector_size as u64);
+
+        let mut vhdx = DynamicWriter::new(File::create(dest)?, virtual_disk_size, options)?;
+        let block_size = vhdx.block_size();
+        let mut buffer = vec![0; block_size as usize];
+
+        for block in 0..vhdx.payload_blocks_count() {
+            let start = block * block_size;
+            let length = block_size.min(size.saturating_sub(start));
+            match vdi.allocation(start, length) {
+                Allocation::Parent => vhdx.set_state(block, BatEntryState::NotPresent)?,
+                Allocation::Zero => vhdx.set_state(block, BatEntryState::Zero)?,
+                Allocation::Data => {
+                    let data = &mut buffer[..length as usize];
+                    vdi.seek(SeekFrom::Start(start))?;
+                    vdi.read_exact(data)?;
+                    vhdx.write_block(block, data)?;
+                }
+            }
+        }
+
+        vhdx.finish()?.sync_all()?;
+        Vhdx::new(dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_utils::{dynamic_image, TempDir, BAT_OFFSET, BLOCK_SIZE as VHDX_BLOCK_SIZE};
+    use pretty_assertions::assert_eq;
+
+    // Data in blocks 0 and 2, block 3 zeroed and block 1 not present.
+    fn image() -> Cursor<Vec<u8>> {
+        let mut image = dynamic_image(4 * VHDX_BLOCK_SIZE, &[(0, 0xAA), (2, 0xBB), (3, 0xCC)]);
+        image.get_mut()[BAT_OFFSET as usize + 3 * 8..][..8]
+            .copy_from_slice(&(BatEntryState::Zero as u64).to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn should_export_block_map() {
+        let mut vhdx = Vhdx::from_reader(image()).unwrap();
+        let mut expected = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut expected).unwrap();
+
+        let mut image = Cursor::new(Vec::new());
+        vhdx.export_vdi_to(&mut image).unwrap();
+
+        let mut vdi = Vdi::from_reader(image).unwrap();
+        assert_eq!(VdiImageType::Normal, vdi.header().image_type);
+        assert_eq!(4 * VHDX_BLOCK_SIZE as u64, vdi.size());
+        assert_eq!(2, vdi.header().blocks_allocated);
+        assert_eq!(vhdx.meta_data.virtual_disk_id, vdi.header().uuid_create);
+        assert_eq!(vec![0, BLOCK_FREE, 1, BLOCK_ZERO], vdi.block_map);
+        let mut content = Vec::new();
+        vdi.read_to_end(&mut content).unwrap();
+        assert!(content == expected);
+    }
+
+    #[test]
+    fn should_import_vdi_keeping_allocation() {
+        let directory = TempDir::new("vdi");
+        let mut source = Vhdx::from_reader(image()).unwrap();
+        let mut expected = Vec::new();
+        source.virtual_disk().read_to_end(&mut expected).unwrap();
+        source.export_vdi(&directory.join("disk.vdi")).unwrap();
+
+        let options = CreateOptions {
+            block_size: VHDX_BLOCK_SIZE as u32,
+            ..CreateOptions::default()
+        };
+        let mut vhdx = Vhdx::import_vdi(
+            &directory.join("disk.vdi"),
+            &directory.join("disk.vhdx"),
+            options,
+        )
+        .unwrap();
+        let states: Vec<BatEntryState> = (0..4)
+            .map(|block| vhdx.payload_entry(block).unwrap().state())
+            .collect();
+        let mut content = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut content).unwrap();
+
+        use BatEntryState::*;
+        assert_eq!(vec![FullyPresent, NotPresent, FullyPresent, Zero], states);
+        assert!(content == expected);
+    }
+
+    #[test]
+    fn should_bound_the_block_map_by_the_file() {
+        let mut vhdx = Vhdx::from_reader(image()).unwrap();
+        let mut image = Cursor::new(Vec::new());
+        vhdx.export_vdi_to(&mut image).unwrap();
+        let image = image.into_inner();
+        let block_map_offset = Vdi::from_reader(Cursor::new(image.clone()))
+            .unwrap()
+            .header()
+            .block_map_offset as usize;
+        let patched = |patches: &[(usize, &[u8])]| {
+            let mut image = image.clone();
+            for (offset, value) in patches {
+                image[*offset..][..value.len()].copy_from_slice(value);
+            }
+            Vdi::from_reader(Cursor::new(image))
+        };
+        let header = HEADER_OFFSET as usize;
+
+        let huge_disk = patched(&[
+            (
+                header + 296,
+                &(u32::MAX as u64 * VHDX_BLOCK_SIZE as u64).to_le_bytes(),
+            ),
+            (header + 312, &u32::MAX.to_le_bytes()),
+        ]);
+        let huge_extra = patched(&[
+            (header + 308, &u32::MAX.to_le_bytes()),
+            (header + 316, &u32::MAX.to_le_bytes()),
+        ]);
+        let unallocated = patched(&[(block_map_offset + 2 * 4, &2u32.to_le_bytes())]);
+
+        assert!(matches!(
+            huge_disk,
+            Err(VhdxError::InvalidVdi("Block map past the end of the file"))
+        ));
+        assert!(matches!(
+            huge_extra,
+            Err(VhdxError::InvalidVdi("Blocks past the end of the file"))
+        ));
+        assert!(matches!(
+            unallocated,
+            Err(VhdxError::InvalidVdi(
+                "Block map entry past the allocated blocks"
+            ))
+        ));
+    }
+
+    #[test]
+    fn should_reject_differencing_vdi() {
+        let mut vhdx = Vhdx::from_reader(image()).unwrap();
+        let mut image = Cursor::new(Vec::new());
+        vhdx.export_vdi_to(&mut image).unwrap();
+        image.get_mut()[HEADER_OFFSET as usize + 4..][..4]
+            .copy_from_slice(&(VdiImageType::Diff as u32).to_le_bytes());
+
+        let result = Vdi::from_reader(image);
+
+        assert!(matches!(result, Err(VhdxError::UnsupportedVdi(_))));
+    }
+}
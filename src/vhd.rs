@@ -0,0 +1,785 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nom::{
+    bytes::complete::take,
+    combinator::map,
+    number::complete::{be_u16, be_u32, be_u64, u8 as t_u8},
+    sequence::tuple,
+    Finish, IResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    create::{CreateOptions, DynamicWriter},
+    error::{VhdxError, VhdxParseError},
+    vhdx::Vhdx,
+};
+
+const SECTOR_SIZE: u64 = 512;
+
+// Largest disk the VHD format supports, 2040 GB.
+pub const MAX_VHD_SIZE: u64 = 2040 * 1024 * 1024 * 1024;
+
+// Seconds between the unix epoch and the VHD epoch, January 1, 2000.
+const EPOCH_2000: u64 = 946_684_800;
+
+// BAT entry of a block not allocated in a dynamic VHD.
+const UNALLOCATED: u32 = u32::MAX;
+
+// The kinds of VHD that can be written, differencing VHDs are only ever read as their type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VhdType {
+    Fixed = 2,
+    Dynamic = 3,
+    Differencing = 4,
+}
+
+// The footer closing every VHD, dynamic VHDs also start with a copy of it. All fields are big
+// endian.
+#[derive(Debug, Clone)]
+pub struct VhdFooter {
+    // Features (4 bytes): Bit 1 is reserved and MUST always be set.
+    pub features: u32,
+
+    // FileFormatVersion (4 bytes): MUST be 0x00010000.
+    pub file_format_version: u32,
+
+    // DataOffset (8 bytes): Offset of the dynamic disk header, all ones for fixed disks.
+    pub data_offset: u64,
+
+    // TimeStamp (4 bytes): Creation time in seconds since January 1, 2000 UTC.
+    pub timestamp: u32,
+
+    pub creator_application: [u8; 4],
+    pub creator_version: u32,
+    pub creator_host_os: [u8; 4],
+
+    // OriginalSize and CurrentSize (8 bytes each): Size of the disk as created and as it is now.
+    pub original_size: u64,
+    pub current_size: u64,
+
+    pub geometry: Geometry,
+    pub disk_type: VhdType,
+
+    // Checksum (4 bytes): Ones' complement of the sum of all bytes of the footer without the
+    // checksum itself.
+    pub checksum: u32,
+
+    pub unique_id: Uuid,
+    pub saved_state: bool,
+}
+
+// Cylinder, heads and sectors per track as reported to BIOS era guests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors_per_track: u8,
+}
+
+impl Geometry {
+    // The CHS geometry algorithm of the VHD specification. The geometry may describe a disk a bit
+    // smaller than its size, never a larger one.
+    pub fn from_size(size: u64) -> Self {
+        let mut total_sectors = size / SECTOR_SIZE;
+        if total_sectors > 65535 * 16 * 255 {
+            total_sectors = 65535 * 16 * 255;
+        }
+
+        let (sectors_per_track, heads, cylinder_times_heads) = if total_sectors >= 65535 * 16 * 63 {
+            (255, 16, total_sectors / 255)
+        } else {
+            let mut sectors_per_track = 17;
+            let mut cylinder_times_heads = total_sectors / sectors_per_track;
+            let mut heads = cylinder_times_heads.div_ceil(1024).max(4);
+
+            if cylinder_times_heads >= heads * 1024 || heads > 16 {
+                sectors_per_track = 31;
+                heads = 16;
+                cylinder_times_heads = total_sectors / sectors_per_track;
+            }
+            if cylinder_times_heads >= heads * 1024 {
+                sectors_per_track = 63;
+                heads = 16;
+                cylinder_times_heads = total_sectors / sectors_per_track;
+            }
+            (sectors_per_track, heads, cylinder_times_heads)
+        };
+
+        Self {
+            cylinders: (cylinder_times_heads / heads) as u16,
+            heads: heads as u8,
+            sectors_per_track: sectors_per_track as u8,
+        }
+    }
+}
+
+impl VhdFooter {
+    pub const COOKIE: &'static [u8] = b"conectix";
+    const CHECKSUM: Range<usize> = 64..68;
+
+    fn new(disk_type: VhdType, size: u64, data_offset: u64, unique_id: Uuid) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH + Duration::from_secs(EPOCH_2000))
+            .map(|elapsed| elapsed.as_secs() as u32)
+            .unwrap_or(0);
+
+        let mut footer = Self {
+            features: 2,
+            file_format_version: 0x0001_0000,
+            data_offset,
+            timestamp,
+            creator_application: *b"vrs ",
+            creator_version: 0x0001_0000,
+            creator_host_os: *b"Wi2k",
+            original_size: size,
+            current_size: size,
+            geometry: Geometry::from_size(size),
+            disk_type,
+            checksum: 0,
+            unique_id,
+            saved_state: false,
+        };
+        footer.checksum = checksum(&footer.to_bytes(), VhdFooter::CHECKSUM);
+        footer
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(SECTOR_SIZE as usize);
+        buffer.extend_from_slice(VhdFooter::COOKIE);
+        buffer.extend_from_slice(&self.features.to_be_bytes());
+        buffer.extend_from_slice(&self.file_format_version.to_be_bytes());
+        buffer.extend_from_slice(&self.data_offset.to_be_bytes());
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+        buffer.extend_from_slice(&self.creator_application);
+        buffer.extend_from_slice(&self.creator_version.to_be_bytes());
+        buffer.extend_from_slice(&self.creator_host_os);
+        buffer.extend_from_slice(&self.original_size.to_be_bytes());
+        buffer.extend_from_slice(&self.current_size.to_be_bytes());
+        buffer.extend_from_slice(&self.geometry.cylinders.to_be_bytes());
+        buffer.push(self.geometry.heads);
+        buffer.push(self.geometry.sectors_per_track);
+        buffer.extend_from_slice(&(self.disk_type as u32).to_be_bytes());
+        buffer.extend_from_slice(&self.checksum.to_be_bytes());
+        buffer.extend_from_slice(self.unique_id.as_bytes());
+        buffer.push(self.saved_state as u8);
+        buffer.resize(SECTOR_SIZE as usize, 0);
+        buffer
+    }
+
+    fn parse(buffer: &[u8]) -> Result<Self, VhdxError> {
+        if !buffer.starts_with(VhdFooter::COOKIE) {
+            return Err(VhdxError::InvalidVhd("Footer cookie not found"));
+        }
+
+        let (_, footer) = t_footer(&buffer[8..]).finish()?;
+        let disk_type = match footer.9 {
+            2 => VhdType::Fixed,
+            3 => VhdType::Dynamic,
+            4 => VhdType::Differencing,
+            disk_type => return Err(VhdxError::UnsupportedVhdType(disk_type)),
+        };
+
+        let footer = Self {
+            features: footer.0,
+            file_format_version: footer.1,
+            data_offset: footer.2,
+            timestamp: footer.3,
+            creator_application: footer.4.try_into().unwrap(),
+            creator_version: footer.5,
+            creator_host_os: footer.6.try_into().unwrap(),
+            original_size: footer.7,
+            current_size: footer.8 .0,
+            geometry: footer.8 .1,
+            disk_type,
+            checksum: footer.10,
+            unique_id: Uuid::from_slice(footer.11).unwrap(),
+            saved_state: footer.12 != 0,
+        };
+
+        let expected = checksum(&buffer[..SECTOR_SIZE as usize], VhdFooter::CHECKSUM);
+        if footer.checksum != expected {
+            return Err(VhdxError::VhdChecksumError(expected, footer.checksum));
+        }
+        Ok(footer)
+    }
+}
+
+type RawFooter<'a> = (
+    u32,
+    u32,
+    u64,
+    u32,
+    &'a [u8],
+    u32,
+    &'a [u8],
+    u64,
+    (u64, Geometry),
+    u32,
+    u32,
+    &'a [u8],
+    u8,
+);
+
+fn t_footer(buffer: &[u8]) -> IResult<&[u8], RawFooter<'_>, VhdxParseError<&[u8]>> {
+    tuple((
+        be_u32,
+        be_u32,
+        be_u64,
+        be_u32,
+        take(4usize),
+        be_u32,
+        take(4usize),
+        be_u64,
+        tuple((
+            be_u64,
+            map(
+                tuple((be_u16, t_u8, t_u8)),
+                |(cylinders, heads, sectors_per_track)| Geometry {
+                    cylinders,
+                    heads,
+                    sectors_per_track,
+                },
+            ),
+        )),
+        be_u32,
+        be_u32,
+        take(16usize),
+        t_u8,
+    ))(buffer)
+}
+
+// The header following the footer copy at the start of dynamic and differencing VHDs.
+#[derive(Debug, Clone)]
+pub struct VhdDynamicHeader {
+    // TableOffset (8 bytes): Offset of the BAT in the file.
+    pub table_offset: u64,
+
+    // MaxTableEntries (4 bytes): Number of BAT entries, one per block of the disk.
+    pub max_table_entries: u32,
+
+    // BlockSize (4 bytes): Size of the data of a block, not counting its sector bitmap.
+    pub block_size: u32,
+}
+
+impl VhdDynamicHeader {
+    pub const COOKIE: &'static [u8] = b"cxsparse";
+    const LENGTH: usize = 1024;
+    const CHECKSUM: Range<usize> = 36..40;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(VhdDynamicHeader::LENGTH);
+        buffer.extend_from_slice(VhdDynamicHeader::COOKIE);
+        buffer.extend_from_slice(&u64::MAX.to_be_bytes());
+        buffer.extend_from_slice(&self.table_offset.to_be_bytes());
+        buffer.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+        buffer.extend_from_slice(&self.max_table_entries.to_be_bytes());
+        buffer.extend_from_slice(&self.block_size.to_be_bytes());
+        buffer.extend_from_slice(&[0; 4]);
+        buffer.resize(VhdDynamicHeader::LENGTH, 0);
+
+        let checksum = checksum(&buffer, VhdDynamicHeader::CHECKSUM);
+        buffer[VhdDynamicHeader::CHECKSUM].copy_from_slice(&checksum.to_be_bytes());
+        buffer
+    }
+
+    fn parse(buffer: &[u8]) -> Result<Self, VhdxError> {
+        if !buffer.starts_with(VhdDynamicHeader::COOKIE) {
+            return Err(VhdxError::InvalidVhd("Dynamic header cookie not found"));
+        }
+
+        let (_, (_, table_offset, _, max_table_entries, block_size, stored)) =
+            tuple((be_u64, be_u64, be_u32, be_u32, be_u32, be_u32))(&buffer[8..])
+                .finish()
+                .map_err(|e: VhdxParseError<&[u8]>| VhdxError::from(e))?;
+
+        let expected = checksum(
+            &buffer[..VhdDynamicHeader::LENGTH],
+            VhdDynamicHeader::CHECKSUM,
+        );
+        if stored != expected {
+            return Err(VhdxError::VhdChecksumError(expected, stored));
+        }
+        if block_size == 0 || !block_size.is_multiple_of(SECTOR_SIZE as u32) {
+            return Err(VhdxError::InvalidVhd(
+                "Block size is not a multiple of sectors",
+            ));
+        }
+
+        Ok(Self {
+            table_offset,
+            max_table_entries,
+            block_size,
+        })
+    }
+}
+
+// Ones' complement of the byte sum of a structure, leaving out its checksum field.
+fn checksum(buffer: &[u8], checksum_field: Range<usize>) -> u32 {
+    !buffer
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !checksum_field.contains(i))
+        .fold(0u32, |sum, (_, b)| sum.wrapping_add(*b as u32))
+}
+
+// A VHD opened for reading. It reads as the virtual disk, blocks missing from a dynamic VHD read
+// as zeros.
+pub struct Vhd<R> {
+    reader: R,
+    footer: VhdFooter,
+    dynamic: Option<(VhdDynamicHeader, Vec<u32>)>,
+    position: u64,
+}
+
+impl Vhd<File> {
+    pub fn open(path: &impl AsRef<Path>) -> Result<Self, VhdxError> {
+        Vhd::from_reader(File::open(path)?)
+    }
+}
+
+impl<R> Vhd<R>
+where
+    R: Read + Seek,
+{
+    // Reads the footer at the end of the file, or its copy at the start of the file when the end
+    // is damaged, and the dynamic header and BAT of dynamic disks.
+    pub fn from_reader(mut reader: R) -> Result<Self, VhdxError> {
+        let length = reader.seek(SeekFrom::End(0))?;
+        if length < SECTOR_SIZE {
+            return Err(VhdxError::InvalidVhd("File too small"));
+        }
+
+        let mut buffer = [0; SECTOR_SIZE as usize];
+        reader.seek(SeekFrom::Start(length - SECTOR_SIZE))?;
+        reader.read_exact(&mut buffer)?;
+        let footer = match VhdFooter::parse(&buffer) {
+            Ok(footer) => footer,
+            Err(error) => {
+                reader.rewind()?;
+                reader.read_exact(&mut buffer)?;
+                VhdFooter::parse(&buffer).map_err(|_| error)?
+            }
+        };
+
+        let dynamic = match footer.disk_type {
+            VhdType::Fixed => None,
+            VhdType::Dynamic => {
+                let mut buffer = [0; VhdDynamicHeader::LENGTH];
+                reader.seek(SeekFrom::Start(footer.data_offset))?;
+                reader.read_exact(&mut buffer)?;
+                let header = VhdDynamicHeader::parse(&buffer)?;
+
+                // Only the entries of the blocks of the disk are read, whatever the header says,
+                // and they have to be in the file.
+                let entries = (header.max_table_entries as u64)
+                    .min(footer.current_size.div_ceil(header.block_size as u64));
+                if header
+                    .table_offset
+                    .checked_add(entries * 4)
+                    .is_none_or(|end| end > length)
+                {
+                    return Err(VhdxError::InvalidVhd("BAT past the end of the file"));
+                }
+
+                let mut table = vec![0; entries as usize * 4];
+                reader.seek(SeekFrom::Start(header.table_offset))?;
+                reader.read_exact(&mut table)?;
+                let bat = table
+                    .chunks_exact(4)
+                    .map(|entry| u32::from_be_bytes(entry.try_into().unwrap()))
+                    .collect();
+                Some((header, bat))
+            }
+            VhdType::Differencing => {
+                return Err(VhdxError::UnsupportedVhdType(VhdType::Differencing as u32))
+            }
+        };
+
+        Ok(Self {
+            reader,
+            footer,
+            dynamic,
+            position: 0,
+        })
+    }
+
+    pub fn footer(&self) -> &VhdFooter {
+        &self.footer
+    }
+
+    pub fn size(&self) -> u64 {
+        self.footer.current_size
+    }
+
+    // Reads within one block of a dynamic disk. Sectors not marked in the sector bitmap of the
+    // block read as zeros.
+    fn read_dynamic(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((header, bat)) = &self.dynamic else {
+            return Ok(0);
+        };
+        let block_size = header.block_size as u64;
+        let block = self.position / block_size;
+        let offset_in_block = self.position % block_size;
+        let length = (buf.len() as u64).min(block_size - offset_in_block) as usize;
+        let buf = &mut buf[..length];
+
+        let entry = bat.get(block as usize).copied().unwrap_or(UNALLOCATED);
+        if entry == UNALLOCATED {
+            buf.fill(0);
+            return Ok(length);
+        }
+
+        let bitmap_length = (block_size / SECTOR_SIZE)
+            .div_ceil(8)
+            .next_multiple_of(SECTOR_SIZE);
+        let block_offset = entry as u64 * SECTOR_SIZE;
+
+        let first_sector = offset_in_block / SECTOR_SIZE;
+        let last_sector = (offset_in_block + length as u64).div_ceil(SECTOR_SIZE);
+        let mut bitmap = vec![0; (last_sector.div_ceil(8) - first_sector / 8) as usize];
+        self.reader
+            .seek(SeekFrom::Start(block_offset + first_sector / 8))?;
+        self.reader.read_exact(&mut bitmap)?;
+
+        self.reader.seek(SeekFrom::Start(
+            block_offset + bitmap_length + offset_in_block,
+        ))?;
+        self.reader.read_exact(buf)?;
+
+        // The sector bitmap of a VHD holds the first sector in the most significant bit.
+        let end = offset_in_block + length as u64;
+        for sector in first_sector..last_sector {
+            let bit = sector - first_sector / 8 * 8;
+            if bitmap[bit as usize / 8] & (0x80 >> (bit % 8)) != 0 {
+                continue;
+            }
+            let start = (sector * SECTOR_SIZE).max(offset_in_block) - offset_in_block;
+            let stop = ((sector + 1) * SECTOR_SIZE).min(end) - offset_in_block;
+            buf[start as usize..stop as usize].fill(0);
+        }
+        Ok(length)
+    }
+}
+
+impl<R> Read for Vhd<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.size();
+        if self.position >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let length = (buf.len() as u64).min(size - self.position) as usize;
+
+        let read = match self.footer.disk_type {
+            VhdType::Fixed => {
+                self.reader.seek(SeekFrom::Start(self.position))?;
+                self.reader.read(&mut buf[..length])?
+            }
+            _ => self.read_dynamic(&mut buf[..length])?,
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R> Seek for Vhd<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    // Writes the virtual disk as a VHD of the given type.
+    pub fn export_vhd(
+        &mut self,
+        dest: &impl AsRef<Path>,
+        vhd_type: VhdType,
+    ) -> Result<(), VhdxError> {
+        let mut file = File::create(dest)?;
+        self.export_vhd_to(&mut file, vhd_type)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    // A fixed VHD is the raw image followed by the footer. A dynamic VHD stores 2 MB blocks,
+    // blocks reading as zeros are left out of it.
+    pub fn export_vhd_to<W>(&mut self, writer: &mut W, vhd_type: VhdType) -> Result<(), VhdxError>
+    where
+        W: Write + Seek,
+    {
+        let size = self.virtual_disk_size();
+        if size > MAX_VHD_SIZE {
+            return Err(VhdxError::VhdTooLarge(size));
+        }
+        let unique_id = self.meta_data.virtual_disk_id;
+
+        match vhd_type {
+            VhdType::Fixed => {
+                self.export_raw_to(writer)?;
+                writer.seek(SeekFrom::Start(size))?;
+                writer.write_all(
+                    &VhdFooter::new(VhdType::Fixed, size, u64::MAX, unique_id).to_bytes(),
+                )?;
+            }
+            VhdType::Dynamic => {
+                const BLOCK_SIZE: u64 = 2 * Vhdx::MB;
+                let footer =
+                    VhdFooter::new(VhdType::Dynamic, size, SECTOR_SIZE, unique_id).to_bytes();
+                let header = VhdDynamicHeader {
+                    table_offset: SECTOR_SIZE + VhdDynamicHeader::LENGTH as u64,
+                    max_table_entries: size.div_ceil(BLOCK_SIZE) as u32,
+                    block_size: BLOCK_SIZE as u32,
+                };
+                let table_length =
+                    (header.max_table_entries as u64 * 4).next_multiple_of(SECTOR_SIZE);
+                let bitmap = vec![0xFF; SECTOR_SIZE as usize];
+
+                let mut bat = vec![UNALLOCATED; header.max_table_entries as usize];
+                let mut next_offset = header.table_offset + table_length;
+                let mut buffer = vec![0; BLOCK_SIZE as usize];
+                let mut disk = self.virtual_disk();
+
+                for (block, entry) in bat.iter_mut().enumerate() {
+                    let start = block as u64 * BLOCK_SIZE;
+                    let length = BLOCK_SIZE.min(size - start) as usize;
+                    buffer.fill(0);
+                    disk.seek(SeekFrom::Start(start))?;
+                    disk.read_exact(&mut buffer[..length])?;
+                    if buffer.iter().all(|b| *b == 0) {
+                        continue;
+                    }
+
+                    *entry = (next_offset / SECTOR_SIZE) as u32;
+                    writer.seek(SeekFrom::Start(next_offset))?;
+                    writer.write_all(&bitmap)?;
+                    writer.write_all(&buffer)?;
+                    next_offset += bitmap.len() as u64 + BLOCK_SIZE;
+                }
+
+                let mut table: Vec<u8> = bat.iter().flat_map(|entry| entry.to_be_bytes()).collect();
+                table.resize(table_length as usize, 0xFF);
+
+                writer.rewind()?;
+                writer.write_all(&footer)?;
+                writer.write_all(&header.to_bytes())?;
+                writer.write_all(&table)?;
+                writer.seek(SeekFrom::Start(next_offset))?;
+                writer.write_all(&footer)?;
+            }
+            VhdType::Differencing => {
+                return Err(VhdxError::UnsupportedVhdType(VhdType::Differencing as u32))
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Vhdx {
+    // Builds a dynamic VHDX at dest from a fixed or dynamic VHD.
+    pub fn import_vhd(
+        src: &impl AsRef<Path>,
+        dest: &impl AsRef<Path>,
+        options: CreateOptions,
+    ) -> Result<Self, VhdxError> {
+        let mut vhd = Vhd::open(src)?;
+        let size = vhd.size();
+        let virtual_disk_size = size.next_multiple_of(options.logical_sector_size as u64);
+
+        let mut vhdx = DynamicWriter::new(File::create(dest)?, virtual_disk_size, options)?;
+        let block_size = vhdx.block_size();
+        let mut buffer = vec![0; block_size as usize];
+
+        for block in 0..vhdx.payload_blocks_count() {
+            let start = block * block_size;
+            let length = block_size.min(size.saturating_sub(start)) as usize;
+            vhd.seek(SeekFrom::Start(start))?;
+            vhd.read_exact(&mut buffer[..length])?;
+            vhdx.write_block(block, &buffer[..length])?;
+        }
+
+        vhdx.finish()?.sync_all()?;
+        Vhdx::new(dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::bat::BatEntryState;
+    use crate::test_utils::{dynamic_image, TempDir, BLOCK_SIZE};
+    use pretty_assertions::assert_eq;
+
+    fn content(vhdx: &mut Vhdx<Cursor<Vec<u8>>>) -> Vec<u8> {
+        let mut content = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn should_calculate_chs_geometry() {
+        let geometry = |cylinders, heads, sectors_per_track| Geometry {
+            cylinders,
+            heads,
+            sectors_per_track,
+        };
+
+        assert_eq!(geometry(963, 4, 17), Geometry::from_size(32 * 1024 * 1024));
+        assert_eq!(
+            geometry(2080, 16, 63),
+            Geometry::from_size(1024 * 1024 * 1024)
+        );
+        assert_eq!(geometry(65535, 16, 255), Geometry::from_size(MAX_VHD_SIZE));
+    }
+
+    #[test]
+    fn should_round_trip_fixed_vhd() {
+        let mut vhdx = Vhdx::from_reader(dynamic_image(3 * BLOCK_SIZE, &[(1, 0x11)])).unwrap();
+
+        let mut vhd = Cursor::new(Vec::new());
+        vhdx.export_vhd_to(&mut vhd, VhdType::Fixed).unwrap();
+
+        assert_eq!(3 * BLOCK_SIZE + 512, vhd.get_ref().len());
+        let mut vhd = Vhd::from_reader(vhd).unwrap();
+        assert_eq!(VhdType::Fixed, vhd.footer().disk_type);
+        assert_eq!(3 * BLOCK_SIZE as u64, vhd.size());
+        let mut exported = Vec::new();
+        vhd.read_to_end(&mut exported).unwrap();
+        assert!(exported == content(&mut vhdx));
+    }
+
+    #[test]
+    fn should_round_trip_dynamic_vhd_leaving_out_zero_blocks() {
+        let mut vhdx =
+            Vhdx::from_reader(dynamic_image(5 * BLOCK_SIZE, &[(0, 0xAA), (4, 0xBB)])).unwrap();
+
+        let mut vhd = Cursor::new(Vec::new());
+        vhdx.export_vhd_to(&mut vhd, VhdType::Dynamic).unwrap();
+
+        // Footer, header and BAT, then the first and the last of the three 2 MB blocks.
+        assert_eq!(
+            512 + 1024 + 512 + 2 * (512 + 2 * BLOCK_SIZE) + 512,
+            vhd.get_ref().len()
+        );
+        let mut vhd = Vhd::from_reader(vhd).unwrap();
+        assert_eq!(VhdType::Dynamic, vhd.footer().disk_type);
+        let mut exported = Vec::new();
+        vhd.read_to_end(&mut exported).unwrap();
+        assert!(exported == content(&mut vhdx));
+    }
+
+    #[test]
+    fn should_zero_sectors_missing_from_sector_bitmap() {
+        let mut vhdx = Vhdx::from_reader(dynamic_image(2 * BLOCK_SIZE, &[(0, 0xAA)])).unwrap();
+        let mut image = Cursor::new(Vec::new());
+        vhdx.export_vhd_to(&mut image, VhdType::Dynamic).unwrap();
+
+        // Clear the bits of sectors 1 and 8 in the bitmap of the only block.
+        let mut image = image.into_inner();
+        image[2048] = 0xBF;
+        image[2049] = 0x7F;
+
+        let mut vhd = Vhd::from_reader(Cursor::new(image)).unwrap();
+        let mut sectors = vec![0; 10 * 512];
+        vhd.seek(SeekFrom::Start(100)).unwrap();
+        vhd.read_exact(&mut sectors[100..]).unwrap();
+
+        for (sector, data) in sectors.chunks(512).enumerate().skip(1) {
+            let expected = if sector == 1 || sector == 8 { 0 } else { 0xAA };
+            assert!(data.iter().all(|b| *b == expected), "sector {sector}");
+        }
+    }
+
+    #[test]
+    fn should_import_dynamic_vhd() {
+        let directory = TempDir::new("vhd");
+        let mut vhdx =
+            Vhdx::from_reader(dynamic_image(4 * BLOCK_SIZE, &[(1, 0x11), (2, 0x22)])).unwrap();
+        let expected = content(&mut vhdx);
+        vhdx.export_vhd(&directory.join("disk.vhd"), VhdType::Dynamic)
+            .unwrap();
+
+        let options = CreateOptions {
+            block_size: BLOCK_SIZE as u32,
+            ..CreateOptions::default()
+        };
+        let mut imported = Vhdx::import_vhd(
+            &directory.join("disk.vhd"),
+            &directory.join("disk.vhdx"),
+            options,
+        )
+        .unwrap();
+        let states: Vec<BatEntryState> = (0..4)
+            .map(|block| imported.payload_entry(block).unwrap().state())
+            .collect();
+        let mut content = Vec::new();
+        imported.virtual_disk().read_to_end(&mut content).unwrap();
+
+        use BatEntryState::*;
+        assert_eq!(
+            vec![NotPresent, FullyPresent, FullyPresent, NotPresent],
+            states
+        );
+        assert!(content == expected);
+    }
+
+    #[test]
+    fn should_bound_the_bat_by_the_disk_and_the_file() {
+        let mut vhdx = Vhdx::from_reader(dynamic_image(2 * BLOCK_SIZE, &[(0, 0xAA)])).unwrap();
+        let mut image = Cursor::new(Vec::new());
+        vhdx.export_vhd_to(&mut image, VhdType::Dynamic).unwrap();
+        let image = image.into_inner();
+
+        // Rewrites fields of the dynamic header, which follows the copy of the footer.
+        let patched = |field: Range<usize>, value: &[u8]| {
+            let mut image = image.clone();
+            let header = &mut image[512..512 + VhdDynamicHeader::LENGTH];
+            header[field].copy_from_slice(value);
+            let checksum = checksum(header, VhdDynamicHeader::CHECKSUM);
+            header[VhdDynamicHeader::CHECKSUM].copy_from_slice(&checksum.to_be_bytes());
+            Cursor::new(image)
+        };
+
+        let mut huge_count = Vhd::from_reader(patched(28..32, &u32::MAX.to_be_bytes())).unwrap();
+        let mut content = Vec::new();
+        huge_count.read_to_end(&mut content).unwrap();
+
+        assert_eq!(2 * BLOCK_SIZE, content.len());
+        assert!(content[..BLOCK_SIZE].iter().all(|b| *b == 0xAA));
+        assert!(matches!(
+            Vhd::from_reader(patched(16..24, &(u64::MAX - 2).to_be_bytes())),
+            Err(VhdxError::InvalidVhd("BAT past the end of the file"))
+        ));
+    }
+}
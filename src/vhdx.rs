@@ -1,66 +1,137 @@
 #![allow(dead_code)]
 
-use crate::bat::BatEntry;
-use crate::log::LogSequence;
+use crate::bat::{payload_bat_index, BatEntry};
+use crate::image_store::{FileSystem, ImageStore};
+use crate::log::{DataDesc, LogHeader, LogSequence};
+use crate::parent_resolver::ParentResolver;
 use crate::vhdx_header::Header;
+use crate::virtual_disk::VirtualDisk;
 use crate::{
     error::{Result, VhdxError},
-    log::{Log, LogEntry},
+    log::{Descriptor, Log, LogEntry},
     meta_data::MetaData,
     parse_utils::t_sign_u32,
-    vhdx_header::{KnowRegion, VhdxHeader},
+    vhdx_header::{KnowRegion, RTEntry, RegionTable, VhdxHeader},
     Signature,
 };
-use crate::{Crc32, DeSerialise, Validation};
+use crate::{Crc32, DeSerialise, Serialise, SyncData, Validation};
 use nom::combinator::peek;
+use std::collections::{btree_map::Entry, BTreeMap};
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use uuid::Uuid;
 
 #[derive(Debug)]
-pub struct Vhdx {
-    pub(crate) file: File,
+pub struct Vhdx<T = File> {
+    pub(crate) file: T,
     pub header: VhdxHeader,
     pub log: Log,
     pub meta_data: MetaData,
     pub bat_table: Vec<BatEntry>,
+
+    // The parent of a differencing disk, its own parent chained the same way.
+    pub(crate) parent: Option<Box<Vhdx<T>>>,
 }
 
 impl Vhdx {
     pub(crate) const KB: u64 = 1024;
     pub(crate) const MB: u64 = Vhdx::KB * Vhdx::KB;
 
+    // Opens the disk at the path on its own, as it is: its log isn't replayed and the parent of
+    // a differencing disk isn't looked for, see open_chain for both.
     pub fn new(path: &impl AsRef<Path>) -> Result<Self, VhdxError> {
-        let mut reader = File::options().read(true).write(true).open(path)?;
+        let reader = File::options().read(true).write(true).open(path)?;
+        Vhdx::from_reader(reader)
+    }
 
-        let header = VhdxHeader::deserialize(&mut reader)?;
-        let (header_no, h) = get_current_header(&header.header_1, &header.header_2)?;
-        h.validate()?;
+    // Opens the disk at the path with its parent chain, replaying its log first.
+    pub fn open_chain(path: &impl AsRef<Path>) -> Result<Self, VhdxError> {
+        Vhdx::open_with_resolver(path, &ParentResolver::default())
+    }
+
+    // Opens a disk and its chain, the parents being found through the resolver, for chains
+    // copied from another host.
+    pub fn open_with_resolver(
+        path: &impl AsRef<Path>,
+        resolver: &ParentResolver,
+    ) -> Result<Self, VhdxError> {
+        Vhdx::open_in(&FileSystem, path, resolver)
+    }
+
+    // Finds the active sequence among the valid entries of the log, keyed by their offset in the
+    // log region. Starting at every entry, the following entries are chained as long as their
+    // sequence numbers increase by one, wrapping around the end of the log. A chain is a valid
+    // sequence once it reaches back to the tail its head entry points to, and the valid sequence
+    // with the highest head sequence number is the active one.
+    pub(crate) fn try_get_log_sequence(
+        log_entries: &BTreeMap<u64, &LogEntry>,
+        log_length: u64,
+    ) -> Result<LogSequence, VhdxError> {
+        let mut active = LogSequence::default();
+
+        for (start, first) in log_entries {
+            let mut chain = vec![(*start, *first)];
+            let mut chain_length = first.header.entry_length as u64;
 
-        let _ = reader.seek(SeekFrom::Start(h.log_offset));
-        let mut log_entries = Vec::new();
-        let log_end = h.log_offset + h.log_length as u64;
-
-        while reader.stream_position()? != log_end {
-            let log_entry = LogEntry::deserialize(&mut reader)?;
-            log_entries.push(log_entry);
-
-            // peeking to see if there are any more logs
-            let mut buffer = [0; 4];
-            reader.read_exact(&mut buffer)?;
-            let mut peeker = peek(t_sign_u32);
-            let (_, signature) = peeker(&buffer)?;
-            match signature {
-                //if there are logs we back up and let the loop run again
-                Signature::Loge => {
-                    reader.seek(SeekFrom::Current(-4))?;
+            loop {
+                let (offset, last) = chain[chain.len() - 1];
+                let next_offset = (offset + last.header.entry_length as u64) % log_length;
+                let Some(next) = log_entries.get(&next_offset) else {
+                    break;
+                };
+                chain_length += next.header.entry_length as u64;
+                if next.header.seq_number != last.header.seq_number + 1 || chain_length > log_length
+                {
+                    break;
                 }
-                // Otherwise that was last entry we break
-                _ => break,
+                chain.push((next_offset, *next));
+            }
+
+            let (head_offset, head) = chain[chain.len() - 1];
+            let Some(tail) = chain
+                .iter()
+                .position(|(offset, _)| *offset == head.header.tail as u64)
+            else {
+                continue;
+            };
+
+            if head.header.seq_number > active.sequence_number {
+                active = LogSequence {
+                    sequence_number: head.header.seq_number,
+                    entries: chain[tail..].iter().map(|(_, e)| (*e).clone()).collect(),
+                    head_value: head_offset,
+                    tail_value: head.header.tail as u64,
+                };
             }
         }
 
+        Ok(active)
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    pub fn from_reader(mut reader: T) -> Result<Self, VhdxError> {
+        let header = VhdxHeader::deserialize(&mut reader)?;
+        Vhdx::load(reader, header)
+    }
+
+    pub(crate) fn load(mut reader: T, header: VhdxHeader) -> Result<Self, VhdxError> {
+        let (header_no, h) = get_current_header(&header.header_1, &header.header_2)?;
+        h.validate()?;
+
+        // The log is read as a whole, entries can wrap around its end.
+        reader.seek(SeekFrom::Start(h.log_offset))?;
+        let mut log_region = Vec::with_capacity(h.log_length as usize);
+        (&mut reader)
+            .take(h.log_length as u64)
+            .read_to_end(&mut log_region)?;
+        log_region.resize(h.log_length as usize, 0);
+        let log = Log::new(&log_region, h.log_guid)?;
+
         let r = match header_no {
             1 => &header.region_table_1,
             2 => &header.region_table_2,
@@ -68,128 +139,403 @@ impl Vhdx {
         };
 
         r.validate()?;
+        let (meta_data, bat_table) = read_meta_data_and_bat(&mut reader, r)?;
 
-        let meta_data_info = &r
-            .table_entries
-            .get(&KnowRegion::MetaData)
-            .ok_or(VhdxError::MissingKnownRegion("MetaData"))?;
-
-        let bat_table_info = &r
-            .table_entries
-            .get(&KnowRegion::Bat)
-            .ok_or(VhdxError::MissingKnownRegion("Bat"))?;
-
-        // Read MetaData
-        reader.seek(SeekFrom::Start(meta_data_info.file_offset))?;
-        let meta_data = MetaData::deserialize(&mut reader).unwrap();
-
-        // Read BAT Table
-        reader.seek(SeekFrom::Start(bat_table_info.file_offset))?;
-        let bat_table: Vec<BatEntry> = (0..meta_data.total_bat_entries_fixed_dynamic)
-            .map(|_| BatEntry::deserialize(&mut reader).unwrap())
-            .collect();
-
-        let log = Log::new(log_entries);
         let vhdx = Vhdx {
             file: reader,
             header,
             log,
             meta_data,
             bat_table,
+            parent: None,
         };
 
-        // vhdx.try_log_replay()?;
-
         Ok(vhdx)
     }
 
-    fn try_log_replay(&mut self) -> Result<(), VhdxError> {
-        if Uuid::is_nil(&self.header().log_guid) {
-            return Ok(());
+    // Attaches the parent of a differencing disk. The parent has to be the version of the disk
+    // the differencing disk was created against, its DataWriteGuid must match the parent linkage.
+    pub fn with_parent(mut self, parent: Vhdx<T>) -> Result<Self, VhdxError> {
+        let data_write_guid = parent.header().data_write_guid();
+        if let Some(locator) = &self.meta_data.parent_locator {
+            if let Some(linkage) = locator.parent_linkage() {
+                if !locator.links_to(data_write_guid) {
+                    return Err(VhdxError::ParentLinkageError(linkage, data_write_guid));
+                }
+            }
         }
 
-        let _active_log = Vhdx::try_get_log_sequence(&self.log.log_entries);
+        self.parent = Some(Box::new(parent));
+        Ok(self)
+    }
 
-        Ok(())
+    pub fn parent(&self) -> Option<&Vhdx<T>> {
+        self.parent.as_deref()
     }
 
-    fn header(&self) -> &Header {
-        &self.header.header_1
+    pub fn has_parent(&self) -> bool {
+        self.meta_data.file_parameters.has_parent
     }
 
-    pub(crate) fn try_get_log_sequence(
-        log_entries: &Vec<LogEntry>,
-    ) -> Result<LogSequence, VhdxError> {
-        let mut active = LogSequence {
-            sequence_number: 0,
-            entries: Vec::new(),
-            head_value: 0,
-            tail_value: 0,
+    // Gives a reader over the guest visible contents of the disk.
+    pub fn virtual_disk(&mut self) -> VirtualDisk<'_, T> {
+        VirtualDisk::new(self)
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.meta_data.file_parameters.block_size as u64
+    }
+
+    pub fn virtual_disk_size(&self) -> u64 {
+        self.meta_data.virtual_disk_size as u64
+    }
+
+    // Reads a range of the file, the part of it past the end of the file reads as zeros.
+    pub(crate) fn read_at(&mut self, offset: u64, length: u64) -> Result<Vec<u8>, VhdxError> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut buffer = Vec::with_capacity(length as usize);
+        (&mut self.file).take(length).read_to_end(&mut buffer)?;
+        buffer.resize(length as usize, 0);
+        Ok(buffer)
+    }
+
+    pub(crate) fn payload_entry(&self, block: u64) -> Option<&BatEntry> {
+        self.bat_table
+            .get(payload_bat_index(block, self.meta_data.chunk_ratio))
+    }
+
+    pub(crate) fn header(&self) -> &Header {
+        self.current_header().1
+    }
+
+    fn current_header(&self) -> (u32, &Header) {
+        get_current_header(&self.header.header_1, &self.header.header_2)
+            .unwrap_or((1, &self.header.header_1))
+    }
+
+    // The region table paired with the current header, the same one the file was loaded from.
+    pub(crate) fn region(&self, region: KnowRegion) -> Option<&RTEntry> {
+        let region_table = match self.current_header().0 {
+            1 => &self.header.region_table_1,
+            _ => &self.header.region_table_2,
         };
+        region_table.table_entries.get(&region)
+    }
 
-        let mut read_items = 0;
-        let mut current_head_offset = 0;
-        let mut seq_tail_offset = 0;
+    fn peek_signature(&mut self) -> Result<Signature, VhdxError> {
+        let mut buffer = [0; 4];
+        self.file.read_exact(&mut buffer)?;
+        let mut peeker = peek(t_sign_u32);
+        let (_, signature) = peeker(&buffer)?;
+        self.file.seek(SeekFrom::Current(-4))?;
+        Ok(signature)
+    }
+}
 
-        loop {
-            let mut candidate = LogSequence {
-                sequence_number: 0,
-                entries: Vec::new(),
-                head_value: 0,
-                tail_value: 0,
-            };
+impl<T> Vhdx<T>
+where
+    T: Read + Write + Seek + SyncData,
+{
+    // Opens a disk of the store, its parents being opened from the same store.
+    pub fn open_in<S>(
+        store: &S,
+        path: &impl AsRef<Path>,
+        resolver: &ParentResolver,
+    ) -> Result<Self, VhdxError>
+    where
+        S: ImageStore<Image = T>,
+    {
+        let mut vhdx = Vhdx::from_reader(store.open(path.as_ref(), true)?)?;
+        vhdx.replay_log()?;
+        vhdx.open_parent(store, path.as_ref(), resolver)
+    }
 
-            candidate.tail_value = seq_tail_offset;
+    // Opens the parent chain of a differencing disk. Parents are only read from, so they are
+    // opened read only.
+    pub(crate) fn open_parent<S>(
+        self,
+        store: &S,
+        path: &Path,
+        resolver: &ParentResolver,
+    ) -> Result<Self, VhdxError>
+    where
+        S: ImageStore<Image = T>,
+    {
+        let Some(locator) = &self.meta_data.parent_locator else {
+            return Ok(self);
+        };
+        let parent_path = resolver.resolve_in(store, path, locator)?.path;
 
-            for (i, entry) in log_entries[read_items..].iter().enumerate() {
-                if entry.validate().is_err() {
-                    read_items = i;
-                    break;
-                }
+        let parent = Vhdx::open_read_only_in(store, &parent_path, resolver)?;
+        self.with_parent(parent)
+    }
 
-                if candidate.is_empty() {
-                    candidate.sequence_number = entry.header.seq_number;
-                    candidate.entries.push(entry.clone());
-                    candidate.head_value = current_head_offset;
-                } else if entry.header.seq_number == candidate.sequence_number + 1 {
-                    candidate.entries.push(entry.clone());
-                    candidate.head_value = current_head_offset;
-                }
+    // Opens a disk of the store read only, with its parent chain. Its log can't be replayed, so
+    // a disk whose log holds entries is refused.
+    pub(crate) fn open_read_only_in<S>(
+        store: &S,
+        path: &Path,
+        resolver: &ParentResolver,
+    ) -> Result<Self, VhdxError>
+    where
+        S: ImageStore<Image = T>,
+    {
+        let vhdx = Vhdx::from_reader(store.open(path, false)?)?;
+        if !vhdx.log.log_sequence.is_empty() {
+            return Err(VhdxError::ParentLogNotReplayed(
+                path.to_string_lossy().into_owned(),
+            ));
+        }
+        vhdx.open_parent(store, path, resolver)
+    }
 
-                seq_tail_offset += entry.header.entry_length as u64;
-                current_head_offset += entry.header.entry_length as u64;
-                read_items += 1;
+    // Applies the active log sequence to the file and writes a new header with a nil log guid, so
+    // the log is not replayed again. Every entry is validated before anything is written, an entry
+    // failing validation is never applied. Returns whether there was anything to replay.
+    pub fn replay_log(&mut self) -> Result<bool, VhdxError> {
+        if self.log.log_sequence.is_empty() {
+            return Ok(false);
+        }
+        self.log
+            .log_sequence
+            .entries
+            .iter()
+            .try_for_each(|entry| entry.validate())?;
+
+        let sequence = std::mem::take(&mut self.log.log_sequence);
+        for entry in &sequence.entries {
+            for descriptor in entry.descriptors() {
+                self.file.seek(SeekFrom::Start(descriptor.file_offset()))?;
+                match descriptor {
+                    Descriptor::Data(desc) => {
+                        let page = desc
+                            .page()
+                            .ok_or(VhdxError::LogEntryLengthError(descriptor.length(), 0))?;
+                        self.file.write_all(&page)?;
+                    }
+                    Descriptor::Zero(zero) => {
+                        let zeros = vec![0; LogEntry::SECTOR_SIZE];
+                        for _ in 0..zero.zero_length() / LogEntry::SECTOR_SIZE as u64 {
+                            self.file.write_all(&zeros)?;
+                        }
+                    }
+                }
             }
+        }
 
-            // Step 4
-            if !candidate.is_valid() {
-                // candidate is empty or not valid break and try the next entries
-                break;
+        // The file has to be at least as large as the structures the log says it holds.
+        if let Some(head) = sequence.head() {
+            let last_file_offset = head.header.last_file_offset;
+            if self.file.seek(SeekFrom::End(0))? < last_file_offset {
+                self.file.seek(SeekFrom::Start(last_file_offset - 1))?;
+                self.file.write_all(&[0])?;
             }
+        }
 
-            // Step 5
-            if candidate.sequence_number > active.sequence_number {
-                active = candidate;
-            }
+        self.file.sync_data()?;
+        let header = self.header().successor(Uuid::nil());
+        self.write_header(header)?;
+
+        // The region tables, the metadata and the BAT may all have been updated by the log.
+        self.header = VhdxHeader::deserialize(&mut self.file)?;
+        let region_table = match self.current_header().0 {
+            1 => &self.header.region_table_1,
+            _ => &self.header.region_table_2,
+        };
+        let (meta_data, bat_table) = read_meta_data_and_bat(&mut self.file, region_table)?;
+        self.meta_data = meta_data;
+        self.bat_table = bat_table;
+
+        Ok(true)
+    }
+
+    // Writes updates of whole 4 KB pages through the log: the pages are logged and synced before
+    // any of them is written to its place, so a crash at any point leaves a log that replays to
+    // the updated file. The metadata and the BAT are reloaded once the pages are applied.
+    //
+    // An update larger than one log sequence isn't atomic: it is committed as several sequences,
+    // and a crash between two of them leaves only the first ones applied. The last pages are
+    // always committed together, so callers put the pages that have to change at once last.
+    pub(crate) fn write_through_log(&mut self, pages: &[(u64, Vec<u8>)]) -> Result<(), VhdxError> {
+        let sector = LogEntry::SECTOR_SIZE as u64;
+        let log_length = self.header().log_length as u64;
+
+        // An entry has a single descriptor sector, which holds the entry header and up to 126
+        // descriptors, and as many pages as fit in the log along with it. Updates larger than
+        // the log are split over several sequences, each of them applied before the next, with
+        // only the first one left short.
+        let per_entry = ((log_length / sector).saturating_sub(1) as usize).min(126);
+        if per_entry == 0 {
+            return Err(VhdxError::NotAllowedToBeZero("Log Length"));
+        }
+        let per_sequence = (log_length / sector) as usize / (per_entry + 1) * per_entry;
+
+        for sequence in pages.rchunks(per_sequence).rev() {
+            self.log.log_sequence = self.log_pages(sequence, per_entry)?;
+            self.replay_log()?;
+        }
+        Ok(())
+    }
+
+    // Updates entries of the BAT through the log, each given with its index in the BAT.
+    pub(crate) fn update_bat(&mut self, updates: &[(usize, BatEntry)]) -> Result<(), VhdxError> {
+        let pages = self.bat_pages(updates)?;
+        self.write_through_log(&pages)
+    }
+
+    // The pages of the BAT as they are once the entries are updated.
+    pub(crate) fn bat_pages(
+        &mut self,
+        updates: &[(usize, BatEntry)],
+    ) -> Result<Vec<(u64, Vec<u8>)>, VhdxError> {
+        let bat_offset = self
+            .region(KnowRegion::Bat)
+            .ok_or(VhdxError::MissingKnownRegion("Bat"))?
+            .file_offset;
+
+        let mut patches = Vec::with_capacity(updates.len());
+        for (index, entry) in updates {
+            let mut bytes = Cursor::new(Vec::with_capacity(8));
+            entry.serialize(&mut bytes)?;
+            patches.push((bat_offset + *index as u64 * 8, bytes.into_inner()));
+        }
+        self.patched_pages(&patches)
+    }
 
-            if read_items == log_entries.len() {
-                break;
+    // The 4 KB pages of the file covering the patches, each given as the bytes to write at a file
+    // offset, as they are once patched.
+    pub(crate) fn patched_pages(
+        &mut self,
+        patches: &[(u64, Vec<u8>)],
+    ) -> Result<Vec<(u64, Vec<u8>)>, VhdxError> {
+        let sector = LogEntry::SECTOR_SIZE as u64;
+
+        let mut pages: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+        for (offset, bytes) in patches {
+            let mut position = *offset;
+            let mut bytes = bytes.as_slice();
+            while !bytes.is_empty() {
+                let page_offset = position - position % sector;
+                let page = match pages.entry(page_offset) {
+                    Entry::Occupied(page) => page.into_mut(),
+                    Entry::Vacant(page) => page.insert(self.read_at(page_offset, sector)?),
+                };
+                let start = (position - page_offset) as usize;
+                let length = bytes.len().min(sector as usize - start);
+                page[start..start + length].copy_from_slice(&bytes[..length]);
+                bytes = &bytes[length..];
+                position += length as u64;
             }
         }
+        Ok(pages.into_iter().collect())
+    }
 
-        Ok(active)
+    // Writes the pages to the log under a new log guid, without applying them.
+    pub(crate) fn log_pages(
+        &mut self,
+        pages: &[(u64, Vec<u8>)],
+        per_entry: usize,
+    ) -> Result<LogSequence, VhdxError> {
+        let log_guid = Uuid::new_v4();
+        let header = self.header().successor(log_guid);
+        let log_offset = header.log_offset;
+        self.write_header(header)?;
+
+        // The file has to cover every page once the log is replayed.
+        let flushed_file_offset = self.file.seek(SeekFrom::End(0))?.next_multiple_of(Vhdx::MB);
+        let last_file_offset = pages
+            .iter()
+            .map(|(offset, _)| (offset + LogEntry::SECTOR_SIZE as u64).next_multiple_of(Vhdx::MB))
+            .fold(flushed_file_offset, u64::max);
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        let mut head_offset = 0;
+        for (seq_number, chunk) in (1..).zip(pages.chunks(per_entry)) {
+            let descriptors: Vec<Descriptor> = chunk
+                .iter()
+                .map(|(file_offset, page)| {
+                    Descriptor::Data(DataDesc::new(*file_offset, seq_number, page))
+                })
+                .collect();
+            let header = LogHeader::new(
+                Signature::Loge,
+                0,
+                ((chunk.len() + 1) * LogEntry::SECTOR_SIZE) as u32,
+                0,
+                seq_number,
+                chunk.len() as u32,
+                log_guid,
+                flushed_file_offset,
+                last_file_offset,
+            );
+            let mut entry = LogEntry::new(header, descriptors);
+            entry.update_checksum();
+
+            self.file.seek(SeekFrom::Start(log_offset + offset))?;
+            entry.serialize(&mut self.file)?;
+            head_offset = offset;
+            offset += entry.header.entry_length as u64;
+            entries.push(entry);
+        }
+        self.file.sync_data()?;
+
+        Ok(LogSequence {
+            sequence_number: entries.len() as u64,
+            entries,
+            head_value: head_offset,
+            tail_value: 0,
+        })
     }
 
-    fn peek_signature(&mut self) -> Result<Signature, VhdxError> {
-        let mut buffer = [0; 4];
-        self.file.read_exact(&mut buffer)?;
-        let mut peeker = peek(t_sign_u32);
-        let (_, signature) = peeker(&buffer)?;
-        self.file.seek(SeekFrom::Current(-4))?;
-        Ok(signature)
+    // Writes the header over the one that is not current, which makes it the current header.
+    pub(crate) fn write_header(&mut self, header: Header) -> Result<(), VhdxError> {
+        let slot = match self.current_header().0 {
+            1 => 2,
+            _ => 1,
+        };
+        self.file.seek(SeekFrom::Start(slot * 64 * Vhdx::KB))?;
+        header.serialize(&mut self.file)?;
+        self.file.sync_data()?;
+
+        match slot {
+            1 => self.header.header_1 = header,
+            _ => self.header.header_2 = header,
+        }
+        Ok(())
     }
 }
 
+fn read_meta_data_and_bat<T>(
+    reader: &mut T,
+    region_table: &RegionTable,
+) -> Result<(MetaData, Vec<BatEntry>), VhdxError>
+where
+    T: Read + Seek,
+{
+    let meta_data_info = region_table
+        .table_entries
+        .get(&KnowRegion::MetaData)
+        .ok_or(VhdxError::MissingKnownRegion("MetaData"))?;
+
+    let bat_table_info = region_table
+        .table_entries
+        .get(&KnowRegion::Bat)
+        .ok_or(VhdxError::MissingKnownRegion("Bat"))?;
+
+    // Read MetaData
+    reader.seek(SeekFrom::Start(meta_data_info.file_offset))?;
+    let meta_data = MetaData::deserialize(reader)?;
+
+    // Read BAT Table
+    reader.seek(SeekFrom::Start(bat_table_info.file_offset))?;
+    let bat_table = (0..meta_data.total_bat_entries())
+        .map(|_| BatEntry::deserialize(reader))
+        .collect::<Result<Vec<BatEntry>, VhdxError>>()?;
+
+    Ok((meta_data, bat_table))
+}
+
 #[allow(clippy::if_same_then_else)]
 fn get_current_header<'a>(h1: &'a Header, h2: &'a Header) -> Result<(u32, &'a Header), VhdxError> {
     let r1 = check_sign_and_crc(h1);
@@ -210,7 +556,7 @@ fn get_current_header<'a>(h1: &'a Header, h2: &'a Header) -> Result<(u32, &'a He
     Ok(current)
 }
 
-fn check_sign_and_crc(header: &Header) -> Result<(), VhdxError> {
+pub(crate) fn check_sign_and_crc(header: &Header) -> Result<(), VhdxError> {
     if header.signature != Signature::Head {
         return Err(VhdxError::SignatureError(
             Signature::Head,
@@ -225,3 +571,157 @@ fn check_sign_and_crc(header: &Header) -> Result<(), VhdxError> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use super::*;
+    use crate::bat::BatEntryState;
+    use crate::image_store::MemoryStore;
+    use crate::test_utils::{
+        differencing_image, dynamic_image, page_log_entry, parent_locator, set_log_guid,
+        write_log_entry, BAT_OFFSET, BLOCK_SIZE, LOG_GUID, LOG_OFFSET, META_DATA_OFFSET,
+        PAYLOAD_OFFSET,
+    };
+    use pretty_assertions::assert_eq;
+
+    // A BAT page allocating block 0 at the start of the payload, logged but not yet written.
+    fn image_with_pending_bat_update() -> Cursor<Vec<u8>> {
+        let mut image = dynamic_image(4 * BLOCK_SIZE, &[]);
+        image
+            .get_mut()
+            .resize(PAYLOAD_OFFSET as usize + BLOCK_SIZE, 0x5A);
+        set_log_guid(&mut image, LOG_GUID);
+
+        let mut page = vec![0; 4096];
+        page[..8].copy_from_slice(&(6 | ((PAYLOAD_OFFSET / Vhdx::MB) << 20)).to_le_bytes());
+        write_log_entry(&mut image, 0, &page_log_entry(1, BAT_OFFSET, &page));
+        image
+    }
+
+    #[test]
+    fn should_replay_active_log_sequence() {
+        let mut vhdx = Vhdx::from_reader(image_with_pending_bat_update()).unwrap();
+        let sequence_number = vhdx.header().sequence_number();
+
+        assert!(vhdx.replay_log().unwrap());
+
+        assert_eq!(BatEntryState::FullyPresent, vhdx.bat_table[0].state());
+        assert_eq!(PAYLOAD_OFFSET, vhdx.bat_table[0].file_offset());
+        assert!(vhdx.header().log_guid.is_nil());
+        assert_eq!(sequence_number + 1, vhdx.header().sequence_number());
+
+        // Reopening finds nothing more to replay.
+        let mut reopened = Vhdx::from_reader(vhdx.file).unwrap();
+        assert_eq!(BatEntryState::FullyPresent, reopened.bat_table[0].state());
+        assert!(!reopened.replay_log().unwrap());
+    }
+
+    #[test]
+    fn should_replay_updates_logged_before_a_crash() {
+        let mut vhdx = Vhdx::from_reader(dynamic_image(4 * BLOCK_SIZE, &[])).unwrap();
+        let mut page = vec![0; 4096];
+        page[8..16].copy_from_slice(&(6 | ((PAYLOAD_OFFSET / Vhdx::MB) << 20)).to_le_bytes());
+
+        // The update is logged but the process stops before it is applied.
+        vhdx.log_pages(&[(BAT_OFFSET, page)], 126).unwrap();
+        assert_eq!(BatEntryState::NotPresent, vhdx.bat_table[1].state());
+
+        let mut reopened = Vhdx::from_reader(vhdx.file).unwrap();
+        assert!(reopened.replay_log().unwrap());
+        assert_eq!(BatEntryState::FullyPresent, reopened.bat_table[1].state());
+        assert_eq!(PAYLOAD_OFFSET, reopened.bat_table[1].file_offset());
+    }
+
+    #[test]
+    fn should_never_apply_invalid_entries() {
+        let mut image = image_with_pending_bat_update();
+
+        // Flip a byte in the data sector of the logged page.
+        let data_sector = (Vhdx::MB + 4 * Vhdx::KB + 100) as usize;
+        image.get_mut()[data_sector] ^= 0xFF;
+
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+
+        assert!(!vhdx.replay_log().unwrap());
+        assert_eq!(BatEntryState::NotPresent, vhdx.bat_table[0].state());
+    }
+
+    #[test]
+    fn should_refuse_parents_with_a_log_to_replay() {
+        let store = MemoryStore::new();
+        store.insert(
+            &"/images/parent.vhdx",
+            image_with_pending_bat_update().into_inner(),
+        );
+        store.insert(
+            &"/images/child.vhdx",
+            differencing_image(4 * BLOCK_SIZE, parent_locator("parent.vhdx"), &[]).into_inner(),
+        );
+
+        let result = Vhdx::open_in(&store, &"/images/child.vhdx", &ParentResolver::default());
+
+        assert!(matches!(result, Err(VhdxError::ParentLogNotReplayed(_))));
+    }
+
+    // An image noting which structure each write goes to, and each sync.
+    struct Recorder {
+        image: Cursor<Vec<u8>>,
+        events: Vec<&'static str>,
+    }
+
+    impl Read for Recorder {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.image.read(buffer)
+        }
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            let event = match self.image.position() {
+                offset if offset < LOG_OFFSET => "header",
+                offset if offset < META_DATA_OFFSET => "log",
+                _ => "bat",
+            };
+            if self.events.last() != Some(&event) {
+                self.events.push(event);
+            }
+            self.image.write(buffer)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Recorder {
+        fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+            self.image.seek(position)
+        }
+    }
+
+    impl SyncData for Recorder {
+        fn sync_data(&mut self) -> io::Result<()> {
+            self.events.push("sync");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_sync_before_committing_what_was_written() {
+        let image = Recorder {
+            image: dynamic_image(4 * BLOCK_SIZE, &[]),
+            events: Vec::new(),
+        };
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+
+        vhdx.update_bat(&[(0, BatEntry::new(BatEntryState::Zero, 0))])
+            .unwrap();
+
+        assert_eq!(
+            vec!["header", "sync", "log", "sync", "bat", "sync", "header", "sync"],
+            vhdx.file.events
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use std::collections::BTreeMap;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::iter;
 
 use crc::{Crc, CRC_32_ISCSI};
@@ -14,10 +14,10 @@ use crate::parse_utils::{
     t_bool_u32, t_creator, t_guid, t_sign_u32, t_sign_u64, t_u16, t_u32, t_u64,
 };
 use crate::vhdx::Vhdx;
-use crate::{Crc32, DeSerialise, Signature, Validation};
+use crate::{Crc32, DeSerialise, Serialise, Signature, Validation};
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct VhdxHeader {
     fti: FileTypeIdentifier,
     pub header_1: Header,
@@ -26,7 +26,7 @@ pub struct VhdxHeader {
     pub region_table_2: RegionTable,
 }
 impl VhdxHeader {
-    fn new(
+    pub(crate) fn new(
         fti: FileTypeIdentifier,
         header_1: Header,
         header_2: Header,
@@ -65,8 +65,27 @@ impl<T> DeSerialise<T> for VhdxHeader {
     }
 }
 
+impl<T> Serialise<T> for VhdxHeader {
+    fn serialize(&self, writer: &mut T) -> Result<(), VhdxError>
+    where
+        T: Write + Seek,
+    {
+        writer.rewind()?;
+        self.fti.serialize(writer)?;
+        writer.seek(SeekFrom::Start(64 * Vhdx::KB))?;
+        self.header_1.serialize(writer)?;
+        writer.seek(SeekFrom::Start(128 * Vhdx::KB))?;
+        self.header_2.serialize(writer)?;
+        writer.seek(SeekFrom::Start(192 * Vhdx::KB))?;
+        self.region_table_1.serialize(writer)?;
+        writer.seek(SeekFrom::Start(256 * Vhdx::KB))?;
+        self.region_table_2.serialize(writer)?;
+        Ok(())
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FileTypeIdentifier {
     signature: Signature,
     creator: String,
@@ -76,7 +95,7 @@ impl FileTypeIdentifier {
     pub const SIGN: &'static [u8] = &[0x76, 0x68, 0x64, 0x78, 0x66, 0x69, 0x6C, 0x65];
     const SIZE: usize = 65536;
 
-    fn new(signature: Signature, creator: String) -> FileTypeIdentifier {
+    pub(crate) fn new(signature: Signature, creator: String) -> FileTypeIdentifier {
         Self { signature, creator }
     }
 }
@@ -98,6 +117,23 @@ impl<T> DeSerialise<T> for FileTypeIdentifier {
     }
 }
 
+impl<T> Serialise<T> for FileTypeIdentifier {
+    fn serialize(&self, writer: &mut T) -> Result<(), VhdxError>
+    where
+        T: Write + Seek,
+    {
+        let mut buffer = Vec::with_capacity(FileTypeIdentifier::SIZE);
+        buffer.extend_from_slice(FileTypeIdentifier::SIGN);
+        self.creator
+            .encode_utf16()
+            .take(255)
+            .for_each(|c| buffer.extend_from_slice(&c.to_le_bytes()));
+        buffer.resize(FileTypeIdentifier::SIZE, 0);
+        writer.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
 // Since the header is used to locate the log, updates to the headers cannot be made through the
 // log. To provide power failure consistency, there are two headers in every VHDX file. Each of the
 // two headers is a 4-KB structure that is aligned to a 64-KB boundary.<1> One header is stored at
@@ -166,7 +202,8 @@ pub struct Header {
 impl Header {
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
     pub const SIGN: &'static [u8] = &[0x68, 0x65, 0x61, 0x64];
-    fn new(
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
         signature: Signature,
         checksum: u32,
         seq_number: u64,
@@ -195,6 +232,37 @@ impl Header {
     pub fn sequence_number(&self) -> u64 {
         self.seq_number
     }
+
+    pub fn data_write_guid(&self) -> Uuid {
+        self.data_write_guid
+    }
+
+    pub(crate) fn update_checksum(&mut self) {
+        self.checksum = self.crc32();
+    }
+
+    // The header to write after this one, with the next sequence number. The file write guid is
+    // renewed since the header is only rewritten when the file is about to be modified.
+    pub(crate) fn successor(&self, log_guid: Uuid) -> Header {
+        let mut header = self.clone();
+        header.seq_number += 1;
+        header.file_write_guid = Uuid::new_v4();
+        header.log_guid = log_guid;
+        header.update_checksum();
+        header
+    }
+
+    // The same header with a new data write guid, for a change readers of the virtual disk can
+    // see. Differencing disks linked to the old guid no longer match this disk.
+    pub(crate) fn with_new_data_write_guid(self) -> Header {
+        self.with_data_write_guid(Uuid::new_v4())
+    }
+
+    pub(crate) fn with_data_write_guid(mut self, data_write_guid: Uuid) -> Header {
+        self.data_write_guid = data_write_guid;
+        self.update_checksum();
+        self
+    }
 }
 
 impl Crc32 for Header {
@@ -229,14 +297,14 @@ impl Validation for Header {
             return Err(VhdxError::NotAllowedToBeZero("Header Log Version"));
         }
 
-        if self.log_length as u64 % Vhdx::MB != 0 {
+        if !(self.log_length as u64).is_multiple_of(Vhdx::MB) {
             return Err(VhdxError::NotDivisbleByMB(
                 "Header Log Length",
                 self.log_length.into(),
             ));
         }
 
-        if self.log_offset % Vhdx::MB != 0 {
+        if !self.log_offset.is_multiple_of(Vhdx::MB) {
             return Err(VhdxError::NotDivisbleByMB(
                 "Header Log Offset",
                 self.log_offset,
@@ -294,12 +362,34 @@ impl<T> DeSerialise<T> for Header {
     }
 }
 
+impl<T> Serialise<T> for Header {
+    fn serialize(&self, writer: &mut T) -> Result<(), VhdxError>
+    where
+        T: Write + Seek,
+    {
+        let mut buffer = Vec::with_capacity((Vhdx::KB * 4) as usize);
+        buffer.extend_from_slice(Header::SIGN);
+        buffer.extend_from_slice(&self.checksum.to_le_bytes());
+        buffer.extend_from_slice(&self.seq_number.to_le_bytes());
+        buffer.extend_from_slice(&self.file_write_guid.to_bytes_le());
+        buffer.extend_from_slice(&self.data_write_guid.to_bytes_le());
+        buffer.extend_from_slice(&self.log_guid.to_bytes_le());
+        buffer.extend_from_slice(&self.log_version.to_le_bytes());
+        buffer.extend_from_slice(&self.version.to_le_bytes());
+        buffer.extend_from_slice(&self.log_length.to_le_bytes());
+        buffer.extend_from_slice(&self.log_offset.to_le_bytes());
+        buffer.resize((Vhdx::KB * 4) as usize, 0);
+        writer.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
 // The region table consists of a header followed by a variable number of entries, which specify
 // the identity and location of regions within the file. There are two copies of the region table,
 // stored at file offset 192 KB and file offset 256 KB. Updates to the region table structures must
 // be made through the log.
 #[allow(dead_code)]
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct RegionTable {
     // MUST be 0x72656769, which is a UTF-8 string representing "regi".
     signature: Signature,
@@ -317,8 +407,8 @@ impl RegionTable {
     pub const SIGN: &'static [u8] = &[0x72, 0x65, 0x67, 0x69];
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
 
-    const BAT_ENTRY: Uuid = uuid!("2DC27766F62342009D64115E9BFD4A08");
-    const META_DATA_ENTRY: Uuid = uuid!("8B7CA20647904B9AB8FE575F050F886E");
+    pub(crate) const BAT_ENTRY: Uuid = uuid!("2DC27766F62342009D64115E9BFD4A08");
+    pub(crate) const META_DATA_ENTRY: Uuid = uuid!("8B7CA20647904B9AB8FE575F050F886E");
 
     fn new(signature: Signature, checksum: u32, entry_count: u32) -> Self {
         Self {
@@ -328,6 +418,13 @@ impl RegionTable {
             table_entries: BTreeMap::new(),
         }
     }
+
+    pub(crate) fn with_entries(table_entries: BTreeMap<KnowRegion, RTEntry>) -> Self {
+        let mut table = RegionTable::new(Signature::Regi, 0, table_entries.len() as u32);
+        table.table_entries = table_entries;
+        table.checksum = table.crc32();
+        table
+    }
 }
 
 impl Validation for RegionTable {
@@ -362,7 +459,7 @@ impl Crc32 for RegionTable {
             entry.crc32_from_digest(&mut digest);
             length -= 32;
         });
-        let dead_space: Vec<u8> = iter::repeat(0).take(length as usize).collect();
+        let dead_space: Vec<u8> = iter::repeat_n(0, length as usize).collect();
         digest.update(&dead_space);
         digest.finalize()
     }
@@ -404,8 +501,27 @@ impl<T> DeSerialise<T> for RegionTable {
     }
 }
 
+impl<T> Serialise<T> for RegionTable {
+    fn serialize(&self, writer: &mut T) -> Result<(), VhdxError>
+    where
+        T: Write + Seek,
+    {
+        let mut buffer = Vec::with_capacity((Vhdx::KB * 64) as usize);
+        buffer.extend_from_slice(RegionTable::SIGN);
+        buffer.extend_from_slice(&self.checksum.to_le_bytes());
+        buffer.extend_from_slice(&self.entry_count.to_le_bytes());
+        buffer.extend_from_slice(&[0; 4]);
+        self.table_entries
+            .values()
+            .for_each(|entry| buffer.extend_from_slice(&entry.to_bytes()));
+        buffer.resize((Vhdx::KB * 64) as usize, 0);
+        writer.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct RTEntry {
     // Guid (16 bytes): Specifies a 128-bit identifier for the object (a GUID in binary form) and
     // MUST be unique within the table.
@@ -415,14 +531,14 @@ pub struct RTEntry {
     pub file_offset: u64,
     // Length (4 bytes): Specifies the 32-bit byte length of the object within the file. The value
     // MUST be a multiple of 1 MB.
-    length: u32,
+    pub length: u32,
     // Required (4 bytes): Specifies whether this region must be recognized by the implementation
     // in order to load the VHDX file. If this field's value is 1 and the impleme
     required: bool,
 }
 impl RTEntry {
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
-    fn new(guid: Uuid, file_offset: u64, length: u32, required: bool) -> Self {
+    pub(crate) fn new(guid: Uuid, file_offset: u64, length: u32, required: bool) -> Self {
         Self {
             guid,
             file_offset,
@@ -430,6 +546,15 @@ impl RTEntry {
             required,
         }
     }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(32);
+        buffer.extend_from_slice(&self.guid.to_bytes_le());
+        buffer.extend_from_slice(&self.file_offset.to_le_bytes());
+        buffer.extend_from_slice(&self.length.to_le_bytes());
+        buffer.extend_from_slice(&(self.required as u32).to_le_bytes());
+        buffer
+    }
 }
 
 impl Crc32 for RTEntry {
@@ -466,7 +591,7 @@ impl<T> DeSerialise<T> for RTEntry {
     }
 }
 
-#[derive(Debug, Ord, PartialOrd, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, Ord, PartialOrd, PartialEq, Eq, Hash)]
 pub enum KnowRegion {
     Bat,
     MetaData,
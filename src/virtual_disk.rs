@@ -0,0 +1,318 @@
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+
+use crate::{
+    bat::{sector_bitmap_bat_index, BatEntryState},
+    vhdx::Vhdx,
+};
+
+// Where the contents of a range of the virtual disk come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Allocation {
+    // Stored in this disk, for some sectors possibly only in its parent.
+    Data,
+    // Reads as zeros without being stored anywhere.
+    Zero,
+    // Not stored in this disk, all of it is read from the parent.
+    Parent,
+}
+
+// A read only view of the guest visible contents of a VHDX. Reads are resolved block by block
+// through the BAT, blocks without any payload read back as zeros.
+pub struct VirtualDisk<'a, T> {
+    vhdx: &'a mut Vhdx<T>,
+    position: u64,
+}
+
+impl<'a, T> VirtualDisk<'a, T>
+where
+    T: Read + Seek,
+{
+    pub(crate) fn new(vhdx: &'a mut Vhdx<T>) -> Self {
+        Self { vhdx, position: 0 }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.vhdx.virtual_disk_size()
+    }
+
+    // The BAT state of the payload block holding the offset.
+    pub(crate) fn state(&self, offset: u64) -> io::Result<BatEntryState> {
+        let block = offset / self.vhdx.block_size();
+        let entry = self.vhdx.payload_entry(block).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("BAT has no entry for payload block {}", block),
+            )
+        })?;
+        Ok(entry.state())
+    }
+
+    // The allocation of a range within one payload block.
+    pub(crate) fn allocation(&mut self, offset: u64, length: u64) -> io::Result<Allocation> {
+        let block_size = self.vhdx.block_size();
+        let block = offset / block_size;
+
+        match self.state(offset)? {
+            BatEntryState::FullyPresent => Ok(Allocation::Data),
+            BatEntryState::PartiallyPresent => {
+                let offset_in_block = offset % block_size;
+                let bitmap = self.sector_bitmap(block, offset_in_block, length)?;
+                let sector_size = self.vhdx.meta_data.logical_sector_size as u64;
+                let first_sector = offset_in_block / sector_size;
+                let last_sector = (offset_in_block + length).div_ceil(sector_size);
+
+                let any_present = (first_sector..last_sector).any(|sector| {
+                    let bit = sector - first_sector / 8 * 8;
+                    bitmap[bit as usize / 8] & (1 << (bit % 8)) != 0
+                });
+                Ok(match any_present {
+                    true => Allocation::Data,
+                    false => Allocation::Parent,
+                })
+            }
+            BatEntryState::NotPresent if self.vhdx.has_parent() => Ok(Allocation::Parent),
+            _ => Ok(Allocation::Zero),
+        }
+    }
+
+    // The ranges of a payload block this disk answers for itself, with its own data or with
+    // zeros, rather than leaving them to its parent.
+    pub(crate) fn own_ranges(&mut self, block: u64) -> io::Result<Vec<(u64, u64)>> {
+        let block_size = self.vhdx.block_size();
+        let start = block * block_size;
+        let length = block_size.min(self.size().saturating_sub(start));
+
+        match self.state(start)? {
+            BatEntryState::PartiallyPresent => {
+                let bitmap = self.sector_bitmap(block, 0, length)?;
+                let sector_size = self.vhdx.meta_data.logical_sector_size as u64;
+
+                let mut ranges: Vec<(u64, u64)> = Vec::new();
+                for sector in 0..length / sector_size {
+                    if bitmap[sector as usize / 8] & (1 << (sector % 8)) == 0 {
+                        continue;
+                    }
+                    let offset = start + sector * sector_size;
+                    match ranges.last_mut() {
+                        Some((first, run)) if *first + *run == offset => *run += sector_size,
+                        _ => ranges.push((offset, sector_size)),
+                    }
+                }
+                Ok(ranges)
+            }
+            BatEntryState::NotPresent if self.vhdx.has_parent() => Ok(Vec::new()),
+            _ => Ok(vec![(start, length)]),
+        }
+    }
+}
+
+impl<T> Read for VirtualDisk<'_, T>
+where
+    T: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.size();
+        if self.position >= size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Never read past the end of the current block, the next block can live anywhere in the
+        // file.
+        let block_size = self.vhdx.block_size();
+        let block = self.position / block_size;
+        let offset_in_block = self.position % block_size;
+        let length = (buf.len() as u64)
+            .min(block_size - offset_in_block)
+            .min(size - self.position) as usize;
+        let buf = &mut buf[..length];
+
+        let entry = self.vhdx.payload_entry(block).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("BAT has no entry for payload block {}", block),
+            )
+        })?;
+        let (state, file_offset) = (entry.state(), entry.file_offset());
+
+        match state {
+            BatEntryState::FullyPresent => {
+                self.read_file(file_offset + offset_in_block, buf)?;
+            }
+            // Only the sectors marked in the sector bitmap are stored in a partially present
+            // block, the other sectors come from the parent.
+            BatEntryState::PartiallyPresent => {
+                let bitmap = self.sector_bitmap(block, offset_in_block, length as u64)?;
+                let sector_size = self.vhdx.meta_data.logical_sector_size as usize;
+                let first_sector = offset_in_block as usize / sector_size;
+                let is_present = |position: usize| {
+                    let bit = (offset_in_block as usize + position) / sector_size - first_sector
+                        + first_sector % 8;
+                    bitmap[bit / 8] & (1 << (bit % 8)) != 0
+                };
+
+                let mut start = 0;
+                while start < length {
+                    let present = is_present(start);
+                    let mut end = start;
+                    while end < length && is_present(end) == present {
+                        end = (end + sector_size - (offset_in_block as usize + end) % sector_size)
+                            .min(length);
+                    }
+
+                    let position = self.position + start as u64;
+                    if present {
+                        self.read_file(
+                            file_offset + offset_in_block + start as u64,
+                            &mut buf[start..end],
+                        )?;
+                    } else {
+                        self.read_parent(position, &mut buf[start..end])?;
+                    }
+                    start = end;
+                }
+            }
+            BatEntryState::NotPresent if self.vhdx.has_parent() => {
+                self.read_parent(self.position, buf)?;
+            }
+            _ => buf.fill(0),
+        }
+
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<T> VirtualDisk<'_, T>
+where
+    T: Read + Seek,
+{
+    fn read_file(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.vhdx.file.seek(SeekFrom::Start(offset))?;
+        self.vhdx.file.read_exact(buf)
+    }
+
+    // Reads the same range of the parent disk. A parent smaller than its child reads as zeros
+    // past its end.
+    fn read_parent(&mut self, position: u64, buf: &mut [u8]) -> io::Result<()> {
+        let parent = self.vhdx.parent.as_deref_mut().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                "Parent of the differencing disk isn't attached",
+            )
+        })?;
+
+        let mut disk = parent.virtual_disk();
+        disk.seek(SeekFrom::Start(position))?;
+        let mut read = 0;
+        while read < buf.len() {
+            match disk.read(&mut buf[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        buf[read..].fill(0);
+        Ok(())
+    }
+
+    // The bytes of the sector bitmap covering a range of a block, starting with the byte holding
+    // the bit of the first sector of the range.
+    fn sector_bitmap(
+        &mut self,
+        block: u64,
+        offset_in_block: u64,
+        length: u64,
+    ) -> io::Result<Vec<u8>> {
+        let meta_data = &self.vhdx.meta_data;
+        let chunk_ratio = meta_data.chunk_ratio;
+        let sector_size = meta_data.logical_sector_size as u64;
+        let sectors_per_block = self.vhdx.block_size() / sector_size;
+
+        let entry = self
+            .vhdx
+            .bat_table
+            .get(sector_bitmap_bat_index(block, chunk_ratio))
+            .filter(|entry| entry.state() == BatEntryState::FullyPresent)
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Sector bitmap of payload block {} isn't present", block),
+                )
+            })?;
+
+        let first_sector =
+            (block % chunk_ratio) * sectors_per_block + offset_in_block / sector_size;
+        let last_sector = (block % chunk_ratio) * sectors_per_block
+            + (offset_in_block + length).div_ceil(sector_size);
+        let offset = entry.file_offset() + first_sector / 8;
+        let bitmap_length = last_sector.div_ceil(8) - first_sector / 8;
+
+        let mut bitmap = vec![0; bitmap_length as usize];
+        self.read_file(offset, &mut bitmap)?;
+        Ok(bitmap)
+    }
+}
+
+impl<T> Seek for VirtualDisk<'_, T>
+where
+    T: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{dynamic_image, BLOCK_SIZE};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_read_allocated_and_unallocated_blocks() {
+        let image = dynamic_image(4 * BLOCK_SIZE, &[(0, 0xAA), (2, 0xBB)]);
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        let mut disk = vhdx.virtual_disk();
+
+        let mut content = Vec::new();
+        disk.read_to_end(&mut content).unwrap();
+
+        assert_eq!(4 * BLOCK_SIZE, content.len());
+        assert!(content[..BLOCK_SIZE].iter().all(|b| *b == 0xAA));
+        assert!(content[BLOCK_SIZE..2 * BLOCK_SIZE].iter().all(|b| *b == 0));
+        assert!(content[2 * BLOCK_SIZE..3 * BLOCK_SIZE]
+            .iter()
+            .all(|b| *b == 0xBB));
+        assert!(content[3 * BLOCK_SIZE..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn should_read_across_block_boundaries() {
+        let image = dynamic_image(2 * BLOCK_SIZE, &[(0, 0x11), (1, 0x22)]);
+        let mut vhdx = Vhdx::from_reader(image).unwrap();
+        let mut disk = vhdx.virtual_disk();
+
+        let mut buffer = [0; 4];
+        disk.seek(SeekFrom::Start(BLOCK_SIZE as u64 - 2)).unwrap();
+        disk.read_exact(&mut buffer).unwrap();
+
+        assert_eq!([0x11, 0x11, 0x22, 0x22], buffer);
+
+        disk.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(0, disk.read(&mut buffer).unwrap());
+    }
+}
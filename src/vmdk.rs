@@ -0,0 +1,752 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use nom::{
+    number::complete::{le_u16, le_u32, le_u64, u8 as t_u8},
+    sequence::tuple,
+    Finish,
+};
+
+use crate::{
+    error::{VhdxError, VhdxParseError},
+    vhdx::Vhdx,
+    virtual_disk::{Allocation, VirtualDisk},
+};
+
+const SECTOR_SIZE: u64 = 512;
+
+// "KDMV" on disk.
+const MAGIC: u32 = 0x564D_444B;
+
+// Grains of 64 KB and grain tables of 512 entries, the values every VMware product writes.
+const GRAIN_SIZE: u64 = 128;
+const GTES_PER_GT: u64 = 512;
+
+// Grains and grain tables are found by 32 bit sector numbers, which caps sparse extents at 2 TB.
+const MAX_SECTOR: u64 = u32::MAX as u64;
+
+// Limits of what readers accept, the ones of QEMU: grains of 1 GB and grain tables of 512 entries.
+const MAX_GRAIN_SIZE: u64 = 0x20_0000;
+const MAX_GTES_PER_GT: u32 = 512;
+
+// Sectors reserved for the embedded descriptor.
+const DESCRIPTOR_SIZE: u64 = 20;
+
+// Stream optimized extents only know where their grain directory is once it is written, the header
+// says so and the footer holds the real offset.
+const GD_AT_END: u64 = u64::MAX;
+
+const VALID_NEW_LINE_DETECTION: u32 = 1;
+const COMPRESSED_GRAINS: u32 = 1 << 16;
+const MARKERS: u32 = 1 << 17;
+
+const COMPRESSION_DEFLATE: u16 = 1;
+
+const MARKER_EOS: u32 = 0;
+const MARKER_GT: u32 = 1;
+const MARKER_GD: u32 = 2;
+const MARKER_FOOTER: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmdkFormat {
+    // A single sparse extent with the grain directory and grain tables preallocated after the
+    // descriptor.
+    MonolithicSparse,
+    // Compressed grains written as one stream, the tables follow the grains they map.
+    StreamOptimized,
+}
+
+impl VmdkFormat {
+    fn create_type(&self) -> &'static str {
+        match self {
+            VmdkFormat::MonolithicSparse => "monolithicSparse",
+            VmdkFormat::StreamOptimized => "streamOptimized",
+        }
+    }
+}
+
+// The header of a hosted sparse extent, all fields are little endian. The stream optimized footer
+// has the same layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseExtentHeader {
+    pub version: u32,
+    pub flags: u32,
+
+    // Capacity of the extent and size of a grain, in sectors.
+    pub capacity: u64,
+    pub grain_size: u64,
+
+    // Embedded descriptor, in sectors.
+    pub descriptor_offset: u64,
+    pub descriptor_size: u64,
+
+    pub num_gtes_per_gt: u32,
+
+    // Offsets of the redundant and the primary grain directory, in sectors.
+    pub rgd_offset: u64,
+    pub gd_offset: u64,
+
+    // Sectors of metadata before the first grain.
+    pub over_head: u64,
+    pub unclean_shutdown: bool,
+    pub compress_algorithm: u16,
+}
+
+impl SparseExtentHeader {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(SECTOR_SIZE as usize);
+        buffer.extend_from_slice(&MAGIC.to_le_bytes());
+        buffer.extend_from_slice(&self.version.to_le_bytes());
+        buffer.extend_from_slice(&self.flags.to_le_bytes());
+        buffer.extend_from_slice(&self.capacity.to_le_bytes());
+        buffer.extend_from_slice(&self.grain_size.to_le_bytes());
+        buffer.extend_from_slice(&self.descriptor_offset.to_le_bytes());
+        buffer.extend_from_slice(&self.descriptor_size.to_le_bytes());
+        buffer.extend_from_slice(&self.num_gtes_per_gt.to_le_bytes());
+        buffer.extend_from_slice(&self.rgd_offset.to_le_bytes());
+        buffer.extend_from_slice(&self.gd_offset.to_le_bytes());
+        buffer.extend_from_slice(&self.over_head.to_le_bytes());
+        buffer.push(self.unclean_shutdown as u8);
+        // The line ending characters let readers detect files mangled by text mode transfers.
+        buffer.extend_from_slice(b"\n \r\n");
+        buffer.extend_from_slice(&self.compress_algorithm.to_le_bytes());
+        buffer.resize(SECTOR_SIZE as usize, 0);
+        buffer
+    }
+
+    fn parse(buffer: &[u8]) -> Result<Self, VhdxError> {
+        let (_, header) = tuple((
+            le_u32, le_u32, le_u32, le_u64, le_u64, le_u64, le_u64, le_u32, le_u64, le_u64, le_u64,
+            t_u8,
+        ))(buffer)
+        .finish()
+        .map_err(|e: VhdxParseError<&[u8]>| VhdxError::from(e))?;
+        let (_, compress_algorithm) = le_u16(&buffer[77..])
+            .finish()
+            .map_err(|e: VhdxParseError<&[u8]>| VhdxError::from(e))?;
+
+        if header.0 != MAGIC {
+            return Err(VhdxError::InvalidVmdk("Magic not found"));
+        }
+        if header.4 == 0 || header.7 == 0 {
+            return Err(VhdxError::InvalidVmdk(
+                "Grain size or grain table size is zero",
+            ));
+        }
+        if header.4 > MAX_GRAIN_SIZE || header.7 > MAX_GTES_PER_GT {
+            return Err(VhdxError::InvalidVmdk(
+                "Grain size or grain table size too large",
+            ));
+        }
+        if header.3.checked_mul(SECTOR_SIZE).is_none() {
+            return Err(VhdxError::InvalidVmdk("Capacity too large"));
+        }
+        if header.2 & COMPRESSED_GRAINS != 0 && compress_algorithm != COMPRESSION_DEFLATE {
+            return Err(VhdxError::InvalidVmdk("Unknown compression algorithm"));
+        }
+
+        Ok(Self {
+            version: header.1,
+            flags: header.2,
+            capacity: header.3,
+            grain_size: header.4,
+            descriptor_offset: header.5,
+            descriptor_size: header.6,
+            num_gtes_per_gt: header.7,
+            rgd_offset: header.8,
+            gd_offset: header.9,
+            over_head: header.10,
+            unclean_shutdown: header.11 != 0,
+            compress_algorithm,
+        })
+    }
+}
+
+// The descriptor of a single extent disk. Its geometry is the IDE one VMware reports for sparse
+// disks.
+fn descriptor(format: VmdkFormat, capacity: u64, extent_name: &str) -> String {
+    let cid = content_id();
+    let cylinders = (capacity / (16 * 63)).min(16383);
+    format!(
+        "# Disk DescriptorFile\n\
+         version=1\n\
+         CID={cid:08x}\n\
+         parentCID=ffffffff\n\
+         createType=\"{}\"\n\
+         \n\
+         # Extent description\n\
+         RW {capacity} SPARSE \"{extent_name}\"\n\
+         \n\
+         # The Disk Data Base\n\
+         #DDB\n\
+         \n\
+         ddb.virtualHWVersion = \"4\"\n\
+         ddb.geometry.cylinders = \"{cylinders}\"\n\
+         ddb.geometry.heads = \"16\"\n\
+         ddb.geometry.sectors = \"63\"\n\
+         ddb.adapterType = \"ide\"\n",
+        format.create_type()
+    )
+}
+
+// Content id of the disk, any value changing with each new disk does.
+fn content_id() -> u32 {
+    let id = uuid::Uuid::new_v4().as_u128();
+    (id as u32) ^ ((id >> 32) as u32)
+}
+
+// The marker of metadata in a stream optimized extent, value is the number of sectors following
+// it.
+fn marker(value: u64, marker_type: u32) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(SECTOR_SIZE as usize);
+    buffer.extend_from_slice(&value.to_le_bytes());
+    buffer.extend_from_slice(&0u32.to_le_bytes());
+    buffer.extend_from_slice(&marker_type.to_le_bytes());
+    buffer.resize(SECTOR_SIZE as usize, 0);
+    buffer
+}
+
+fn table_bytes(entries: &[u32]) -> Vec<u8> {
+    let mut buffer: Vec<u8> = entries
+        .iter()
+        .flat_map(|entry| entry.to_le_bytes())
+        .collect();
+    buffer.resize(buffer.len().next_multiple_of(SECTOR_SIZE as usize), 0);
+    buffer
+}
+
+// A VMDK sparse extent opened for reading, monolithicSparse or streamOptimized. Unallocated grains
+// read as zeros.
+pub struct Vmdk<R> {
+    reader: R,
+    header: SparseExtentHeader,
+    // Sectors of the grain tables, and the tables found there. Tables are only read once however
+    // many entries of the directory point to them.
+    directory: Vec<u32>,
+    grain_tables: BTreeMap<u32, Vec<u32>>,
+    // The last grain decompressed, reads are much smaller than a grain.
+    grain_cache: Option<(u64, Vec<u8>)>,
+    position: u64,
+}
+
+impl Vmdk<File> {
+    pub fn open(path: &impl AsRef<Path>) -> Result<Self, VhdxError> {
+        Vmdk::from_reader(File::open(path)?)
+    }
+}
+
+impl<R> Vmdk<R>
+where
+    R: Read + Seek,
+{
+    pub fn from_reader(mut reader: R) -> Result<Self, VhdxError> {
+        let file_length = reader.seek(SeekFrom::End(0))?;
+        let mut buffer = [0; SECTOR_SIZE as usize];
+        reader.rewind()?;
+        reader.read_exact(&mut buffer)?;
+        let mut header = SparseExtentHeader::parse(&buffer)?;
+
+        // The footer sits before the end of stream marker, right after its own marker.
+        if header.gd_offset == GD_AT_END {
+            reader.seek(SeekFrom::End(-2 * SECTOR_SIZE as i64))?;
+            reader.read_exact(&mut buffer)?;
+            header = SparseExtentHeader::parse(&buffer)?;
+        }
+
+        // The directory is sized by the capacity in the header, which is only trusted as far as
+        // the file holds that directory.
+        let grains = header.capacity.div_ceil(header.grain_size);
+        let gt_count = grains.div_ceil(header.num_gtes_per_gt as u64);
+        if header
+            .gd_offset
+            .checked_mul(SECTOR_SIZE)
+            .and_then(|offset| offset.checked_add(gt_count * 4))
+            .is_none_or(|end| end > file_length)
+        {
+            return Err(VhdxError::InvalidVmdk(
+                "Grain directory past the end of the file",
+            ));
+        }
+        let mut directory = vec![0; gt_count as usize * 4];
+        reader.seek(SeekFrom::Start(header.gd_offset * SECTOR_SIZE))?;
+        reader.read_exact(&mut directory)?;
+        let directory: Vec<u32> = directory
+            .chunks_exact(4)
+            .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+            .collect();
+
+        let mut grain_tables = BTreeMap::new();
+        for offset in &directory {
+            if *offset == 0 || grain_tables.contains_key(offset) {
+                continue;
+            }
+            let mut table = vec![0; header.num_gtes_per_gt as usize * 4];
+            reader.seek(SeekFrom::Start(*offset as u64 * SECTOR_SIZE))?;
+            reader.read_exact(&mut table)?;
+            let table = table
+                .chunks_exact(4)
+                .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+                .collect();
+            grain_tables.insert(*offset, table);
+        }
+
+        Ok(Self {
+            reader,
+            header,
+            directory,
+            grain_tables,
+            grain_cache: None,
+            position: 0,
+        })
+    }
+
+    pub fn header(&self) -> &SparseExtentHeader {
+        &self.header
+    }
+
+    pub fn size(&self) -> u64 {
+        self.header.capacity * SECTOR_SIZE
+    }
+
+    // Number of grains stored in the extent.
+    pub fn allocated_grains(&self) -> usize {
+        self.directory
+            .iter()
+            .filter_map(|offset| self.grain_tables.get(offset))
+            .map(|table| table.iter().filter(|entry| **entry > 1).count())
+            .sum()
+    }
+
+    // The grain table entry of the grain, 0 for grains of tables that aren't allocated.
+    fn grain_entry(&self, grain: u64) -> u32 {
+        let per_table = self.header.num_gtes_per_gt as u64;
+        self.directory
+            .get((grain / per_table) as usize)
+            .and_then(|offset| self.grain_tables.get(offset))
+            .map_or(0, |table| table[(grain % per_table) as usize])
+    }
+
+    fn grain_bytes(&self) -> u64 {
+        self.header.grain_size * SECTOR_SIZE
+    }
+
+    // Reads a whole compressed grain, its marker gives the length of the deflated data.
+    fn read_compressed_grain(&mut self, grain: u64, offset: u64) -> io::Result<()> {
+        if self
+            .grain_cache
+            .as_ref()
+            .is_some_and(|(cached, _)| *cached == grain)
+        {
+            return Ok(());
+        }
+
+        let mut marker = [0; 12];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut marker)?;
+        let size = u32::from_le_bytes(marker[8..].try_into().unwrap());
+
+        let mut data = vec![0; self.grain_bytes() as usize];
+        let mut decoder = ZlibDecoder::new((&mut self.reader).take(size as u64));
+        decoder.read_exact(&mut data)?;
+        self.grain_cache = Some((grain, data));
+        Ok(())
+    }
+}
+
+impl<R> Read for Vmdk<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.size();
+        if self.position >= size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let grain_bytes = self.grain_bytes();
+        let grain = self.position / grain_bytes;
+        let offset_in_grain = self.position % grain_bytes;
+        let length = (buf.len() as u64)
+            .min(grain_bytes - offset_in_grain)
+            .min(size - self.position) as usize;
+        let buf = &mut buf[..length];
+
+        // Entries of 0 are unallocated and of 1 zeroed grains, both read as zeros.
+        match self.grain_entry(grain) {
+            0 | 1 => buf.fill(0),
+            sector if self.header.flags & COMPRESSED_GRAINS != 0 => {
+                self.read_compressed_grain(grain, sector as u64 * SECTOR_SIZE)?;
+                let (_, data) = self.grain_cache.as_ref().unwrap();
+                buf.copy_from_slice(&data[offset_in_grain as usize..][..length]);
+            }
+            sector => {
+                self.reader.seek(SeekFrom::Start(
+                    sector as u64 * SECTOR_SIZE + offset_in_grain,
+                ))?;
+                self.reader.read_exact(buf)?;
+            }
+        }
+
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<R> Seek for Vmdk<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    // Writes the disk as a single extent VMDK, its descriptor names the file itself.
+    pub fn export_vmdk(
+        &mut self,
+        dest: &impl AsRef<Path>,
+        format: VmdkFormat,
+    ) -> Result<(), VhdxError> {
+        let extent_name = dest
+            .as_ref()
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut file = File::create(dest)?;
+        self.export_vmdk_to(&mut file, format, &extent_name)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    // Writes the disk as a sparse extent with its descriptor embedded. Only grains stored in the
+    // VHDX are written, and grains of a flattened parent holding data, all others stay
+    // unallocated.
+    pub fn export_vmdk_to<W>(
+        &mut self,
+        writer: &mut W,
+        format: VmdkFormat,
+        extent_name: &str,
+    ) -> Result<(), VhdxError>
+    where
+        W: Write + Seek,
+    {
+        let size = self.virtual_disk_size();
+        let capacity = size.div_ceil(SECTOR_SIZE);
+        let grains = capacity.div_ceil(GRAIN_SIZE);
+        let gt_count = grains.div_ceil(GTES_PER_GT);
+        let gt_sectors = GTES_PER_GT * 4 / SECTOR_SIZE;
+        let gd_sectors = (gt_count * 4).div_ceil(SECTOR_SIZE);
+        let metadata_sectors = 1 + DESCRIPTOR_SIZE + gd_sectors + gt_count * gt_sectors;
+        if metadata_sectors + grains * GRAIN_SIZE > MAX_SECTOR {
+            return Err(VhdxError::InvalidVmdk(
+                "Disk too large for the sectors a sparse extent addresses",
+            ));
+        }
+
+        let mut descriptor = descriptor(format, capacity, extent_name).into_bytes();
+        if descriptor.len() as u64 > DESCRIPTOR_SIZE * SECTOR_SIZE {
+            return Err(VhdxError::InvalidVmdk("Descriptor doesn't fit its sectors"));
+        }
+        descriptor.resize((DESCRIPTOR_SIZE * SECTOR_SIZE) as usize, 0);
+
+        let mut header = SparseExtentHeader {
+            version: 1,
+            flags: VALID_NEW_LINE_DETECTION,
+            capacity,
+            grain_size: GRAIN_SIZE,
+            descriptor_offset: 1,
+            descriptor_size: DESCRIPTOR_SIZE,
+            num_gtes_per_gt: GTES_PER_GT as u32,
+            rgd_offset: 0,
+            gd_offset: 0,
+            over_head: 0,
+            unclean_shutdown: false,
+            compress_algorithm: 0,
+        };
+        let mut grain_tables = vec![0u32; (gt_count * GTES_PER_GT) as usize];
+        let mut buffer = vec![0; (GRAIN_SIZE * SECTOR_SIZE) as usize];
+        let mut disk = self.virtual_disk();
+
+        match format {
+            VmdkFormat::MonolithicSparse => {
+                header.gd_offset = 1 + DESCRIPTOR_SIZE;
+                let gt_offset = header.gd_offset + gd_sectors;
+                header.over_head = (gt_offset + gt_count * gt_sectors).next_multiple_of(GRAIN_SIZE);
+
+                let mut next_sector = header.over_head;
+                for (grain, entry) in grain_tables.iter_mut().enumerate() {
+                    if grain as u64 >= grains || !read_grain(&mut disk, grain as u64, &mut buffer)?
+                    {
+                        continue;
+                    }
+                    writer.seek(SeekFrom::Start(next_sector * SECTOR_SIZE))?;
+                    writer.write_all(&buffer)?;
+                    *entry = sector_number(next_sector)?;
+                    next_sector += GRAIN_SIZE;
+                }
+
+                let directory = (0..gt_count)
+                    .map(|gt| sector_number(gt_offset + gt * gt_sectors))
+                    .collect::<Result<Vec<u32>, VhdxError>>()?;
+                writer.rewind()?;
+                writer.write_all(&header.to_bytes())?;
+                writer.write_all(&descriptor)?;
+                writer.write_all(&table_bytes(&directory))?;
+                writer.write_all(&table_bytes(&grain_tables))?;
+                // An image without any grain still covers its preallocated metadata.
+                if writer.seek(SeekFrom::End(0))? < header.over_head * SECTOR_SIZE {
+                    writer.seek(SeekFrom::Start(header.over_head * SECTOR_SIZE - 1))?;
+                    writer.write_all(&[0])?;
+                }
+            }
+            VmdkFormat::StreamOptimized => {
+                header.version = 3;
+                header.flags |= COMPRESSED_GRAINS | MARKERS;
+                header.compress_algorithm = COMPRESSION_DEFLATE;
+                header.over_head = (1 + DESCRIPTOR_SIZE).next_multiple_of(GRAIN_SIZE);
+                header.gd_offset = GD_AT_END;
+
+                writer.rewind()?;
+                writer.write_all(&header.to_bytes())?;
+                writer.write_all(&descriptor)?;
+                let mut sector = header.over_head;
+                writer.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+
+                let mut directory = vec![0u32; gt_count as usize];
+                for (gt, table) in grain_tables.chunks_mut(GTES_PER_GT as usize).enumerate() {
+                    for (i, entry) in table.iter_mut().enumerate() {
+                        let grain = gt as u64 * GTES_PER_GT + i as u64;
+                        if grain >= grains || !read_grain(&mut disk, grain, &mut buffer)? {
+                            continue;
+                        }
+
+                        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                        encoder.write_all(&buffer)?;
+                        let compressed = encoder.finish()?;
+
+                        let mut grain_marker = Vec::with_capacity(12 + compressed.len());
+                        grain_marker.extend_from_slice(&(grain * GRAIN_SIZE).to_le_bytes());
+                        grain_marker.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+                        grain_marker.extend_from_slice(&compressed);
+                        grain_marker
+                            .resize(grain_marker.len().next_multiple_of(SECTOR_SIZE as usize), 0);
+                        writer.write_all(&grain_marker)?;
+                        *entry = sector_number(sector)?;
+                        sector += grain_marker.len() as u64 / SECTOR_SIZE;
+                    }
+
+                    // Grain tables without any grain are left out, the directory maps them to 0.
+                    if table.iter().any(|entry| *entry != 0) {
+                        writer.write_all(&marker(gt_sectors, MARKER_GT))?;
+                        writer.write_all(&table_bytes(table))?;
+                        directory[gt] = sector_number(sector + 1)?;
+                        sector += 1 + gt_sectors;
+                    }
+                }
+
+                writer.write_all(&marker(gd_sectors, MARKER_GD))?;
+                writer.write_all(&table_bytes(&directory))?;
+                header.gd_offset = sector + 1;
+
+                writer.write_all(&marker(1, MARKER_FOOTER))?;
+                writer.write_all(&header.to_bytes())?;
+                writer.write_all(&marker(0, MARKER_EOS))?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// The sector number of a grain or grain table as it is written. Compressed grains can take more
+// room than the grains themselves, so the size of the disk checked up front isn't enough.
+fn sector_number(sector: u64) -> Result<u32, VhdxError> {
+    u32::try_from(sector).map_err(|_| {
+        VhdxError::InvalidVmdk("Disk too large for the sectors a sparse extent addresses")
+    })
+}
+
+// Reads the grain into the buffer, zero padded past the end of the disk. Returns false for grains
+// that stay unallocated: zeros, and data of the parent that only holds zeros.
+fn read_grain<T>(disk: &mut VirtualDisk<'_, T>, grain: u64, buffer: &mut [u8]) -> io::Result<bool>
+where
+    T: Read + Seek,
+{
+    let offset = grain * buffer.len() as u64;
+    let length = (buffer.len() as u64).min(disk.size() - offset);
+    let allocation = disk.allocation(offset, length)?;
+    if allocation == Allocation::Zero {
+        return Ok(false);
+    }
+
+    buffer.fill(0);
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.read_exact(&mut buffer[..length as usize])?;
+    Ok(allocation == Allocation::Data || buffer.iter().any(|b| *b != 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_utils::{differencing_image, dynamic_image, parent_locator, BLOCK_SIZE};
+    use pretty_assertions::assert_eq;
+
+    const GRAINS_PER_BLOCK: usize = BLOCK_SIZE / (GRAIN_SIZE * SECTOR_SIZE) as usize;
+
+    fn round_trip(
+        vhdx: &mut Vhdx<Cursor<Vec<u8>>>,
+        format: VmdkFormat,
+    ) -> (Vmdk<Cursor<Vec<u8>>>, u64) {
+        let mut image = Cursor::new(Vec::new());
+        vhdx.export_vmdk_to(&mut image, format, "disk.vmdk")
+            .unwrap();
+        let length = image.get_ref().len() as u64;
+
+        let mut vmdk = Vmdk::from_reader(image).unwrap();
+        let mut expected = Vec::new();
+        vhdx.virtual_disk().read_to_end(&mut expected).unwrap();
+        let mut content = Vec::new();
+        vmdk.read_to_end(&mut content).unwrap();
+        assert!(content == expected);
+        (vmdk, length)
+    }
+
+    fn descriptor_of(vmdk: &Vmdk<Cursor<Vec<u8>>>) -> String {
+        let header = vmdk.header().clone();
+        let image = vmdk.reader.get_ref();
+        let start = (header.descriptor_offset * SECTOR_SIZE) as usize;
+        let descriptor = &image[start..][..(header.descriptor_size * SECTOR_SIZE) as usize];
+        String::from_utf8_lossy(descriptor)
+            .trim_end_matches('\0')
+            .to_string()
+    }
+
+    #[test]
+    fn should_round_trip_monolithic_sparse() {
+        let mut vhdx =
+            Vhdx::from_reader(dynamic_image(6 * BLOCK_SIZE, &[(1, 0x11), (4, 0x44)])).unwrap();
+
+        let (vmdk, length) = round_trip(&mut vhdx, VmdkFormat::MonolithicSparse);
+
+        assert_eq!(1, vmdk.header().version);
+        assert_eq!(6 * BLOCK_SIZE as u64 / SECTOR_SIZE, vmdk.header().capacity);
+        assert_eq!(2 * GRAINS_PER_BLOCK, vmdk.allocated_grains());
+        assert_eq!(
+            vmdk.header().over_head * SECTOR_SIZE + 2 * BLOCK_SIZE as u64,
+            length
+        );
+        let descriptor = descriptor_of(&vmdk);
+        assert!(descriptor.contains("createType=\"monolithicSparse\""));
+        assert!(descriptor.contains("RW 12288 SPARSE \"disk.vmdk\""));
+    }
+
+    #[test]
+    fn should_round_trip_stream_optimized() {
+        let mut vhdx =
+            Vhdx::from_reader(dynamic_image(6 * BLOCK_SIZE, &[(0, 0xAA), (5, 0x55)])).unwrap();
+
+        let (vmdk, length) = round_trip(&mut vhdx, VmdkFormat::StreamOptimized);
+
+        assert_eq!(3, vmdk.header().version);
+        assert_eq!(
+            COMPRESSED_GRAINS | MARKERS,
+            vmdk.header().flags & (COMPRESSED_GRAINS | MARKERS)
+        );
+        assert_eq!(2 * GRAINS_PER_BLOCK, vmdk.allocated_grains());
+        // Grains of one repeated byte deflate to almost nothing.
+        assert!(length < BLOCK_SIZE as u64);
+        assert!(descriptor_of(&vmdk).contains("createType=\"streamOptimized\""));
+    }
+
+    #[test]
+    fn should_flatten_differencing_chain_into_vmdk() {
+        let parent =
+            Vhdx::from_reader(dynamic_image(4 * BLOCK_SIZE, &[(0, 0xAA), (1, 0xBB)])).unwrap();
+        let child = differencing_image(
+            4 * BLOCK_SIZE,
+            parent_locator("parent.vhdx"),
+            &[(0, 0xCC, Some(vec![0x0F])), (2, 0xDD, None)],
+        );
+        let mut vhdx = Vhdx::from_reader(child)
+            .unwrap()
+            .with_parent(parent)
+            .unwrap();
+
+        for format in [VmdkFormat::MonolithicSparse, VmdkFormat::StreamOptimized] {
+            let (vmdk, _) = round_trip(&mut vhdx, format);
+            assert_eq!(3 * GRAINS_PER_BLOCK, vmdk.allocated_grains());
+        }
+    }
+
+    #[test]
+    fn should_refuse_disks_of_two_terabytes() {
+        // Exactly 2 TB, the metadata before the grains pushes the last ones out of reach.
+        let mut vhdx = Vhdx::from_reader(dynamic_image(2 << 40, &[])).unwrap();
+
+        for format in [VmdkFormat::MonolithicSparse, VmdkFormat::StreamOptimized] {
+            let result = vhdx.export_vmdk_to(&mut Cursor::new(Vec::new()), format, "disk.vmdk");
+            assert!(matches!(result, Err(VhdxError::InvalidVmdk(_))));
+        }
+    }
+
+    #[test]
+    fn should_bound_tables_by_the_file() {
+        // Two grain tables, the second one without any grain.
+        let mut vhdx = Vhdx::from_reader(dynamic_image(64 * BLOCK_SIZE, &[(0, 0xAA)])).unwrap();
+        let mut image = Cursor::new(Vec::new());
+        vhdx.export_vmdk_to(&mut image, VmdkFormat::MonolithicSparse, "disk.vmdk")
+            .unwrap();
+        let image = image.into_inner();
+        let patched = |offset: usize, value: &[u8]| {
+            let mut image = image.clone();
+            image[offset..offset + value.len()].copy_from_slice(value);
+            Cursor::new(image)
+        };
+
+        // Capacity, grain table entries, and a directory whose entries all name the first table.
+        let huge_capacity = patched(12, &(u64::MAX / SECTOR_SIZE).to_le_bytes());
+        let huge_tables = patched(44, &u32::MAX.to_le_bytes());
+        let gd_offset = (1 + DESCRIPTOR_SIZE) as usize * SECTOR_SIZE as usize;
+        let first_table = image[gd_offset..gd_offset + 4].to_vec();
+        let shared = patched(gd_offset + 4, &first_table);
+
+        assert!(matches!(
+            Vmdk::from_reader(huge_capacity),
+            Err(VhdxError::InvalidVmdk(
+                "Grain directory past the end of the file"
+            ))
+        ));
+        assert!(matches!(
+            Vmdk::from_reader(huge_tables),
+            Err(VhdxError::InvalidVmdk(_))
+        ));
+        let vmdk = Vmdk::from_reader(shared).unwrap();
+        assert_eq!(1, vmdk.grain_tables.len());
+        assert_eq!(2 * GRAINS_PER_BLOCK, vmdk.allocated_grains());
+    }
+}